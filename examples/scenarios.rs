@@ -0,0 +1,633 @@
+// End-to-end scenarios against real server processes. Each scenario starts
+// the nodes it needs on local ports, drives them over the wire and checks
+// the invariants that matter for that subsystem.
+//
+//     cargo build && cargo run --example scenarios [-- <scenario>...]
+//
+// The server binary defaults to target/debug/redis-starter-rust; set
+// CREDIS_BIN to use another build. Cluster scenarios (failover, resharding)
+// belong here too once the server grows cluster support.
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(port: u16) -> Result<Client> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .map_err(|e| e.to_string())?;
+        Ok(Client {
+            reader: BufReader::new(stream),
+        })
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<Reply> {
+        let mut req = format!("*{}\r\n", args.len());
+        for arg in args {
+            req.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.reader
+            .get_mut()
+            .write_all(req.as_bytes())
+            .map_err(|e| e.to_string())?;
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Result<Reply> {
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1.min(line.len()));
+        let number = || {
+            rest.parse::<i64>()
+                .map_err(|_| format!("bad reply: {}", line))
+        };
+        match kind {
+            "+" => Ok(Reply::Simple(rest.to_string())),
+            "-" => Ok(Reply::Error(rest.to_string())),
+            ":" => Ok(Reply::Integer(number()?)),
+            "$" if number()? < 0 => Ok(Reply::Bulk(None)),
+            "$" => {
+                let mut data = vec![0; number()? as usize + 2];
+                self.reader
+                    .read_exact(&mut data)
+                    .map_err(|e| e.to_string())?;
+                data.truncate(data.len() - 2);
+                Ok(Reply::Bulk(Some(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )))
+            }
+            "*" => (0..number()?.max(0))
+                .map(|_| self.read_reply())
+                .collect::<Result<_>>()
+                .map(Reply::Array),
+            _ => Err(format!("bad reply: {}", line)),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.call(&["GET", key])? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(format!("GET {}: unexpected {:?}", key, other)),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        expect(
+            self.call(&["SET", key, value])?,
+            Reply::Simple("OK".to_string()),
+        )
+    }
+
+    fn info(&mut self, section: &str) -> Result<String> {
+        match self.call(&["INFO", section])? {
+            Reply::Bulk(Some(info)) => Ok(info),
+            other => Err(format!("INFO {}: unexpected {:?}", section, other)),
+        }
+    }
+}
+
+fn expect<T: PartialEq + std::fmt::Debug>(got: T, want: T) -> Result<()> {
+    if got == want {
+        Ok(())
+    } else {
+        Err(format!("expected {:?}, got {:?}", want, got))
+    }
+}
+
+// Polls until `f` returns `want`, for state that converges asynchronously.
+fn eventually<T, F>(mut f: F, want: T) -> Result<()>
+where
+    T: PartialEq + std::fmt::Debug,
+    F: FnMut() -> Result<T>,
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let got = f()?;
+        if got == want || Instant::now() > deadline {
+            return expect(got, want);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// A server process, killed when dropped.
+struct Node {
+    port: u16,
+    child: Child,
+}
+
+impl Node {
+    fn start(port: u16, args: &[&str]) -> Result<Node> {
+        let node = Node::spawn(port, args)?;
+        node.wait_ready()?;
+        Ok(node)
+    }
+
+    fn spawn(port: u16, args: &[&str]) -> Result<Node> {
+        let bin = env::var("CREDIS_BIN").unwrap_or("target/debug/redis-starter-rust".to_string());
+        let child = Command::new(&bin)
+            .arg("--port")
+            .arg(port.to_string())
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("failed to start {}: {}", bin, e))?;
+        Ok(Node { port, child })
+    }
+
+    fn wait_ready(&self) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if TcpStream::connect(("127.0.0.1", self.port)).is_ok() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Err(format!("node on port {} never came up", self.port))
+    }
+
+    fn client(&self) -> Result<Client> {
+        Client::connect(self.port)
+    }
+
+    // Waits for the process to exit on its own, as after a handoff.
+    fn wait_exit(mut self) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if self.child.try_wait().map_err(|e| e.to_string())?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Err(format!("node on port {} did not exit", self.port))
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("credis-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// A master with two replicas attached: every node reports the right role,
+// and both the initial snapshot and later writes reach the replicas.
+fn replication() -> Result<()> {
+    let master = Node::start(7101, &[])?;
+    let mut client = master.client()?;
+    client.set("before", "snapshot")?;
+    let replicas = [
+        Node::start(7102, &["--replicaof", "127.0.0.1 7101"])?,
+        Node::start(7103, &["--replicaof", "127.0.0.1 7101"])?,
+    ];
+
+    let info = client.info("replication")?;
+    expect(info.contains("role:master"), true)?;
+    eventually(
+        || Ok(client.info("replication")?.contains("connected_slaves:2")),
+        true,
+    )?;
+    for replica in &replicas {
+        let info = replica.client()?.info("replication")?;
+        expect(info.contains("role:slave"), true)?;
+        eventually(
+            || {
+                Ok(replica
+                    .client()?
+                    .info("replication")?
+                    .contains("master_link_status:up"))
+            },
+            true,
+        )?;
+        eventually(
+            || replica.client()?.get("before"),
+            Some("snapshot".to_string()),
+        )?;
+    }
+
+    client.set("after", "stream")?;
+    for replica in &replicas {
+        eventually(
+            || replica.client()?.get("after"),
+            Some("stream".to_string()),
+        )?;
+        expect(
+            replica.client()?.call(&["SET", "after", "diverged"])?,
+            Reply::Error("READONLY You can't write against a read only replica.".to_string()),
+        )?;
+    }
+    Ok(())
+}
+
+// Replicas that ask for a full sync within the delay share one streamed
+// snapshot, and stay in sync afterwards.
+fn diskless_sync() -> Result<()> {
+    let master = Node::start(
+        7181,
+        &[
+            "--repl-diskless-sync",
+            "yes",
+            "--repl-diskless-sync-delay",
+            "1",
+        ],
+    )?;
+    let mut client = master.client()?;
+    for i in 0..1000 {
+        client.set(&format!("key:{}", i), &"x".repeat(100))?;
+    }
+    let replicas = [
+        Node::start(7182, &["--replicaof", "127.0.0.1 7181"])?,
+        Node::start(7183, &["--replicaof", "127.0.0.1 7181"])?,
+    ];
+    eventually(
+        || Ok(client.info("replication")?.contains("connected_slaves:2")),
+        true,
+    )?;
+    client.set("after", "snapshot")?;
+    for replica in &replicas {
+        eventually(
+            || replica.client()?.get("after"),
+            Some("snapshot".to_string()),
+        )?;
+        expect(replica.client()?.get("key:999")?, Some("x".repeat(100)))?;
+    }
+    Ok(())
+}
+
+// A replica behind NAT announces the address its master should list it at.
+fn replica_announce() -> Result<()> {
+    let master = Node::start(7171, &[])?;
+    let _replica = Node::start(
+        7172,
+        &[
+            "--replicaof",
+            "127.0.0.1 7171",
+            "--replica-announce-ip",
+            "10.9.8.7",
+            "--replica-announce-port",
+            "7777",
+        ],
+    )?;
+    let mut client = master.client()?;
+    eventually(
+        || {
+            Ok(client
+                .info("replication")?
+                .contains("slave0:ip=10.9.8.7,port=7777,"))
+        },
+        true,
+    )?;
+    let role = client.call(&["ROLE"])?;
+    let Reply::Array(role) = &role else {
+        return Err(format!("ROLE: unexpected {:?}", role));
+    };
+    let [_, _, Reply::Array(replicas)] = &role[..] else {
+        return Err(format!("ROLE: unexpected {:?}", role));
+    };
+    let [Reply::Array(replica)] = &replicas[..] else {
+        return Err(format!("ROLE: unexpected replicas {:?}", replicas));
+    };
+    expect(
+        &replica[..2],
+        &[
+            Reply::Bulk(Some("10.9.8.7".to_string())),
+            Reply::Bulk(Some("7777".to_string())),
+        ],
+    )
+}
+
+// A replica serving its own replica: writes flow down the whole chain and
+// every node ends up at the same replication offset.
+fn chained_replication() -> Result<()> {
+    let master = Node::start(7131, &[])?;
+    let middle = Node::start(7132, &["--replicaof", "127.0.0.1 7131"])?;
+    let leaf = Node::start(7133, &["--replicaof", "127.0.0.1 7132"])?;
+    let mut client = master.client()?;
+    eventually(
+        || {
+            Ok(middle
+                .client()?
+                .info("replication")?
+                .contains("connected_slaves:1"))
+        },
+        true,
+    )?;
+
+    client.set("chained", "yes")?;
+    eventually(|| leaf.client()?.get("chained"), Some("yes".to_string()))?;
+
+    let offset = |node: &Node| -> Result<String> {
+        let info = node.client()?.info("replication")?;
+        Ok(info
+            .lines()
+            .find_map(|line| line.strip_prefix("master_repl_offset:"))
+            .unwrap_or_default()
+            .to_string())
+    };
+    let want = offset(&middle)?;
+    eventually(|| offset(&leaf), want)
+}
+
+// REPLICAOF at runtime: a master demoted to replica takes on the new
+// master's dataset, and NO ONE promotes it back without losing it.
+fn runtime_replicaof() -> Result<()> {
+    let master = Node::start(7141, &[])?;
+    let other = Node::start(7142, &[])?;
+    master.client()?.set("from", "master")?;
+    let mut client = other.client()?;
+    client.set("local", "only")?;
+
+    expect(
+        client.call(&["REPLICAOF", "127.0.0.1", "7141"])?,
+        Reply::Simple("OK".to_string()),
+    )?;
+    eventually(|| other.client()?.get("from"), Some("master".to_string()))?;
+    expect(client.get("local")?, None)?;
+
+    expect(
+        client.call(&["REPLICAOF", "NO", "ONE"])?,
+        Reply::Simple("OK".to_string()),
+    )?;
+    client.set("local", "again")?;
+    expect(client.get("from")?, Some("master".to_string()))?;
+    expect(client.info("replication")?.contains("role:master"), true)
+}
+
+// FAILOVER hands the master role to a caught-up replica: the old master
+// follows it, and writes to the new master reach every node.
+fn failover() -> Result<()> {
+    let old = Node::start(7161, &[])?;
+    let new = Node::start(7162, &["--replicaof", "127.0.0.1 7161"])?;
+    let other = Node::start(7163, &["--replicaof", "127.0.0.1 7161"])?;
+    let mut client = old.client()?;
+    eventually(
+        || Ok(client.info("replication")?.contains("connected_slaves:2")),
+        true,
+    )?;
+    client.set("before", "failover")?;
+
+    expect(
+        client.call(&["FAILOVER", "ABORT"])?,
+        Reply::Error("ERR No failover in progress.".to_string()),
+    )?;
+    expect(
+        client.call(&["FAILOVER", "TO", "127.0.0.1", "7199"])?,
+        Reply::Error("ERR FAILOVER target HOST and PORT is not a replica.".to_string()),
+    )?;
+    expect(
+        client.call(&["FAILOVER", "TO", "127.0.0.1", "7162"])?,
+        Reply::Simple("OK".to_string()),
+    )?;
+    let role = |node: &Node| -> Result<String> {
+        let info = node.client()?.info("replication")?;
+        Ok(info
+            .lines()
+            .find_map(|line| line.strip_prefix("role:"))
+            .unwrap_or_default()
+            .to_string())
+    };
+    eventually(|| role(&new), "master".to_string())?;
+    eventually(|| role(&old), "slave".to_string())?;
+    expect(
+        client.call(&["SET", "after", "old"])?,
+        Reply::Error("READONLY You can't write against a read only replica.".to_string()),
+    )?;
+
+    new.client()?.set("after", "failover")?;
+    for node in [&old, &other] {
+        eventually(|| node.client()?.get("after"), Some("failover".to_string()))?;
+        expect(node.client()?.get("before")?, Some("failover".to_string()))?;
+    }
+    Ok(())
+}
+
+// A replica whose master goes away keeps retrying, and picks the stream back
+// up once the master returns.
+fn master_reconnect() -> Result<()> {
+    let aof = scratch("reconnect.aof");
+    let aof_arg = aof.to_str().unwrap();
+    let args = ["--appendonly", "yes", "--appendfilename", aof_arg];
+    let replica = {
+        let master = Node::start(7151, &args)?;
+        let replica = Node::start(7152, &["--replicaof", "127.0.0.1 7151"])?;
+        master.client()?.set("before", "restart")?;
+        eventually(
+            || replica.client()?.get("before"),
+            Some("restart".to_string()),
+        )?;
+        replica
+    };
+    eventually(
+        || {
+            Ok(replica
+                .client()?
+                .info("replication")?
+                .contains("master_link_status:down"))
+        },
+        true,
+    )?;
+
+    let master = Node::start(7151, &args)?;
+    master.client()?.set("after", "restart")?;
+    eventually(
+        || replica.client()?.get("after"),
+        Some("restart".to_string()),
+    )?;
+    expect(
+        replica.client()?.get("before")?,
+        Some("restart".to_string()),
+    )?;
+    let _ = std::fs::remove_file(&aof);
+    Ok(())
+}
+
+// Writes logged to the AOF survive a rewrite, a hard kill and a restart.
+fn persistence_restart() -> Result<()> {
+    let aof = scratch("scenario.aof");
+    let aof_arg = aof.to_str().unwrap();
+    let args = ["--appendonly", "yes", "--appendfilename", aof_arg];
+    {
+        let node = Node::start(7111, &args)?;
+        let mut client = node.client()?;
+        for i in 0..100 {
+            client.set(&format!("key:{}", i), &i.to_string())?;
+        }
+        client.set("key:0", "overwritten")?;
+        expect(
+            client.call(&["BGREWRITEAOF"])?,
+            Reply::Simple("Background append only file rewriting started".to_string()),
+        )?;
+        client.set("key:100", "after rewrite")?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client
+            .info("persistence")?
+            .contains("aof_rewrite_in_progress:1")
+        {
+            if Instant::now() > deadline {
+                return Err("BGREWRITEAOF did not finish".to_string());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let info = client.info("persistence")?;
+        expect(info.contains("aof_last_bgrewrite_status:ok"), true)?;
+        expect(info.contains("aof_records:101"), true)?;
+    }
+
+    let node = Node::start(7111, &args)?;
+    let mut client = node.client()?;
+    expect(client.get("key:0")?, Some("overwritten".to_string()))?;
+    expect(client.get("key:99")?, Some("99".to_string()))?;
+    expect(client.get("key:100")?, Some("after rewrite".to_string()))?;
+    let info = client.info("persistence")?;
+    expect(info.contains("aof_records:101"), true)?;
+    let _ = std::fs::remove_file(&aof);
+    Ok(())
+}
+
+// A background save is what a restarted node comes back with; writes after
+// it are lost without the AOF.
+fn rdb_restart() -> Result<()> {
+    let dir = scratch("rdb");
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let dir_arg = dir.to_str().unwrap();
+    let args = ["--dir", dir_arg, "--dbfilename", "scenario.rdb"];
+    {
+        let node = Node::start(7201, &args)?;
+        let mut client = node.client()?;
+        for i in 0..100 {
+            client.set(&format!("key:{}", i), &i.to_string())?;
+        }
+        client.call(&["SET", "ttl", "soon", "PX", "60000"])?;
+        expect(
+            client.call(&["BGSAVE"])?,
+            Reply::Simple("Background saving started".to_string()),
+        )?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client
+            .info("persistence")?
+            .contains("rdb_bgsave_in_progress:1")
+        {
+            if Instant::now() > deadline {
+                return Err("BGSAVE did not finish".to_string());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let info = client.info("persistence")?;
+        expect(info.contains("rdb_last_bgsave_status:ok"), true)?;
+        expect(info.contains("rdb_changes_since_last_save:0"), true)?;
+        let Reply::Integer(saved_at) = client.call(&["LASTSAVE"])? else {
+            return Err("LASTSAVE did not return an integer".to_string());
+        };
+        expect(
+            info.contains(&format!("rdb_last_save_time:{}", saved_at)),
+            true,
+        )?;
+        client.set("unsaved", "x")?;
+    }
+
+    let node = Node::start(7201, &args)?;
+    let mut client = node.client()?;
+    expect(client.get("key:42")?, Some("42".to_string()))?;
+    expect(client.get("ttl")?, Some("soon".to_string()))?;
+    expect(client.get("unsaved")?, None)?;
+    expect(
+        client.call(&["CONFIG", "GET", "dbfilename"])?,
+        Reply::Array(vec![
+            Reply::Bulk(Some("dbfilename".to_string())),
+            Reply::Bulk(Some("scenario.rdb".to_string())),
+        ]),
+    )?;
+    expect(client.call(&["SAVE"])?, Reply::Simple("OK".to_string()))?;
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+// A replacement instance takes over the dataset and the port of a running one.
+fn warm_handoff() -> Result<()> {
+    let socket = scratch("handoff.sock");
+    let socket_arg = socket.to_str().unwrap();
+    let old = Node::start(7121, &["--handoff-socket", socket_arg])?;
+    let mut client = old.client()?;
+    client.set("session", "abc")?;
+    expect(
+        client.call(&["SET", "token", "xyz", "PX", "60000"])?,
+        Reply::Simple("OK".to_string()),
+    )?;
+
+    let mut new = Node::spawn(7121, &["--handoff-from", socket_arg])?;
+    old.wait_exit()?;
+    new.wait_ready()?;
+
+    let mut client = new.client()?;
+    expect(client.get("session")?, Some("abc".to_string()))?;
+    expect(client.get("token")?, Some("xyz".to_string()))?;
+    expect(new.child.try_wait().map_err(|e| e.to_string())?, None)?;
+    Ok(())
+}
+
+type Scenario = fn() -> Result<()>;
+
+const SCENARIOS: &[(&str, Scenario)] = &[
+    ("replication", replication),
+    ("replica-announce", replica_announce),
+    ("diskless-sync", diskless_sync),
+    ("chained-replication", chained_replication),
+    ("runtime-replicaof", runtime_replicaof),
+    ("master-reconnect", master_reconnect),
+    ("failover", failover),
+    ("persistence-restart", persistence_restart),
+    ("rdb-restart", rdb_restart),
+    ("warm-handoff", warm_handoff),
+];
+
+fn main() {
+    let selected: Vec<String> = env::args().skip(1).collect();
+    let mut failed = 0;
+    for (name, scenario) in SCENARIOS {
+        if !selected.is_empty() && !selected.iter().any(|s| s == name) {
+            continue;
+        }
+        match scenario() {
+            Ok(()) => println!("ok      {}", name),
+            Err(e) => {
+                println!("FAILED  {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::{
+    command::{self, Command, CommandError},
+    context::ConnCtx,
+    memprof,
+    protocol::Resp,
+    server::Info,
+    store::Store,
+};
+
+// --store-actor: one task runs every data command, one at a time in the
+// order connections submit them, instead of connections locking shards for
+// themselves. Clients then never contend with each other, and anything the
+// task runs back to back is atomic. Background jobs such as snapshots and
+// active expiry still lock the store as before, and EXEC and scripts still
+// hold the gate in atomic.rs for their batches.
+
+type Reply = Result<Vec<Resp>, CommandError>;
+
+struct Job {
+    cmd: Command,
+    // The submitting connection's, as it was; data commands only read it.
+    ctx: ConnCtx,
+    reply: oneshot::Sender<Reply>,
+}
+
+// How many submitted commands may wait for the task before connections
+// wait to submit more.
+const QUEUE: usize = 1024;
+
+#[derive(Clone)]
+pub struct StoreActor(mpsc::Sender<Job>);
+
+impl StoreActor {
+    pub fn spawn(cache: Arc<Store>, info: Arc<Mutex<Info>>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Job>(QUEUE);
+        tokio::spawn(async move {
+            while let Some(Job {
+                cmd,
+                mut ctx,
+                reply,
+            }) = rx.recv().await
+            {
+                let family = cmd.family();
+                let run = command::execute_command(cmd, &mut ctx, cache.clone(), info.clone());
+                // The submitter may have hung up meanwhile.
+                let _ = reply.send(memprof::tagged(family, run).await);
+            }
+        });
+        Self(tx)
+    }
+
+    pub async fn submit(&self, cmd: Command, ctx: &ConnCtx) -> Reply {
+        let (reply, replied) = oneshot::channel();
+        let job = Job {
+            cmd,
+            ctx: ctx.clone(),
+            reply,
+        };
+        // Info keeps a sender, so the task outlives every connection.
+        if self.0.send(job).await.is_err() {
+            panic!("store task stopped");
+        }
+        replied.await.expect("store task dropped a command")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::TestServer, store::Keyspace};
+
+    #[tokio::test]
+    async fn test_commands_run_on_the_store_task() {
+        let server = TestServer::new();
+        let actor = StoreActor::spawn(server.cache.clone(), server.info.clone());
+        let ctx = ConnCtx::new(1);
+        let run = async |args: &[&str]| {
+            let cmd = Command::from_resp(Resp::array(args.iter().copied())).unwrap();
+            actor.submit(cmd, &ctx).await.unwrap()
+        };
+
+        let sets: Vec<_> = (0..100)
+            .map(|i| {
+                let actor = actor.clone();
+                tokio::spawn(async move {
+                    let cmd = Command::from_resp(Resp::array(["SET", "k", &i.to_string()]));
+                    actor.submit(cmd.unwrap(), &ConnCtx::new(2)).await
+                })
+            })
+            .collect();
+        for set in sets {
+            assert_eq!(set.await.unwrap().unwrap(), vec![Resp::ok()]);
+        }
+        let last = &run(&["GET", "k"]).await[0];
+        assert!((0..100).any(|i| *last == Resp::bulk(i.to_string())));
+        assert_eq!(run(&["DEL", "k"]).await, vec![Resp::Integer(1)]);
+        assert_eq!(run(&["GET", "k"]).await, vec![Resp::Null]);
+        assert!(server.cache.lock_all().await.is_empty());
+    }
+}
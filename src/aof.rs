@@ -0,0 +1,743 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use tokio::sync::Mutex;
+use tokio_util::codec::Decoder;
+
+use crate::{
+    command::{self, Command, CommandError},
+    context::ConnCtx,
+    functions::Functions,
+    persistence::{secs, Persistence},
+    protocol::{Resp, RespCodec, RespEncoding},
+    rdb,
+    server::Info,
+    store::{Keyspace, Store},
+};
+
+// Fsyncs slower than this are logged, as a sign the disk can't keep up.
+const SLOW_FSYNC: Duration = Duration::from_secs(2);
+
+// When appends are flushed to disk: after every write, once a second from a
+// timer task, or whenever the OS gets round to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    Always,
+    Everysec,
+    No,
+}
+
+impl Fsync {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fsync::Always => "always",
+            Fsync::Everysec => "everysec",
+            Fsync::No => "no",
+        }
+    }
+}
+
+impl FromStr for Fsync {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Fsync, CommandError> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::Everysec),
+            "no" => Ok(Fsync::No),
+            _ => Err(CommandError::InvalidArguments(
+                "appendfsync must be always, everysec or no",
+            )),
+        }
+    }
+}
+
+// Append-only log of every write command. Alongside the file we keep a count
+// of how many records each key has accumulated, which tells us how much of
+// the log a rewrite would reclaim. A deleted key's count is dropped, as a
+// rewrite would reclaim every one of its records.
+//
+// A rewrite replaces the log with an RDB snapshot of the dataset, as Redis
+// does with aof-use-rdb-preamble, followed by whatever was written while the
+// snapshot was being saved.
+pub struct Aof {
+    file: File,
+    path: PathBuf,
+    records: u64,
+    key_records: HashMap<String, u64>,
+    // Writes made during a rewrite, with their keys, to go after the
+    // snapshot in the new log.
+    rewrite_buffer: Option<Vec<(Vec<u8>, Vec<String>)>>,
+    // Appends that have yet to be written in full, after a failed write.
+    pending: Vec<u8>,
+    rewrite_started: Instant,
+    last_rewrite_time: Option<Duration>,
+    last_rewrite_ok: bool,
+    // Completed rewrites since startup.
+    rewrites: u64,
+    // Bytes in the log now, and when it was opened or last rewritten.
+    size: u64,
+    base_size: u64,
+    // Whether there are appends not yet known to be on disk.
+    unsynced: bool,
+    fsync_in_progress: bool,
+    // Background fsyncs skipped because the previous one hadn't finished.
+    delayed_fsyncs: u64,
+    last_fsync_latency: Duration,
+    // For LATENCY DOCTOR: the slowest fsync, and how many were over SLOW_FSYNC.
+    max_fsync_latency: Duration,
+    slow_fsyncs: u64,
+}
+
+impl Aof {
+    pub fn open(path: &Path) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Aof {
+            file,
+            path: path.to_path_buf(),
+            records: 0,
+            key_records: HashMap::new(),
+            rewrite_buffer: None,
+            pending: Vec::new(),
+            rewrite_started: Instant::now(),
+            last_rewrite_time: None,
+            last_rewrite_ok: true,
+            rewrites: 0,
+            size,
+            base_size: size,
+            unsynced: false,
+            fsync_in_progress: false,
+            delayed_fsyncs: 0,
+            last_fsync_latency: Duration::ZERO,
+            max_fsync_latency: Duration::ZERO,
+            slow_fsyncs: 0,
+        })
+    }
+
+    // Logs a write. If it can't be written it stays pending, behind any
+    // appends already waiting, until a later append or retry gets through.
+    pub fn append(&mut self, cmd: &Resp, keys: &[String]) -> io::Result<()> {
+        let encoded = cmd.encode();
+        self.pending.extend_from_slice(&encoded);
+        self.record(keys);
+        if deletes(cmd) {
+            for key in keys {
+                self.key_records.remove(key);
+            }
+        }
+        if let Some(buffer) = &mut self.rewrite_buffer {
+            buffer.push((encoded, keys.to_vec()));
+        }
+        self.flush()
+    }
+
+    // Writes out the pending appends. What part of them did get written
+    // before a failure isn't written again.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.file.write(&self.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    self.size += n as u64;
+                    self.unsynced = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Zeroes the counters INFO reports, for CONFIG RESETSTAT.
+    pub fn reset_stats(&mut self) {
+        self.rewrites = 0;
+        self.delayed_fsyncs = 0;
+    }
+
+    // Flushes appends to disk before returning, for appendfsync always.
+    pub fn fsync(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        self.file.sync_data()?;
+        self.unsynced = false;
+        self.synced(started.elapsed());
+        Ok(())
+    }
+
+    // A handle for a background fsync, if there is anything to flush and no
+    // earlier fsync is still running.
+    fn start_background_fsync(&mut self) -> Option<io::Result<File>> {
+        if !self.unsynced {
+            return None;
+        }
+        if self.fsync_in_progress {
+            self.delayed_fsyncs += 1;
+            return None;
+        }
+        self.fsync_in_progress = true;
+        self.unsynced = false;
+        Some(self.file.try_clone())
+    }
+
+    fn finish_background_fsync(&mut self, result: io::Result<Duration>) -> io::Result<()> {
+        self.fsync_in_progress = false;
+        match result {
+            Ok(latency) => {
+                self.synced(latency);
+                Ok(())
+            }
+            Err(e) => {
+                // Try again on the next tick.
+                self.unsynced = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn synced(&mut self, latency: Duration) {
+        if latency > SLOW_FSYNC {
+            println!("AOF fsync took {}ms (disk is busy?)", latency.as_millis());
+            self.slow_fsyncs += 1;
+        }
+        self.last_fsync_latency = latency;
+        self.max_fsync_latency = self.max_fsync_latency.max(latency);
+    }
+
+    // Completes a rewrite whose snapshot of `keys` is in `temp`: appends the
+    // writes made since, then swaps it in for the current log.
+    fn finish_rewrite(&mut self, temp: &Path, keys: Vec<String>) -> io::Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let mut file = OpenOptions::new().append(true).open(temp)?;
+        for (cmd, _) in &buffer {
+            file.write_all(cmd)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(temp, &self.path)?;
+        self.size = file.metadata()?.len();
+        self.base_size = self.size;
+        self.rewrites += 1;
+        self.file = file;
+        // Covered by the snapshot, or by the buffer just written.
+        self.pending.clear();
+        self.records = 0;
+        self.key_records.clear();
+        for key in keys {
+            self.record(&[key]);
+        }
+        for (_, keys) in buffer {
+            self.record(&keys);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, keys: &[String]) {
+        self.records += 1;
+        for key in keys {
+            *self.key_records.entry(key.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    // Why the log should be rewritten now, if it should: it is at least
+    // `min_size` and has grown by `percentage` since it was last rewritten,
+    // or holds `amplification` records per key still live, if that is set.
+    pub fn rewrite_due(
+        &self,
+        percentage: u64,
+        min_size: u64,
+        amplification: u64,
+    ) -> Option<String> {
+        if self.rewrite_buffer.is_some() || self.size < min_size {
+            return None;
+        }
+        let growth = (self.size - self.base_size.min(self.size)) * 100 / self.base_size.max(1);
+        if percentage > 0 && growth >= percentage {
+            return Some(format!("{}% growth", growth));
+        }
+        let per_key = self.records / self.key_records.len().max(1) as u64;
+        if amplification > 0 && per_key >= amplification {
+            return Some(format!("{} records per live key", per_key));
+        }
+        None
+    }
+
+    // LATENCY DOCTOR's report. AOF fsyncs are the only events we time.
+    pub fn latency_doctor(&self) -> String {
+        let slowest = format!(
+            "The slowest AOF fsync took {}ms and the last {}ms.",
+            self.max_fsync_latency.as_millis(),
+            self.last_fsync_latency.as_millis()
+        );
+        if self.slow_fsyncs == 0 {
+            return format!(
+                "No AOF fsync took longer than {}ms. {}",
+                SLOW_FSYNC.as_millis(),
+                slowest
+            );
+        }
+        format!(
+            "{} AOF fsyncs took longer than {}ms. {} The disk can't keep up: \
+             move the append only file to a faster disk, or set appendfsync \
+             to everysec or no.",
+            self.slow_fsyncs,
+            SLOW_FSYNC.as_millis(),
+            slowest
+        )
+    }
+
+    // Write amplification figures for INFO persistence. Records for keys that
+    // no longer exist count towards the total but not the live set, since a
+    // rewrite would drop them entirely.
+    pub fn info(&self, keyspace: &dyn Keyspace) -> String {
+        let live = self
+            .key_records
+            .iter()
+            .filter(|(key, _)| keyspace.contains_key(key))
+            .map(|(_, records)| *records);
+        let (live_keys, live_records, max_records) = live.fold((0u64, 0u64, 0u64), |acc, n| {
+            (acc.0 + 1, acc.1 + n, acc.2.max(n))
+        });
+        let amplification = if live_keys == 0 {
+            0.0
+        } else {
+            self.records as f64 / live_keys as f64
+        };
+        let rewriting = self.rewrite_buffer.is_some();
+        let current_rewrite = rewriting.then(|| self.rewrite_started.elapsed());
+        [
+            "aof_enabled:1".to_string(),
+            format!("aof_rewrite_in_progress:{}", rewriting as u8),
+            format!("aof_rewrites:{}", self.rewrites),
+            format!("aof_last_rewrite_time_sec:{}", secs(self.last_rewrite_time)),
+            format!("aof_current_rewrite_time_sec:{}", secs(current_rewrite)),
+            format!(
+                "aof_last_bgrewrite_status:{}",
+                if self.last_rewrite_ok { "ok" } else { "err" }
+            ),
+            format!("aof_current_size:{}", self.size),
+            format!("aof_base_size:{}", self.base_size),
+            format!("aof_delayed_fsync:{}", self.delayed_fsyncs),
+            format!(
+                "aof_last_fsync_latency_ms:{}",
+                self.last_fsync_latency.as_millis()
+            ),
+            format!("aof_filename:{}", self.path.display()),
+            format!("aof_records:{}", self.records),
+            format!("aof_live_keys:{}", live_keys),
+            format!("aof_live_records:{}", live_records),
+            format!("aof_max_records_per_key:{}", max_records),
+            format!("aof_write_amplification:{:.2}", amplification),
+        ]
+        .join("\n")
+    }
+}
+
+// Whether a logged command deletes its keys, as DEL does and expiry
+// propagates.
+fn deletes(cmd: &Resp) -> bool {
+    match cmd {
+        Resp::Array(args) => matches!(
+            args.first(),
+            Some(Resp::Bulk(Some(name))) if name.eq_ignore_ascii_case("DEL") || name.eq_ignore_ascii_case("UNLINK")
+        ),
+        _ => false,
+    }
+}
+
+// Replays an existing log into the cache and returns it opened for appending.
+// A crash mid-append leaves the last command cut short; with
+// aof-load-truncated that tail is cut off the file and startup goes on with
+// everything before it, otherwise loading fails.
+pub async fn load(path: &Path, cache: Arc<Store>, info: Arc<Mutex<Info>>) -> anyhow::Result<Aof> {
+    let mut aof = Aof::open(path)?;
+    let data = std::fs::read(path)?;
+    let mut preamble = 0;
+    if data.starts_with(b"REDIS") {
+        let checksum = info.lock().await.persistence.rdbchecksum;
+        let mut cache = cache.lock_all().await;
+        let (loaded, len) = rdb::load_prefix(&data, &mut cache, checksum)?;
+        info.lock().await.functions.restore(loaded.functions)?;
+        preamble = len;
+        for (key, _) in cache.iter() {
+            aof.record(std::slice::from_ref(key));
+        }
+    }
+    let mut buf = BytesMut::from(&data[preamble..]);
+    let mut codec = RespCodec::default();
+    let mut ctx = ConnCtx::default();
+
+    let corrupt = |buf: &BytesMut| {
+        let offset = data.len() - buf.len();
+        anyhow::anyhow!("AOF {} is corrupt at byte {}", path.display(), offset)
+    };
+    loop {
+        let req = match codec.decode(&mut buf) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(_) => return Err(corrupt(&buf)),
+        };
+        let cmd = Command::from_resp(req).map_err(|_| corrupt(&buf))?;
+        let keys = cmd.keys();
+        command::execute_command(cmd, &mut ctx, cache.clone(), info.clone()).await?;
+        aof.record(&keys);
+    }
+    if !buf.is_empty() {
+        let valid = data.len() - buf.len();
+        if !info.lock().await.persistence.aof_load_truncated {
+            anyhow::bail!(
+                "AOF {} ends with a truncated command at byte {}",
+                path.display(),
+                valid
+            );
+        }
+        aof.file.set_len(valid as u64)?;
+        aof.size = valid as u64;
+        aof.base_size = aof.size;
+        println!(
+            "AOF {} ends with a truncated command; loaded anyway because aof-load-truncated is enabled, dropping the last {} bytes",
+            path.display(),
+            buf.len()
+        );
+    }
+    println!("loaded {} commands from {}", aof.records, path.display());
+    Ok(aof)
+}
+
+// Flushes the AOF once a second under appendfsync everysec. Each fsync runs
+// on a blocking thread with its own handle, so writers never wait on it; if
+// one is still running a second later, the next is skipped and counted in
+// aof_delayed_fsync. Writes or fsyncs that failed are retried every tick
+// too, as writes are refused until they go through.
+pub async fn fsync_every_second(info: Arc<Mutex<Info>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let file = {
+            let persistence = &mut info.lock().await.persistence;
+            persistence.retry_aof();
+            if persistence.appendfsync != Fsync::Everysec {
+                continue;
+            }
+            match persistence
+                .aof
+                .as_mut()
+                .and_then(Aof::start_background_fsync)
+            {
+                Some(file) => file,
+                None => continue,
+            }
+        };
+        let info = info.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let synced = match file {
+                Ok(file) => tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e))),
+                Err(e) => Err(e),
+            };
+            let persistence = &mut info.lock().await.persistence;
+            let Some(aof) = &mut persistence.aof else {
+                return;
+            };
+            match aof.finish_background_fsync(synced.map(|()| started.elapsed())) {
+                Ok(()) => persistence.aof_last_fsync_ok = true,
+                Err(e) => {
+                    println!("failed to fsync AOF: {}", e);
+                    persistence.aof_last_fsync_ok = false;
+                }
+            }
+        });
+    }
+}
+
+// BGREWRITEAOF: snapshots the dataset into a temporary file on a blocking
+// task while new writes are buffered, then swaps it in for the log.
+pub fn bgrewrite(
+    keyspace: &dyn Keyspace,
+    persistence: &mut Persistence,
+    functions: &Functions,
+    shared: Arc<Mutex<Info>>,
+) -> Result<(), CommandError> {
+    let (compression, checksum) = (persistence.rdbcompression, persistence.rdbchecksum);
+    let Some(aof) = &mut persistence.aof else {
+        return Err(CommandError::Persistence("Append only file is not enabled"));
+    };
+    if aof.rewrite_buffer.is_some() {
+        return Err(CommandError::Persistence(
+            "Background append only file rewriting already in progress",
+        ));
+    }
+    aof.rewrite_buffer = Some(vec![]);
+    aof.rewrite_started = Instant::now();
+    let snapshot = rdb::Snapshot::new(keyspace)
+        .functions(functions.codes())
+        .compression(compression)
+        .checksum(checksum);
+    let keys: Vec<String> = snapshot.keys().map(str::to_string).collect();
+    let temp = aof
+        .path
+        .with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    tokio::spawn(async move {
+        let writing = tokio::task::spawn_blocking({
+            let temp = temp.clone();
+            move || rdb::save(snapshot, &temp)
+        });
+        let written = writing.await.unwrap_or_else(|e| Err(io::Error::other(e)));
+        let mut info = shared.lock().await;
+        let Some(aof) = &mut info.persistence.aof else {
+            let _ = std::fs::remove_file(&temp);
+            return;
+        };
+        let result = written.and_then(|()| aof.finish_rewrite(&temp, keys));
+        aof.rewrite_buffer = None;
+        aof.last_rewrite_time = Some(aof.rewrite_started.elapsed());
+        aof.last_rewrite_ok = result.is_ok();
+        match result {
+            Ok(()) => println!("Background AOF rewrite finished successfully"),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp);
+                println!("background AOF rewrite failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clients::Clients, eviction::Eviction, persistence::Persistence, server::Role};
+
+    #[tokio::test]
+    async fn test_load_replays_and_counts_records_per_key() {
+        let path = std::env::temp_dir().join(format!("credis-aof-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut aof = Aof::open(&path).unwrap();
+            for value in ["1", "2", "3"] {
+                let cmd = crate::format_resp!["SET", "counter", value];
+                aof.append(&cmd, &["counter".to_string()]).unwrap();
+            }
+            let cmd = crate::format_resp!["SET", "other", "x"];
+            aof.append(&cmd, &["other".to_string()]).unwrap();
+        }
+
+        let cache = Arc::new(Store::default());
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let aof = load(&path, cache.clone(), info).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let cache = cache.lock_all().await;
+        assert_eq!(cache.get("counter").unwrap().value, "3");
+        assert_eq!(aof.records, 4);
+        assert_eq!(aof.key_records.get("counter"), Some(&3));
+        assert!(aof.info(&cache).contains("aof_write_amplification:2.00"));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_keeps_writes_made_during_it() {
+        let path = std::env::temp_dir().join(format!("credis-rewrite-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cache = Arc::new(Store::default());
+        let new_info = || {
+            Arc::new(Mutex::new(Info::new(
+                Role::Master,
+                Persistence::new(true),
+                Eviction::new(0),
+                Clients::new(10, 0),
+            )))
+        };
+        let info = new_info();
+        info.lock().await.persistence.aof = Some(Aof::open(&path).unwrap());
+        for value in ["1", "2", "3"] {
+            let cmd = crate::format_resp!["SET", "counter", value];
+            command::execute_command(
+                Command::from_resp(cmd.clone()).unwrap(),
+                &mut ConnCtx::default(),
+                cache.clone(),
+                info.clone(),
+            )
+            .await
+            .unwrap();
+            info.lock().await.propagate(&cmd, &["counter".to_string()]);
+        }
+
+        {
+            let cache = cache.lock_all().await;
+            let guard = &mut *info.lock().await;
+            let (persistence, functions) = (&mut guard.persistence, &guard.functions);
+            bgrewrite(&cache, persistence, functions, info.clone()).unwrap();
+            assert!(bgrewrite(&cache, persistence, functions, info.clone()).is_err());
+            // Not yet in the snapshot, so it must come from the buffer.
+            let cmd = crate::format_resp!["SET", "late", "x"];
+            guard.propagate(&cmd, &["late".to_string()]);
+        }
+        while info
+            .lock()
+            .await
+            .persistence
+            .aof
+            .as_ref()
+            .unwrap()
+            .rewrite_buffer
+            .is_some()
+        {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let status = info.lock().await.persistence.info(&cache.lock_all().await);
+        assert!(status.contains("aof_last_bgrewrite_status:ok"));
+        assert!(status.contains("aof_records:2"));
+        assert!(status.contains("aof_rewrites:1"));
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert!(status.contains(&format!(
+            "aof_current_size:{}\naof_base_size:{}",
+            size, size
+        )));
+
+        let replayed = Arc::new(Store::default());
+        let aof = load(&path, replayed.clone(), new_info()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let replayed = replayed.lock_all().await;
+        assert_eq!(replayed["counter"].value, "3");
+        assert_eq!(replayed["late"].value, "x");
+        assert_eq!(aof.records, 2);
+    }
+
+    #[tokio::test]
+    async fn test_load_drops_truncated_tail_only_when_allowed() {
+        let path =
+            std::env::temp_dir().join(format!("credis-truncated-{}.aof", std::process::id()));
+        let mut data = crate::format_resp!["SET", "a", "1"].encode();
+        let complete = data.len() as u64;
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        let new_info = |truncated| {
+            let mut persistence = Persistence::new(true);
+            persistence.aof_load_truncated = truncated;
+            Arc::new(Mutex::new(Info::new(
+                Role::Master,
+                persistence,
+                Eviction::new(0),
+                Clients::new(10, 0),
+            )))
+        };
+
+        std::fs::write(&path, &data).unwrap();
+        let cache = Arc::new(Store::default());
+        assert!(load(&path, cache.clone(), new_info(false)).await.is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+
+        let aof = load(&path, cache.clone(), new_info(true)).await.unwrap();
+        assert_eq!(aof.records, 1);
+        assert_eq!(cache.lock_all().await["a"].value, "1");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+
+        std::fs::write(&path, b"*1\r\n:5\r\n").unwrap();
+        let Err(err) = load(&path, cache, new_info(true)).await else {
+            panic!("Expected a corrupt AOF to fail loading");
+        };
+        assert!(err.to_string().contains("corrupt at byte"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deleted_keys_are_forgotten_and_amplification_triggers_rewrites() {
+        let path = std::env::temp_dir().join(format!("credis-amp-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut aof = Aof::open(&path).unwrap();
+        let key = |k: &str| vec![k.to_string()];
+        for i in 0..10 {
+            aof.append(&crate::format_resp!["SET", "hot", i], &key("hot"))
+                .unwrap();
+        }
+        aof.append(&crate::format_resp!["SET", "gone", "x"], &key("gone"))
+            .unwrap();
+        aof.append(&crate::format_resp!["DEL", "gone"], &key("gone"))
+            .unwrap();
+        assert_eq!(aof.key_records.keys().collect::<Vec<_>>(), ["hot"]);
+
+        // Twelve records, the DEL included, for the one live key. Neither
+        // trigger fires while both are off or the log is under the minimum
+        // size.
+        assert_eq!(aof.rewrite_due(0, 0, 0), None);
+        assert_eq!(aof.rewrite_due(0, aof.size + 1, 10), None);
+        assert_eq!(
+            aof.rewrite_due(0, 0, 10).as_deref(),
+            Some("12 records per live key")
+        );
+        assert!(aof.rewrite_due(100, 0, 0).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_appends_are_retried_until_they_succeed() {
+        // Every write to /dev/full fails for lack of space.
+        let Ok(full) = Aof::open(Path::new("/dev/full")) else {
+            return;
+        };
+        let mut persistence = Persistence::new(true);
+        persistence.appendfsync = Fsync::Always;
+        persistence.aof = Some(full);
+        let set = crate::format_resp!["SET", "k", "v"];
+        persistence.record_write(&set, &["k".to_string()]);
+        assert!(persistence.write_error().is_some());
+        persistence.retry_aof();
+        assert!(persistence.write_error().is_some());
+
+        // The disk has room again.
+        let path = std::env::temp_dir().join(format!("credis-retry-{}.aof", std::process::id()));
+        let aof = persistence.aof.as_mut().unwrap();
+        aof.file = File::create(&path).unwrap();
+        persistence.retry_aof();
+        assert!(persistence.write_error().is_none());
+        assert_eq!(std::fs::read(&path).unwrap(), set.encode());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_background_fsync_skips_while_one_is_running() {
+        assert_eq!("EVERYSEC".parse::<Fsync>().unwrap(), Fsync::Everysec);
+        assert!("sometimes".parse::<Fsync>().is_err());
+
+        let path = std::env::temp_dir().join(format!("credis-fsync-{}.aof", std::process::id()));
+        let mut aof = Aof::open(&path).unwrap();
+        assert!(aof.start_background_fsync().is_none());
+
+        let cmd = crate::format_resp!["SET", "k", "v"];
+        aof.append(&cmd, &["k".to_string()]).unwrap();
+        assert!(aof.start_background_fsync().is_some());
+        aof.append(&cmd, &["k".to_string()]).unwrap();
+        assert!(aof.start_background_fsync().is_none());
+        assert_eq!(aof.delayed_fsyncs, 1);
+
+        let failed = aof.finish_background_fsync(Err(io::Error::other("disk gone")));
+        assert!(failed.is_err());
+        assert!(aof.start_background_fsync().is_some());
+        aof.finish_background_fsync(Ok(Duration::from_millis(3)))
+            .unwrap();
+        assert!(aof.start_background_fsync().is_none());
+        assert!(aof
+            .info(&HashMap::new())
+            .contains("aof_last_fsync_latency_ms:3"));
+        assert!(aof.latency_doctor().starts_with("No AOF fsync took longer"));
+        aof.synced(Duration::from_secs(3));
+        assert!(aof.latency_doctor().starts_with(
+            "1 AOF fsyncs took longer than 2000ms. The slowest AOF fsync took 3000ms"
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
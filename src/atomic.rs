@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// What makes a batch of commands atomic, whether EXEC's queue or a script
+// and what it calls: nothing else reads or changes the dataset until the
+// batch is done. Shard locks only cover one command at a time, so a batch
+// holds this gate exclusively instead, and everything else that touches the
+// dataset holds it shared: every command a connection runs, the commands a
+// replica applies from its master, and background jobs such as active
+// expiry and save-point snapshots. A batch waits for those already running
+// and holds back the rest. Take it before any shard lock or Info.
+#[derive(Clone, Default)]
+pub struct Gate(Arc<RwLock<()>>);
+
+impl Gate {
+    // For one command, or one background pass over the dataset.
+    pub async fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.read().await
+    }
+
+    // For a batch. The commands in it run without taking the gate again.
+    pub async fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.0.write().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_batches_exclude_everything_else() {
+        let gate = Gate::default();
+        let wait = Duration::from_millis(50);
+
+        let first = gate.shared().await;
+        let second = gate.shared().await;
+        assert!(timeout(wait, gate.exclusive()).await.is_err());
+        drop((first, second));
+
+        let batch = gate.exclusive().await;
+        assert!(timeout(wait, gate.shared()).await.is_err());
+        assert!(timeout(wait, gate.clone().exclusive()).await.is_err());
+        drop(batch);
+        assert!(timeout(wait, gate.shared()).await.is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use crate::protocol::{Resp, RespCodec};
+
+// `credis bench`: a small redis-benchmark. Clients share one request
+// counter, each claiming a pipeline's worth of requests at a time, sending
+// them in one write and waiting for every reply. Each request's latency is
+// that of the round trip it was part of, as redis-benchmark counts it.
+
+/// Measure a server's throughput and latency
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Server to benchmark
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    #[arg(long, default_value_t = 6379)]
+    port: u16,
+
+    /// Parallel connections
+    #[arg(short, long, default_value_t = 50)]
+    clients: usize,
+
+    /// Total requests, across all connections
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    requests: usize,
+
+    /// Requests sent per round trip
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+
+    /// Size of SET values, in bytes
+    #[arg(short = 'd', long, default_value_t = 3)]
+    data_size: usize,
+
+    /// Distinct keys that SET, GET and DEL pick from
+    #[arg(short = 'r', long, default_value_t = 10_000)]
+    keyspace: usize,
+
+    /// Commands to send, with their weights, e.g. "set:1,get:9"
+    #[arg(long, default_value = "set:1,get:1")]
+    mix: Mix,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Ping,
+    Set,
+    Get,
+    Del,
+}
+
+// The commands to send, each repeated as often as its weight, so request
+// `i` sends the `i % len`th.
+#[derive(Clone, Debug)]
+pub struct Mix(Vec<(Op, usize)>);
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ops = Vec::new();
+        for entry in s.split(',') {
+            let (name, weight) = entry.split_once(':').unwrap_or((entry, "1"));
+            let op = match name.trim().to_lowercase().as_str() {
+                "ping" => Op::Ping,
+                "set" => Op::Set,
+                "get" => Op::Get,
+                "del" => Op::Del,
+                _ => return Err(format!("unknown command '{}'", name)),
+            };
+            let weight = weight
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|weight| (1..=100).contains(weight))
+                .ok_or_else(|| format!("weight of {} must be 1 to 100", name))?;
+            ops.push((op, weight));
+        }
+        Ok(Mix(ops))
+    }
+}
+
+impl fmt::Display for Mix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: usize = self.0.iter().map(|(_, weight)| weight).sum();
+        let shares: Vec<String> = self
+            .0
+            .iter()
+            .map(|(op, weight)| format!("{:?} {}%", op, weight * 100 / total).to_uppercase())
+            .collect();
+        f.write_str(&shares.join(", "))
+    }
+}
+
+// What every connection sends: the mix laid out in full, and the payload.
+struct Plan {
+    ops: Vec<Op>,
+    keyspace: usize,
+    value: String,
+}
+
+impl Plan {
+    fn new(args: &Args) -> Self {
+        let ops = args
+            .mix
+            .0
+            .iter()
+            .flat_map(|(op, weight)| std::iter::repeat_n(*op, *weight))
+            .collect();
+        Self {
+            ops,
+            keyspace: args.keyspace.max(1),
+            value: "x".repeat(args.data_size),
+        }
+    }
+
+    fn request(&self, i: usize) -> Resp {
+        // Scattered over the keyspace, but the same keys on every run.
+        let key = format!("key:{:012}", i.wrapping_mul(2_654_435_761) % self.keyspace);
+        match self.ops[i % self.ops.len()] {
+            Op::Ping => Resp::array(["PING"]),
+            Op::Set => Resp::array(["SET", &key, &self.value]),
+            Op::Get => Resp::array(["GET", &key]),
+            Op::Del => Resp::array(["DEL", &key]),
+        }
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.clients == 0 || args.pipeline == 0 {
+        bail!("--clients and --pipeline must be at least 1");
+    }
+    let plan = Arc::new(Plan::new(&args));
+    let claimed = Arc::new(AtomicUsize::new(0));
+    let mut connections = Vec::with_capacity(args.clients);
+    for _ in 0..args.clients {
+        let stream = TcpStream::connect((args.host.as_str(), args.port))
+            .await
+            .map_err(|e| anyhow!("failed to connect to {}:{}: {}", args.host, args.port, e))?;
+        connections.push(stream);
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = connections
+        .into_iter()
+        .map(|stream| {
+            let (plan, claimed) = (plan.clone(), claimed.clone());
+            let (total, pipeline) = (args.requests, args.pipeline);
+            tokio::spawn(drive(stream, plan, claimed, total, pipeline))
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(args.requests);
+    let mut errors = 0;
+    for task in tasks {
+        let (mut latency, failed) = task.await??;
+        latencies.append(&mut latency);
+        errors += failed;
+    }
+    let elapsed = start.elapsed();
+
+    println!("====== {} ======", args.mix);
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "  {} parallel clients, pipeline {}, {} byte payloads",
+        args.clients, args.pipeline, args.data_size
+    );
+    if errors > 0 {
+        println!("  {} error replies", errors);
+    }
+    println!(
+        "throughput: {:.2} requests per second",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("latency (msec): {}", Summary::new(latencies));
+    Ok(())
+}
+
+// Sends batches of requests until all of them are claimed. Returns the
+// latency of each request sent and how many got error replies.
+async fn drive(
+    stream: TcpStream,
+    plan: Arc<Plan>,
+    claimed: Arc<AtomicUsize>,
+    total: usize,
+    pipeline: usize,
+) -> anyhow::Result<(Vec<Duration>, usize)> {
+    let mut framed = Framed::new(stream, RespCodec::default());
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    loop {
+        let first = claimed.fetch_add(pipeline, Ordering::Relaxed);
+        if first >= total {
+            return Ok((latencies, errors));
+        }
+        let batch = first..total.min(first + pipeline);
+        let sent = Instant::now();
+        for i in batch.clone() {
+            framed.feed(plan.request(i)).await?;
+        }
+        SinkExt::<Resp>::flush(&mut framed).await?;
+        for _ in batch.clone() {
+            match framed.next().await {
+                Some(Ok(Resp::SimpleError(_))) => errors += 1,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!("server closed the connection"),
+            }
+        }
+        let latency = sent.elapsed();
+        latencies.extend(batch.map(|_| latency));
+    }
+}
+
+struct Summary {
+    sorted: Vec<Duration>,
+}
+
+impl Summary {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort_unstable();
+        Self { sorted: latencies }
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let rank = ((self.sorted.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.sorted[rank]
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sorted.is_empty() {
+            return f.write_str("no requests");
+        }
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let avg = self.sorted.iter().sum::<Duration>() / self.sorted.len() as u32;
+        write!(
+            f,
+            "avg {:.3} p50 {:.3} p95 {:.3} p99 {:.3} max {:.3}",
+            ms(avg),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(95.0)),
+            ms(self.percentile(99.0)),
+            ms(self.percentile(100.0)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_and_plan() {
+        let args = Args::parse_from(["bench", "--mix", "get:3,set", "-r", "5", "-d", "2"]);
+        assert_eq!(args.mix.to_string(), "GET 75%, SET 25%");
+        let plan = Plan::new(&args);
+        let requests: Vec<Resp> = (0..5).map(|i| plan.request(i)).collect();
+        assert!(matches!(&requests[3], Resp::Array(args) if args.len() == 3));
+        assert_eq!(plan.request(0), plan.request(20));
+        assert!("get:0".parse::<Mix>().is_err());
+        assert!("flushall".parse::<Mix>().is_err());
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        let summary = Summary::new((1..=100).rev().map(Duration::from_millis).collect());
+        assert_eq!(summary.percentile(50.0), Duration::from_millis(51));
+        assert_eq!(summary.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(
+            summary.to_string(),
+            "avg 50.500 p50 51.000 p95 95.000 p99 99.000 max 100.000"
+        );
+    }
+}
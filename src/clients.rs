@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Notify,
+};
+
+use crate::{
+    obuf::{Limit, Output, OutputLimits},
+    protocol::Resp,
+};
+
+// Out-of-band instructions delivered to a connection task.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Kill,
+    // A pub/sub message for the connection to write out.
+    Message(Resp),
+}
+
+// How pub/sub subscriptions deliver to a connection: through its control
+// channel, counting each message against its output buffer limit.
+#[derive(Clone)]
+pub struct Mailbox {
+    pub control: UnboundedSender<Control>,
+    pub output: Arc<Output>,
+}
+
+impl Mailbox {
+    // Queues a message that encodes to `size` bytes, or tells the
+    // connection to close if that takes it over its limit. False once it
+    // has been told to, or has gone away.
+    pub fn deliver(&self, message: Resp, size: usize) -> bool {
+        if !self.output.queue(size) {
+            let _ = self.control.send(Control::Kill);
+            return false;
+        }
+        self.control.send(Control::Message(message)).is_ok()
+    }
+}
+
+struct Client {
+    addr: SocketAddr,
+    laddr: SocketAddr,
+    control: UnboundedSender<Control>,
+    // Set once the connection has turned into a replica with PSYNC.
+    replica: bool,
+    name: Option<String>,
+    connected: Instant,
+    activity: Arc<Activity>,
+    // CLIENT NO-EVICT and NO-TOUCH.
+    no_evict: bool,
+    no_touch: bool,
+    output: Arc<Output>,
+}
+
+// When a client last sent a command, and which. Its connection records
+// these itself, under their own lock rather than the registry's.
+pub struct Activity(std::sync::Mutex<(Instant, &'static str)>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self(std::sync::Mutex::new((Instant::now(), "NULL")))
+    }
+}
+
+impl Activity {
+    // `cmd` is the command table's name for it, as CLIENT LIST shows it.
+    pub fn record(&self, cmd: &'static str) {
+        *self.0.lock().unwrap() = (Instant::now(), cmd);
+    }
+
+    fn last(&self) -> (Instant, &'static str) {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Client {
+    fn flags(&self) -> String {
+        let flags: String = [
+            (self.replica, 'S'),
+            (self.no_evict, 'e'),
+            (self.no_touch, 'T'),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, flag)| flag)
+        .collect();
+        if flags.is_empty() {
+            "N".to_string()
+        } else {
+            flags
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum KillFilter {
+    Id(u64),
+    Addr(String),
+    LocalAddr(String),
+    Type(ClientType),
+}
+
+// CLIENT KILL TYPE, and the classes of client-output-buffer-limit. Our own
+// link to a master never shows up in the registry, so Master matches
+// nothing. Connections with subscriptions are Pubsub.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientType {
+    Normal,
+    Master,
+    Replica,
+    Pubsub,
+}
+
+impl std::str::FromStr for ClientType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "normal" => Ok(ClientType::Normal),
+            "master" => Ok(ClientType::Master),
+            "replica" | "slave" => Ok(ClientType::Replica),
+            "pubsub" => Ok(ClientType::Pubsub),
+            _ => Err(()),
+        }
+    }
+}
+
+// A CLIENT PAUSE in effect: until when, and whether reads still go ahead.
+struct Pause {
+    until: Instant,
+    writes_only: bool,
+}
+
+// Registry of open client connections. Normal clients are capped at
+// `maxclients`; local (admin) connections may use `reserved_admin` extra
+// slots so operators can always get in during an incident.
+pub struct Clients {
+    pub maxclients: usize,
+    pub reserved_admin: usize,
+    next_id: u64,
+    clients: HashMap<u64, Client>,
+    pause: Option<Pause>,
+    // Set while a pause may be in effect, so connections can skip checking
+    // for one without taking the lock Clients lives under.
+    pausing: Arc<AtomicBool>,
+    // Woken when a pause is lifted early with CLIENT UNPAUSE.
+    unpaused: Arc<Notify>,
+    // Connections accepted, and those turned away for lack of a slot.
+    total_connections: u64,
+    rejected_connections: u64,
+    // client-output-buffer-limit, shared with every connection's Output.
+    output_limits: Arc<Mutex<OutputLimits>>,
+}
+
+impl Clients {
+    pub fn new(maxclients: usize, reserved_admin: usize) -> Self {
+        Self {
+            maxclients,
+            reserved_admin,
+            next_id: 1,
+            clients: HashMap::new(),
+            pause: None,
+            pausing: Arc::default(),
+            unpaused: Arc::new(Notify::new()),
+            total_connections: 0,
+            rejected_connections: 0,
+            output_limits: Arc::default(),
+        }
+    }
+
+    // Registers a new connection, returning its id and control channel, or
+    // None if there is no slot left for it.
+    pub fn admit(
+        &mut self,
+        addr: SocketAddr,
+        laddr: SocketAddr,
+    ) -> Option<(u64, UnboundedReceiver<Control>)> {
+        let limit = if addr.ip().is_loopback() {
+            self.maxclients + self.reserved_admin
+        } else {
+            self.maxclients
+        };
+        if self.clients.len() >= limit {
+            self.rejected_connections += 1;
+            return None;
+        }
+        self.total_connections += 1;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let (control, rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        let client = Client {
+            addr,
+            laddr,
+            control,
+            replica: false,
+            name: None,
+            connected: now,
+            activity: Arc::default(),
+            no_evict: false,
+            no_touch: false,
+            output: Arc::new(Output::new(self.output_limits.clone())),
+        };
+        self.clients.insert(id, client);
+        Some((id, rx))
+    }
+
+    // The Clients section of INFO.
+    pub fn info(&self) -> String {
+        format!(
+            "# Clients\nconnected_clients:{}\nmaxclients:{}",
+            self.clients.len(),
+            self.maxclients
+        )
+    }
+
+    // Connection counters for the Stats section of INFO.
+    pub fn stats(&self) -> String {
+        format!(
+            "total_connections_received:{}\nrejected_connections:{}",
+            self.total_connections, self.rejected_connections
+        )
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.total_connections = 0;
+        self.rejected_connections = 0;
+    }
+
+    pub fn set_replica(&mut self, id: u64) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.replica = true;
+            client.output.set_class(ClientType::Replica);
+        }
+    }
+
+    pub fn output_limits(&self) -> OutputLimits {
+        *self.output_limits.lock().unwrap()
+    }
+
+    pub fn set_output_limit(&mut self, class: ClientType, limit: Limit) {
+        self.output_limits.lock().unwrap().set(class, limit);
+    }
+
+    pub fn set_name(&mut self, id: u64, name: Option<String>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.name = name;
+        }
+    }
+
+    pub fn set_no_evict(&mut self, id: u64, on: bool) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.no_evict = on;
+        }
+    }
+
+    pub fn set_no_touch(&mut self, id: u64, on: bool) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.no_touch = on;
+        }
+    }
+
+    // Where connection `id` records its commands, for CLIENT LIST's idle
+    // and cmd.
+    pub fn activity(&self, id: u64) -> Option<Arc<Activity>> {
+        self.clients.get(&id).map(|client| client.activity.clone())
+    }
+
+    // Connection `id`'s count of output waiting to be written.
+    pub fn output(&self, id: u64) -> Option<Arc<Output>> {
+        self.clients.get(&id).map(|client| client.output.clone())
+    }
+
+    pub fn pausing(&self) -> Arc<AtomicBool> {
+        self.pausing.clone()
+    }
+
+    // CLIENT LIST: one line per connection, oldest first, with ages in
+    // seconds. Flags use Redis's letters: S for a replica, e for no-evict,
+    // T for no-touch, or N for none of them. omem is the output it has yet
+    // to write, in bytes.
+    pub fn list(&self) -> String {
+        let now = Instant::now();
+        let mut ids: Vec<_> = self.clients.keys().copied().collect();
+        ids.sort_unstable();
+        ids.iter()
+            .map(|id| {
+                let client = &self.clients[id];
+                let (last_interaction, last_cmd) = client.activity.last();
+                format!(
+                    "id={} addr={} laddr={} name={} age={} idle={} flags={} omem={} cmd={}\n",
+                    id,
+                    client.addr,
+                    client.laddr,
+                    client.name.as_deref().unwrap_or(""),
+                    now.duration_since(client.connected).as_secs(),
+                    now.duration_since(last_interaction).as_secs(),
+                    client.flags(),
+                    client.output.pending(),
+                    last_cmd,
+                )
+            })
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    // Whether connection `id` matches every one of `filters`.
+    pub fn matches(&self, id: u64, filters: &[KillFilter]) -> bool {
+        let Some(client) = self.clients.get(&id) else {
+            return false;
+        };
+        filters.iter().all(|filter| match filter {
+            KillFilter::Id(target) => id == *target,
+            KillFilter::Addr(addr) => client.addr.to_string() == *addr,
+            KillFilter::LocalAddr(laddr) => client.laddr.to_string() == *laddr,
+            KillFilter::Type(class) => client.output.class() == *class,
+        })
+    }
+
+    // Where pub/sub subscriptions deliver to connection `id`.
+    pub fn mailbox(&self, id: u64) -> Option<Mailbox> {
+        self.clients.get(&id).map(|client| Mailbox {
+            control: client.control.clone(),
+            output: client.output.clone(),
+        })
+    }
+
+    // Signals every matching connection to close and returns how many were hit.
+    pub fn kill(&self, filters: &[KillFilter]) -> usize {
+        self.clients
+            .keys()
+            .filter(|id| self.matches(**id, filters))
+            .filter(|id| self.clients[id].control.send(Control::Kill).is_ok())
+            .count()
+    }
+
+    // CLIENT PAUSE: holds client commands, or only writes, for `timeout`.
+    // A new pause replaces the last one, as in Redis.
+    pub fn pause(&mut self, timeout: Duration, writes_only: bool) {
+        self.pause = Some(Pause {
+            until: Instant::now() + timeout,
+            writes_only,
+        });
+        self.pausing.store(true, Ordering::Release);
+    }
+
+    pub fn unpause(&mut self) {
+        self.pause = None;
+        self.pausing.store(false, Ordering::Release);
+        self.unpaused.notify_waiters();
+    }
+
+    // If a command must wait for a pause, when the pause ends and how to
+    // hear about it ending early.
+    pub fn paused(&self, is_write: bool) -> Option<(Instant, Arc<Notify>)> {
+        let pause = self.pause.as_ref()?;
+        if pause.until <= Instant::now() {
+            // Over, so connections can stop checking.
+            self.pausing.store(false, Ordering::Release);
+            return None;
+        }
+        if pause.writes_only && !is_write {
+            return None;
+        }
+        Some((pause.until, self.unpaused.clone()))
+    }
+
+    // Whether the dataset must not change behind paused clients' backs, so
+    // expired keys are left in place until the pause ends.
+    pub fn writes_paused(&self) -> bool {
+        self.paused(true).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laddr() -> SocketAddr {
+        "127.0.0.1:6379".parse().unwrap()
+    }
+
+    #[test]
+    fn test_local_clients_get_reserved_slots() {
+        let mut clients = Clients::new(1, 1);
+        let remote: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        assert!(clients.admit(remote, laddr()).is_some());
+        assert!(clients.admit(remote, laddr()).is_none());
+        assert!(clients.admit(local, laddr()).is_some());
+        assert!(clients.admit(local, laddr()).is_none());
+        assert_eq!(
+            clients.stats(),
+            "total_connections_received:2\nrejected_connections:2"
+        );
+        assert!(clients.info().contains("connected_clients:2\nmaxclients:1"));
+    }
+
+    #[test]
+    fn test_kill_signals_matching_connection() {
+        let mut clients = Clients::new(10, 0);
+        let (id, mut rx) = clients
+            .admit("10.0.0.1:5000".parse().unwrap(), laddr())
+            .unwrap();
+        let (_, mut other) = clients
+            .admit("10.0.0.2:5000".parse().unwrap(), laddr())
+            .unwrap();
+
+        assert_eq!(
+            clients.kill(&[KillFilter::Addr("10.0.0.1:5000".to_string())]),
+            1
+        );
+        assert_eq!(rx.try_recv().unwrap(), Control::Kill);
+        assert!(other.try_recv().is_err());
+        assert_eq!(clients.kill(&[KillFilter::Id(id + 100)]), 0);
+
+        clients.set_replica(id);
+        let replicas = [
+            KillFilter::Type(ClientType::Replica),
+            KillFilter::LocalAddr("127.0.0.1:6379".to_string()),
+        ];
+        assert_eq!(clients.kill(&replicas), 1);
+        assert_eq!(rx.try_recv().unwrap(), Control::Kill);
+        assert!(other.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_list_shows_flags() {
+        let mut clients = Clients::new(10, 0);
+        let (id, _rx) = clients
+            .admit("10.0.0.1:5000".parse().unwrap(), laddr())
+            .unwrap();
+        assert!(clients.list().contains(" flags=N "));
+        clients.set_no_evict(id, true);
+        clients.set_no_touch(id, true);
+        assert!(clients.list().contains(" flags=eT "));
+        clients.set_no_evict(id, false);
+        assert!(clients.list().contains(" flags=T "));
+    }
+
+    #[test]
+    fn test_pause_holds_writes_or_everything() {
+        let mut clients = Clients::new(10, 0);
+        clients.pause(Duration::from_secs(60), true);
+        assert!(clients.paused(false).is_none());
+        assert!(clients.paused(true).is_some());
+        assert!(clients.writes_paused());
+
+        clients.pause(Duration::from_secs(60), false);
+        assert!(clients.paused(false).is_some());
+        assert!(clients.pausing().load(Ordering::Acquire));
+        clients.unpause();
+        assert!(clients.paused(true).is_none());
+        assert!(!clients.pausing().load(Ordering::Acquire));
+
+        clients.pause(Duration::ZERO, false);
+        assert!(clients.paused(true).is_none());
+        assert!(!clients.pausing().load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_list_shows_names_and_last_command() {
+        let mut clients = Clients::new(10, 0);
+        let (first, _rx) = clients
+            .admit("10.0.0.1:5000".parse().unwrap(), laddr())
+            .unwrap();
+        let (second, _other) = clients
+            .admit("10.0.0.2:5000".parse().unwrap(), laddr())
+            .unwrap();
+        clients.set_name(second, Some("worker".to_string()));
+        clients.activity(second).unwrap().record("get");
+
+        assert_eq!(
+            clients.list(),
+            format!(
+                "id={} addr=10.0.0.1:5000 laddr=127.0.0.1:6379 name= age=0 idle=0 flags=N omem=0 cmd=NULL\n\
+                 id={} addr=10.0.0.2:5000 laddr=127.0.0.1:6379 name=worker age=0 idle=0 flags=N omem=0 cmd=get\n",
+                first, second
+            )
+        );
+    }
+}
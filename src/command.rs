@@ -1,140 +1,635 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashSet},
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 
-use crate::{protocol::Resp, server::Query};
+use crate::{
+    aof,
+    clients::{ClientType, KillFilter},
+    command_table::{self, CommandSpec},
+    config,
+    context::ConnCtx,
+    eviction, expire, failover,
+    functions::{self, Fcall, Library},
+    glob::glob_match,
+    memprof, persistence,
+    protocol::{BulkString, Protocol, Resp},
+    pubsub::{self, Kind},
+    rdb, replica,
+    scripting::{self, Called, Eval, Source},
+    server::{HostSpec, Query},
+    shutdown,
+    store::{Keyspace, Store},
+    value::Value,
+};
 
 #[derive(Debug, Clone)]
 pub enum Command {
-    Echo(String),
+    Echo(BulkString),
     Ping,
-    Get(String),
-    Set(String, String, Option<u64>), // <KEY> <VALUE> <TIMEOUT>
+    Get(BulkString),
+    Set(BulkString, BulkString, Option<SystemTime>), // <KEY> <VALUE> <EXPIRY>
+    Del(Vec<BulkString>),
     Info(Option<String>),
     Replconf(ReplconfArgs),
     Psync(PsyncArgs),
+    Hello(HelloArgs),
+    Memory(MemoryArgs),
+    // OBJECT REFCOUNT <key>.
+    ObjectRefcount(String),
+    Vscan(VscanArgs),
+    Client(ClientArgs),
+    // None is `REPLICAOF NO ONE`.
+    ReplicaOf(Option<HostSpec>),
+    Failover(FailoverArgs),
+    Role,
+    // CONFIG GET with its glob patterns.
+    ConfigGet(Vec<String>),
+    // CONFIG SET with its parameter, value pairs.
+    ConfigSet(Vec<(String, String)>),
+    ConfigResetstat,
+    ConfigRewrite,
+    Save,
+    Bgsave,
+    Bgrewriteaof,
+    Lastsave,
+    // LATENCY DOCTOR.
+    LatencyDoctor,
+    Lolwut,
+    Debug(DebugArgs),
+    Commands(CommandArgs),
+    // SHUTDOWN, with SAVE or NOSAVE if given.
+    Shutdown(Option<bool>),
+    // SUBSCRIBE and PSUBSCRIBE, with their channels or patterns.
+    Subscribe(Kind, Vec<String>),
+    // UNSUBSCRIBE and PUNSUBSCRIBE. None given means all of them.
+    Unsubscribe(Kind, Vec<String>),
+    // PUBLISH <channel> <message>.
+    Publish(String, BulkString),
+    Pubsub(PubsubArgs),
+    // Transactions, which connection handlers queue and run themselves.
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+    // QUIT and RESET, which connection handlers also run themselves.
+    Quit,
+    Reset,
+    // EVAL and EVALSHA.
+    Eval(Eval),
+    Script(ScriptArgs),
+    Function(FunctionArgs),
+    Fcall(Fcall),
+}
+
+#[derive(Debug, Clone)]
+pub enum PubsubArgs {
+    // Active channels, matching the pattern if given.
+    Channels(Option<String>),
+    Numsub(Vec<String>),
+    Numpat,
+}
+
+#[derive(Debug, Clone)]
+pub enum ScriptArgs {
+    Load(BulkString),
+    Exists(Vec<String>),
+    Flush,
+}
+
+#[derive(Debug, Clone)]
+pub enum FunctionArgs {
+    Load {
+        code: String,
+        replace: bool,
+    },
+    Delete(String),
+    Flush,
+    List {
+        pattern: Option<String>,
+        with_code: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum CommandArgs {
+    // Every command, as COMMAND with no arguments.
+    All,
+    Count,
+    Info(Vec<String>),
+    // DOCS with no names documents every command.
+    Docs(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub enum DebugArgs {
+    // Saves the dataset and loads it back, unless told not to save it or
+    // not to empty the keyspace first.
+    Reload { save: bool, flush: bool },
+    // Holds the keyspace for this long, blocking every command that needs it.
+    Sleep(Duration),
+    // Describes how a key's value is stored.
+    Object(String),
+    // Turns the background deletion of expired keys on or off.
+    SetActiveExpire(bool),
+    // A heap histogram of the keyspace, like `jmap -histo`: keys and bytes
+    // per value type.
+    Jmap,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FailoverArgs {
+    pub target: Option<HostSpec>,
+    pub abort: bool,
+    // Milliseconds to wait for a replica to catch up before giving up.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientArgs {
+    Kill {
+        filters: Vec<KillFilter>,
+        force: bool,
+    },
+    // Holds client commands, or only writes, for the given time.
+    Pause {
+        timeout: Duration,
+        writes_only: bool,
+    },
+    Unpause,
+    SetName(String),
+    GetName,
+    Id,
+    List,
+    // Exempts the connection from client eviction.
+    NoEvict(bool),
+    // Keeps the connection's reads from updating keys' access time and hits.
+    NoTouch(bool),
+    // Has RESP3 GET replies carry the key's access count as an attribute.
+    KeyPopularity(bool),
+}
+
+#[derive(Debug, Clone)]
+pub struct VscanArgs {
+    pub cursor: u64,
+    pub pattern: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum MemoryArgs {
+    Stats,
+    Doctor,
+    // The key, and how many of a container's elements to estimate its size
+    // from, or 0 for all of them.
+    Usage(String, usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HelloArgs {
+    pub protover: Option<u8>,
+    pub auth: Option<(String, String)>,
+    pub setname: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ReplconfArgs {
-    Port(String),
+    Port(u16),
+    IpAddress(String),
     Capa(Vec<String>),
+    GetAck,
+    Ack(u64),
 }
 
 #[derive(Debug, Clone)]
 pub enum PsyncArgs {
-    Question(String),
+    Question,
     Id(String, String),
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum CommandError {
-    #[error("Command Error: Invalid Command - {}", .0)]
+    #[error("ERR {}", .0)]
+    InvalidPacket(&'static str),
+    #[error("ERR {}", .0)]
     InvalidCommand(&'static str),
-    #[error("Command Error: Invalid Arguments - {}", .0)]
+    #[error("ERR {}", .0)]
     InvalidArguments(&'static str),
+    // The command name, and its first few arguments already quoted.
+    #[error("ERR unknown command '{}', with args beginning with: {}", .0, .1)]
+    UnknownCommand(String, String),
+    #[error("ERR wrong number of arguments for '{}' command", .0)]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("NOPROTO sorry, this protocol version is not supported.")]
+    NoProto,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("MISCONF {}", .0)]
+    Misconf(&'static str),
+    #[error("OOM command not allowed when used memory > 'maxmemory'.")]
+    Oom,
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", .0)]
+    SubscriberMode(&'static str),
+    #[error("NOREPLICAS Not enough good replicas to write.")]
+    NoReplicas,
+    #[error("ERR {}", .0)]
+    Failover(&'static str),
+    #[error("ERR {}", .0)]
+    Persistence(&'static str),
+    #[error("ERR {}", .0)]
+    Config(&'static str),
+    #[error("ERR {}", .0)]
+    Transaction(&'static str),
+    #[error("EXECABORT Transaction discarded because of previous errors.")]
+    ExecAbort,
+    #[error("NOSCRIPT No matching script. Please use EVAL.")]
+    NoScript,
+    // What a script failed with, or the error it replied with, verbatim.
+    #[error("{}", .0)]
+    Script(String),
+    #[error("ERR {}", .0)]
+    Function(String),
+}
+
+// Every error reads exactly as Redis words it, code prefix first, since
+// client libraries match on these.
+impl CommandError {
+    pub fn to_resp(&self) -> Resp {
+        Resp::error(self.to_string())
+    }
+
+    // Wrong arity for the command named in `args[0]`.
+    fn arity(args: &[Resp]) -> Self {
+        let name = match args.first() {
+            Some(Resp::Bulk(Some(name))) => name.to_lowercase(),
+            _ => String::new(),
+        };
+        CommandError::WrongArity(name)
+    }
 }
 
 impl Command {
     pub fn from_resp(resp: Resp) -> Result<Command, CommandError> {
+        Self::parse(&resp)
+    }
+
+    // As from_resp, for callers that keep the request, such as to propagate
+    // it as it came.
+    pub fn parse(resp: &Resp) -> Result<Command, CommandError> {
         match resp {
             Resp::Array(args) => parse_command(args),
-            _ => Err(CommandError::InvalidCommand("resp should be an array")),
+            _ => Err(CommandError::InvalidPacket("RESP should be an array")),
+        }
+    }
+
+    // Commands that modify the dataset and must be propagated to replicas.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::Del(_)
+                | Command::Function(
+                    FunctionArgs::Load { .. } | FunctionArgs::Delete(_) | FunctionArgs::Flush
+                )
+        )
+    }
+
+    // What replicas and the AOF get in place of the command as the client
+    // sent it, if that differs. A relative TTL is sent as the time it ends,
+    // so a replayed or late applied SET expires when the original does.
+    pub fn propagated(&self) -> Option<Resp> {
+        match self {
+            Command::Set(key, value, Some(expiry)) => {
+                let millis = expiry.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Some(crate::format_resp![
+                    "SET",
+                    key,
+                    value,
+                    "PXAT",
+                    millis.as_millis()
+                ])
+            }
+            _ => None,
+        }
+    }
+
+    // Keys the command reads or writes.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.keys_into(&mut keys);
+        keys
+    }
+
+    // Appends the command's keys to a buffer the caller reuses.
+    pub fn keys_into(&self, keys: &mut Vec<String>) {
+        match self {
+            Command::Get(key) | Command::Set(key, ..) => keys.push(key.to_string()),
+            Command::Memory(MemoryArgs::Usage(key, _)) | Command::ObjectRefcount(key) => {
+                keys.push(key.clone())
+            }
+            Command::Del(del)
+            | Command::Eval(Eval { keys: del, .. })
+            | Command::Fcall(Fcall { keys: del, .. }) => {
+                keys.extend(del.iter().map(|key| key.to_string()))
+            }
+            _ => {}
+        }
+    }
+
+    // Family the command's allocations are attributed to in memory profiling.
+    pub fn family(&self) -> memprof::Family {
+        use memprof::Family;
+        match self {
+            Command::Get(_) | Command::Set(..) | Command::Del(_) | Command::Vscan(_) => {
+                Family::String
+            }
+            Command::Echo(_)
+            | Command::Ping
+            | Command::Hello(_)
+            | Command::Client(_)
+            | Command::Subscribe(..)
+            | Command::Unsubscribe(..)
+            | Command::Publish(..)
+            | Command::Pubsub(_)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Unwatch
+            | Command::Quit
+            | Command::Reset => Family::Connection,
+            Command::Info(_)
+            | Command::Memory(_)
+            | Command::ObjectRefcount(_)
+            | Command::ConfigGet(_)
+            | Command::ConfigSet(_)
+            | Command::ConfigResetstat
+            | Command::ConfigRewrite
+            | Command::Save
+            | Command::Bgsave
+            | Command::Bgrewriteaof
+            | Command::Lastsave
+            | Command::LatencyDoctor
+            | Command::Lolwut
+            | Command::Debug(_)
+            | Command::Commands(_)
+            | Command::Shutdown(_)
+            | Command::Eval(_)
+            | Command::Script(_)
+            | Command::Function(_)
+            | Command::Fcall(_) => Family::Server,
+            Command::Replconf(_)
+            | Command::Psync(_)
+            | Command::ReplicaOf(_)
+            | Command::Failover(_)
+            | Command::Role => Family::Replication,
         }
     }
 }
 
-fn parse_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+fn parse_command(args: &[Resp]) -> Result<Command, CommandError> {
     use CommandError::*;
     let command_str = match args.first() {
         Some(Resp::Bulk(Some(string))) => string,
         _ => return Err(InvalidCommand("Command must be a bulk string")),
     };
 
-    match command_str.to_uppercase().as_str() {
-        "ECHO" => parse_echo(&args),
-        "PING" => parse_ping(&args),
-        "GET" => parse_get(&args),
-        "SET" => parse_set(&args),
-        "INFO" => parse_info(&args),
-        "REPLCONF" => parse_replconf(&args),
-        "PSYNC" => parse_psync(&args),
-        _ => Err(InvalidCommand("Unsupported command")),
+    if !args.iter().all(|arg| matches!(arg, Resp::Bulk(Some(_)))) {
+        return Err(InvalidArguments("All arguments must be bulk strings"));
+    }
+
+    let spec = command_table::lookup(command_str).ok_or_else(|| {
+        let quoted = args.iter().skip(1).take(3).map(|arg| match arg {
+            Resp::Bulk(Some(arg)) => format!("'{}' ", arg.as_str()),
+            _ => String::new(),
+        });
+        UnknownCommand(command_str.to_string(), quoted.collect())
+    })?;
+    if !spec.accepts(args.len()) {
+        return Err(CommandError::arity(args));
     }
+    (spec.parse)(args)
 }
 
-fn parse_echo(args: &[Resp]) -> Result<Command, CommandError> {
+pub fn parse_bgsave(args: &[Resp]) -> Result<Command, CommandError> {
+    match args.len() {
+        1 => Ok(Command::Bgsave),
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+pub fn parse_shutdown(args: &[Resp]) -> Result<Command, CommandError> {
+    match &args[1..] {
+        [] => Ok(Command::Shutdown(None)),
+        [Resp::Bulk(Some(mode))] if mode.eq_ignore_ascii_case("SAVE") => {
+            Ok(Command::Shutdown(Some(true)))
+        }
+        [Resp::Bulk(Some(mode))] if mode.eq_ignore_ascii_case("NOSAVE") => {
+            Ok(Command::Shutdown(Some(false)))
+        }
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+pub fn parse_echo(args: &[Resp]) -> Result<Command, CommandError> {
     use CommandError::*;
     match args.len() {
         2 => {
             if let Resp::Bulk(Some(data)) = &args[1] {
-                Ok(Command::Echo(data.to_string()))
+                Ok(Command::Echo(data.clone()))
             } else {
                 Err(InvalidArguments("Argument must be a bulk string"))
             }
         }
-        _ => Err(InvalidArguments("command expects exactly one argument")),
+        _ => Err(CommandError::arity(args)),
     }
 }
 
-fn parse_ping(args: &[Resp]) -> Result<Command, CommandError> {
-    use CommandError::*;
+pub fn parse_ping(args: &[Resp]) -> Result<Command, CommandError> {
     match args.len() {
         1 => Ok(Command::Ping),
-        _ => Err(InvalidArguments("PING command expects no arguments")),
+        _ => Err(CommandError::arity(args)),
     }
 }
 
-fn parse_get(args: &[Resp]) -> Result<Command, CommandError> {
-    use CommandError::*;
-    match args.get(1) {
-        Some(Resp::Bulk(Some(key))) => Ok(Command::Get(key.to_string())),
-        _ => Err(InvalidArguments("Usage: GET <key>")),
+pub fn parse_get(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(key))] => Ok(Command::Get(key.clone())),
+        _ => Err(CommandError::arity(args)),
     }
 }
 
-fn parse_set(args: &[Resp]) -> Result<Command, CommandError> {
+pub fn parse_set(args: &[Resp]) -> Result<Command, CommandError> {
     // HACK: After careful reflection, this is awful...
     use CommandError::*;
     match args {
         [_, Resp::Bulk(Some(key)), Resp::Bulk(Some(val))] => {
-            Ok(Command::Set(key.to_string(), val.to_string(), None))
+            Ok(Command::Set(key.clone(), val.clone(), None))
         }
-        [_, Resp::Bulk(Some(key)), Resp::Bulk(Some(val)), Resp::Bulk(Some(px)), Resp::Bulk(Some(millis))] => {
-            if px.to_uppercase() == "PX" {
-                match millis.parse::<u64>() {
-                    Ok(ms) => Ok(Command::Set(key.to_string(), val.to_string(), Some(ms))),
-                    Err(_) => Err(InvalidArguments("Invalid millisecond value")),
-                }
+        [_, Resp::Bulk(Some(key)), Resp::Bulk(Some(val)), Resp::Bulk(Some(px)), Resp::Bulk(Some(millis))] =>
+        {
+            let Ok(ms) = millis.parse::<u64>() else {
+                return Err(NotInteger);
+            };
+            // PXAT is what masters propagate, so replicas and AOF replays
+            // keep the original deadline.
+            let expiry = if px.eq_ignore_ascii_case("PX") {
+                SystemTime::now() + Duration::from_millis(ms)
+            } else if px.eq_ignore_ascii_case("PXAT") {
+                UNIX_EPOCH + Duration::from_millis(ms)
             } else {
-                Err(InvalidArguments("Unrecognized argument"))
-            }
+                return Err(Syntax);
+            };
+            Ok(Command::Set(key.clone(), val.clone(), Some(expiry)))
+        }
+        [] | [_] | [_, _] => Err(CommandError::arity(args)),
+        _ => Err(Syntax),
+    }
+}
+
+pub fn parse_del(args: &[Resp]) -> Result<Command, CommandError> {
+    let keys: Vec<BulkString> = args
+        .iter()
+        .skip(1)
+        .filter_map(|arg| match arg {
+            Resp::Bulk(Some(key)) => Some(key.clone()),
+            _ => None,
+        })
+        .collect();
+    Ok(Command::Del(keys))
+}
+
+pub fn parse_config(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    match args {
+        [_, Resp::Bulk(Some(sub)), patterns @ ..]
+            if sub.eq_ignore_ascii_case("GET") && !patterns.is_empty() =>
+        {
+            let patterns = patterns.iter().filter_map(|pattern| match pattern {
+                Resp::Bulk(Some(pattern)) => Some(pattern.to_lowercase()),
+                _ => None,
+            });
+            Ok(Command::ConfigGet(patterns.collect()))
+        }
+        [_, Resp::Bulk(Some(sub)), params @ ..]
+            if sub.eq_ignore_ascii_case("SET") && !params.is_empty() && params.len() % 2 == 0 =>
+        {
+            let params = params.chunks(2).filter_map(|pair| match pair {
+                [Resp::Bulk(Some(name)), Resp::Bulk(Some(value))] => {
+                    Some((name.to_lowercase(), value.to_string()))
+                }
+                _ => None,
+            });
+            Ok(Command::ConfigSet(params.collect()))
+        }
+        [_, Resp::Bulk(Some(sub))] if sub.eq_ignore_ascii_case("RESETSTAT") => {
+            Ok(Command::ConfigResetstat)
+        }
+        [_, Resp::Bulk(Some(sub))] if sub.eq_ignore_ascii_case("REWRITE") => {
+            Ok(Command::ConfigRewrite)
         }
         _ => Err(InvalidArguments(
-            "Usage: SET <key> <value> <px> <milliseconds>",
+            "Usage: CONFIG GET <parameter> [parameter ...] | CONFIG SET <parameter> <value> [parameter value ...] | CONFIG RESETSTAT | CONFIG REWRITE",
         )),
     }
 }
 
-fn parse_info(args: &[Resp]) -> Result<Command, CommandError> {
+pub fn parse_command_introspection(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    let args = args
+        .iter()
+        .skip(1)
+        .filter_map(|arg| match arg {
+            Resp::Bulk(Some(s)) => Some(s.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let args = match args.split_first() {
+        None => CommandArgs::All,
+        Some((sub, [])) if sub.eq_ignore_ascii_case("COUNT") => CommandArgs::Count,
+        Some((sub, names)) if sub.eq_ignore_ascii_case("INFO") => CommandArgs::Info(names.to_vec()),
+        Some((sub, names)) if sub.eq_ignore_ascii_case("DOCS") => CommandArgs::Docs(names.to_vec()),
+        _ => {
+            return Err(InvalidArguments(
+                "Usage: COMMAND [COUNT | INFO [command ...] | DOCS [command ...]]",
+            ))
+        }
+    };
+    Ok(Command::Commands(args))
+}
+
+pub fn parse_debug(args: &[Resp]) -> Result<Command, CommandError> {
     use CommandError::*;
     match args {
-        [_, Resp::Bulk(Some(category))] => {
-            if category.to_uppercase() == "REPLICATION" {
-                Ok(Command::Info(Some(category.to_string())))
-            } else {
-                Err(InvalidArguments("Unrecognized argument"))
+        [_, Resp::Bulk(Some(sub)), options @ ..] if sub.eq_ignore_ascii_case("RELOAD") => {
+            let (mut save, mut flush) = (true, true);
+            for option in options {
+                match option {
+                    Resp::Bulk(Some(o)) if o.eq_ignore_ascii_case("NOSAVE") => save = false,
+                    Resp::Bulk(Some(o)) if o.eq_ignore_ascii_case("NOFLUSH") => flush = false,
+                    _ => return Err(InvalidArguments("Usage: DEBUG RELOAD [NOSAVE] [NOFLUSH]")),
+                }
+            }
+            Ok(Command::Debug(DebugArgs::Reload { save, flush }))
+        }
+        [_, Resp::Bulk(Some(sub)), Resp::Bulk(Some(secs))] if sub.eq_ignore_ascii_case("SLEEP") => {
+            let secs = secs
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .ok_or(InvalidArguments("value is not a valid float"))?;
+            Ok(Command::Debug(DebugArgs::Sleep(Duration::from_secs_f64(
+                secs,
+            ))))
+        }
+        [_, Resp::Bulk(Some(sub)), Resp::Bulk(Some(key))] if sub.eq_ignore_ascii_case("OBJECT") => {
+            Ok(Command::Debug(DebugArgs::Object(key.as_str().to_string())))
+        }
+        [_, Resp::Bulk(Some(sub))] if sub.eq_ignore_ascii_case("JMAP") => {
+            Ok(Command::Debug(DebugArgs::Jmap))
+        }
+        [_, Resp::Bulk(Some(sub)), Resp::Bulk(Some(on))]
+            if sub.eq_ignore_ascii_case("SET-ACTIVE-EXPIRE") =>
+        {
+            match on.as_str() {
+                "0" => Ok(Command::Debug(DebugArgs::SetActiveExpire(false))),
+                "1" => Ok(Command::Debug(DebugArgs::SetActiveExpire(true))),
+                _ => Err(InvalidArguments("Usage: DEBUG SET-ACTIVE-EXPIRE <0|1>")),
             }
         }
-        _ => Err(InvalidArguments("Usage: INFO <category>")),
+        _ => Err(InvalidArguments("Unsupported DEBUG subcommand")),
     }
 }
 
-fn parse_replconf(args: &[Resp]) -> Result<Command, CommandError> {
+pub fn parse_info(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    match args {
+        [_] => Ok(Command::Info(None)),
+        [_, Resp::Bulk(Some(category))] => match category.to_lowercase().as_str() {
+            "replication" | "persistence" | "clients" | "stats" | "commandstats" => {
+                Ok(Command::Info(Some(category.to_lowercase())))
+            }
+            "all" | "default" | "everything" => Ok(Command::Info(None)),
+            _ => Err(InvalidArguments("Unrecognized argument")),
+        },
+        _ => Err(InvalidArguments("Usage: INFO [category]")),
+    }
+}
+
+pub fn parse_replconf(args: &[Resp]) -> Result<Command, CommandError> {
     use CommandError::*;
     let mut iter = args.iter().skip(1);
 
@@ -157,18 +652,36 @@ fn parse_replconf(args: &[Resp]) -> Result<Command, CommandError> {
                     if let Some(next_wrapped) = iter.next() {
                         match next_wrapped {
                             Resp::Bulk(Some(port)) => {
-                                //TODO: Add port parsing/validation???
-                                return Ok(Command::Replconf(ReplconfArgs::Port(port.to_string())));
+                                let port = port.parse().map_err(|_| {
+                                    InvalidArguments("listening-port must be a valid port")
+                                })?;
+                                return Ok(Command::Replconf(ReplconfArgs::Port(port)));
                             }
                             _ => return Err(InvalidArguments("No valid value found after 'capa'")),
                         }
                     }
                 }
+                "ip-address" => match iter.next() {
+                    Some(Resp::Bulk(Some(ip))) if !ip.is_empty() => {
+                        return Ok(Command::Replconf(ReplconfArgs::IpAddress(ip.to_string())));
+                    }
+                    _ => return Err(InvalidArguments("No valid value found after 'ip-address'")),
+                },
+                "getack" => return Ok(Command::Replconf(ReplconfArgs::GetAck)),
+                "ack" => match iter.next() {
+                    Some(Resp::Bulk(Some(offset))) => {
+                        let offset = offset
+                            .parse()
+                            .map_err(|_| InvalidArguments("ACK offset must be a number"))?;
+                        return Ok(Command::Replconf(ReplconfArgs::Ack(offset)));
+                    }
+                    _ => return Err(InvalidArguments("No valid value found after 'ack'")),
+                },
                 _ => return Err(InvalidArguments("Unrecognized arguments")),
             },
             _ => {
                 return Err(InvalidArguments(
-                    "Usage: REPLCONF listening-port | capa <ARGS>",
+                    "Usage: REPLCONF listening-port | capa <ARGS> | getack | ack <OFFSET>",
                 ))
             }
         }
@@ -178,13 +691,13 @@ fn parse_replconf(args: &[Resp]) -> Result<Command, CommandError> {
     )))
 }
 
-fn parse_psync(args: &[Resp]) -> Result<Command, CommandError> {
+pub fn parse_psync(args: &[Resp]) -> Result<Command, CommandError> {
     use CommandError::*;
     match args {
         [_, Resp::Bulk(Some(psynccmd)), Resp::Bulk(Some(offset))] => {
             match psynccmd.to_lowercase().as_str() {
                 "?" => match offset.as_str() {
-                    "-1" => Ok(Command::Psync(PsyncArgs::Question(offset.to_string()))),
+                    "-1" => Ok(Command::Psync(PsyncArgs::Question)),
                     _ => Err(InvalidArguments(
                         "Byte offset should be -1 when querying for replid",
                     )),
@@ -199,105 +712,1419 @@ fn parse_psync(args: &[Resp]) -> Result<Command, CommandError> {
     }
 }
 
+pub fn parse_hello(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    let mut hello = HelloArgs::default();
+    let mut iter = args.iter().skip(1).map(|arg| match arg {
+        Resp::Bulk(Some(s)) => s.as_str(),
+        _ => "",
+    });
+
+    let Some(protover) = iter.next() else {
+        return Ok(Command::Hello(hello));
+    };
+    hello.protover = Some(protover.parse::<u8>().map_err(|_| NoProto)?);
+
+    while let Some(opt) = iter.next() {
+        match opt.to_uppercase().as_str() {
+            "AUTH" => match (iter.next(), iter.next()) {
+                (Some(user), Some(pass)) => hello.auth = Some((user.to_string(), pass.to_string())),
+                _ => return Err(InvalidArguments("AUTH expects a username and password")),
+            },
+            "SETNAME" => match iter.next() {
+                Some(name) => hello.setname = Some(name.to_string()),
+                None => return Err(InvalidArguments("SETNAME expects a connection name")),
+            },
+            _ => {
+                return Err(InvalidArguments(
+                    "Usage: HELLO [protover [AUTH username password] [SETNAME clientname]]",
+                ))
+            }
+        }
+    }
+    Ok(Command::Hello(hello))
+}
+
+pub fn parse_memory(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    const USAGE: &str = "Usage: MEMORY STATS|DOCTOR|USAGE <key> [SAMPLES <count>]";
+    match args {
+        [_, Resp::Bulk(Some(sub))] => match sub.to_uppercase().as_str() {
+            "STATS" => Ok(Command::Memory(MemoryArgs::Stats)),
+            "DOCTOR" => Ok(Command::Memory(MemoryArgs::Doctor)),
+            _ => Err(InvalidArguments(USAGE)),
+        },
+        [_, Resp::Bulk(Some(sub)), Resp::Bulk(Some(key)), rest @ ..]
+            if sub.eq_ignore_ascii_case("usage") =>
+        {
+            // Redis samples five elements unless told otherwise.
+            let samples = match rest {
+                [] => 5,
+                [Resp::Bulk(Some(opt)), Resp::Bulk(Some(count))]
+                    if opt.eq_ignore_ascii_case("samples") =>
+                {
+                    count.parse().map_err(|_| NotInteger)?
+                }
+                _ => return Err(Syntax),
+            };
+            Ok(Command::Memory(MemoryArgs::Usage(key.to_string(), samples)))
+        }
+        _ => Err(InvalidArguments(USAGE)),
+    }
+}
+
+pub fn parse_object(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(sub)), Resp::Bulk(Some(key))]
+            if sub.eq_ignore_ascii_case("REFCOUNT") =>
+        {
+            Ok(Command::ObjectRefcount(key.to_string()))
+        }
+        _ => Err(CommandError::InvalidArguments(
+            "Usage: OBJECT REFCOUNT <key>",
+        )),
+    }
+}
+
+pub fn parse_latency(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(sub))] if sub.eq_ignore_ascii_case("DOCTOR") => {
+            Ok(Command::LatencyDoctor)
+        }
+        _ => Err(CommandError::InvalidArguments("Usage: LATENCY DOCTOR")),
+    }
+}
+
+// LOLWUT [VERSION <version>]. Every version draws the same picture.
+pub fn parse_lolwut(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_] => Ok(Command::Lolwut),
+        [_, Resp::Bulk(Some(opt)), Resp::Bulk(Some(version))]
+            if opt.eq_ignore_ascii_case("VERSION") =>
+        {
+            version
+                .parse::<u64>()
+                .map_err(|_| CommandError::NotInteger)?;
+            Ok(Command::Lolwut)
+        }
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+// The bulk strings after the command name, as Strings.
+fn strings(args: &[Resp]) -> Vec<String> {
+    args.iter()
+        .skip(1)
+        .filter_map(|arg| match arg {
+            Resp::Bulk(Some(s)) => Some(s.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn parse_subscribe(args: &[Resp]) -> Result<Command, CommandError> {
+    Ok(Command::Subscribe(Kind::Channel, strings(args)))
+}
+
+pub fn parse_psubscribe(args: &[Resp]) -> Result<Command, CommandError> {
+    Ok(Command::Subscribe(Kind::Pattern, strings(args)))
+}
+
+pub fn parse_unsubscribe(args: &[Resp]) -> Result<Command, CommandError> {
+    Ok(Command::Unsubscribe(Kind::Channel, strings(args)))
+}
+
+pub fn parse_punsubscribe(args: &[Resp]) -> Result<Command, CommandError> {
+    Ok(Command::Unsubscribe(Kind::Pattern, strings(args)))
+}
+
+pub fn parse_watch(args: &[Resp]) -> Result<Command, CommandError> {
+    Ok(Command::Watch(strings(args)))
+}
+
+pub fn parse_eval(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(body)), Resp::Bulk(Some(numkeys)), rest @ ..] => {
+            eval(Source::Body(body.clone()), numkeys, rest)
+        }
+        _ => Err(CommandError::arity(args)),
+    }
+}
+
+pub fn parse_evalsha(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(sha)), Resp::Bulk(Some(numkeys)), rest @ ..] => {
+            eval(Source::Sha(sha.to_string()), numkeys, rest)
+        }
+        _ => Err(CommandError::arity(args)),
+    }
+}
+
+fn eval(script: Source, numkeys: &str, rest: &[Resp]) -> Result<Command, CommandError> {
+    let (keys, args) = keys_and_args(numkeys, rest)?;
+    Ok(Command::Eval(Eval { script, keys, args }))
+}
+
+pub fn parse_fcall(args: &[Resp]) -> Result<Command, CommandError> {
+    fcall(args, false)
+}
+
+pub fn parse_fcall_ro(args: &[Resp]) -> Result<Command, CommandError> {
+    fcall(args, true)
+}
+
+fn fcall(args: &[Resp], read_only: bool) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(function)), Resp::Bulk(Some(numkeys)), rest @ ..] => {
+            let (keys, args) = keys_and_args(numkeys, rest)?;
+            Ok(Command::Fcall(Fcall {
+                function: function.to_string(),
+                keys,
+                args,
+                read_only,
+            }))
+        }
+        _ => Err(CommandError::arity(args)),
+    }
+}
+
+// `<numkeys> [key ...] [arg ...]`, after the script or function.
+fn keys_and_args(
+    numkeys: &str,
+    rest: &[Resp],
+) -> Result<(Vec<BulkString>, Vec<BulkString>), CommandError> {
+    use CommandError::*;
+    let numkeys = numkeys.parse::<i64>().map_err(|_| NotInteger)?;
+    let numkeys = usize::try_from(numkeys)
+        .map_err(|_| InvalidArguments("Number of keys can't be negative"))?;
+    if numkeys > rest.len() {
+        return Err(InvalidArguments(
+            "Number of keys can't be greater than number of args",
+        ));
+    }
+    let mut keys: Vec<BulkString> = rest
+        .iter()
+        .filter_map(|arg| match arg {
+            Resp::Bulk(Some(arg)) => Some(arg.clone()),
+            _ => None,
+        })
+        .collect();
+    let args = keys.split_off(numkeys);
+    Ok((keys, args))
+}
+
+pub fn parse_function(args: &[Resp]) -> Result<Command, CommandError> {
+    let mut names = strings(args);
+    let sub = names.remove(0);
+    let is = |word: &String, expected| word.eq_ignore_ascii_case(expected);
+    let args = match names.as_slice() {
+        [code] if is(&sub, "LOAD") => FunctionArgs::Load {
+            code: code.clone(),
+            replace: false,
+        },
+        [replace, code] if is(&sub, "LOAD") && is(replace, "REPLACE") => FunctionArgs::Load {
+            code: code.clone(),
+            replace: true,
+        },
+        [name] if is(&sub, "DELETE") => FunctionArgs::Delete(name.clone()),
+        [] if is(&sub, "FLUSH") => FunctionArgs::Flush,
+        [mode] if is(&sub, "FLUSH") && (is(mode, "ASYNC") || is(mode, "SYNC")) => FunctionArgs::Flush,
+        options if is(&sub, "LIST") => {
+            let (mut pattern, mut with_code) = (None, false);
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match options.next() {
+                    Some(value) if is(option, "LIBRARYNAME") => pattern = Some(value.clone()),
+                    _ if is(option, "WITHCODE") => with_code = true,
+                    _ => return Err(CommandError::Syntax),
+                }
+            }
+            FunctionArgs::List { pattern, with_code }
+        }
+        _ => {
+            return Err(CommandError::InvalidArguments(
+                "Usage: FUNCTION LOAD [REPLACE] <code> | DELETE <library> | FLUSH [ASYNC|SYNC] | LIST [LIBRARYNAME pattern] [WITHCODE]",
+            ))
+        }
+    };
+    Ok(Command::Function(args))
+}
+
+pub fn parse_script(args: &[Resp]) -> Result<Command, CommandError> {
+    let mut names = strings(args);
+    let sub = names.remove(0);
+    let args = match (args, names.as_slice()) {
+        ([_, _, Resp::Bulk(Some(body))], _) if sub.eq_ignore_ascii_case("LOAD") => {
+            ScriptArgs::Load(body.clone())
+        }
+        (_, [_, ..]) if sub.eq_ignore_ascii_case("EXISTS") => ScriptArgs::Exists(names),
+        // Flushing is quick enough that ASYNC needn't be any different.
+        (_, []) if sub.eq_ignore_ascii_case("FLUSH") => ScriptArgs::Flush,
+        (_, [mode])
+            if sub.eq_ignore_ascii_case("FLUSH")
+                && (mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC")) =>
+        {
+            ScriptArgs::Flush
+        }
+        _ => {
+            return Err(CommandError::InvalidArguments(
+                "Usage: SCRIPT LOAD <script> | EXISTS <sha1> [sha1 ...] | FLUSH [ASYNC|SYNC]",
+            ))
+        }
+    };
+    Ok(Command::Script(args))
+}
+
+pub fn parse_publish(args: &[Resp]) -> Result<Command, CommandError> {
+    match args {
+        [_, Resp::Bulk(Some(channel)), Resp::Bulk(Some(message))] => {
+            Ok(Command::Publish(channel.to_string(), message.clone()))
+        }
+        _ => Err(CommandError::arity(args)),
+    }
+}
+
+pub fn parse_pubsub(args: &[Resp]) -> Result<Command, CommandError> {
+    let mut names = strings(args);
+    let sub = names.remove(0);
+    let args = match names.as_slice() {
+        [] if sub.eq_ignore_ascii_case("CHANNELS") => PubsubArgs::Channels(None),
+        [pattern] if sub.eq_ignore_ascii_case("CHANNELS") => {
+            PubsubArgs::Channels(Some(pattern.clone()))
+        }
+        _ if sub.eq_ignore_ascii_case("NUMSUB") => PubsubArgs::Numsub(names),
+        [] if sub.eq_ignore_ascii_case("NUMPAT") => PubsubArgs::Numpat,
+        _ => {
+            return Err(CommandError::InvalidArguments(
+                "Usage: PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT",
+            ))
+        }
+    };
+    Ok(Command::Pubsub(args))
+}
+
+pub fn parse_vscan(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    const USAGE: &str = "Usage: VSCAN <cursor> [MATCH pattern] [COUNT count]";
+    let mut iter = args.iter().skip(1).map(|arg| match arg {
+        Resp::Bulk(Some(s)) => s.as_str(),
+        _ => "",
+    });
+    let cursor = iter
+        .next()
+        .ok_or(InvalidArguments(USAGE))?
+        .parse::<u64>()
+        .map_err(|_| InvalidArguments("invalid cursor"))?;
+    let mut vscan = VscanArgs {
+        cursor,
+        pattern: "*".to_string(),
+        count: 10,
+    };
+    while let Some(opt) = iter.next() {
+        match (opt.to_uppercase().as_str(), iter.next()) {
+            ("MATCH", Some(pattern)) => vscan.pattern = pattern.to_string(),
+            ("COUNT", Some(count)) => {
+                vscan.count = count
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|c| *c > 0)
+                    .ok_or(InvalidArguments("COUNT must be a positive integer"))?;
+            }
+            _ => return Err(InvalidArguments(USAGE)),
+        }
+    }
+    Ok(Command::Vscan(vscan))
+}
+
+pub fn parse_replicaof(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    match args {
+        [_, Resp::Bulk(Some(host)), Resp::Bulk(Some(port))] => {
+            if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                return Ok(Command::ReplicaOf(None));
+            }
+            let master = format!("{} {}", host.as_str(), port.as_str())
+                .parse::<HostSpec>()
+                .map_err(|_| InvalidArguments("Invalid master host or port"))?;
+            Ok(Command::ReplicaOf(Some(master)))
+        }
+        _ => Err(InvalidArguments("Usage: REPLICAOF <host> <port> | NO ONE")),
+    }
+}
+
+// FAILOVER [TO <host> <port>] [TIMEOUT <ms>] | FAILOVER ABORT
+pub fn parse_failover(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    const USAGE: &str = "Usage: FAILOVER [TO <host> <port>] [TIMEOUT <ms>] | FAILOVER ABORT";
+    let mut iter = args.iter().skip(1).map(|arg| match arg {
+        Resp::Bulk(Some(s)) => s.as_str(),
+        _ => "",
+    });
+    let mut failover = FailoverArgs::default();
+    while let Some(opt) = iter.next() {
+        match opt.to_uppercase().as_str() {
+            "TO" => match (iter.next(), iter.next()) {
+                (Some(host), Some(port)) => {
+                    let target = format!("{} {}", host, port)
+                        .parse::<HostSpec>()
+                        .map_err(|_| InvalidArguments("Invalid target host or port"))?;
+                    failover.target = Some(target);
+                }
+                _ => return Err(InvalidArguments(USAGE)),
+            },
+            "TIMEOUT" => {
+                failover.timeout = iter
+                    .next()
+                    .and_then(|ms| ms.parse::<u64>().ok())
+                    .filter(|ms| *ms > 0)
+                    .map(Some)
+                    .ok_or(InvalidArguments("FAILOVER timeout must be greater than 0"))?;
+            }
+            "ABORT" => failover.abort = true,
+            _ => return Err(InvalidArguments(USAGE)),
+        }
+    }
+    if failover.abort && (failover.target.is_some() || failover.timeout.is_some()) {
+        return Err(InvalidArguments(
+            "FAILOVER ABORT cannot be combined with other options",
+        ));
+    }
+    Ok(Command::Failover(failover))
+}
+
+pub fn parse_client(args: &[Resp]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    let args = args
+        .iter()
+        .skip(1)
+        .map(|arg| match arg {
+            Resp::Bulk(Some(s)) => s.as_str(),
+            _ => "",
+        })
+        .collect::<Vec<_>>();
+    match (
+        args.first().map(|sub| sub.to_uppercase()).as_deref(),
+        &args[..],
+    ) {
+        (Some("KILL"), _) => parse_client_kill(&args[1..]),
+        (Some("SETNAME"), [_, name]) => {
+            // Names show up in space separated CLIENT LIST lines.
+            if name.chars().any(|c| !c.is_ascii_graphic()) {
+                return Err(InvalidArguments(
+                    "Client names cannot contain spaces, newlines or special characters.",
+                ));
+            }
+            Ok(Command::Client(ClientArgs::SetName(name.to_string())))
+        }
+        (Some("GETNAME"), [_]) => Ok(Command::Client(ClientArgs::GetName)),
+        (Some("ID"), [_]) => Ok(Command::Client(ClientArgs::Id)),
+        (Some("LIST"), [_]) => Ok(Command::Client(ClientArgs::List)),
+        (Some("PAUSE"), [_, timeout, mode @ ..]) => {
+            let timeout = timeout
+                .parse::<u64>()
+                .map_err(|_| InvalidArguments("timeout is not an integer or out of range"))?;
+            let writes_only = match mode {
+                [] => false,
+                [mode] if mode.eq_ignore_ascii_case("ALL") => false,
+                [mode] if mode.eq_ignore_ascii_case("WRITE") => true,
+                _ => return Err(InvalidArguments("Usage: CLIENT PAUSE <timeout> [WRITE|ALL]")),
+            };
+            Ok(Command::Client(ClientArgs::Pause {
+                timeout: Duration::from_millis(timeout),
+                writes_only,
+            }))
+        }
+        (Some("UNPAUSE"), [_]) => Ok(Command::Client(ClientArgs::Unpause)),
+        (Some(sub @ ("NO-EVICT" | "NO-TOUCH" | "KEY-POPULARITY")), [_, mode]) => {
+            let on = match mode.to_uppercase().as_str() {
+                "ON" => true,
+                "OFF" => false,
+                _ => return Err(InvalidArguments("argument must be 'on' or 'off'")),
+            };
+            Ok(Command::Client(match sub {
+                "NO-EVICT" => ClientArgs::NoEvict(on),
+                "NO-TOUCH" => ClientArgs::NoTouch(on),
+                _ => ClientArgs::KeyPopularity(on),
+            }))
+        }
+        _ => Err(InvalidArguments(
+            "Usage: CLIENT KILL <filter> | PAUSE <timeout> [WRITE|ALL] | UNPAUSE | SETNAME <name> | GETNAME | ID | LIST | NO-EVICT <on|off> | NO-TOUCH <on|off> | KEY-POPULARITY <on|off>",
+        )),
+    }
+}
+
+// CLIENT KILL <addr> | CLIENT KILL <filter> <value> [<filter> <value> ...] [FORCE]
+// where a filter is ID, ADDR, LADDR or TYPE. A client must match them all.
+fn parse_client_kill(args: &[&str]) -> Result<Command, CommandError> {
+    use CommandError::*;
+    const USAGE: &str =
+        "Usage: CLIENT KILL ID <id> | ADDR <addr> | LADDR <addr> | TYPE <type> ... [FORCE]";
+    let (args, force) = match args {
+        [rest @ .., flag] if flag.eq_ignore_ascii_case("FORCE") => (rest, true),
+        _ => (args, false),
+    };
+    if let [addr] = args {
+        let filters = vec![KillFilter::Addr(addr.to_string())];
+        return Ok(Command::Client(ClientArgs::Kill { filters, force }));
+    }
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(InvalidArguments(USAGE));
+    }
+    let filters = args
+        .chunks(2)
+        .map(|pair| match pair[0].to_uppercase().as_str() {
+            "ID" => pair[1]
+                .parse::<u64>()
+                .map(KillFilter::Id)
+                .map_err(|_| InvalidArguments("client-id should be greater than 0")),
+            "ADDR" => Ok(KillFilter::Addr(pair[1].to_string())),
+            "LADDR" => Ok(KillFilter::LocalAddr(pair[1].to_string())),
+            "TYPE" => pair[1]
+                .parse()
+                .map(KillFilter::Type)
+                .map_err(|_| InvalidArguments("Unknown client type")),
+            _ => Err(InvalidArguments("Unsupported CLIENT KILL filter")),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Command::Client(ClientArgs::Kill { filters, force }))
+}
+
+// What Redis reports as the refcount of its shared objects, which no
+// reference ever frees.
+const SHARED_REFCOUNT: i64 = i32::MAX as i64;
+
+fn refcount(query: &Query) -> i64 {
+    match query.value.as_string().is_some_and(|s| s.is_shared()) {
+        true => SHARED_REFCOUNT,
+        false => 1,
+    }
+}
+
+// DEBUG OBJECT's description of a value. String encodings are the ones
+// Redis would pick for the same string: integers, short strings embedded in
+// their object, and everything else raw. Containers are named after the
+// encoding Redis uses once they outgrow a listpack.
+fn debug_object(query: &Query, compression: bool) -> String {
+    let encoding = match &query.value {
+        Value::String(s) => match s.parse::<i64>() {
+            Ok(n) if n.to_string() == s.as_str() => "int",
+            _ if s.len() <= 44 => "embstr",
+            _ => "raw",
+        },
+        Value::List(_) => "quicklist",
+        Value::Set(_) | Value::Hash(_) => "hashtable",
+        Value::SortedSet(_) => "skiplist",
+        Value::Stream(_) => "stream",
+    };
+    let idle = query.access.last().elapsed().unwrap_or_default().as_secs();
+    format!(
+        "Value at:0x0 refcount:{} encoding:{} serializedlength:{} lru_seconds_idle:{} hits:{}",
+        refcount(query),
+        encoding,
+        rdb::serialized_len(&query.value, compression),
+        idle,
+        query.access.hits()
+    )
+}
+
+// DEBUG JMAP's histogram: live keys and their entry_size per value type,
+// biggest first.
+fn debug_jmap(cache: &dyn Keyspace) -> String {
+    let now = SystemTime::now();
+    let mut totals: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (key, query) in cache.iter().filter(|(_, query)| !query.is_expired(now)) {
+        let (count, bytes) = totals.entry(query.value.type_name()).or_default();
+        *count += 1;
+        *bytes += eviction::entry_size(key, &query.value);
+    }
+    let mut types: Vec<_> = totals.into_iter().collect();
+    types.sort_by_key(|(_, (_, bytes))| std::cmp::Reverse(*bytes));
+    let mut histogram = format!(
+        "{:>5} {:>14} {:>14}  type\n{}\n",
+        "num",
+        "#instances",
+        "#bytes",
+        "-".repeat(44)
+    );
+    for (i, (name, (count, bytes))) in types.iter().enumerate() {
+        histogram.push_str(&format!(
+            "{:>4}: {:>14} {:>14}  {}\n",
+            i + 1,
+            count,
+            bytes,
+            name
+        ));
+    }
+    let (count, bytes) = types
+        .iter()
+        .fold((0, 0), |(c, b), (_, (count, bytes))| (c + count, b + bytes));
+    histogram.push_str(&format!("{:<5} {:>14} {:>14}\n", "Total", count, bytes));
+    histogram
+}
+
+// Replies to `PSYNC ? -1`: the FULLRESYNC line carrying our replication id
+// and offset, then a snapshot of the dataset as a bulk string without the
+// trailing CRLF.
+pub fn full_resync(cache: &dyn Keyspace, info: &crate::Info) -> Vec<Resp> {
+    let snapshot = info.persistence.snapshot(cache, &info.functions).encode();
+    vec![
+        Resp::simple(format!(
+            "FULLRESYNC {} {}",
+            info.id(),
+            info.replicas.offset()
+        )),
+        Resp::RDBLen(snapshot.len()),
+        Resp::Raw(snapshot.into()),
+    ]
+}
+
+// GET's reply. Unless `touch` is off, as for CLIENT NO-TOUCH connections,
+// the read counts as an access for eviction. With `popularity`, as for
+// CLIENT KEY-POPULARITY connections, RESP3 clients also get the key's
+// access count as an attribute.
+pub async fn get(
+    store: &Store,
+    info: &Mutex<crate::Info>,
+    key: &str,
+    touch: bool,
+    popularity: bool,
+) -> Result<Resp, CommandError> {
+    let now = SystemTime::now();
+    let mut cache = store.read(&[key]).await;
+    // Deleting an expired key takes the shard to ourselves.
+    if cache.get(key).is_some_and(|query| query.is_expired(now)) {
+        drop(cache);
+        cache = store.lock(&[key]).await;
+        if expire::expire_if_needed(&mut cache, info, key, now).await {
+            return Ok(Resp::Null);
+        }
+    }
+    let Some(query) = cache.get(key) else {
+        return Ok(Resp::Null);
+    };
+    let value = query.value.as_string().ok_or(CommandError::WrongType)?;
+    if touch {
+        query.touch(now);
+    }
+    let reply = Resp::bulk(value.clone());
+    if !popularity {
+        return Ok(reply);
+    }
+    Ok(reply.with_attributes(vec![(
+        Resp::simple("key-popularity"),
+        Resp::Integer(query.access.hits() as i64),
+    )]))
+}
+
+// Switches the connection's protocol and returns the server metadata map.
+// Without a password configured only the default user can authenticate.
+async fn hello(
+    ctx: &mut ConnCtx,
+    info: &Mutex<crate::Info>,
+    args: HelloArgs,
+) -> Result<Vec<Resp>, CommandError> {
+    let protocol = match args.protover {
+        Some(version) => Protocol::from_version(version).ok_or(CommandError::NoProto)?,
+        None => ctx.protocol,
+    };
+    if let Some((user, _)) = &args.auth {
+        if user != "default" {
+            return Err(CommandError::WrongPass);
+        }
+    }
+    let mut info = info.lock().await;
+    if let Some(name) = args.setname {
+        set_name(ctx, &mut info, name);
+    }
+    ctx.protocol = protocol;
+
+    let role = match info.role {
+        crate::Role::Master => "master",
+        crate::Role::Slave => "replica",
+    };
+    Ok(vec![Resp::map()
+        .entry("server", "redis")
+        .entry("version", env!("CARGO_PKG_VERSION"))
+        .entry("proto", protocol.version())
+        .entry("id", ctx.id as i64)
+        .entry("mode", "standalone")
+        .entry("role", role)
+        .entry("modules", Resp::Array(vec![]))
+        .build()])
+}
+
+fn set_name(ctx: &mut ConnCtx, info: &mut crate::Info, name: String) {
+    // An empty name clears it.
+    ctx.name = Some(name).filter(|name| !name.is_empty());
+    info.clients.set_name(ctx.id, ctx.name.clone());
+}
+
+// The CLIENT subcommands about this connection, and CLIENT LIST.
+async fn client(
+    ctx: &mut ConnCtx,
+    info: &Mutex<crate::Info>,
+    args: ClientArgs,
+) -> Result<Vec<Resp>, CommandError> {
+    let mut info = info.lock().await;
+    match args {
+        ClientArgs::SetName(name) => {
+            set_name(ctx, &mut info, name);
+            Ok(vec![Resp::ok()])
+        }
+        ClientArgs::GetName => Ok(vec![match &ctx.name {
+            Some(name) => Resp::bulk(name.clone()),
+            None => Resp::Null,
+        }]),
+        ClientArgs::Id => Ok(vec![Resp::Integer(ctx.id as i64)]),
+        ClientArgs::List => Ok(vec![Resp::verbatim(info.clients.list())]),
+        ClientArgs::Kill { filters, force } => {
+            // Killing the connection the command arrived on is almost always
+            // a mistake made mid-incident, so it has to be asked for
+            // explicitly.
+            if !force && info.clients.matches(ctx.id, &filters) {
+                return Err(CommandError::InvalidArguments(
+                    "refusing to kill the current connection without FORCE",
+                ));
+            }
+            Ok(vec![Resp::Integer(info.clients.kill(&filters) as i64)])
+        }
+        ClientArgs::Pause {
+            timeout,
+            writes_only,
+        } => {
+            info.clients.pause(timeout, writes_only);
+            Ok(vec![Resp::ok()])
+        }
+        ClientArgs::Unpause => {
+            info.clients.unpause();
+            Ok(vec![Resp::ok()])
+        }
+        ClientArgs::NoEvict(on) => {
+            info.clients.set_no_evict(ctx.id, on);
+            Ok(vec![Resp::ok()])
+        }
+        ClientArgs::NoTouch(on) => {
+            ctx.no_touch = on;
+            info.clients.set_no_touch(ctx.id, on);
+            Ok(vec![Resp::ok()])
+        }
+        ClientArgs::KeyPopularity(on) => {
+            ctx.key_popularity = on;
+            Ok(vec![Resp::ok()])
+        }
+    }
+}
+
 // executes a command and returns the unencoded response.
 pub async fn execute_command(
     cmd: Command,
-    cache: Arc<Mutex<HashMap<String, Query>>>,
+    ctx: &mut ConnCtx,
+    cache: Arc<Store>,
     info: Arc<Mutex<crate::Info>>,
 ) -> Result<Vec<Resp>, CommandError> {
     match cmd {
-        Command::Echo(arg) => Ok(vec![Resp::Bulk(Some(arg))]),
-        Command::Ping => Ok(vec![Resp::SimpleString("PONG".to_string())]),
-        Command::Get(key) => {
-            let mut cache = cache.lock().await;
-            let now = SystemTime::now();
-            if let Some(value) = cache.get(&key) {
-                let value = value.clone();
-                if let Some(timeout) = value.expiry {
-                    if timeout < now {
-                        cache.remove(&key);
-                        Ok(vec![Resp::Null])
-                    } else {
-                        Ok(vec![Resp::Bulk(Some(value.clone().value))])
-                    }
-                } else {
-                    Ok(vec![Resp::Bulk(Some(value.clone().value))])
-                }
+        Command::Echo(arg) => Ok(vec![arg.into()]),
+        // Subscribers get theirs in the shape of a message.
+        Command::Ping if ctx.in_subscriber_mode() => Ok(vec![Resp::array(["pong", ""])]),
+        Command::Ping => Ok(vec![Resp::simple("PONG")]),
+        Command::Get(key) => Ok(vec![
+            get(
+                &cache,
+                &info,
+                key.as_str(),
+                !ctx.no_touch,
+                ctx.key_popularity,
+            )
+            .await?,
+        ]),
+        Command::Hello(args) => hello(ctx, &info, args).await,
+        Command::Client(args) => client(ctx, &info, args).await,
+        Command::Set(key, value, expiry) => {
+            // Eviction picks its victims from the whole keyspace, so only
+            // without a memory limit is the key's own shard enough.
+            let limited = info.lock().await.eviction.maxmemory > 0;
+            let mut cache = if limited {
+                cache.lock_all().await
             } else {
-                Ok(vec![Resp::Null])
+                cache.lock(&[key.as_str()]).await
+            };
+            let now = SystemTime::now();
+            let incoming = eviction::string_entry_size(&key, &value);
+            // A limit set since we looked waits for the next write.
+            if limited && !info.lock().await.eviction.make_room(&mut cache, incoming) {
+                return Err(CommandError::Oom);
             }
+            // Copied out of the request, which shares the connection's read
+            // buffer and would keep all of it alive for as long as the key.
+            let value =
+                BulkString::shared(&value).unwrap_or_else(|| BulkString::from(value.as_str()));
+            cache.insert(key.to_string(), Query::new(value, expiry, now));
+            Ok(vec![Resp::ok()])
         }
-        Command::Set(key, value, timeout) => {
-            let mut cache = cache.lock().await;
-            let mut expiry = None::<SystemTime>;
+        Command::Del(keys) => {
+            let keys: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+            let mut cache = cache.lock(&keys).await;
             let now = SystemTime::now();
-
-            if let Some(timeout_val) = timeout {
-                expiry = Some(now + Duration::from_millis(timeout_val));
-            }
-            cache.insert(
-                key,
-                Query {
-                    value,
-                    created_at: now,
-                    expiry,
-                },
-            );
-            Ok(vec![Resp::SimpleString("OK".to_string())])
+            let deleted = keys
+                .iter()
+                .filter_map(|key| cache.remove(key))
+                .filter(|query| !query.is_expired(now))
+                .count();
+            Ok(vec![Resp::Integer(deleted as i64)])
         }
         Command::Info(category) => {
+            let cache = cache.read_all().await;
             let info = info.lock().await;
-            if category.is_some() {
-                Ok(vec![Resp::Bulk(Some(info.replication()))])
-            } else {
-                Ok(vec![Resp::Null])
-            }
+            let sections = match category.as_deref() {
+                Some("replication") => info.replication(),
+                Some("persistence") => info.persistence.info(&cache),
+                Some("clients") => info.clients.info(),
+                Some("stats") => info.stats(),
+                Some("commandstats") => info.command_stats.info(),
+                _ => [
+                    info.clients.info(),
+                    info.persistence.info(&cache),
+                    info.stats(),
+                    info.replication(),
+                ]
+                .join("\n\n"),
+            };
+            Ok(vec![Resp::verbatim(sections)])
         }
         Command::Replconf(c) => match c {
-            ReplconfArgs::Port(_) => Ok(vec![Resp::SimpleString("OK".to_string())]),
-            ReplconfArgs::Capa(_) => Ok(vec![Resp::SimpleString("OK".to_string())]),
+            ReplconfArgs::Port(port) => {
+                println!("replica announced listening port {}", port);
+                Ok(vec![Resp::ok()])
+            }
+            ReplconfArgs::IpAddress(ip) => {
+                println!("replica announced ip address {}", ip);
+                Ok(vec![Resp::ok()])
+            }
+            ReplconfArgs::Capa(_) => Ok(vec![Resp::ok()]),
+            ReplconfArgs::GetAck => {
+                let offset = info.lock().await.replicas.offset();
+                Ok(vec![crate::format_resp!["REPLCONF", "ACK", offset]])
+            }
+            // Acks only mean something on a replica link, where the
+            // connection handler records them. Redis never replies to them.
+            ReplconfArgs::Ack(_) => Ok(vec![]),
         },
-        Command::Psync(p) => match p {
-            PsyncArgs::Question(_) => {
-                let info = info.lock().await;
-                let mut resp_queue: Vec<Resp> = Vec::new();
-                resp_queue.push(Resp::SimpleString(format!("FULLRESYNC {} 0", info.id())));
-                // resp_queue.push(Resp::RDBLen(crate::RDB_64.len()));
-                // resp_queue.push(Resp::Verbatim(crate::RDB_64.to_string()));
-                Ok(resp_queue)
+        // Resuming from the backlog needs a connection to stream it down,
+        // which only the client handler has.
+        Command::Psync(_) => {
+            let cache = cache.lock_all().await;
+            Ok(full_resync(&cache, &*info.lock().await))
+        }
+        Command::ReplicaOf(None) => {
+            replica::stop(&mut *info.lock().await);
+            Ok(vec![Resp::ok()])
+        }
+        Command::ReplicaOf(Some(master)) => {
+            let mut guard = info.lock().await;
+            if matches!(&guard.master_link, Some(link) if link.is(&master)) {
+                return Ok(vec![Resp::simple(
+                    "OK Already connected to specified master",
+                )]);
+            }
+            replica::start(&mut guard, master, cache, info.clone());
+            Ok(vec![Resp::ok()])
+        }
+        Command::Failover(args) if args.abort => {
+            failover::abort(&mut *info.lock().await)?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Failover(args) => {
+            failover::start(&mut *info.lock().await, args, cache, info.clone())?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Role => Ok(vec![info.lock().await.role_reply()]),
+        Command::Save => {
+            let cache = cache.read_all().await;
+            let info = &mut *info.lock().await;
+            persistence::save(&cache, &mut info.persistence, &info.functions)?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Bgsave => {
+            let mut locked = cache.lock_all().await;
+            let guard = &mut *info.lock().await;
+            let (persistence, functions) = (&mut guard.persistence, &guard.functions);
+            persistence::bgsave(
+                cache.clone(),
+                &mut locked,
+                persistence,
+                functions,
+                info.clone(),
+            )?;
+            Ok(vec![Resp::simple("Background saving started")])
+        }
+        Command::Debug(DebugArgs::Reload { save, flush }) => {
+            let mut cache = cache.lock_all().await;
+            let info = &mut *info.lock().await;
+            let (persistence, functions) = (&mut info.persistence, &mut info.functions);
+            if save {
+                persistence::save(&cache, persistence, functions)?;
+            }
+            if flush {
+                cache.clear();
+                functions.flush();
+            }
+            let path = persistence.rdb_path();
+            let loaded = rdb::load_file(&path, &mut cache, persistence.rdbchecksum)
+                .map_err(|e| e.to_string())
+                .and_then(|loaded| {
+                    functions
+                        .restore(loaded.functions.clone())
+                        .map_err(|e| e.to_string())?;
+                    Ok(loaded)
+                });
+            match loaded {
+                Ok(loaded) => println!("DB reloaded by DEBUG RELOAD: {}", loaded),
+                Err(e) => {
+                    println!("failed to reload {}: {}", path.display(), e);
+                    return Err(CommandError::Persistence(
+                        "Error trying to load the RDB dump, check server logs.",
+                    ));
+                }
             }
-            PsyncArgs::Id(id, offset) => {
+            Ok(vec![Resp::ok()])
+        }
+        Command::Debug(DebugArgs::Sleep(duration)) => {
+            let _cache = cache.lock_all().await;
+            tokio::time::sleep(duration).await;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Debug(DebugArgs::Object(key)) => {
+            let cache = cache.read(&[key.as_str()]).await;
+            let query = cache
+                .get(&key)
+                .filter(|query| !query.is_expired(SystemTime::now()))
+                .ok_or(CommandError::InvalidArguments("no such key"))?;
+            let compression = info.lock().await.persistence.rdbcompression;
+            Ok(vec![Resp::simple(debug_object(query, compression))])
+        }
+        Command::Debug(DebugArgs::Jmap) => {
+            let cache = cache.read_all().await;
+            Ok(vec![Resp::verbatim(debug_jmap(&cache))])
+        }
+        Command::Debug(DebugArgs::SetActiveExpire(on)) => {
+            info.lock().await.active_expire = on;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Commands(CommandArgs::All) => {
+            let specs = command_table::COMMANDS.iter().map(CommandSpec::info);
+            Ok(vec![Resp::Array(specs.collect())])
+        }
+        Command::Commands(CommandArgs::Count) => {
+            Ok(vec![Resp::Integer(command_table::COMMANDS.len() as i64)])
+        }
+        Command::Commands(CommandArgs::Info(names)) => {
+            let specs = names.iter().map(|name| match command_table::lookup(name) {
+                Some(spec) => spec.info(),
+                None => Resp::Null,
+            });
+            Ok(vec![Resp::Array(specs.collect())])
+        }
+        Command::Commands(CommandArgs::Docs(names)) => {
+            let mut reply = Resp::map();
+            let specs: Vec<_> = match names.is_empty() {
+                true => command_table::COMMANDS.iter().collect(),
+                false => names
+                    .iter()
+                    .filter_map(|name| command_table::lookup(name))
+                    .collect(),
+            };
+            for spec in specs {
+                reply = reply.entry(spec.name, spec.docs());
+            }
+            Ok(vec![reply.build()])
+        }
+        Command::Shutdown(save) => {
+            shutdown::prepare(&cache, &info, save).await?;
+            // Like Redis, no reply: the connection just closes as we exit.
+            info.lock().await.shutdown.notify_one();
+            Ok(vec![])
+        }
+        // Connections queue and run their transactions themselves. From the
+        // AOF or a master, the commands in between just run in order.
+        Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::Watch(_)
+        | Command::Unwatch
+        | Command::Quit => Ok(vec![Resp::ok()]),
+        Command::Reset => Ok(vec![Resp::simple("RESET")]),
+        Command::Eval(eval) => {
+            let (sha, body, verbatim) = {
                 let mut info = info.lock().await;
-                let offset = offset.parse::<u64>().map_err(|_| {
-                    CommandError::InvalidArguments("byte offset must be a valid number")
-                })?;
-                info.master_replid = id;
-                info.master_repl_offset = offset;
-                Ok(vec![Resp::SimpleString(format!("REPLCONF ACK {}", offset))])
+                let (sha, body) = info.scripts.resolve(&eval.script)?;
+                let verbatim = !info.lua_replicate_commands;
+                (sha, body, verbatim)
+            };
+            // EVALSHA goes out as EVAL, for replicas that never saw the body.
+            let verbatim = verbatim.then(|| request("EVAL", &body, &eval.keys, &eval.args));
+            let keys = eval.keys.iter().map(|key| key.to_string()).collect();
+            let script = move |bridge| scripting::run(&sha, &body, &eval.keys, &eval.args, bridge);
+            run_script(script, false, verbatim, keys, ctx, &cache, &info).await
+        }
+        Command::Lastsave => {
+            let last_save = info.lock().await.persistence.last_save();
+            Ok(vec![Resp::Integer(last_save as i64)])
+        }
+        Command::Bgrewriteaof => {
+            let cache = cache.lock_all().await;
+            let guard = &mut *info.lock().await;
+            aof::bgrewrite(
+                &cache,
+                &mut guard.persistence,
+                &guard.functions,
+                info.clone(),
+            )?;
+            Ok(vec![Resp::simple(
+                "Background append only file rewriting started",
+            )])
+        }
+        Command::ConfigSet(params) => {
+            config::set(&mut *info.lock().await, &params)?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::ConfigGet(patterns) => {
+            let mut reply = Resp::map();
+            for (name, value) in config::get(&*info.lock().await, &patterns) {
+                reply = reply.entry(name, value);
             }
-        },
+            Ok(vec![reply.build()])
+        }
+        Command::ConfigResetstat => {
+            info.lock().await.reset_stats();
+            Ok(vec![Resp::ok()])
+        }
+        Command::ConfigRewrite => {
+            config::rewrite(&*info.lock().await)?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Subscribe(kind, names) => {
+            let (registry, mailbox) = {
+                let info = info.lock().await;
+                (info.pubsub.clone(), info.clients.mailbox(ctx.id))
+            };
+            if let Some(mailbox) = &mailbox {
+                mailbox.output.set_class(ClientType::Pubsub);
+            }
+            let replies = names.into_iter().map(|name| {
+                let count = registry.subscribe(ctx.id, kind, &name, mailbox.clone());
+                ctx.subscriptions = count;
+                pubsub::confirmation(kind.subscribe_reply(), Some(name), count)
+            });
+            Ok(replies.collect())
+        }
+        Command::Unsubscribe(kind, mut names) => {
+            let registry = info.lock().await.pubsub.clone();
+            if names.is_empty() {
+                names = registry.subscriptions(ctx.id, kind);
+            }
+            // With nothing to unsubscribe from, Redis still confirms, with
+            // no channel and the connection's remaining count.
+            if names.is_empty() {
+                let count = registry.unsubscribe(ctx.id, kind, "");
+                let reply = pubsub::confirmation(kind.unsubscribe_reply(), None, count);
+                return Ok(vec![reply]);
+            }
+            let replies = names
+                .into_iter()
+                .map(|name| {
+                    let count = registry.unsubscribe(ctx.id, kind, &name);
+                    ctx.subscriptions = count;
+                    pubsub::confirmation(kind.unsubscribe_reply(), Some(name), count)
+                })
+                .collect();
+            if ctx.subscriptions == 0 {
+                if let Some(output) = info.lock().await.clients.output(ctx.id) {
+                    output.set_class(ClientType::Normal);
+                }
+            }
+            Ok(replies)
+        }
+        Command::Publish(channel, message) => {
+            let registry = info.lock().await.pubsub.clone();
+            let receivers = registry.publish(&channel, &message);
+            Ok(vec![Resp::Integer(receivers as i64)])
+        }
+        Command::Pubsub(PubsubArgs::Channels(pattern)) => {
+            let channels = info.lock().await.pubsub.active_channels(pattern.as_deref());
+            Ok(vec![Resp::array(channels)])
+        }
+        Command::Pubsub(PubsubArgs::Numsub(channels)) => {
+            let registry = info.lock().await.pubsub.clone();
+            let counts = channels.into_iter().flat_map(|channel| {
+                let count = registry.numsub(&channel) as i64;
+                [channel.into(), Resp::Integer(count)]
+            });
+            Ok(vec![Resp::Array(counts.collect())])
+        }
+        Command::Script(ScriptArgs::Load(body)) => {
+            scripting::compile(&body)?;
+            let sha = info.lock().await.scripts.load(&body);
+            Ok(vec![Resp::bulk(sha)])
+        }
+        Command::Script(ScriptArgs::Exists(shas)) => {
+            let info = info.lock().await;
+            let exists = shas
+                .iter()
+                .map(|sha| Resp::Integer(info.scripts.exists(sha) as i64));
+            Ok(vec![Resp::Array(exists.collect())])
+        }
+        Command::Function(FunctionArgs::Load { code, replace }) => {
+            let library = Library::new(&code)?;
+            let name = info.lock().await.functions.insert(library, replace)?;
+            Ok(vec![Resp::bulk(name)])
+        }
+        Command::Function(FunctionArgs::Delete(name)) => {
+            info.lock().await.functions.delete(&name)?;
+            Ok(vec![Resp::ok()])
+        }
+        Command::Function(FunctionArgs::Flush) => {
+            info.lock().await.functions.flush();
+            Ok(vec![Resp::ok()])
+        }
+        Command::Function(FunctionArgs::List { pattern, with_code }) => {
+            let info = info.lock().await;
+            Ok(vec![info.functions.list(pattern.as_deref(), with_code)])
+        }
+        Command::Fcall(fcall) => {
+            let (library, no_writes, verbatim) = {
+                let info = info.lock().await;
+                match info.functions.get(&fcall.function) {
+                    None => return Err(CommandError::Function("Function not found".into())),
+                    Some((library, function)) => {
+                        let no_writes = function.flags.iter().any(|f| f == "no-writes");
+                        (library, no_writes, !info.lua_replicate_commands)
+                    }
+                }
+            };
+            if fcall.read_only && !no_writes {
+                return Err(CommandError::Function(
+                    "Can not execute a script with write flag using *_ro command.".into(),
+                ));
+            }
+            let verbatim =
+                verbatim.then(|| request("FCALL", &fcall.function, &fcall.keys, &fcall.args));
+            let keys = fcall.keys.iter().map(|key| key.to_string()).collect();
+            let script = move |bridge| functions::call(&library, &fcall, bridge);
+            run_script(script, no_writes, verbatim, keys, ctx, &cache, &info).await
+        }
+        Command::Script(ScriptArgs::Flush) => {
+            info.lock().await.scripts.flush();
+            Ok(vec![Resp::ok()])
+        }
+        Command::Pubsub(PubsubArgs::Numpat) => {
+            Ok(vec![
+                Resp::Integer(info.lock().await.pubsub.numpat() as i64),
+            ])
+        }
+        Command::Memory(MemoryArgs::Stats) => Ok(vec![memprof::stats()]),
+        Command::Memory(MemoryArgs::Doctor) => Ok(vec![Resp::verbatim(memprof::doctor())]),
+        Command::LatencyDoctor => {
+            let report = match &info.lock().await.persistence.aof {
+                Some(aof) => aof.latency_doctor(),
+                None => "No latency was measured: AOF fsyncs are the only events timed, and appendonly is off.".to_string(),
+            };
+            Ok(vec![Resp::verbatim(report)])
+        }
+        Command::Lolwut => Ok(vec![Resp::verbatim(lolwut())]),
+        Command::ObjectRefcount(key) => {
+            let cache = cache.read(&[key.as_str()]).await;
+            let query = cache
+                .get(&key)
+                .filter(|query| !query.is_expired(SystemTime::now()));
+            Ok(vec![query.map_or(Resp::Null, |query| {
+                Resp::Integer(refcount(query))
+            })])
+        }
+        Command::Memory(MemoryArgs::Usage(key, samples)) => {
+            let cache = cache.read(&[key.as_str()]).await;
+            let usage = cache
+                .get(&key)
+                .filter(|query| !query.is_expired(SystemTime::now()))
+                .map(|query| eviction::sampled_entry_size(&key, &query.value, samples));
+            Ok(vec![
+                usage.map_or(Resp::Null, |bytes| Resp::Integer(bytes as i64))
+            ])
+        }
+        Command::Vscan(args) => {
+            let now = SystemTime::now();
+            let (next_cursor, keys) = cache
+                .scan(args.cursor, args.count, |key, query| {
+                    // Only strings have a value to match.
+                    let matches = !query.is_expired(now)
+                        && query.value.as_string().is_some_and(|value| {
+                            glob_match(args.pattern.as_bytes(), value.as_bytes())
+                        });
+                    matches.then(|| Resp::bulk(key))
+                })
+                .await;
+            Ok(vec![Resp::Array(vec![
+                Resp::bulk(next_cursor.to_string()),
+                Resp::Array(keys),
+            ])])
+        }
+    }
+}
+
+// Runs a script on a blocking thread, and the commands it calls here as it
+// calls them, with its connection's context, one at a time while it waits.
+// Its writes are propagated together once it's done, or with `verbatim`,
+// the request that ran it is, if it wrote anything. A replayed script was
+// propagated already.
+async fn run_script(
+    script: impl FnOnce(scripting::Bridge) -> Result<Resp, CommandError> + Send + 'static,
+    read_only: bool,
+    verbatim: Option<Resp>,
+    keys: Vec<String>,
+    ctx: &mut ConnCtx,
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<crate::Info>>,
+) -> Result<Vec<Resp>, CommandError> {
+    let effects = verbatim.is_none() && !ctx.is_replayed();
+    let batch = effects && info.lock().await.begin_batch();
+    let (bridge, mut calls) = mpsc::unbounded_channel();
+    let running = tokio::task::spawn_blocking(move || script(bridge));
+    let mut wrote = false;
+    // Closed once the script is done and its Lua state is gone.
+    while let Some(Called { args, reply }) = calls.recv().await {
+        let called = script_call(args, read_only, effects, ctx, cache, info).await;
+        let _ = reply.send(called.map(|(reply, write)| {
+            wrote |= write;
+            reply
+        }));
+    }
+    let reply = running
+        .await
+        .unwrap_or_else(|e| Err(CommandError::Script(format!("ERR {}", e))));
+    // Writes before an error still happened.
+    let mut info = info.lock().await;
+    if batch {
+        info.end_batch();
+    }
+    match verbatim {
+        Some(request) if wrote && !ctx.is_replayed() => info.propagate(&request, &keys),
+        _ => {}
+    }
+    Ok(vec![reply?])
+}
+
+// LOLWUT's art: a Sierpinski triangle, the odd entries of Pascal's
+// triangle, then the version like Redis's.
+fn lolwut() -> String {
+    const ROWS: usize = 16;
+    let mut art = String::new();
+    for row in 0..ROWS {
+        art.push_str(&" ".repeat(ROWS - row - 1));
+        for col in 0..=row {
+            art.push_str(if col & row == col { "* " } else { "  " });
+        }
+        art.truncate(art.trim_end().len());
+        art.push('\n');
+    }
+    art.push_str(&format!("\nRedis ver. {}\n", env!("CARGO_PKG_VERSION")));
+    art
+}
+
+// A script's EVAL or FCALL, as propagated verbatim.
+fn request(name: &str, script: &str, keys: &[BulkString], args: &[BulkString]) -> Resp {
+    let mut request = vec![
+        Resp::bulk(name),
+        Resp::bulk(script),
+        Resp::bulk(keys.len().to_string()),
+    ];
+    request.extend(
+        keys.iter()
+            .chain(args)
+            .map(|arg| Resp::Bulk(Some(arg.clone()))),
+    );
+    Resp::Array(request)
+}
+
+// A command a script called, with whether it wrote. Those flagged noscript
+// can't be, nor writes from a read-only script, and writes are refused as
+// a client's would be, unless the script is being replayed. With `effects`,
+// writes are propagated as they happen.
+async fn script_call(
+    args: Vec<BulkString>,
+    read_only: bool,
+    effects: bool,
+    ctx: &mut ConnCtx,
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<crate::Info>>,
+) -> Result<(Resp, bool), CommandError> {
+    let Some(spec) = command_table::lookup(args[0].as_str()) else {
+        return Err(CommandError::Script(
+            "ERR Unknown Redis command called from script".into(),
+        ));
+    };
+    if spec.flags.contains(&"noscript") {
+        return Err(CommandError::Script(
+            "ERR This Redis command is not allowed from script".into(),
+        ));
+    }
+    let req = Resp::Array(args.into_iter().map(|arg| Resp::Bulk(Some(arg))).collect());
+    let cmd = Command::parse(&req)?;
+    if cmd.is_write() && read_only {
+        return Err(CommandError::Script(
+            "ERR Write commands are not allowed from read-only scripts.".into(),
+        ));
+    }
+    let is_write = cmd.is_write();
+    if is_write && !ctx.is_replayed() {
+        info.lock().await.refuses_write()?;
+    }
+    let keys = cmd.keys();
+    let propagated = cmd.propagated().unwrap_or(req);
+    // Scripts can't call anything that replies more than once.
+    let replies = Box::pin(execute_command(cmd, ctx, cache.clone(), info.clone())).await?;
+    if is_write && effects {
+        info.lock().await.propagate(&propagated, &keys);
+    }
+    Ok((replies.into_iter().next().unwrap_or(Resp::Null), is_write))
+}
+
+// A store and server with nothing configured, and a connection to run
+// commands on them with, for tests.
+#[cfg(test)]
+pub struct TestServer {
+    pub cache: Arc<Store>,
+    pub info: Arc<Mutex<crate::Info>>,
+    pub ctx: ConnCtx,
+}
+
+#[cfg(test)]
+impl TestServer {
+    pub fn new() -> Self {
+        Self {
+            cache: Arc::default(),
+            info: Arc::new(Mutex::new(crate::Info::new(
+                crate::Role::Master,
+                persistence::Persistence::new(true),
+                eviction::Eviction::new(0),
+                crate::clients::Clients::new(10, 0),
+            ))),
+            ctx: ConnCtx::new(1),
+        }
+    }
+
+    pub async fn execute(&mut self, cmd: Command) -> Result<Vec<Resp>, CommandError> {
+        execute_command(cmd, &mut self.ctx, self.cache.clone(), self.info.clone()).await
+    }
+
+    // Parses a request from its arguments and runs it.
+    pub async fn run(&mut self, args: &[&str]) -> Result<Vec<Resp>, CommandError> {
+        self.execute(Command::from_resp(Resp::array(args.iter().copied()))?)
+            .await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::Resp;
+    use crate::protocol::{Resp, RespEncoding};
 
     #[test]
     fn test_parse_echo_command() {
         let input = Resp::Array(vec![
-            Resp::Bulk(Some("ECHO".to_string())),
-            Resp::Bulk(Some("hello".to_string())),
+            Resp::Bulk(Some("ECHO".into())),
+            Resp::Bulk(Some("hello".into())),
         ]);
 
         let command = Command::from_resp(input).unwrap();
         match command {
-            Command::Echo(args) => assert_eq!(args, "hello".to_string()),
+            Command::Echo(args) => assert_eq!(args, *"hello"),
             _ => panic!("Expected Echo command"),
         }
     }
 
+    #[test]
+    fn test_parse_hello_command() {
+        let input = Resp::Array(vec![
+            Resp::Bulk(Some("HELLO".into())),
+            Resp::Bulk(Some("3".into())),
+            Resp::Bulk(Some("AUTH".into())),
+            Resp::Bulk(Some("default".into())),
+            Resp::Bulk(Some("secret".into())),
+            Resp::Bulk(Some("SETNAME".into())),
+            Resp::Bulk(Some("worker".into())),
+        ]);
+
+        match Command::from_resp(input).unwrap() {
+            Command::Hello(hello) => {
+                assert_eq!(hello.protover, Some(3));
+                assert_eq!(
+                    hello.auth,
+                    Some(("default".to_string(), "secret".to_string()))
+                );
+                assert_eq!(hello.setname, Some("worker".to_string()));
+            }
+            _ => panic!("Expected Hello command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_share_the_stored_value() {
+        let mut server = TestServer::new();
+        let request = bytes::Bytes::from_static(b"k value");
+        let value = BulkString::from_bytes(request.slice(2..)).unwrap();
+        server
+            .execute(Command::Set("k".into(), value, None))
+            .await
+            .unwrap();
+        let read = || async {
+            match get(&server.cache, &server.info, "k", true, false)
+                .await
+                .unwrap()
+            {
+                Resp::Bulk(Some(value)) => value.as_ptr(),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        };
+        // The stored value is its own copy, which every read then shares.
+        let first = read().await;
+        assert_ne!(first, request[2..].as_ptr());
+        assert_eq!(first, read().await);
+    }
+
+    #[tokio::test]
+    async fn test_vscan_filters_by_value() {
+        let mut server = TestServer::new();
+        for (key, value) in [("a", "error: disk full"), ("b", "ok"), ("c", "fatal error")] {
+            server.run(&["SET", key, value]).await.unwrap();
+        }
+
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        loop {
+            let vscan = Command::Vscan(VscanArgs {
+                cursor,
+                pattern: "*error*".to_string(),
+                count: 1,
+            });
+            let reply = server.execute(vscan).await.unwrap();
+            let Resp::Array(parts) = &reply[0] else {
+                panic!("Expected array reply");
+            };
+            let (Resp::Bulk(Some(next)), Resp::Array(keys)) = (&parts[0], &parts[1]) else {
+                panic!("Expected cursor and keys");
+            };
+            found.extend(keys.iter().cloned());
+            cursor = next.parse().unwrap();
+            if cursor == 0 {
+                break;
+            }
+        }
+        found.sort_by_key(|k| format!("{:?}", k));
+        assert_eq!(
+            found,
+            vec![Resp::Bulk(Some("a".into())), Resp::Bulk(Some("c".into()))]
+        );
+    }
+
     #[test]
     fn test_invalid_command_type() {
         let input = Resp::SimpleString("ECHO".to_string());
@@ -306,22 +2133,417 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Command Error: Invalid Packet - RESP should be an array"
+            "ERR RESP should be an array"
         );
     }
 
     #[test]
     fn test_invalid_command_argument_type() {
-        let input = Resp::Array(vec![
-            Resp::Bulk(Some("ECHO".to_string())),
-            Resp::Integer(42),
-        ]);
+        let input = Resp::Array(vec![Resp::Bulk(Some("ECHO".into())), Resp::Integer(42)]);
 
         let result = Command::from_resp(input);
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Command Error: Invalid Arguments - All arguments must be bulk strings"
+            "ERR All arguments must be bulk strings"
+        );
+    }
+
+    #[test]
+    fn test_errors_use_redis_wording() {
+        let error = |args: &[&str]| {
+            let err = Command::from_resp(Resp::array(args.iter().copied())).unwrap_err();
+            err.to_resp()
+        };
+        assert_eq!(
+            error(&["FLUSHALL", "ASYNC"]),
+            Resp::error("ERR unknown command 'FLUSHALL', with args beginning with: 'ASYNC' ")
+        );
+        assert_eq!(
+            error(&["GET"]),
+            Resp::error("ERR wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            error(&["unlink"]),
+            Resp::error("ERR wrong number of arguments for 'unlink' command")
+        );
+        assert_eq!(
+            error(&["SET", "k", "v", "EX", "10"]),
+            Resp::error("ERR syntax error")
+        );
+        assert_eq!(
+            error(&["SET", "k", "v", "PX", "soon"]),
+            Resp::error("ERR value is not an integer or out of range")
+        );
+    }
+
+    #[test]
+    fn test_set_propagates_absolute_expiry() {
+        let parse = |args: &[&str]| Command::from_resp(Resp::array(args.iter().copied())).unwrap();
+        let set = parse(&["SET", "k", "v", "PX", "60000"]);
+        let Command::Set(_, _, Some(expiry)) = set else {
+            panic!("Expected a SET with an expiry");
+        };
+        let millis = expiry.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let propagated = set.propagated().unwrap();
+        assert_eq!(
+            propagated,
+            crate::format_resp!["SET", "k", "v", "PXAT", millis]
+        );
+
+        // Replaying it later still ends at the same millisecond.
+        let Command::Set(_, _, Some(replayed)) = Command::from_resp(propagated).unwrap() else {
+            panic!("Expected a SET with an expiry");
+        };
+        assert_eq!(
+            replayed.duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            millis
+        );
+        assert!(parse(&["SET", "k", "v"]).propagated().is_none());
+    }
+
+    #[test]
+    fn test_parse_replicaof() {
+        let parse = |host: &str, port: &str| {
+            Command::from_resp(crate::format_resp!["REPLICAOF", host, port])
+        };
+        assert!(matches!(parse("no", "one"), Ok(Command::ReplicaOf(None))));
+        let Ok(Command::ReplicaOf(Some(master))) = parse("localhost", "6380") else {
+            panic!("Expected a master address");
+        };
+        assert_eq!(master.to_string(), "127.0.0.1:6380");
+        assert!(parse("127.0.0.1", "http").is_err());
+    }
+
+    #[test]
+    fn test_parse_failover() {
+        let parse = |args: &[&str]| {
+            let mut req = vec![Resp::bulk("FAILOVER")];
+            req.extend(args.iter().map(|arg| Resp::bulk(*arg)));
+            Command::from_resp(Resp::Array(req))
+        };
+        let Ok(Command::Failover(args)) = parse(&["TO", "localhost", "6380", "TIMEOUT", "500"])
+        else {
+            panic!("Expected FAILOVER");
+        };
+        assert_eq!(args.target.unwrap().to_string(), "127.0.0.1:6380");
+        assert_eq!(args.timeout, Some(500));
+        assert!(!args.abort);
+
+        assert!(matches!(parse(&["abort"]), Ok(Command::Failover(args)) if args.abort));
+        assert!(parse(&["ABORT", "TIMEOUT", "500"]).is_err());
+        assert!(parse(&["TIMEOUT", "0"]).is_err());
+        assert!(parse(&["TO", "localhost"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_config_get_matches_patterns() {
+        let mut server = TestServer::new();
+        server.info.lock().await.persistence.dir = "/var/lib/credis".to_string();
+        let reply = server.run(&["config", "get", "DIR", "db*"]).await.unwrap();
+        let expected = Resp::map()
+            .entry("dir", "/var/lib/credis")
+            .entry("dbfilename", "dump.rdb")
+            .build();
+        assert_eq!(reply, vec![expected]);
+
+        let set = Resp::array(["CONFIG", "SET", "dir"]);
+        assert!(Command::from_resp(set).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_debug_reload_round_trips_dataset() {
+        let dir = std::env::temp_dir().join(format!("credis-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut server = TestServer::new();
+        server.info.lock().await.persistence.dir = dir.to_str().unwrap().to_string();
+        let (cache, info) = (server.cache.clone(), server.info.clone());
+        let mut run = async |args: &[&str]| server.run(args).await;
+        run(&["SET", "plain", "12345"]).await.unwrap();
+        run(&["SET", "ttl", "v", "PX", "60000"]).await.unwrap();
+        let before = cache.lock_all().await["ttl"].expiry.unwrap();
+        let hash = Value::Hash(Arc::new(vec![("f".into(), "v".into())]));
+        let query = crate::server::Query::new(hash.clone(), None, SystemTime::now());
+        cache.lock_all().await.insert("hash".to_string(), query);
+
+        assert_eq!(run(&["DEBUG", "RELOAD"]).await.unwrap(), vec![Resp::ok()]);
+        assert_eq!(cache.lock_all().await["plain"].value, "12345");
+        assert_eq!(cache.lock_all().await["hash"].value, hash);
+        assert!(matches!(
+            run(&["GET", "hash"]).await,
+            Err(CommandError::WrongType)
+        ));
+        let after = cache.lock_all().await["ttl"].expiry.unwrap();
+        assert!(before.duration_since(after).unwrap() < Duration::from_millis(1));
+
+        run(&["SET", "plain", "changed"]).await.unwrap();
+        run(&["DEBUG", "RELOAD", "NOSAVE"]).await.unwrap();
+        assert_eq!(cache.lock_all().await["plain"].value, "12345");
+        assert!(Command::from_resp(Resp::array(["DEBUG", "RELOAD", "LATER"])).is_err());
+
+        let saves = |info: &crate::Info| info.persistence.info(&std::collections::HashMap::new());
+        assert!(saves(&*info.lock().await).contains("rdb_saves:1"));
+        assert_eq!(
+            run(&["CONFIG", "RESETSTAT"]).await.unwrap(),
+            vec![Resp::ok()]
+        );
+        assert!(saves(&*info.lock().await).contains("rdb_saves:0"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_object_and_active_expire() {
+        let mut server = TestServer::new();
+        let (cache, info) = (server.cache.clone(), server.info.clone());
+        let mut run = async |args: &[&str]| server.run(args).await;
+        let long = "x".repeat(100);
+        for (value, encoding) in [("12", "int"), ("012", "embstr"), (long.as_str(), "raw")] {
+            run(&["SET", "k", value]).await.unwrap();
+            let Resp::SimpleString(reply) = &run(&["DEBUG", "OBJECT", "k"]).await.unwrap()[0]
+            else {
+                panic!("expected a simple string");
+            };
+            assert!(
+                reply.contains(&format!("encoding:{} ", encoding)),
+                "{}",
+                reply
+            );
+        }
+        assert!(run(&["DEBUG", "OBJECT", "missing"]).await.is_err());
+
+        run(&["SET", "gone", "v", "PX", "1"]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        run(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await.unwrap();
+        let cycle = || async {
+            crate::expire::active_expire_cycle(&mut cache.lock_all().await, &mut *info.lock().await)
+        };
+        assert_eq!(cycle().await, 0);
+        run(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await.unwrap();
+        assert_eq!(cycle().await, 1);
+        assert!(Command::from_resp(Resp::array(["DEBUG", "SLEEP", "-1"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_debug_jmap_sizes_each_value_type() {
+        let mut server = TestServer::new();
+        server.run(&["SET", "a", "1"]).await.unwrap();
+        server.run(&["SET", "b", "2"]).await.unwrap();
+        let list = Value::List(Arc::new(vec!["x".repeat(1000).into()]));
+        let query = crate::server::Query::new(list.clone(), None, SystemTime::now());
+        server.cache.lock_all().await.insert("l".to_string(), query);
+
+        let [Resp::Verbatim(_, histogram)] = &server.run(&["DEBUG", "JMAP"]).await.unwrap()[..]
+        else {
+            panic!("expected verbatim text");
+        };
+        let strings = 2 * eviction::entry_size("a", &"1".into());
+        let lists = eviction::entry_size("l", &list);
+        let lines: Vec<_> = histogram.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            [
+                format!("   1: {:>14} {:>14}  list", 1, lists),
+                format!("   2: {:>14} {:>14}  string", 2, strings),
+                format!("Total {:>14} {:>14}", 3, lists + strings),
+            ]
         );
     }
+
+    #[tokio::test]
+    async fn test_connection_commands_update_context() {
+        let mut server = TestServer::new();
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let (id, _control) = server
+            .info
+            .lock()
+            .await
+            .clients
+            .admit(local, local)
+            .unwrap();
+        server.ctx = ConnCtx::new(id);
+        let mut run = async |args: &[&str]| server.run(args).await.unwrap();
+        run(&["HELLO", "3", "SETNAME", "worker"]).await;
+        assert_eq!(
+            run(&["CLIENT", "GETNAME"]).await,
+            vec![Resp::bulk("worker")]
+        );
+        assert_eq!(run(&["CLIENT", "ID"]).await, vec![Resp::Integer(id as i64)]);
+
+        run(&["SET", "k", "v"]).await;
+        run(&["CLIENT", "NO-TOUCH", "ON"]).await;
+        run(&["GET", "k"]).await;
+        assert_eq!(server.ctx.protocol, Protocol::Resp3);
+        assert_eq!(server.ctx.name.as_deref(), Some("worker"));
+        assert!(server.ctx.no_touch);
+        assert_eq!(server.cache.lock_all().await["k"].access.hits(), 0);
+        assert!(server
+            .info
+            .lock()
+            .await
+            .clients
+            .list()
+            .contains("name=worker"));
+    }
+
+    #[tokio::test]
+    async fn test_get_carries_key_popularity_only_when_asked() {
+        let mut server = TestServer::new();
+        let mut run = async |args: &[&str]| server.run(args).await.unwrap();
+        run(&["SET", "k", "v"]).await;
+        assert_eq!(run(&["GET", "k"]).await, vec![Resp::bulk("v")]);
+
+        run(&["CLIENT", "KEY-POPULARITY", "ON"]).await;
+        assert_eq!(
+            run(&["GET", "k"]).await,
+            vec![Resp::bulk("v")
+                .with_attributes(vec![(Resp::simple("key-popularity"), Resp::Integer(2),)])]
+        );
+        run(&["CLIENT", "KEY-POPULARITY", "OFF"]).await;
+        assert_eq!(run(&["GET", "k"]).await, vec![Resp::bulk("v")]);
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_replies_are_pushes_between_other_replies() {
+        let mut server = TestServer::new();
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let (id, mut control) = server
+            .info
+            .lock()
+            .await
+            .clients
+            .admit(local, local)
+            .unwrap();
+        server.ctx = ConnCtx::new(id);
+        let mut run = async |args: &[&str]| server.run(args).await.unwrap();
+        run(&["HELLO", "3"]).await;
+        let subscribed = run(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(subscribed[0].encode_as(Protocol::Resp3)[0], b'>');
+        assert_eq!(subscribed[0].encode_as(Protocol::Resp2)[0], b'*');
+        assert_eq!(run(&["SET", "k", "v"]).await, vec![Resp::ok()]);
+        assert_eq!(
+            run(&["PUBLISH", "news", "hi"]).await,
+            vec![Resp::Integer(1)]
+        );
+        let Some(crate::clients::Control::Message(message)) = control.recv().await else {
+            panic!("expected a message");
+        };
+        assert_eq!(
+            message,
+            Resp::Push(vec!["message".into(), "news".into(), "hi".into()])
+        );
+        let unsubscribed = run(&["UNSUBSCRIBE"]).await;
+        assert_eq!(
+            unsubscribed,
+            [pubsub::confirmation("unsubscribe", Some("news".into()), 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scripts_call_commands() {
+        let mut server = TestServer::new();
+        let (cache, info) = (server.cache.clone(), server.info.clone());
+        let mut run = async |args: &[&str]| server.run(args).await.map_err(|e| e.to_string());
+        let body = "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('GET', KEYS[1])";
+        assert_eq!(
+            run(&["EVAL", body, "1", "k", "v"]).await.unwrap(),
+            [Resp::bulk("v")]
+        );
+        // Replicas get the script's write, or the script itself.
+        let offset = async || info.lock().await.replicas.offset() as usize;
+        assert_eq!(
+            offset().await,
+            Resp::array(["SET", "k", "v"]).encode().len()
+        );
+        run(&["CONFIG", "SET", "lua-replicate-commands", "no"])
+            .await
+            .unwrap();
+        run(&["EVALSHA", &scripting::sha1(body), "1", "k", "v"])
+            .await
+            .unwrap();
+        let verbatim = Resp::array(["EVAL", body, "1", "k", "v"]).encode().len();
+        assert_eq!(
+            offset().await,
+            Resp::array(["SET", "k", "v"]).encode().len() + verbatim
+        );
+
+        let refused = run(&["EVAL", "return redis.call('SAVE')", "0"]).await;
+        assert!(refused
+            .unwrap_err()
+            .starts_with("ERR This Redis command is not allowed from script script:"));
+
+        let library = "#!lua name=lib\nredis.register_function{function_name='set', \
+            callback=function(keys) return redis.call('SET', keys[1], 'x') end, flags={'no-writes'}}";
+        run(&["FUNCTION", "LOAD", library]).await.unwrap();
+        let refused = run(&["FCALL_RO", "set", "1", "k"]).await;
+        assert!(refused
+            .unwrap_err()
+            .starts_with("ERR Write commands are not allowed from read-only scripts."));
+
+        // A replica's clients can't write through scripts either.
+        info.lock().await.role = crate::Role::Slave;
+        let refused = run(&["EVAL", "return redis.pcall('SET', 'k', 'w')", "0"]).await;
+        assert!(refused.unwrap_err().starts_with("READONLY"));
+        assert_eq!(cache.lock_all().await["k"].value, "v");
+    }
+
+    #[tokio::test]
+    async fn test_latency_doctor_and_lolwut_reply_with_text() {
+        let mut server = TestServer::new();
+        let text = |reply: Vec<Resp>| match reply.as_slice() {
+            [Resp::Verbatim(format, text)] if format == "txt" => text.clone(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let doctor = text(server.run(&["LATENCY", "DOCTOR"]).await.unwrap());
+        assert!(doctor.contains("appendonly is off"));
+        let art = text(server.run(&["LOLWUT", "VERSION", "5"]).await.unwrap());
+        assert!(art.starts_with("               *\n              * *\n"));
+        assert!(art.ends_with(&format!("Redis ver. {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(matches!(
+            server.run(&["LOLWUT", "VERSION", "x"]).await,
+            Err(CommandError::NotInteger)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_usage_and_shared_values() {
+        let mut server = TestServer::new();
+        let mut run = async |args: &[&str]| server.run(args).await.unwrap();
+        let usage = |reply: Vec<Resp>| match reply[..] {
+            [Resp::Integer(bytes)] => bytes,
+            _ => panic!("unexpected reply {:?}", reply),
+        };
+        run(&["SET", "short", "v"]).await;
+        run(&["SET", "long", &"v".repeat(1000)]).await;
+        let short = usage(run(&["MEMORY", "USAGE", "short"]).await);
+        let long = usage(run(&["MEMORY", "USAGE", "long", "SAMPLES", "0"]).await);
+        // The table slot and allocator overheads come on top of the bytes.
+        assert!(short > 64);
+        assert!((long - short) > 950);
+        assert_eq!(run(&["MEMORY", "USAGE", "none"]).await, vec![Resp::Null]);
+
+        // Every key holding a small integer uses the same copy of it.
+        run(&["SET", "counter", "1"]).await;
+        run(&["SET", "other", "1"]).await;
+        let counter = usage(run(&["MEMORY", "USAGE", "counter"]).await);
+        assert!(counter < short);
+        assert_eq!(
+            run(&["OBJECT", "REFCOUNT", "other"]).await,
+            vec![Resp::Integer(i32::MAX as i64)]
+        );
+        assert_eq!(
+            run(&["OBJECT", "REFCOUNT", "short"]).await,
+            vec![Resp::Integer(1)]
+        );
+        assert_eq!(run(&["OBJECT", "REFCOUNT", "none"]).await, vec![Resp::Null]);
+
+        let parse = |args: &[&str]| Command::from_resp(Resp::array(args.iter().copied()));
+        assert!(matches!(
+            parse(&["MEMORY", "USAGE", "k", "SAMPLES", "x"]),
+            Err(CommandError::NotInteger)
+        ));
+        assert!(matches!(
+            parse(&["MEMORY", "USAGE", "k", "COUNT", "5"]),
+            Err(CommandError::Syntax)
+        ));
+    }
 }
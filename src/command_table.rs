@@ -0,0 +1,236 @@
+use crate::{
+    command::{self, Command, CommandError},
+    protocol::Resp,
+};
+
+// Every command we serve: how to parse it, and what COMMAND reports about
+// it, in Redis's terms. Arity counts the command name; a negative arity -n
+// means at least n arguments. Requests are checked against it before they
+// reach the parser. Keys sit at first_key, first_key + step, ... up to
+// last_key, where a negative last_key counts from the end; all zero means
+// no keys.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub parse: Parse,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+}
+
+pub type Parse = fn(&[Resp]) -> Result<Command, CommandError>;
+
+#[allow(clippy::too_many_arguments)]
+const fn spec(
+    name: &'static str,
+    parse: Parse,
+    arity: i64,
+    flags: &'static [&'static str],
+    (first_key, last_key, step): (i64, i64, i64),
+    group: &'static str,
+    since: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        parse,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        group,
+        since,
+        summary,
+    }
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+
+pub static COMMANDS: &[CommandSpec] = &[
+    spec("bgrewriteaof", |_| Ok(Command::Bgrewriteaof), 1, &["admin", "noscript", "no_async_loading"], NO_KEYS, "server", "1.0.0",
+        "Asynchronously rewrites the append-only file to disk."),
+    spec("bgsave", command::parse_bgsave, -1, &["admin", "noscript", "no_async_loading"], NO_KEYS, "server", "1.0.0",
+        "Asynchronously saves the database(s) to disk."),
+    spec("client", command::parse_client, -2, &["noscript", "loading", "stale"], NO_KEYS, "connection", "2.4.0",
+        "A container for client connection commands."),
+    spec("command", command::parse_command_introspection, -1, &["loading", "stale"], NO_KEYS, "server", "2.8.13",
+        "Returns detailed information about all commands."),
+    spec("config", command::parse_config, -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "2.0.0",
+        "A container for server configuration commands."),
+    spec("debug", command::parse_debug, -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "1.0.0",
+        "A container for debugging commands."),
+    spec("del", command::parse_del, -2, &["write"], (1, -1, 1), "generic", "1.0.0",
+        "Deletes one or more keys."),
+    spec("discard", |_| Ok(Command::Discard), 1, &["noscript", "loading", "stale", "fast", "allow_busy"], NO_KEYS,
+        "transactions", "2.0.0", "Discards a transaction."),
+    spec("echo", command::parse_echo, 2, &["fast"], NO_KEYS, "connection", "1.0.0",
+        "Returns the given string."),
+    spec("eval", command::parse_eval, -3,
+        &["noscript", "stale", "skip_monitor", "may_replicate", "no_mandatory_keys", "movablekeys"], NO_KEYS,
+        "scripting", "2.6.0", "Executes a server-side Lua script."),
+    spec("evalsha", command::parse_evalsha, -3,
+        &["noscript", "stale", "skip_monitor", "may_replicate", "no_mandatory_keys", "movablekeys"], NO_KEYS,
+        "scripting", "2.6.0", "Executes a server-side Lua script by SHA1 digest."),
+    spec("exec", |_| Ok(Command::Exec), 1, &["noscript", "loading", "stale", "skip_slowlog"], NO_KEYS,
+        "transactions", "1.2.0", "Executes all commands in a transaction."),
+    spec("failover", command::parse_failover, -1, &["admin", "noscript", "stale"], NO_KEYS, "server", "6.2.0",
+        "Starts a coordinated failover from a server to one of its replicas."),
+    spec("fcall", command::parse_fcall, -3,
+        &["noscript", "stale", "skip_monitor", "may_replicate", "no_mandatory_keys", "movablekeys"], NO_KEYS,
+        "scripting", "7.0.0", "Invokes a function."),
+    spec("fcall_ro", command::parse_fcall_ro, -3,
+        &["readonly", "noscript", "stale", "skip_monitor", "no_mandatory_keys", "movablekeys"], NO_KEYS,
+        "scripting", "7.0.0", "Invokes a read-only function."),
+    spec("function", command::parse_function, -2, &[], NO_KEYS, "scripting", "7.0.0",
+        "A container for function commands."),
+    spec("get", command::parse_get, 2, &["readonly", "fast"], (1, 1, 1), "string", "1.0.0",
+        "Returns the string value of a key."),
+    spec("hello", command::parse_hello, -1, &["noscript", "loading", "stale", "fast", "no_auth"], NO_KEYS,
+        "connection", "6.0.0", "Handshakes with the Redis server."),
+    spec("info", command::parse_info, -1, &["loading", "stale"], NO_KEYS, "server", "1.0.0",
+        "Returns information and statistics about the server."),
+    spec("lastsave", |_| Ok(Command::Lastsave), 1, &["loading", "stale", "fast"], NO_KEYS, "server", "1.0.0",
+        "Returns the Unix timestamp of the last successful save to disk."),
+    spec("latency", command::parse_latency, -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "2.8.13",
+        "A container for latency diagnostics commands."),
+    spec("lolwut", command::parse_lolwut, -1, &["readonly", "fast"], NO_KEYS, "server", "5.0.0",
+        "Displays computer art and the Redis version."),
+    spec("memory", command::parse_memory, -2, &[], NO_KEYS, "server", "4.0.0",
+        "A container for memory diagnostics commands."),
+    spec("multi", |_| Ok(Command::Multi), 1, &["noscript", "loading", "stale", "fast", "allow_busy"], NO_KEYS,
+        "transactions", "1.2.0", "Starts a transaction."),
+    spec("object", command::parse_object, -2, &[], NO_KEYS, "generic", "2.2.3",
+        "A container for object introspection commands."),
+    spec("ping", command::parse_ping, -1, &["fast"], NO_KEYS, "connection", "1.0.0",
+        "Returns the server's liveliness response."),
+    spec("psubscribe", command::parse_psubscribe, -2, &["pubsub", "noscript", "loading", "stale"], NO_KEYS,
+        "pubsub", "2.0.0", "Listens for messages published to channels that match one or more patterns."),
+    spec("psync", command::parse_psync, -3, &["admin", "noscript", "no_async_loading", "no_multi"], NO_KEYS,
+        "server", "2.8.0", "An internal command used in replication."),
+    spec("publish", command::parse_publish, 3, &["pubsub", "loading", "stale", "fast"], NO_KEYS, "pubsub",
+        "2.0.0", "Posts a message to a channel."),
+    spec("pubsub", command::parse_pubsub, -2, &[], NO_KEYS, "pubsub", "2.8.0",
+        "A container for Pub/Sub commands."),
+    spec("punsubscribe", command::parse_punsubscribe, -1, &["pubsub", "noscript", "loading", "stale"], NO_KEYS,
+        "pubsub", "2.0.0", "Stops listening to messages published to channels that match one or more patterns."),
+    spec("quit", |_| Ok(Command::Quit), -1, &["noscript", "loading", "stale", "fast", "no_auth", "allow_busy"],
+        NO_KEYS, "connection", "1.0.0", "Closes the connection."),
+    spec("replconf", command::parse_replconf, -1, &["admin", "noscript", "loading", "stale", "allow_busy"], NO_KEYS,
+        "server", "3.0.0", "An internal command for configuring the replication stream."),
+    spec("replicaof", command::parse_replicaof, 3, &["admin", "noscript", "stale", "no_async_loading"], NO_KEYS,
+        "server", "5.0.0", "Configures a server as replica of another, or promotes it to a master."),
+    spec("reset", |_| Ok(Command::Reset), 1, &["noscript", "loading", "stale", "fast", "no_auth", "allow_busy"],
+        NO_KEYS, "connection", "6.2.0", "Resets the connection."),
+    spec("role", |_| Ok(Command::Role), 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, "server", "2.8.12",
+        "Returns the replication role."),
+    spec("save", |_| Ok(Command::Save), 1, &["admin", "noscript", "no_async_loading", "no_multi"], NO_KEYS, "server",
+        "1.0.0", "Synchronously saves the database(s) to disk."),
+    spec("script", command::parse_script, -2, &["noscript"], NO_KEYS, "scripting", "2.6.0",
+        "A container for Lua scripts management commands."),
+    spec("set", command::parse_set, -3, &["write", "denyoom"], (1, 1, 1), "string", "1.0.0",
+        "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist."),
+    spec("shutdown", command::parse_shutdown, -1, &["admin", "noscript", "loading", "stale", "no_multi", "allow_busy"],
+        NO_KEYS, "server", "1.0.0", "Synchronously saves the database(s) to disk and shuts down the Redis server."),
+    spec("slaveof", command::parse_replicaof, 3, &["admin", "noscript", "stale", "no_async_loading"], NO_KEYS,
+        "server", "1.0.0", "Sets a Redis server as a replica of another, or promotes it to being a master."),
+    spec("subscribe", command::parse_subscribe, -2, &["pubsub", "noscript", "loading", "stale"], NO_KEYS,
+        "pubsub", "2.0.0", "Listens for messages published to channels."),
+    spec("unlink", command::parse_del, -2, &["write", "fast"], (1, -1, 1), "generic", "4.0.0",
+        "Asynchronously deletes one or more keys."),
+    spec("unsubscribe", command::parse_unsubscribe, -1, &["pubsub", "noscript", "loading", "stale"], NO_KEYS,
+        "pubsub", "2.0.0", "Stops listening to messages posted to channels."),
+    spec("unwatch", |_| Ok(Command::Unwatch), 1, &["noscript", "loading", "stale", "fast", "allow_busy"], NO_KEYS,
+        "transactions", "2.2.0", "Forgets about watched keys of a transaction."),
+    spec("vscan", command::parse_vscan, -2, &["readonly"], NO_KEYS, "generic", "0.1.0",
+        "Iterates over keys whose values match a pattern."),
+    spec("watch", command::parse_watch, -2, &["noscript", "loading", "stale", "fast", "allow_busy"], (1, -1, 1),
+        "transactions", "2.2.0", "Monitors changes to keys to determine the execution of a transaction."),
+];
+
+// Any case goes, compared a byte at a time rather than lowercased first,
+// since every request looks its command up.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    let lowered = name.bytes().map(|b| b.to_ascii_lowercase());
+    let i = COMMANDS
+        .binary_search_by(|spec| spec.name.bytes().cmp(lowered.clone()))
+        .ok()?;
+    Some(&COMMANDS[i])
+}
+
+impl CommandSpec {
+    // Whether a request of `argc` arguments, name included, fits the arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        match self.arity {
+            n if n >= 0 => argc as i64 == n,
+            n => argc as i64 >= -n,
+        }
+    }
+
+    // One COMMAND / COMMAND INFO entry. ACL categories, tips, key specs and
+    // subcommands are left empty.
+    pub fn info(&self) -> Resp {
+        let flags = self.flags.iter().map(|flag| Resp::simple(*flag)).collect();
+        Resp::Array(vec![
+            Resp::bulk(self.name),
+            Resp::Integer(self.arity),
+            Resp::Set(flags),
+            Resp::Integer(self.first_key),
+            Resp::Integer(self.last_key),
+            Resp::Integer(self.step),
+            Resp::Array(vec![]),
+            Resp::Set(vec![]),
+            Resp::Array(vec![]),
+            Resp::Array(vec![]),
+        ])
+    }
+
+    // One COMMAND DOCS entry.
+    pub fn docs(&self) -> Resp {
+        Resp::map()
+            .entry("summary", self.summary)
+            .entry("since", self.since)
+            .entry("group", self.group)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_are_checked_against_the_table() {
+        let parse = |args: &[&str]| Command::from_resp(Resp::array(args.iter().copied()));
+        for args in [
+            &["GET"][..],
+            &["GET", "a", "b"],
+            &["SET", "k"],
+            &["ROLE", "now"],
+        ] {
+            assert!(matches!(parse(args), Err(CommandError::WrongArity(_))));
+        }
+        // The write flag and what replication treats as a write agree.
+        for args in [
+            &["SET", "k", "v"][..],
+            &["DEL", "k"],
+            &["UNLINK", "k"],
+            &["GET", "k"],
+        ] {
+            let spec = lookup(args[0]).unwrap();
+            let cmd = parse(args).unwrap();
+            assert_eq!(spec.flags.contains(&"write"), cmd.is_write(), "{}", args[0]);
+        }
+        assert!(COMMANDS.windows(2).all(|w| w[0].name < w[1].name));
+        assert_eq!(lookup("GET").unwrap().first_key, 1);
+        assert!(lookup("flushall").is_none());
+        for spec in COMMANDS {
+            let name = spec.name.to_uppercase();
+            assert_eq!(lookup(&name).map(|found| found.name), Some(spec.name));
+        }
+    }
+}
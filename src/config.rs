@@ -0,0 +1,594 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{command::CommandError, eviction, glob::glob_match, obuf, persistence, server::Info};
+
+// Every parameter CONFIG GET and CONFIG SET know about. Values live where
+// the server uses them; each entry reads its value from there and, if it
+// can change at runtime, parses a new one into a setter. CONFIG SET parses
+// every value before applying any, so one bad value changes nothing.
+struct Param {
+    name: &'static str,
+    get: fn(&Info) -> String,
+    // None for parameters only settable at startup.
+    set: Option<Parse>,
+}
+
+type Parse = fn(&Info, &str) -> Result<Setter, CommandError>;
+type Setter = Box<dyn FnOnce(&mut Info) + Send>;
+
+static PARAMS: &[Param] = &[
+    Param {
+        name: "dir",
+        get: |info| info.persistence.dir.clone(),
+        set: Some(|_, value| {
+            if !Path::new(value).is_dir() {
+                return Err(CommandError::InvalidArguments("No such directory"));
+            }
+            Ok(setter(value.to_string(), |info, dir| {
+                info.persistence.dir = dir
+            }))
+        }),
+    },
+    Param {
+        name: "dbfilename",
+        get: |info| info.persistence.dbfilename.clone(),
+        set: Some(|_, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err(CommandError::InvalidArguments(
+                    "dbfilename can't be a path, just a filename",
+                ));
+            }
+            Ok(setter(value.to_string(), |info, name| {
+                info.persistence.dbfilename = name
+            }))
+        }),
+    },
+    Param {
+        name: "rdbcompression",
+        get: |info| yes_no(info.persistence.rdbcompression),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.persistence.rdbcompression = on
+            }))
+        }),
+    },
+    Param {
+        name: "rdbchecksum",
+        get: |info| yes_no(info.persistence.rdbchecksum),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.persistence.rdbchecksum = on
+            }))
+        }),
+    },
+    Param {
+        name: "save",
+        get: |info| persistence::format_save_points(&info.persistence.save_points),
+        set: Some(|_, value| {
+            Ok(setter(
+                persistence::parse_save_points(value)?,
+                |info, points| info.persistence.save_points = points,
+            ))
+        }),
+    },
+    Param {
+        name: "stop-writes-on-bgsave-error",
+        get: |info| yes_no(info.persistence.stop_writes_on_bgsave_error),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.persistence.stop_writes_on_bgsave_error = on
+            }))
+        }),
+    },
+    Param {
+        name: "appendonly",
+        get: |info| yes_no(info.persistence.aof.is_some()),
+        set: None,
+    },
+    Param {
+        name: "appendfsync",
+        get: |info| info.persistence.appendfsync.as_str().to_string(),
+        set: Some(|_, value| {
+            Ok(setter(value.parse()?, |info, policy| {
+                info.persistence.appendfsync = policy
+            }))
+        }),
+    },
+    Param {
+        name: "aof-load-truncated",
+        get: |info| yes_no(info.persistence.aof_load_truncated),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.persistence.aof_load_truncated = on
+            }))
+        }),
+    },
+    Param {
+        name: "auto-aof-rewrite-percentage",
+        get: |info| info.persistence.auto_aof_rewrite_percentage.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, percentage| {
+                info.persistence.auto_aof_rewrite_percentage = percentage
+            }))
+        }),
+    },
+    Param {
+        name: "auto-aof-rewrite-min-size",
+        get: |info| info.persistence.auto_aof_rewrite_min_size.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, size| {
+                info.persistence.auto_aof_rewrite_min_size = size
+            }))
+        }),
+    },
+    Param {
+        name: "auto-aof-rewrite-amplification",
+        get: |info| info.persistence.auto_aof_rewrite_amplification.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, records| {
+                info.persistence.auto_aof_rewrite_amplification = records
+            }))
+        }),
+    },
+    Param {
+        name: "bind",
+        get: |info| info.bind.join(" "),
+        set: None,
+    },
+    Param {
+        name: "port",
+        get: |info| info.port.to_string(),
+        set: None,
+    },
+    Param {
+        name: "maxclients",
+        get: |info| info.clients.maxclients.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, max| {
+                info.clients.maxclients = max
+            }))
+        }),
+    },
+    Param {
+        name: "client-output-buffer-limit",
+        get: |info| info.clients.output_limits().to_string(),
+        set: Some(|_, value| {
+            Ok(setter(obuf::parse_limits(value)?, |info, limits| {
+                for (class, limit) in limits {
+                    info.clients.set_output_limit(class, limit)
+                }
+            }))
+        }),
+    },
+    Param {
+        name: "maxmemory",
+        get: |info| info.eviction.maxmemory.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, max| {
+                info.eviction.maxmemory = max
+            }))
+        }),
+    },
+    Param {
+        name: "maxmemory-policy",
+        get: |info| info.eviction.policy().name().to_string(),
+        set: Some(|info, value| {
+            if !info.eviction.has_policy(value) {
+                return Err(CommandError::InvalidArguments("unknown maxmemory policy"));
+            }
+            Ok(setter(value.to_string(), |info, policy| {
+                let _ = info.eviction.set_policy(&policy);
+            }))
+        }),
+    },
+    Param {
+        name: "maxmemory-samples",
+        get: |info| info.eviction.samples.to_string(),
+        set: Some(|_, value| {
+            let samples = parse_number(value)?;
+            if !(1..=64).contains(&samples) {
+                return Err(CommandError::InvalidArguments(
+                    "argument must be between 1 and 64 inclusive",
+                ));
+            }
+            Ok(setter(samples, |info, samples| {
+                info.eviction.samples = samples
+            }))
+        }),
+    },
+    Param {
+        name: "lfu-log-factor",
+        get: |_| eviction::lfu_log_factor().to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |_, factor| {
+                eviction::set_lfu_log_factor(factor)
+            }))
+        }),
+    },
+    Param {
+        name: "lfu-decay-time",
+        get: |_| eviction::lfu_decay_time().to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |_, minutes| {
+                eviction::set_lfu_decay_time(minutes)
+            }))
+        }),
+    },
+    Param {
+        name: "lua-replicate-commands",
+        get: |info| yes_no(info.lua_replicate_commands),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.lua_replicate_commands = on
+            }))
+        }),
+    },
+    Param {
+        name: "replica-read-only",
+        get: |info| yes_no(info.replica_read_only),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.replica_read_only = on
+            }))
+        }),
+    },
+    Param {
+        name: "repl-diskless-sync",
+        get: |info| yes_no(info.diskless_sync),
+        set: Some(|_, value| {
+            Ok(setter(parse_yes_no(value)?, |info, on| {
+                info.diskless_sync = on
+            }))
+        }),
+    },
+    Param {
+        name: "repl-diskless-sync-delay",
+        get: |info| info.diskless_sync_delay.as_secs().to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, secs| {
+                info.diskless_sync_delay = Duration::from_secs(secs)
+            }))
+        }),
+    },
+    Param {
+        name: "min-replicas-to-write",
+        get: |info| info.min_replicas_to_write.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, count| {
+                info.min_replicas_to_write = count
+            }))
+        }),
+    },
+    Param {
+        name: "min-replicas-max-lag",
+        get: |info| info.min_replicas_max_lag.to_string(),
+        set: Some(|_, value| {
+            Ok(setter(parse_number(value)?, |info, lag| {
+                info.min_replicas_max_lag = lag
+            }))
+        }),
+    },
+];
+
+// CONFIG GET: every parameter matching one of the glob `patterns`, with
+// its current value.
+pub fn get(info: &Info, patterns: &[String]) -> Vec<(&'static str, String)> {
+    PARAMS
+        .iter()
+        .filter(|param| {
+            patterns
+                .iter()
+                .any(|p| glob_match(p.as_bytes(), param.name.as_bytes()))
+        })
+        .map(|param| (param.name, (param.get)(info)))
+        .collect()
+}
+
+// CONFIG SET.
+pub fn set(info: &mut Info, params: &[(String, String)]) -> Result<(), CommandError> {
+    let mut setters = Vec::with_capacity(params.len());
+    for (name, value) in params {
+        let Some(param) = PARAMS.iter().find(|param| param.name == name) else {
+            return Err(CommandError::InvalidArguments(
+                "Unsupported CONFIG parameter",
+            ));
+        };
+        let Some(set) = param.set else {
+            return Err(CommandError::InvalidArguments("can't set immutable config"));
+        };
+        setters.push((param.name, set(info, value)?));
+    }
+    for (name, apply) in setters {
+        apply(info);
+        info.config_file.changed.insert(name);
+    }
+    Ok(())
+}
+
+// The config file the server was started with, if any, and the parameters
+// CONFIG SET has changed since, which CONFIG REWRITE adds to it.
+#[derive(Default)]
+pub struct ConfigFile {
+    pub path: Option<PathBuf>,
+    changed: BTreeSet<&'static str>,
+}
+
+// The directives in a redis.conf style file: one per line, a name and its
+// arguments separated by spaces. Arguments may be double quoted, and blank
+// lines and lines starting with # are skipped.
+fn directives(text: &str) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let mut directives = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let mut words = split(line).with_context(|| format!("line {}", number + 1))?;
+        if words.is_empty() {
+            continue;
+        }
+        let name = words.remove(0).to_lowercase();
+        directives.push((name, words));
+    }
+    Ok(directives)
+}
+
+fn split(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    if chars.peek() == Some(&'#') {
+        return Ok(words);
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => continue,
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("unbalanced quotes")),
+                    }
+                }
+                words.push(word);
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    Ok(words)
+}
+
+// The command line flags `path`'s directives stand for. Directive names are
+// the flags' names, and a directive's arguments become the flag's value.
+pub fn file_args(path: &Path) -> anyhow::Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let directives =
+        directives(&text).with_context(|| format!("bad config file {}", path.display()))?;
+    let mut args = Vec::new();
+    for (name, values) in directives {
+        args.push(format!("--{}", name));
+        if !values.is_empty() {
+            args.push(values.join(" "));
+        }
+    }
+    Ok(args)
+}
+
+// Re-reads the config file on SIGHUP and applies the parameters in it that
+// can change at runtime, all or nothing as for CONFIG SET. Everything else
+// in it only takes effect on restart. Returns how many were applied.
+pub fn reload(info: &mut Info) -> anyhow::Result<usize> {
+    let path = info
+        .config_file
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("running without a config file"))?;
+    let text = fs::read_to_string(&path)?;
+    let params: Vec<(String, String)> = directives(&text)?
+        .into_iter()
+        .filter(|(name, _)| {
+            PARAMS
+                .iter()
+                .any(|param| param.name == name && param.set.is_some())
+        })
+        .map(|(name, values)| (name, values.join(" ")))
+        .collect();
+    set(info, &params)?;
+    Ok(params.len())
+}
+
+// CONFIG REWRITE: updates the config file's directives for known
+// parameters to their current values, drops repeats of them, and appends
+// the ones CONFIG SET changed that the file didn't mention. Comments and
+// everything else are kept as they were.
+pub fn rewrite(info: &Info) -> Result<(), CommandError> {
+    let Some(path) = &info.config_file.path else {
+        return Err(CommandError::Config(
+            "The server is running without a config file",
+        ));
+    };
+    write_config(info, path).map_err(|e| {
+        println!("failed to rewrite {}: {}", path.display(), e);
+        CommandError::Config("Rewriting config file failed, check server logs")
+    })
+}
+
+fn write_config(info: &Info, path: &Path) -> io::Result<()> {
+    let text = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        text => text?,
+    };
+    let directive = |param: &Param| match (param.get)(info) {
+        value if value.is_empty() => format!("{} \"\"", param.name),
+        value => format!("{} {}", param.name, value),
+    };
+    let mut written = BTreeSet::new();
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let name = split(line).ok().and_then(|words| words.into_iter().next());
+        let param = name.and_then(|name| {
+            PARAMS
+                .iter()
+                .find(|param| param.name.eq_ignore_ascii_case(&name))
+        });
+        match param {
+            Some(param) if written.insert(param.name) => lines.push(directive(param)),
+            Some(_) => {}
+            None => lines.push(line.to_string()),
+        }
+    }
+    for param in PARAMS {
+        if info.config_file.changed.contains(param.name) && !written.contains(param.name) {
+            lines.push(directive(param));
+        }
+    }
+    // Written beside the old file and renamed over it, so a crash midway
+    // leaves one or the other.
+    let tmp = path.with_extension("rewrite.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+// Resolves on SIGHUP.
+pub async fn hangup() {
+    let mut hup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    hup.recv().await;
+}
+
+fn setter<T: Send + 'static>(value: T, apply: fn(&mut Info, T)) -> Setter {
+    Box::new(move |info| apply(info, value))
+}
+
+fn yes_no(on: bool) -> String {
+    if on { "yes" } else { "no" }.to_string()
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, CommandError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(CommandError::InvalidArguments(
+            "argument must be 'yes' or 'no'",
+        )),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, CommandError> {
+    value
+        .parse()
+        .map_err(|_| CommandError::InvalidArguments("argument couldn't be parsed into an integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clients::Clients, eviction::Eviction, persistence::Persistence, server::Role};
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_set_applies_all_or_nothing() {
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        let set_params = params(&[("maxmemory", "1000"), ("maxmemory-policy", "volatile-lru")]);
+        set(&mut info, &set_params).unwrap();
+        let got = get(&info, &["maxmemory*".to_string()]);
+        assert_eq!(
+            got,
+            vec![
+                ("maxmemory", "1000".to_string()),
+                ("maxmemory-policy", "volatile-lru".to_string()),
+                ("maxmemory-samples", "5".to_string())
+            ]
+        );
+        assert!(set(&mut info, &params(&[("maxmemory-samples", "0")])).is_err());
+
+        for bad in [
+            params(&[("maxclients", "5"), ("maxmemory-policy", "most-recent")]),
+            params(&[("maxclients", "5"), ("repl-diskless-sync", "maybe")]),
+            params(&[("maxclients", "5"), ("port", "7000")]),
+            params(&[("maxclients", "5"), ("no-such-param", "1")]),
+        ] {
+            assert!(set(&mut info, &bad).is_err());
+        }
+        assert_eq!(info.clients.maxclients, 10);
+        assert_eq!(get(&info, &["port".to_string()]).len(), 1);
+    }
+
+    #[test]
+    fn test_rewrite_and_reload_config_file() {
+        let path = std::env::temp_dir().join(format!("credis-{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            "# limits\nmaxmemory 100\nport 6379\nmaxmemory 5\nsave \"\"\n",
+        )
+        .unwrap();
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        info.config_file.path = Some(path.clone());
+        let args = file_args(&path).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--maxmemory",
+                "100",
+                "--port",
+                "6379",
+                "--maxmemory",
+                "5",
+                "--save",
+                ""
+            ]
+        );
+
+        info.persistence.save_points.clear();
+        set(
+            &mut info,
+            &params(&[("maxmemory", "200"), ("maxclients", "50")]),
+        )
+        .unwrap();
+        rewrite(&info).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# limits\nmaxmemory 200\nport 6379\nsave \"\"\nmaxclients 50\n"
+        );
+
+        fs::write(&path, "maxclients 7\nport 7000\nappendonly \"yes\"\n").unwrap();
+        assert_eq!(reload(&mut info).unwrap(), 1);
+        assert_eq!(info.clients.maxclients, 7);
+        assert_eq!(info.port, 6379);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+use crate::protocol::Protocol;
+
+// What a command may need to know or change about the connection it came
+// in on. The connection's Handler owns it and passes it along with every
+// command; commands replayed from the AOF or our master run with a default
+// context, like Redis's fake clients.
+#[derive(Debug, Default, Clone)]
+pub struct ConnCtx {
+    pub id: u64,
+    pub name: Option<String>,
+    // Negotiated with HELLO. The Handler encodes replies with it.
+    pub protocol: Protocol,
+    // Set by CLIENT NO-TOUCH.
+    pub no_touch: bool,
+    // Set by CLIENT KEY-POPULARITY.
+    pub key_popularity: bool,
+    // Channels and patterns the connection is subscribed to.
+    pub subscriptions: usize,
+}
+
+impl ConnCtx {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            ..Default::default()
+        }
+    }
+
+    // RESP2 can't tell messages from replies, so a subscribed RESP2
+    // connection only takes the commands in pubsub::SUBSCRIBER_COMMANDS.
+    pub fn in_subscriber_mode(&self) -> bool {
+        self.protocol == Protocol::Resp2 && self.subscriptions > 0
+    }
+
+    // Whether this is the default context of a replay rather than a client
+    // connection's. Client ids start at 1.
+    pub fn is_replayed(&self) -> bool {
+        self.id == 0
+    }
+}
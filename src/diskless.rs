@@ -0,0 +1,52 @@
+use std::{iter, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::future::join_all;
+use tokio::sync::Mutex;
+
+use crate::{replication::random_id, server::Info, store::Store};
+
+// Diskless full sync. Rather than dumping the whole RDB before replying, the
+// master copies the live entries and streams them to every replica that
+// asked for a full sync during `delay`, encoding as it goes. The payload's
+// length isn't known up front, so it is sent as `$EOF:<mark>\r\n`, the RDB,
+// then the same 40 byte mark, which replicas opt into with `capa eof`.
+
+pub async fn transfer(delay: Duration, cache: Arc<Store>, info: Arc<Mutex<Info>>) {
+    tokio::time::sleep(delay).await;
+    let mark = random_id();
+    let (snapshot, header, mut targets) = {
+        let cache = cache.lock_all().await;
+        let mut info = info.lock().await;
+        let header = format!(
+            "+FULLRESYNC {} {}\r\n$EOF:{}\r\n",
+            info.id(),
+            info.replicas.offset(),
+            mark
+        );
+        (
+            info.persistence.snapshot(&cache, &info.functions),
+            header,
+            info.replicas.start_snapshot(),
+        )
+    };
+    let replicas = targets.len();
+
+    let chunks = iter::once(header.into_bytes())
+        .chain(snapshot.chunks())
+        .chain(iter::once(mark.into_bytes()));
+    for chunk in chunks.map(Bytes::from) {
+        // Replicas that hung up mid-transfer are dropped; the rest go on.
+        let sent = join_all(targets.iter().map(|tx| tx.send(chunk.clone()))).await;
+        let mut sent = sent.into_iter();
+        targets.retain(|_| sent.next().is_some_and(|result| result.is_ok()));
+        if targets.is_empty() {
+            break;
+        }
+    }
+    println!(
+        "streamed diskless snapshot to {} of {} replicas",
+        targets.len(),
+        replicas
+    );
+}
@@ -0,0 +1,554 @@
+use std::{
+    cmp::Reverse,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    protocol::BulkString,
+    server::{Access, Query},
+    store::Keyspace,
+    value::{Fields, StreamId, Value},
+};
+
+// Decides which key to drop when the dataset is over `maxmemory`. Policies
+// see the whole keyspace so they can use whatever per-key metadata they need,
+// and may look at just `samples` keys of it, as Redis does, rather than
+// finding the very best victim. Returning None means nothing is eligible and
+// the write is refused.
+pub trait EvictionPolicy: Send + Sync {
+    fn name(&self) -> &str;
+    fn select_victim(&self, keyspace: &dyn Keyspace, samples: usize) -> Option<String>;
+}
+
+// Evicts the key with the highest score, out of a pool of the best
+// candidates seen so far, as Redis's eviction pool does. Each eviction adds
+// a fresh sample to the pool, so candidates from earlier samples still
+// compete and the choice gets closer to the true best over time. Among all
+// keys or only those with a TTL.
+pub struct Pooled {
+    name: &'static str,
+    volatile: bool,
+    score: fn(&Query, SystemTime) -> u64,
+    pool: Mutex<Vec<(u64, String)>>,
+}
+
+const POOL_SIZE: usize = 16;
+
+impl Pooled {
+    pub fn new(name: &'static str, volatile: bool, score: fn(&Query, SystemTime) -> u64) -> Self {
+        Self {
+            name,
+            volatile,
+            score,
+            pool: Mutex::new(Vec::with_capacity(POOL_SIZE)),
+        }
+    }
+
+    // Approximately the least recently accessed key.
+    pub fn lru(volatile: bool) -> Self {
+        let name = if volatile {
+            "volatile-lru"
+        } else {
+            "allkeys-lru"
+        };
+        Self::new(name, volatile, |query, now| {
+            let idle = now.duration_since(query.access.last()).unwrap_or_default();
+            idle.as_millis() as u64
+        })
+    }
+
+    // Approximately the least frequently accessed key, by its LFU counter.
+    pub fn lfu(volatile: bool) -> Self {
+        let name = if volatile {
+            "volatile-lfu"
+        } else {
+            "allkeys-lfu"
+        };
+        Self::new(name, volatile, |query, now| {
+            255 - lfu_decayed(&query.access, now) as u64
+        })
+    }
+}
+
+impl EvictionPolicy for Pooled {
+    fn name(&self) -> &str {
+        self.name
+    }
+    fn select_victim(&self, keyspace: &dyn Keyspace, samples: usize) -> Option<String> {
+        let now = SystemTime::now();
+        let mut pool = self.pool.lock().unwrap();
+        for (key, query) in sample(keyspace, self.volatile, samples) {
+            let score = (self.score)(query, now);
+            match pool.iter_mut().find(|(_, pooled)| pooled == key) {
+                Some(entry) => entry.0 = score,
+                None => pool.push((score, key.clone())),
+            }
+        }
+        pool.sort_by_key(|(score, _)| Reverse(*score));
+        pool.truncate(POOL_SIZE);
+        // Pooled keys may have been deleted, or lost their TTL, since.
+        while !pool.is_empty() {
+            let (_, key) = pool.remove(0);
+            let eligible = keyspace
+                .get(&key)
+                .is_some_and(|query| !self.volatile || query.expiry.is_some());
+            if eligible {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+// Up to `n` keys, or only keys with a TTL if `volatile`, read from a random
+// point in the keyspace onwards.
+fn sample(keyspace: &dyn Keyspace, volatile: bool, n: usize) -> Vec<(&String, &Query)> {
+    let candidates = || {
+        keyspace
+            .iter()
+            .filter(move |(_, query)| !volatile || query.expiry.is_some())
+    };
+    let len = if volatile {
+        candidates().count()
+    } else {
+        keyspace.len()
+    };
+    if len == 0 {
+        return Vec::new();
+    }
+    candidates()
+        .skip(random() % len)
+        .chain(candidates())
+        .take(n.clamp(1, len))
+        .collect()
+}
+
+fn random() -> usize {
+    RandomState::new().build_hasher().finish() as usize
+}
+
+// LFU counters, as in Redis: a key starts at LFU_INIT_VAL and each access
+// increments it with a probability that falls as it grows, scaled by
+// lfu-log-factor, so 255 takes about a million hits at the default factor
+// of 10. It also loses one for every lfu-decay-time minutes the key goes
+// unaccessed, worked out from its last access rather than stored. Both
+// settings live outside Info since every read consults them.
+pub const LFU_INIT_VAL: u8 = 5;
+
+static LFU_LOG_FACTOR: AtomicU64 = AtomicU64::new(10);
+static LFU_DECAY_TIME: AtomicU64 = AtomicU64::new(1);
+
+pub fn lfu_log_factor() -> u64 {
+    LFU_LOG_FACTOR.load(Ordering::Relaxed)
+}
+
+pub fn set_lfu_log_factor(factor: u64) {
+    LFU_LOG_FACTOR.store(factor, Ordering::Relaxed);
+}
+
+pub fn lfu_decay_time() -> u64 {
+    LFU_DECAY_TIME.load(Ordering::Relaxed)
+}
+
+pub fn set_lfu_decay_time(minutes: u64) {
+    LFU_DECAY_TIME.store(minutes, Ordering::Relaxed);
+}
+
+// A key's counter as of `now`, after decay.
+pub fn lfu_decayed(access: &Access, now: SystemTime) -> u8 {
+    let idle = now.duration_since(access.last()).unwrap_or_default();
+    decay(access.counter(), idle, lfu_decay_time())
+}
+
+// Updates a key's counter for an access at `now`.
+pub fn lfu_touch(access: &Access, now: SystemTime) {
+    let counter = lfu_decayed(access, now);
+    access.set_counter(log_increment(counter, lfu_log_factor(), random()));
+}
+
+fn decay(counter: u8, idle: Duration, decay_time: u64) -> u8 {
+    if decay_time == 0 {
+        return counter;
+    }
+    let periods = idle.as_secs() / 60 / decay_time;
+    counter.saturating_sub(periods.min(255) as u8)
+}
+
+// Increments `counter` if `roll`, uniform over usize, comes up under the
+// odds of it doing so.
+fn log_increment(counter: u8, factor: u64, roll: usize) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let odds = 1.0 / (base * factor as f64 + 1.0);
+    if (roll as f64 / usize::MAX as f64) < odds {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+pub struct Random;
+
+impl EvictionPolicy for Random {
+    fn name(&self) -> &str {
+        "allkeys-random"
+    }
+    fn select_victim(&self, keyspace: &dyn Keyspace, _samples: usize) -> Option<String> {
+        if keyspace.is_empty() {
+            return None;
+        }
+        let n = random() % keyspace.len();
+        keyspace.iter().nth(n).map(|(key, _)| key.to_string())
+    }
+}
+
+// Key with the nearest expiry; keys without a TTL are never evicted.
+pub struct Ttl;
+
+impl EvictionPolicy for Ttl {
+    fn name(&self) -> &str {
+        "volatile-ttl"
+    }
+    fn select_victim(&self, keyspace: &dyn Keyspace, _samples: usize) -> Option<String> {
+        keyspace
+            .iter()
+            .filter_map(|(key, query)| query.expiry.map(|expiry| (key, expiry)))
+            .min_by_key(|(_, expiry)| *expiry)
+            .map(|(key, _)| key.to_string())
+    }
+}
+
+// Never evicts; writes over the limit fail with OOM.
+pub struct NoEviction;
+
+impl EvictionPolicy for NoEviction {
+    fn name(&self) -> &str {
+        "noeviction"
+    }
+    fn select_victim(&self, _keyspace: &dyn Keyspace, _samples: usize) -> Option<String> {
+        None
+    }
+}
+
+// Roughly what a key and its value cost the allocator: the entry's slot in
+// its shard's table, which hashbrown keeps at most 7/8 full, plus one
+// control byte, and the heap blocks of the key and the value. A container's
+// elements are each a block of their own in a vector.
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    sampled_entry_size(key, value, 0)
+}
+
+// entry_size, with a container's elements estimated from the first
+// `samples` of them, or all of them if 0, for MEMORY USAGE SAMPLES. A
+// stream is sampled by entry.
+pub fn sampled_entry_size(key: &str, value: &Value, samples: usize) -> usize {
+    let element = |s: &Bytes| size_of::<Bytes>() + allocation(s.len());
+    let pairs = |fields: &Fields, samples| {
+        let size = sampled(fields, samples, |(f, v)| element(f) + element(v));
+        allocation(size_of_val(&fields[..])) + size
+    };
+    let value = match value {
+        Value::String(s) => string_size(s),
+        Value::List(items) | Value::Set(items) => sampled(items, samples, element),
+        Value::SortedSet(members) => sampled(members, samples, |(member, _)| {
+            element(member) + size_of::<f64>()
+        }),
+        Value::Hash(fields) => pairs(fields, samples),
+        Value::Stream(stream) => sampled(&stream.entries, samples, |(_, fields)| {
+            size_of::<(StreamId, Fields)>() + pairs(fields, 0)
+        }),
+    };
+    key_size(key) + value
+}
+
+// The total `size` of `items`, scaled up from the first `samples` of them
+// unless that is 0 or covers them all.
+fn sampled<T>(items: &[T], samples: usize, size: impl Fn(&T) -> usize) -> usize {
+    match samples {
+        n if n > 0 && n < items.len() => {
+            items[..n].iter().map(size).sum::<usize>() * items.len() / n
+        }
+        _ => items.iter().map(size).sum(),
+    }
+}
+
+// entry_size of a string value, before it is stored.
+pub fn string_entry_size(key: &str, value: &str) -> usize {
+    key_size(key) + string_size(value)
+}
+
+fn key_size(key: &str) -> usize {
+    let slot = (size_of::<(String, Query)>() + 1) * 8 / 7;
+    slot + allocation(key.len())
+}
+
+// A string's block, and the header Bytes allocates once it is shared with a
+// reply. Values with a shared copy, which every key holding them uses, cost
+// nothing extra.
+fn string_size(value: &str) -> usize {
+    match BulkString::shared(value) {
+        Some(_) => 0,
+        None => allocation(value.len()) + allocation(BYTES_SHARED),
+    }
+}
+
+// Bytes' shared header: the buffer's pointer and capacity, and a refcount.
+const BYTES_SHARED: usize = 24;
+
+// The block malloc hands out for `n` bytes: 8 bytes of its own header on
+// top, rounded up to 16 bytes, and never under 32.
+fn allocation(n: usize) -> usize {
+    match n {
+        0 => 0,
+        n => (n + 8).next_multiple_of(16).max(32),
+    }
+}
+
+pub fn used_memory(keyspace: &dyn Keyspace) -> usize {
+    keyspace
+        .iter()
+        .map(|(key, query)| entry_size(key, &query.value))
+        .sum()
+}
+
+pub struct Eviction {
+    pub maxmemory: usize,
+    // How many keys sampling policies look at per victim, maxmemory-samples.
+    pub samples: usize,
+    policies: Vec<Box<dyn EvictionPolicy>>,
+    active: usize,
+}
+
+impl Eviction {
+    pub fn new(maxmemory: usize) -> Self {
+        let mut eviction = Self {
+            maxmemory,
+            samples: 5,
+            policies: Vec::new(),
+            active: 0,
+        };
+        eviction.register(Box::new(NoEviction));
+        eviction.register(Box::new(Pooled::lru(false)));
+        eviction.register(Box::new(Pooled::lru(true)));
+        eviction.register(Box::new(Pooled::lfu(false)));
+        eviction.register(Box::new(Pooled::lfu(true)));
+        eviction.register(Box::new(Random));
+        eviction.register(Box::new(Ttl));
+        eviction
+    }
+
+    // Adds a policy selectable by name, replacing any existing one with the
+    // same name.
+    pub fn register(&mut self, policy: Box<dyn EvictionPolicy>) {
+        match self.policies.iter().position(|p| p.name() == policy.name()) {
+            Some(i) => self.policies[i] = policy,
+            None => self.policies.push(policy),
+        }
+    }
+
+    pub fn has_policy(&self, name: &str) -> bool {
+        self.policies.iter().any(|p| p.name() == name)
+    }
+
+    pub fn set_policy(&mut self, name: &str) -> Result<(), String> {
+        self.active = self
+            .policies
+            .iter()
+            .position(|p| p.name() == name)
+            .ok_or_else(|| format!("unknown maxmemory policy '{}'", name))?;
+        Ok(())
+    }
+
+    pub fn policy(&self) -> &dyn EvictionPolicy {
+        self.policies[self.active].as_ref()
+    }
+
+    // Evicts keys until `incoming` more bytes fit under maxmemory. Returns
+    // false if the policy ran out of victims first.
+    pub fn make_room(&self, keyspace: &mut dyn Keyspace, incoming: usize) -> bool {
+        if self.maxmemory == 0 {
+            return true;
+        }
+        let mut used = used_memory(keyspace);
+        while used + incoming > self.maxmemory {
+            let Some(victim) = self.policy().select_victim(keyspace, self.samples) else {
+                return false;
+            };
+            // A victim that isn't in the keyspace would loop forever.
+            let Some(query) = keyspace.remove(&victim) else {
+                return false;
+            };
+            used -= entry_size(&victim, &query.value);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    fn query(value: &str, last_access: SystemTime, counter: u8) -> Query {
+        Query {
+            access: Access::new(last_access, 0, counter),
+            ..Query::new(value.to_string(), None, last_access)
+        }
+    }
+
+    #[test]
+    fn test_builtin_policies_pick_expected_victims() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        keyspace.insert(
+            "old".to_string(),
+            query("v", now - Duration::from_secs(60), 10),
+        );
+        keyspace.insert("cold".to_string(), query("v", now, 1));
+
+        let lru = Pooled::lru(false);
+        let lfu = Pooled::lfu(false);
+        assert_eq!(lru.select_victim(&keyspace, 2), Some("old".to_string()));
+        assert_eq!(lfu.select_victim(&keyspace, 2), Some("cold".to_string()));
+        assert_eq!(Ttl.select_victim(&keyspace, 2), None);
+    }
+
+    #[test]
+    fn test_sampling_and_volatile_policies_spare_persistent_keys() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        for i in 0..100 {
+            keyspace.insert(format!("k{}", i), query("v", now, LFU_INIT_VAL));
+        }
+        keyspace.insert(
+            "oldest".to_string(),
+            query("v", now - Duration::from_secs(60), LFU_INIT_VAL),
+        );
+        let mut expiring = query("v", now, 200);
+        expiring.expiry = Some(now + Duration::from_secs(60));
+        keyspace.insert("expiring".to_string(), expiring);
+
+        let lru = Pooled::lru(false);
+        // Sampling every key finds the true LRU; a sample of one is any key.
+        assert_eq!(
+            lru.select_victim(&keyspace, 200),
+            Some("oldest".to_string())
+        );
+        assert!(lru.select_victim(&keyspace, 1).is_some());
+        for volatile in [Pooled::lru(true), Pooled::lfu(true)] {
+            for _ in 0..10 {
+                assert_eq!(
+                    volatile.select_victim(&keyspace, 5),
+                    Some("expiring".to_string())
+                );
+            }
+        }
+        keyspace.remove("expiring");
+        assert_eq!(Pooled::lfu(true).select_victim(&keyspace, 5), None);
+    }
+
+    #[test]
+    fn test_pool_keeps_candidates_between_evictions() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        for i in 0..10 {
+            let last_access = now - Duration::from_secs(i);
+            keyspace.insert(format!("k{}", i), query("v", last_access, 0));
+        }
+        let lru = Pooled::lru(false);
+        assert_eq!(lru.select_victim(&keyspace, 10), Some("k9".to_string()));
+        keyspace.remove("k9");
+        // A sample of one would rarely find k8, but the pool remembers it.
+        assert_eq!(lru.select_victim(&keyspace, 1), Some("k8".to_string()));
+    }
+
+    #[test]
+    fn test_lfu_counter_grows_logarithmically_and_decays() {
+        assert_eq!(
+            log_increment(LFU_INIT_VAL, 10, usize::MAX / 2),
+            LFU_INIT_VAL + 1
+        );
+        assert_eq!(log_increment(u8::MAX, 10, 0), u8::MAX);
+        // One above the initial value, an access has a 1 in 11 chance.
+        assert_eq!(
+            log_increment(LFU_INIT_VAL + 1, 10, usize::MAX / 10),
+            LFU_INIT_VAL + 1
+        );
+        assert_eq!(
+            log_increment(LFU_INIT_VAL + 1, 10, usize::MAX / 12),
+            LFU_INIT_VAL + 2
+        );
+        let mut counter = LFU_INIT_VAL;
+        for _ in 0..1000 {
+            counter = log_increment(counter, 10, random());
+        }
+        assert!((12..=30).contains(&counter), "{}", counter);
+
+        assert_eq!(decay(20, Duration::from_secs(150), 1), 18);
+        assert_eq!(decay(20, Duration::from_secs(150), 2), 19);
+        assert_eq!(decay(20, Duration::from_secs(150), 0), 20);
+        assert_eq!(decay(1, Duration::from_secs(3600), 1), 0);
+    }
+
+    struct Longest;
+
+    impl EvictionPolicy for Longest {
+        fn name(&self) -> &str {
+            "longest-value"
+        }
+        fn select_victim(&self, keyspace: &dyn Keyspace, _samples: usize) -> Option<String> {
+            keyspace
+                .iter()
+                .max_by_key(|(_, query)| query.value.as_string().map_or(0, |s| s.len()))
+                .map(|(key, _)| key.to_string())
+        }
+    }
+
+    #[test]
+    fn test_registered_policy_drives_make_room() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        keyspace.insert("big".to_string(), query(&"x".repeat(100), now, 0));
+        keyspace.insert("small".to_string(), query("x", now, 0));
+
+        let mut eviction = Eviction::new(used_memory(&keyspace));
+        eviction.register(Box::new(Longest));
+        eviction.set_policy("longest-value").unwrap();
+
+        assert!(eviction.make_room(&mut keyspace, 10));
+        assert!(keyspace.contains_key("small"));
+        assert!(!keyspace.contains_key("big"));
+    }
+
+    #[test]
+    fn test_samples_estimate_container_sizes() {
+        // Ten short elements, then ten long ones the first samples miss.
+        let items = (0..20)
+            .map(|i| {
+                Bytes::from(if i < 10 {
+                    "x".repeat(8)
+                } else {
+                    "x".repeat(1000)
+                })
+            })
+            .collect();
+        let list = Value::List(std::sync::Arc::new(items));
+        let exact = entry_size("k", &list);
+        assert_eq!(sampled_entry_size("k", &list, 0), exact);
+        assert_eq!(sampled_entry_size("k", &list, 20), exact);
+        assert!(sampled_entry_size("k", &list, 5) < exact / 4);
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::SystemTime;
+
+use tokio::sync::Mutex;
+
+use crate::{
+    format_resp,
+    protocol::Resp,
+    server::{Info, Role},
+    store::Keyspace,
+};
+
+// Only a master deletes expired keys, and it propagates a DEL for each one
+// so that replicas and the AOF agree on when the key went away. Replicas
+// treat expired keys as missing but keep them until that DEL arrives, so a
+// replica never diverges from its master on its own clock.
+
+// Most expired keys one active cycle deletes, to bound how long it holds
+// the shard's lock.
+const ACTIVE_EXPIRE_LIMIT: usize = 200;
+
+// Whether `key` has expired, deleting it if we are a master.
+pub async fn expire_if_needed(
+    cache: &mut dyn Keyspace,
+    info: &Mutex<Info>,
+    key: &str,
+    now: SystemTime,
+) -> bool {
+    if !cache.get(key).is_some_and(|query| query.is_expired(now)) {
+        return false;
+    }
+    let mut info = info.lock().await;
+    if !matches!(info.role, Role::Slave) && !info.clients.writes_paused() {
+        delete(cache, &mut info, key);
+    }
+    true
+}
+
+// Deletes up to ACTIVE_EXPIRE_LIMIT expired keys that nobody has asked for,
+// returning how many. The store finds them by deadline, so a cycle costs
+// the keys it deletes, not the keyspace. Does nothing on a replica, or while
+// CLIENT PAUSE holds writes, since the deletions would change the dataset
+// under the pause, or after DEBUG SET-ACTIVE-EXPIRE 0.
+pub fn active_expire_cycle(cache: &mut dyn Keyspace, info: &mut Info) -> usize {
+    if matches!(info.role, Role::Slave) || info.clients.writes_paused() || !info.active_expire {
+        return 0;
+    }
+    let expired = cache.expired(SystemTime::now(), ACTIVE_EXPIRE_LIMIT);
+    for key in &expired {
+        delete(cache, info, key);
+    }
+    expired.len()
+}
+
+fn delete(cache: &mut dyn Keyspace, info: &mut Info, key: &str) {
+    cache.remove(key);
+    info.propagate(&format_resp!["DEL", key], &[key.to_string()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::Clients, eviction::Eviction, persistence::Persistence, protocol::RespEncoding,
+        server::Query,
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    #[tokio::test]
+    async fn test_only_masters_delete_expired_keys() {
+        let now = SystemTime::now();
+        let mut cache = HashMap::new();
+        for (key, expiry) in [
+            ("old", now - Duration::from_secs(1)),
+            ("new", now + Duration::from_secs(60)),
+        ] {
+            let query = Query::new("v".to_string(), Some(expiry), now);
+            cache.insert(key.to_string(), query);
+        }
+        let info = |role| {
+            Info::new(
+                role,
+                Persistence::new(true),
+                Eviction::new(0),
+                Clients::new(10, 0),
+            )
+        };
+
+        let replica = Mutex::new(info(Role::Slave));
+        assert!(expire_if_needed(&mut cache, &replica, "old", now).await);
+        assert!(cache.contains_key("old"));
+        assert_eq!(
+            active_expire_cycle(&mut cache, &mut *replica.lock().await),
+            0
+        );
+
+        let master = Mutex::new(info(Role::Master));
+        assert!(!expire_if_needed(&mut cache, &master, "new", now).await);
+        assert_eq!(
+            active_expire_cycle(&mut cache, &mut *master.lock().await),
+            1
+        );
+        assert!(!cache.contains_key("old"));
+        let del = format_resp!["DEL", "old"].encode();
+        assert_eq!(master.lock().await.replicas.offset(), del.len() as u64);
+    }
+}
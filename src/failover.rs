@@ -0,0 +1,165 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, Notify},
+    task::AbortHandle,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    command::{CommandError, FailoverArgs},
+    format_resp,
+    protocol::{Resp, RespCodec},
+    replica,
+    server::{HostSpec, Info, Role},
+    store::Store,
+};
+
+// Coordinated manual failover. The master pauses client writes, waits for a
+// replica to acknowledge everything written so far, tells it to promote
+// itself with REPLICAOF NO ONE and then follows it as a replica. Writes that
+// were waiting on the pause resume against the demoted node, and so get
+// READONLY.
+
+// How often to ask replicas for their offset while waiting for one to sync.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    WaitingForSync,
+    InProgress,
+}
+
+// A failover under way. Client writes stay paused for as long as it exists.
+pub struct Failover {
+    pub state: State,
+    resumed: Arc<Notify>,
+    task: Option<AbortHandle>,
+}
+
+impl Failover {
+    // Woken once writes may go ahead again.
+    pub fn resumed(&self) -> Arc<Notify> {
+        self.resumed.clone()
+    }
+}
+
+impl Drop for Failover {
+    fn drop(&mut self) {
+        self.resumed.notify_waiters();
+    }
+}
+
+// `master_failover_state` for INFO replication.
+pub fn state(failover: Option<&Failover>) -> &'static str {
+    match failover.map(|f| f.state) {
+        None => "no-failover",
+        Some(State::WaitingForSync) => "waiting-for-sync",
+        Some(State::InProgress) => "failover-in-progress",
+    }
+}
+
+// Validates the request and starts the failover in the background.
+pub fn start(
+    info: &mut Info,
+    args: FailoverArgs,
+    cache: Arc<Store>,
+    shared: Arc<Mutex<Info>>,
+) -> Result<(), CommandError> {
+    use CommandError::Failover as Error;
+    if matches!(info.role, Role::Slave) {
+        return Err(Error("FAILOVER is not valid when server is a replica."));
+    }
+    if info.replicas.is_empty() {
+        return Err(Error("FAILOVER requires connected replicas."));
+    }
+    if info.failover.is_some() {
+        return Err(Error("FAILOVER already in progress."));
+    }
+    if let Some(target) = &args.target {
+        if !info.replicas.contains(target) {
+            return Err(Error("FAILOVER target HOST and PORT is not a replica."));
+        }
+    }
+    let deadline = args
+        .timeout
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let task = tokio::spawn(run(args.target, deadline, cache, shared));
+    info.failover = Some(Failover {
+        state: State::WaitingForSync,
+        resumed: Arc::new(Notify::new()),
+        task: Some(task.abort_handle()),
+    });
+    Ok(())
+}
+
+pub fn abort(info: &mut Info) -> Result<(), CommandError> {
+    let Some(mut failover) = info.failover.take() else {
+        return Err(CommandError::Failover("No failover in progress."));
+    };
+    if failover.state == State::InProgress {
+        // The target may already have been promoted; too late to back out.
+        info.failover = Some(failover);
+        return Err(CommandError::Failover(
+            "Failover is already being finalized.",
+        ));
+    }
+    if let Some(task) = failover.task.take() {
+        task.abort();
+    }
+    println!("failover aborted");
+    Ok(())
+}
+
+async fn run(
+    target: Option<HostSpec>,
+    deadline: Option<Instant>,
+    cache: Arc<Store>,
+    shared: Arc<Mutex<Info>>,
+) {
+    let target = loop {
+        {
+            let mut info = shared.lock().await;
+            if let Some(target) = info.caught_up_replica(target.as_ref()) {
+                if let Some(failover) = &mut info.failover {
+                    failover.state = State::InProgress;
+                }
+                break target;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                println!("failover timed out waiting for a replica to sync");
+                info.failover = None;
+                return;
+            }
+            info.replicas.request_ack();
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+
+    let promoted = promote(&target).await;
+    let mut info = shared.lock().await;
+    match promoted {
+        Ok(()) => {
+            println!("failed over to {}", target);
+            replica::start(&mut info, target, cache, shared.clone());
+        }
+        Err(e) => println!("failover to {} failed: {}", target, e),
+    }
+    info.failover = None;
+}
+
+async fn promote(target: &HostSpec) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(target.to_string()).await?;
+    let mut framed = Framed::new(stream, RespCodec::default());
+    framed.send(format_resp!["REPLICAOF", "NO", "ONE"]).await?;
+    match framed.next().await {
+        Some(Ok(Resp::SimpleString(s))) if s == "OK" => Ok(()),
+        reply => bail!("unexpected reply to REPLICAOF NO ONE: {:?}", reply),
+    }
+}
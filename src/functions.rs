@@ -0,0 +1,455 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use mlua::{Function, Lua, Table, Value};
+
+use crate::{
+    command::CommandError,
+    glob::glob_match,
+    protocol::{BulkString, Resp},
+    scripting::{self, Bridge},
+};
+
+#[cfg(feature = "wasm")]
+use crate::wasm;
+
+// FUNCTION LOAD's libraries: Lua code that registers functions with
+// redis.register_function as it runs, for FCALL to call by name, or with
+// the wasm feature, a WebAssembly module, whose functions are its exports.
+// As in Redis, the code is what's kept, saved and replicated, and a call
+// runs it again in a fresh state to get at the function. A module is only
+// compiled once, though, when it is loaded.
+#[derive(Default)]
+pub struct Functions {
+    libraries: BTreeMap<String, Arc<Library>>,
+}
+
+pub struct Library {
+    pub name: String,
+    pub engine: Engine,
+    pub code: Arc<str>,
+    pub functions: Vec<Registered>,
+    #[cfg(feature = "wasm")]
+    module: Option<wasm::Compiled>,
+}
+
+// What runs a library's code, as its first line names it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Lua,
+    #[cfg(feature = "wasm")]
+    Wasm,
+}
+
+impl Engine {
+    fn parse(name: &str) -> Option<Engine> {
+        match name.to_ascii_lowercase().as_str() {
+            "lua" => Some(Engine::Lua),
+            #[cfg(feature = "wasm")]
+            "wasm" => Some(Engine::Wasm),
+            _ => None,
+        }
+    }
+
+    // As FUNCTION LIST names it.
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Lua => "LUA",
+            #[cfg(feature = "wasm")]
+            Engine::Wasm => "WASM",
+        }
+    }
+}
+
+pub struct Registered {
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+// FCALL and FCALL_RO.
+#[derive(Debug, Clone)]
+pub struct Fcall {
+    pub function: String,
+    pub keys: Vec<BulkString>,
+    pub args: Vec<BulkString>,
+    pub read_only: bool,
+}
+
+const FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+// Where register_function collects what a library registers.
+const REGISTERED: &str = "registered_functions";
+
+impl Library {
+    // Checks the `#!lua name=<library>` line the code starts with, and runs
+    // the rest to see what it registers.
+    pub fn new(code: &str) -> Result<Library, CommandError> {
+        let (engine, name, body) = metadata(code)?;
+        #[cfg(feature = "wasm")]
+        let mut module = None;
+        let functions: Vec<Registered> = match engine {
+            Engine::Lua => {
+                let lua = scripting::sandbox().map_err(|e| registering(&e))?;
+                let registered = register(&lua, body)?;
+                registered
+                    .into_iter()
+                    .map(|(registered, _)| registered)
+                    .collect()
+            }
+            #[cfg(feature = "wasm")]
+            Engine::Wasm => {
+                let compiled = wasm::compile(body)?;
+                let functions = wasm::register(&compiled)?;
+                module = Some(compiled);
+                functions
+            }
+        };
+        check(&functions)?;
+        Ok(Library {
+            name,
+            engine,
+            code: code.into(),
+            functions,
+            #[cfg(feature = "wasm")]
+            module,
+        })
+    }
+
+    fn get(&self, function: &str) -> Option<&Registered> {
+        self.functions
+            .iter()
+            .find(|registered| registered.name == function)
+    }
+}
+
+impl Functions {
+    // Adds a library, or with `replace` swaps it for the one of the same
+    // name. Function names are unique across libraries.
+    pub fn insert(&mut self, library: Library, replace: bool) -> Result<String, CommandError> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(CommandError::Function(format!(
+                "Library '{}' already exists",
+                library.name
+            )));
+        }
+        let others = self
+            .libraries
+            .values()
+            .filter(|other| other.name != library.name);
+        for other in others {
+            if let Some(taken) = library
+                .functions
+                .iter()
+                .find(|function| other.get(&function.name).is_some())
+            {
+                return Err(CommandError::Function(format!(
+                    "Function {} already exists",
+                    taken.name
+                )));
+            }
+        }
+        let name = library.name.clone();
+        self.libraries.insert(name.clone(), Arc::new(library));
+        Ok(name)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), CommandError> {
+        match self.libraries.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(CommandError::Function("Library not found".into())),
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    // The library a function is in, and the function.
+    pub fn get(&self, function: &str) -> Option<(Arc<Library>, &Registered)> {
+        self.libraries
+            .values()
+            .find_map(|library| Some((library.clone(), library.get(function)?)))
+    }
+
+    // Every library's code, as snapshots save them.
+    pub fn codes(&self) -> Vec<Arc<str>> {
+        self.libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    // Libraries a snapshot held, replacing any of the same name.
+    pub fn restore(&mut self, codes: Vec<String>) -> Result<(), CommandError> {
+        for code in codes {
+            self.insert(Library::new(&code)?, true)?;
+        }
+        Ok(())
+    }
+
+    // FUNCTION LIST, as Redis shapes it.
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> Resp {
+        let libraries = self
+            .libraries
+            .values()
+            .filter(|library| {
+                pattern
+                    .is_none_or(|pattern| glob_match(pattern.as_bytes(), library.name.as_bytes()))
+            })
+            .map(|library| {
+                let functions = library.functions.iter().map(|function| {
+                    let flags = function
+                        .flags
+                        .iter()
+                        .map(|flag| Resp::simple(flag.as_str()));
+                    Resp::Map(vec![
+                        (Resp::simple("name"), Resp::bulk(function.name.as_str())),
+                        (Resp::simple("description"), Resp::Null),
+                        (Resp::simple("flags"), Resp::Set(flags.collect())),
+                    ])
+                });
+                let mut fields = vec![
+                    (
+                        Resp::simple("library_name"),
+                        Resp::bulk(library.name.as_str()),
+                    ),
+                    (Resp::simple("engine"), Resp::bulk(library.engine.name())),
+                    (Resp::simple("functions"), Resp::Array(functions.collect())),
+                ];
+                if with_code {
+                    fields.push((Resp::simple("library_code"), Resp::bulk(&*library.code)));
+                }
+                Resp::Map(fields)
+            });
+        Resp::Array(libraries.collect())
+    }
+}
+
+// Runs a library's code again and calls one of its functions with the
+// call's keys and args. It blocks for as long as the function runs, and
+// the commands it calls run over the bridge as with scripts.
+pub fn call(library: &Library, fcall: &Fcall, bridge: Bridge) -> Result<Resp, CommandError> {
+    let failed = |e: mlua::Error| scripting::failed(&e, &fcall.function);
+    #[cfg(feature = "wasm")]
+    if let Some(module) = &library.module {
+        return wasm::call(module, fcall, bridge);
+    }
+    let (_, _, body) = metadata(&library.code)?;
+    let lua = scripting::sandbox().map_err(failed)?;
+    scripting::connect(&lua, bridge).map_err(failed)?;
+    let Some((_, callback)) = register(&lua, body)?
+        .into_iter()
+        .find(|(registered, _)| registered.name == fcall.function)
+    else {
+        return Err(CommandError::Function("Function not found".into()));
+    };
+    let keys = scripting::sequence(&lua, &fcall.keys).map_err(failed)?;
+    let args = scripting::sequence(&lua, &fcall.args).map_err(failed)?;
+    let value = callback.call::<_, Value>((keys, args)).map_err(failed)?;
+    scripting::reply(value)
+}
+
+// The engine and library name from the code's first line, and the code
+// after it.
+fn metadata(code: &str) -> Result<(Engine, String, &str), CommandError> {
+    use CommandError::Function;
+    let (first, body) = code.split_once('\n').unwrap_or((code, ""));
+    let Some(shebang) = first.strip_prefix("#!") else {
+        return Err(Function("Missing library metadata".into()));
+    };
+    let mut words = shebang.split_whitespace();
+    let engine = words.next().unwrap_or_default();
+    let Some(engine) = Engine::parse(engine) else {
+        return Err(Function(format!("Engine '{}' not found", engine)));
+    };
+    let mut name = None;
+    for word in words {
+        match word.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(Function(format!("Invalid metadata value given: {}", word))),
+        }
+    }
+    let name = name.ok_or_else(|| Function("Library name was not given".into()))?;
+    if !valid_name(&name) {
+        return Err(Function(
+            "Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".into(),
+        ));
+    }
+    Ok((engine, name, body))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+// Runs a library's code with redis.register_function collecting what it
+// registers, each with its callback: `register_function(name, callback)`,
+// or with a table of `function_name`, `callback` and optional `flags`.
+fn register<'lua>(
+    lua: &'lua Lua,
+    body: &str,
+) -> Result<Vec<(Registered, Function<'lua>)>, CommandError> {
+    let registered = collect(lua, body).map_err(|e| registering(&e))?;
+    let mut functions: Vec<(Registered, Function)> = Vec::new();
+    for entry in registered.sequence_values::<Table>() {
+        let (name, flags, callback): (String, Vec<String>, Function) = entry
+            .and_then(|entry| {
+                Ok((
+                    entry.get("name")?,
+                    entry.get("flags")?,
+                    entry.get("callback")?,
+                ))
+            })
+            .map_err(|e| registering(&e))?;
+        functions.push((Registered { name, flags }, callback));
+    }
+    Ok(functions)
+}
+
+// What any engine's library must register: at least one function, each
+// with a valid name of its own and known flags.
+fn check(functions: &[Registered]) -> Result<(), CommandError> {
+    for (i, function) in functions.iter().enumerate() {
+        if !valid_name(&function.name) {
+            return Err(CommandError::Function(
+                "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".into(),
+            ));
+        }
+        if functions[..i]
+            .iter()
+            .any(|other| other.name == function.name)
+        {
+            return Err(CommandError::Function(
+                "Function already exists in the library".into(),
+            ));
+        }
+        if !function
+            .flags
+            .iter()
+            .all(|flag| FLAGS.contains(&flag.as_str()))
+        {
+            return Err(CommandError::Function("Unknown flag given".into()));
+        }
+    }
+    if functions.is_empty() {
+        return Err(CommandError::Function("No functions registered".into()));
+    }
+    Ok(())
+}
+
+fn collect<'lua>(lua: &'lua Lua, body: &str) -> mlua::Result<Table<'lua>> {
+    lua.set_named_registry_value(REGISTERED, lua.create_table()?)?;
+    let register_function =
+        lua.create_function(|lua, (first, callback): (Value, Option<Function>)| {
+            let entry = lua.create_table()?;
+            match first {
+                Value::Table(args) => {
+                    entry.set("name", args.get::<_, String>("function_name")?)?;
+                    entry.set("callback", args.get::<_, Function>("callback")?)?;
+                    let flags: Option<Vec<String>> = args.get("flags")?;
+                    entry.set("flags", flags.unwrap_or_default())?;
+                }
+                name => {
+                    let callback = callback.ok_or_else(|| {
+                        mlua::Error::RuntimeError(
+                            "wrong number of arguments to redis.register_function".into(),
+                        )
+                    })?;
+                    entry.set("name", name)?;
+                    entry.set("callback", callback)?;
+                    entry.set("flags", lua.create_table()?)?;
+                }
+            }
+            lua.named_registry_value::<Table>(REGISTERED)?.push(entry)
+        })?;
+    let redis: Table = lua.globals().get("redis")?;
+    redis.set("register_function", register_function)?;
+    lua.load(body).set_name("@user_function").exec()?;
+    lua.named_registry_value(REGISTERED)
+}
+
+fn registering(e: &mlua::Error) -> CommandError {
+    CommandError::Function(format!(
+        "Error registering functions: {}",
+        scripting::message(e)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = "#!lua name=mylib
+local function echo(keys, args)
+  return {keys[1], args[1]}
+end
+redis.register_function('echo', echo)
+redis.register_function{function_name='fixed', callback=function() return 7 end, flags={'no-writes'}}
+";
+
+    #[test]
+    fn test_libraries_register_functions_by_name() {
+        let mut functions = Functions::default();
+        assert_eq!(
+            functions
+                .insert(Library::new(LIBRARY).unwrap(), false)
+                .unwrap(),
+            "mylib"
+        );
+        let (code, fixed) = functions.get("fixed").unwrap();
+        assert_eq!(fixed.flags, ["no-writes"]);
+        let fcall = Fcall {
+            function: "echo".into(),
+            keys: vec!["k".into()],
+            args: vec!["a".into()],
+            read_only: false,
+        };
+        assert_eq!(
+            call(&code, &fcall, tokio::sync::mpsc::unbounded_channel().0).unwrap(),
+            Resp::Array(vec![Resp::bulk("k"), Resp::bulk("a")])
+        );
+
+        // Names are unique, among libraries and among their functions.
+        assert!(functions
+            .insert(Library::new(LIBRARY).unwrap(), false)
+            .is_err());
+        let other = LIBRARY.replace("mylib", "other");
+        assert_eq!(
+            functions
+                .insert(Library::new(&other).unwrap(), false)
+                .unwrap_err()
+                .to_string(),
+            "ERR Function echo already exists"
+        );
+        functions
+            .insert(Library::new(LIBRARY).unwrap(), true)
+            .unwrap();
+        functions.delete("mylib").unwrap();
+        assert!(functions.get("echo").is_none());
+        functions.restore(vec![other]).unwrap();
+        assert!(functions.get("echo").is_some());
+    }
+
+    #[test]
+    fn test_library_code_is_checked() {
+        let error = |code: &str| Library::new(code).err().unwrap().to_string();
+        assert_eq!(error("return 1"), "ERR Missing library metadata");
+        assert_eq!(error("#!js name=x\n"), "ERR Engine 'js' not found");
+        assert_eq!(error("#!lua\n"), "ERR Library name was not given");
+        assert_eq!(
+            error("#!lua name=x\nlocal y = 1"),
+            "ERR No functions registered"
+        );
+        assert_eq!(
+            error("#!lua name=x\nredis.register_function{function_name='f', callback=print, flags={'bogus'}}"),
+            "ERR Unknown flag given"
+        );
+        assert!(error("#!lua name=x\nredis.register_function('f')")
+            .starts_with("ERR Error registering functions:"));
+    }
+}
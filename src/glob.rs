@@ -0,0 +1,117 @@
+// Redis-style glob matching: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\`
+// escapes. Works on bytes since keys and values need not be valid UTF-8.
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Position to resume from when the last `*` has to swallow another byte.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, i));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    i += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, s[i]) {
+                        if matched {
+                            p = next;
+                            i += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == s[i] {
+                        p += 2;
+                        i += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == s[i] {
+                        p += 1;
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match backtrack {
+            Some((star, matched)) => {
+                p = star + 1;
+                i = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches `c` against the character class opening at `pattern[start]`,
+// returning whether it matched and the index just past the closing `]`.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    loop {
+        match pattern.get(p)? {
+            b']' => break,
+            b'\\' => {
+                p += 1;
+                if *pattern.get(p)? == c {
+                    matched = true;
+                }
+                p += 1;
+            }
+            &lo if pattern.get(p + 1) == Some(&b'-') && pattern.get(p + 2) != Some(&b']') => {
+                let hi = *pattern.get(p + 2)?;
+                let (lo, hi) = if lo > hi { (hi, lo) } else { (lo, hi) };
+                if (lo..=hi).contains(&c) {
+                    matched = true;
+                }
+                p += 3;
+            }
+            &other => {
+                if other == c {
+                    matched = true;
+                }
+                p += 1;
+            }
+        }
+    }
+    Some((matched != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"*err*", b"fatal error: disk"));
+        assert!(!glob_match(b"h*llo", b"hellx"));
+        assert!(glob_match(b"a*b*c", b"aXXbYYc"));
+    }
+
+    #[test]
+    fn test_glob_classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"user:[0-9]", b"user:7"));
+        assert!(glob_match(b"what\\?", b"what?"));
+        assert!(!glob_match(b"what\\?", b"whats"));
+    }
+}
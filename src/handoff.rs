@@ -0,0 +1,236 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    eviction::LFU_INIT_VAL,
+    protocol::{Resp, RespCodec},
+    rdb,
+    server::{Access, Info, Query},
+    shutdown,
+    store::{Keyspace, Store},
+    value::Value,
+};
+
+// Warm restart. The outgoing instance listens on a Unix socket; its
+// replacement connects on startup and receives the keyspace as one
+// `[key, value, expiry-ms, hits]` array per entry followed by `+END`. Once
+// the new instance acknowledges, the old one shuts down as SHUTDOWN NOSAVE
+// would, since the dataset is the new one's to save now, and frees the port.
+// Strings go as they are; any other value goes as a one element array of its
+// RDB encoding in hex, as bulk strings here only carry text.
+
+fn millis(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+pub async fn send<T>(stream: T, keyspace: &dyn Keyspace) -> anyhow::Result<usize>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, RespCodec::default());
+    let now = SystemTime::now();
+    let mut sent = 0;
+    for (key, query) in keyspace.iter().filter(|(_, q)| !q.is_expired(now)) {
+        let entry = Resp::Array(vec![
+            Resp::bulk(key.as_str()),
+            match &query.value {
+                Value::String(s) => Resp::Bulk(Some(s.clone())),
+                value => Resp::array([Resp::bulk(to_hex(&rdb::dump_value(value)))]),
+            },
+            Resp::Integer(query.expiry.map_or(-1, millis)),
+            Resp::Integer(query.access.hits() as i64),
+        ]);
+        framed.feed(entry).await?;
+        sent += 1;
+    }
+    framed.send(Resp::simple("END")).await?;
+
+    match framed.next().await {
+        Some(Ok(Resp::SimpleString(ack))) if ack == "OK" => Ok(sent),
+        _ => bail!("replacement did not acknowledge the handoff"),
+    }
+}
+
+pub async fn receive<T>(stream: T, keyspace: &mut dyn Keyspace) -> anyhow::Result<usize>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, RespCodec::default());
+    let now = SystemTime::now();
+    let mut received = 0;
+    loop {
+        let frame = framed
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("handoff ended before END"))??;
+        let (key, value, expiry, hits) = match frame {
+            Resp::SimpleString(s) if s == "END" => break,
+            Resp::Array(entry) => match &entry[..] {
+                [Resp::Bulk(Some(key)), value, Resp::Integer(expiry), Resp::Integer(hits)] => {
+                    (key.to_string(), entry_value(value)?, *expiry, *hits)
+                }
+                _ => bail!("malformed handoff entry"),
+            },
+            _ => bail!("unexpected handoff frame"),
+        };
+        let expiry = (expiry >= 0).then(|| UNIX_EPOCH + Duration::from_millis(expiry as u64));
+        keyspace.insert(
+            key,
+            Query {
+                access: Access::new(now, hits as u64, LFU_INIT_VAL),
+                ..Query::new(value, expiry, now)
+            },
+        );
+        received += 1;
+    }
+    framed.send(Resp::ok()).await?;
+    Ok(received)
+}
+
+fn entry_value(value: &Resp) -> anyhow::Result<Value> {
+    match value {
+        Resp::Bulk(Some(s)) => Ok(Value::String(s.clone())),
+        Resp::Array(dump) => match &dump[..] {
+            [Resp::Bulk(Some(hex))] => rdb::restore_value(&from_hex(hex)?),
+            _ => bail!("malformed handoff value"),
+        },
+        _ => bail!("malformed handoff value"),
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd length hex in handoff");
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+// Longest client writes are held while the dataset is being handed off.
+const HANDOFF_PAUSE: Duration = Duration::from_secs(60);
+
+// Waits for a replacement instance, streams the dataset to it and shuts
+// down. Client writes are paused from before the snapshot until exit so no
+// write is lost, and only lifted again if the handoff fails.
+pub async fn serve(path: &Path, cache: Arc<Store>, info: Arc<Mutex<Info>>) -> anyhow::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        info.lock().await.clients.pause(HANDOFF_PAUSE, true);
+        let sent = send(stream, &cache.lock_all().await).await;
+        let sent = match sent {
+            Ok(sent) => sent,
+            Err(e) => {
+                println!("handoff failed: {}", e);
+                info.lock().await.clients.unpause();
+                continue;
+            }
+        };
+        println!(
+            "handed off {} keys via {}, shutting down",
+            sent,
+            path.display()
+        );
+        let _ = std::fs::remove_file(path);
+        if let Err(e) = shutdown::prepare(&cache, &info, Some(false)).await {
+            bail!("shutdown after handoff failed: {}", e);
+        }
+        info.lock().await.shutdown.notify_one();
+        return Ok(());
+    }
+}
+
+pub async fn load(path: &Path, cache: &mut dyn Keyspace) -> anyhow::Result<usize> {
+    let stream = UnixStream::connect(path).await?;
+    receive(stream, cache).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_handoff_transfers_live_keys() {
+        let now = SystemTime::now();
+        let query = |value: &str, expiry: Option<SystemTime>| Query {
+            access: Access::new(now, 3, LFU_INIT_VAL),
+            ..Query::new(value.to_string(), expiry, now)
+        };
+        let mut old = HashMap::new();
+        old.insert("plain".to_string(), query("a", None));
+        old.insert(
+            "ttl".to_string(),
+            query("b", Some(now + Duration::from_secs(60))),
+        );
+        old.insert(
+            "gone".to_string(),
+            query("c", Some(now - Duration::from_secs(1))),
+        );
+        let list = Value::List(Arc::new(vec!["x".into(), "y".into()]));
+        old.insert("list".to_string(), Query::new(list.clone(), None, now));
+
+        let (a, b) = tokio::io::duplex(64);
+        let mut new = HashMap::new();
+        let (sent, received) = tokio::join!(send(a, &old), receive(b, &mut new));
+        assert_eq!(sent.unwrap(), 3);
+        assert_eq!(received.unwrap(), 3);
+
+        assert_eq!(new["plain"].value, "a");
+        assert_eq!(new["plain"].expiry, None);
+        assert_eq!(new["plain"].access.hits(), 3);
+        assert_eq!(
+            millis(new["ttl"].expiry.unwrap()),
+            millis(old["ttl"].expiry.unwrap())
+        );
+        assert!(!new.contains_key("gone"));
+        assert_eq!(new["list"].value, list);
+    }
+
+    #[tokio::test]
+    async fn test_serve_shuts_down_after_handing_off() {
+        let path = std::env::temp_dir().join(format!("credis-handoff-{}.sock", std::process::id()));
+        let server = crate::command::TestServer::new();
+        let (cache, info) = (server.cache.clone(), server.info.clone());
+        cache.lock_all().await.insert(
+            "k".to_string(),
+            Query::new("v".to_string(), None, SystemTime::now()),
+        );
+        let serving = tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, cache, info).await }
+        });
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut new = HashMap::new();
+        assert_eq!(load(&path, &mut new).await.unwrap(), 1);
+        serving.await.unwrap().unwrap();
+        let shutdown = server.info.lock().await.shutdown.clone();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.notified())
+            .await
+            .unwrap();
+        assert!(server.info.lock().await.clients.writes_paused());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,5 @@
+// The wire protocol as a library, so embedders and test harnesses can speak
+// RESP without the server. The server binary uses it from here too.
+pub mod protocol;
+#[cfg(feature = "serde")]
+pub mod resp_serde;
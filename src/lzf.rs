@@ -0,0 +1,128 @@
+// LZF, the compression Redis applies to long strings in RDB files. The
+// compressed stream is a series of literal runs and back references:
+//
+//   000LLLLL <L+1 literal bytes>
+//   LLLooooo oooooooo              copy L+2 bytes from `o+1` bytes back
+//   111ooooo LLLLLLLL oooooooo     copy L+9 bytes from `o+1` bytes back
+
+const HASH_LOG: u32 = 14;
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_MATCH: usize = (1 << 8) + (1 << 3);
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    // Last position + 1 at which each 3 byte sequence's hash was seen.
+    let mut seen = vec![0usize; 1 << HASH_LOG];
+    let mut literals = 0;
+    let mut pos = 0;
+    while pos + 2 < input.len() {
+        let slot = hash(&input[pos..pos + 3]);
+        let candidate = std::mem::replace(&mut seen[slot], pos + 1);
+        let matched = candidate.checked_sub(1).filter(|&from| {
+            pos - from <= MAX_OFFSET && input[from..from + 3] == input[pos..pos + 3]
+        });
+        let Some(from) = matched else {
+            pos += 1;
+            continue;
+        };
+        let max = (input.len() - pos).min(MAX_MATCH);
+        let mut len = 3;
+        while len < max && input[from + len] == input[pos + len] {
+            len += 1;
+        }
+        put_literals(&mut out, &input[literals..pos]);
+        let offset = pos - from - 1;
+        let short = len - 2;
+        if short < 7 {
+            out.push((short << 5 | offset >> 8) as u8);
+        } else {
+            out.push((7 << 5 | offset >> 8) as u8);
+            out.push((short - 7) as u8);
+        }
+        out.push(offset as u8);
+        pos += len;
+        literals = pos;
+    }
+    put_literals(&mut out, &input[literals..]);
+    out
+}
+
+// Expands `input`, which must decode to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // No token yields more than MAX_MATCH bytes, so a larger `len` can't be
+    // right and isn't worth allocating for.
+    if len > input.len().saturating_mul(MAX_MATCH) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+        if ctrl < MAX_LITERAL {
+            let run = input.get(pos..pos + ctrl + 1)?;
+            out.extend_from_slice(run);
+            pos += run.len();
+        } else {
+            let mut short = ctrl >> 5;
+            if short == 7 {
+                short += *input.get(pos)? as usize;
+                pos += 1;
+            }
+            let offset = (ctrl & 0x1f) << 8 | *input.get(pos)? as usize;
+            pos += 1;
+            let from = out.len().checked_sub(offset + 1)?;
+            // The source may overlap what is being written, so go byte by byte.
+            for i in from..from + short + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn put_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERAL) {
+        out.push((run.len() - 1) as u8);
+        out.extend_from_slice(run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let repetitive = "abcabcabc".repeat(200);
+        let mixed: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for input in [&b""[..], b"a", b"aaaa", repetitive.as_bytes(), &mixed] {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(compress(repetitive.as_bytes()).len() < 40);
+    }
+
+    #[test]
+    fn test_decompress_handles_every_token() {
+        let compressed = [
+            0x02, b'a', b'b', b'c', // literal "abc"
+            0xe0, 0x03, 0x02, // copy 7+3+2 bytes from 3 back
+            0x20, 0x00, // copy 1+2 bytes from 1 back
+        ];
+        let want = b"abcabcabcabcabcccc";
+        assert_eq!(decompress(&compressed, want.len()).unwrap(), want);
+        assert!(decompress(&compressed, want.len() - 1).is_none());
+        assert!(decompress(&compressed[4..], 12).is_none());
+        assert!(decompress(&compressed, usize::MAX).is_none());
+    }
+}
@@ -1,85 +1,669 @@
+mod actor;
+mod aof;
+mod atomic;
+mod bench;
+mod clients;
 mod command;
-mod protocol;
+mod command_table;
+mod config;
+mod context;
+mod diskless;
+mod eviction;
+mod expire;
+mod failover;
+mod functions;
+mod glob;
+mod handoff;
+mod lzf;
+mod memprof;
+mod middleware;
+mod obuf;
+mod outbox;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod replica;
+mod replication;
+mod scripting;
 mod server;
-use crate::protocol::{Resp, RespEncoding};
-use bytes::BytesMut;
+mod shutdown;
+mod store;
+mod transaction;
+mod value;
+#[cfg(feature = "wasm")]
+mod wasm;
+use crate::protocol::{Limits, Resp, RespCodec};
 use clap::Parser;
 use clap_num::number_range;
-use server::{Handler, HostSpec, Info, Query, Role};
-use std::{collections::HashMap, sync::Arc};
+use clients::Clients;
+use eviction::Eviction;
+use functions::Functions;
+use futures::SinkExt;
+use persistence::Persistence;
+use redis_starter_rust::{format_resp, protocol};
+use server::{Handler, HostSpec, Info, Role};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use store::{Keyspace, Store};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    net::{lookup_host, TcpListener, TcpStream},
+    runtime::{self, Handle},
+    sync::{oneshot, Mutex},
 };
+use tokio_util::codec::Framed;
 
-// base64 encoded empty rdb file for demo purposes
-pub const RDB_64: &str = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
-
-async fn repl_handshake(port: u16, address: HostSpec) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(address.to_string()).await?;
-    stream.write_all(b"*1\r\n$4\r\nping\r\n").await?;
-    let mut buf = BytesMut::with_capacity(512);
-    stream.read_buf(&mut buf).await?;
-    stream.flush().await?;
-    stream
-        .write_all(format_resp!["REPLCONF", "listening-port", port.to_string()])
-        .await?;
-    stream.flush().await?;
-    stream.read_buf(&mut buf).await?;
-    stream
-        .write_all(format_resp!["REPLCONF", "capa", "psync2"])
-        .await?;
-    stream.flush().await?;
-    stream.read_buf(&mut buf).await?;
-    stream.write_all(format_resp!["PSYNC", "?", "-1"]).await?;
-    stream.flush().await?;
-    Ok(())
-}
+#[global_allocator]
+static GLOBAL: memprof::ProfilingAllocator = memprof::ProfilingAllocator;
+
+// Seconds between PINGs a master sends down an otherwise idle stream.
+const REPL_PING_PERIOD: u64 = 10;
 
 fn port_range(s: &str) -> Result<u16, String> {
     number_range(s, 1024, 65535)
 }
 
+fn samples_range(s: &str) -> Result<usize, String> {
+    number_range(s, 1, 64)
+}
+
+fn fsync_policy(s: &str) -> Result<aof::Fsync, String> {
+    s.parse()
+        .map_err(|_| "expected always, everysec or no".to_string())
+}
+
+fn yes_no(s: &str) -> Result<bool, String> {
+    match s.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("expected yes or no".to_string()),
+    }
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Args {
+    /// Config file of `name value` lines, one per flag. Flags given on the
+    /// command line override it, and SIGHUP reloads what can be changed at
+    /// runtime
+    config_file: Option<PathBuf>,
+
     /// Number of times to greet
     #[arg(long, default_value_t = 6379, value_parser=port_range)]
     port: u16,
 
+    /// Addresses or hostnames to listen on, separated by spaces or given as
+    /// repeated flags. A hostname is listened on at every address it
+    /// resolves to
+    #[arg(long, default_value = "127.0.0.1", num_args = 1.., value_delimiter = ' ',
+        value_parser = server::parse_host)]
+    bind: Vec<String>,
+
+    /// Master to replicate, as "host port" or host:port
     #[arg(long, default_value = None)]
-    replicaof: Option<String>,
+    replicaof: Option<HostSpec>,
+
+    /// Attribute allocations to command families, reported by MEMORY STATS
+    #[arg(long)]
+    memory_profile: bool,
+
+    /// Refuse writes while the last background save or AOF write failed
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    stop_writes_on_bgsave_error: bool,
+
+    /// Refuse writes from clients while running as a replica
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    replica_read_only: bool,
+
+    /// Replicate scripts as the writes they make rather than as the scripts themselves
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    lua_replicate_commands: bool,
+
+    /// Address a replica tells its master to reach it at, when its own is not reachable
+    #[arg(long)]
+    replica_announce_ip: Option<String>,
+
+    /// Port a replica tells its master to reach it at, instead of --port
+    #[arg(long, value_parser = port_range)]
+    replica_announce_port: Option<u16>,
+
+    /// Stream snapshots to replicas that support it instead of building them first
+    #[arg(long, default_value = "no", value_parser = yes_no, action = clap::ArgAction::Set)]
+    repl_diskless_sync: bool,
+
+    /// Seconds to wait for more replicas to share a diskless snapshot
+    #[arg(long, default_value_t = 5)]
+    repl_diskless_sync_delay: u64,
+
+    /// Refuse writes unless at least this many replicas are connected and acking (0 = off)
+    #[arg(long, default_value_t = 0)]
+    min_replicas_to_write: usize,
+
+    /// Seconds since its last ack after which a replica no longer counts towards min-replicas-to-write
+    #[arg(long, default_value_t = 10)]
+    min_replicas_max_lag: u64,
+
+    /// Maximum number of simultaneous client connections
+    #[arg(long, default_value_t = 10000)]
+    maxclients: usize,
+
+    /// Extra connection slots kept free for local admin connections
+    #[arg(long, default_value_t = 4)]
+    admin_reserved_clients: usize,
+
+    /// Output a client may have pending before it is disconnected, as
+    /// "<class> <hard> <soft> <soft seconds>" for the normal, replica or
+    /// pubsub class. May be repeated, and classes not given keep their defaults
+    #[arg(long)]
+    client_output_buffer_limit: Vec<String>,
+
+    /// Log every write to an append-only file and replay it on startup
+    #[arg(long, default_value = "no", value_parser = yes_no, action = clap::ArgAction::Set)]
+    appendonly: bool,
+
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    /// Load an AOF whose last command was cut short instead of refusing to start
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    aof_load_truncated: bool,
+
+    /// When to fsync the AOF: always, everysec or no
+    #[arg(long, default_value = "everysec", value_parser = fsync_policy)]
+    appendfsync: aof::Fsync,
+
+    /// Directory the RDB file is read from and written to
+    #[arg(long, default_value = ".")]
+    dir: String,
+
+    /// Save points as "<seconds> <changes>" pairs: BGSAVE once that many keys
+    /// changed in that long. Empty to disable
+    #[arg(long, default_value = "3600 1 300 100 60 10000")]
+    save: String,
+
+    /// LZF compress long strings in RDB files
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    rdbcompression: bool,
+
+    /// Write a CRC64 at the end of RDB files and refuse to load ones that don't match it
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    rdbchecksum: bool,
+
+    /// Name of the RDB file within --dir
+    #[arg(long, default_value = "dump.rdb")]
+    dbfilename: String,
+
+    /// Approximate dataset size in bytes above which keys are evicted (0 = no limit)
+    #[arg(long, default_value_t = 0)]
+    maxmemory: usize,
+
+    /// Eviction policy applied when over maxmemory
+    #[arg(long, default_value = "noeviction")]
+    maxmemory_policy: String,
+
+    /// Keys the LRU policies sample to pick each victim
+    #[arg(long, default_value_t = 5, value_parser = samples_range)]
+    maxmemory_samples: usize,
+
+    /// How slowly the LFU policies' access counters grow
+    #[arg(long, default_value_t = 10)]
+    lfu_log_factor: u64,
+
+    /// Minutes without access for the LFU policies' counters to drop by one (0 = never)
+    #[arg(long, default_value_t = 1)]
+    lfu_decay_time: u64,
+
+    /// Longest bulk string a client may send, in bytes
+    #[arg(long, default_value_t = Limits::default().max_bulk_len)]
+    proto_max_bulk_len: usize,
+
+    /// Most elements a client may send in a single array
+    #[arg(long, default_value_t = Limits::default().max_multibulk_len)]
+    proto_max_multibulk_len: usize,
+
+    /// Unix socket on which to hand the dataset to a replacement instance, then exit
+    #[arg(long)]
+    handoff_socket: Option<String>,
+
+    /// Unix socket of a running instance to take the dataset over from on startup
+    #[arg(long)]
+    handoff_from: Option<String>,
+
+    /// Threads serving connections (0 = one per core)
+    #[arg(long, default_value_t = 0)]
+    worker_threads: usize,
+
+    /// Serve everything from the main thread instead of a pool of workers
+    #[arg(long, conflicts_with = "worker_threads")]
+    current_thread: bool,
+
+    /// Accept connections on a dedicated thread per listener, handing them
+    /// to the workers, so accepting never waits behind busy connections
+    #[arg(long)]
+    pin_accept: bool,
+
+    /// Bytes of read buffer each connection starts with
+    #[arg(long, default_value_t = 8 * 1024)]
+    client_buffer_size: usize,
+
+    /// Let reads of a key run alongside each other, only writes excluding
+    /// them, rather than every command taking the key's shard in turn
+    #[arg(long, default_value = "yes", value_parser = yes_no, action = clap::ArgAction::Set)]
+    shared_reads: bool,
+
+    /// Run every data command on one task that connections hand them to,
+    /// rather than having connections lock the keyspace themselves
+    #[arg(long, default_value = "no", value_parser = yes_no, action = clap::ArgAction::Set)]
+    store_actor: bool,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<(), anyhow::Error> {
-    let args = Args::parse();
+// What accepted connections are set up with.
+#[derive(Clone)]
+struct Serving {
+    limits: Limits,
+    buffer_size: usize,
+    // The runtime connections are served on, when accepting happens on a
+    // pinned thread of its own.
+    workers: Option<Handle>,
+}
 
-    let role = if let Some(address) = args.replicaof {
-        let master = address
-            .parse::<HostSpec>()
-            .expect("failed to parse master address");
-        repl_handshake(args.port, master)
+// The outgoing instance only releases the port once the handoff completes,
+// so keep retrying for a while.
+// Every address the --bind hosts resolve to, each once.
+async fn bind_addrs(hosts: &[String], port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for host in hosts {
+        let resolved = lookup_host((host.as_str(), port))
             .await
-            .expect("failed to perform handshake");
-        Role::Slave
+            .map_err(|e| anyhow::anyhow!("failed to resolve {}: {}", host, e))?;
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+async fn bind_with_retry(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let mut attempts = 0;
+    loop {
+        match TcpListener::bind(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempts < 50 => {
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<(), anyhow::Error> {
+    let mut argv: Vec<String> = std::env::args().collect();
+    if argv.get(1).is_some_and(|arg| arg == "bench") {
+        let args = bench::Args::parse_from(&argv[1..]);
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        return runtime.block_on(bench::run(args));
+    }
+    // As with redis-server, a first argument that isn't a flag is a config
+    // file. Its directives go ahead of the other flags, so those win.
+    if let Some(path) = argv.get(1).filter(|arg| !arg.starts_with('-')) {
+        let directives = config::file_args(Path::new(path))?;
+        argv.splice(2..2, directives);
+    }
+    let args = Args::parse_from(argv);
+    let mut runtime = if args.current_thread {
+        runtime::Builder::new_current_thread()
     } else {
-        Role::Master
+        runtime::Builder::new_multi_thread()
     };
+    if args.worker_threads > 0 {
+        runtime.worker_threads(args.worker_threads);
+    }
+    runtime.enable_all().build()?.block_on(run(args))
+}
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", args.port)).await?;
-    let info = Arc::new(Mutex::new(Info::new(role)));
-    let cache: Arc<Mutex<HashMap<String, Query>>> = Arc::new(Mutex::new(HashMap::new()));
+async fn run(args: Args) -> anyhow::Result<(), anyhow::Error> {
+    if args.memory_profile {
+        memprof::enable();
+    }
+
+    let master = args.replicaof;
+    let mut eviction = Eviction::new(args.maxmemory);
+    eviction
+        .set_policy(&args.maxmemory_policy)
+        .map_err(anyhow::Error::msg)?;
+    eviction.samples = args.maxmemory_samples;
+    eviction::set_lfu_log_factor(args.lfu_log_factor);
+    eviction::set_lfu_decay_time(args.lfu_decay_time);
+    let save_points = persistence::parse_save_points(&args.save).map_err(|_| {
+        anyhow::anyhow!(
+            "invalid save '{}': expected pairs of seconds and changes",
+            args.save
+        )
+    })?;
+
+    let cache = Arc::new(Store::new(store::SHARDS, args.shared_reads));
+    if let Some(path) = &args.handoff_from {
+        let received = handoff::load(Path::new(path), &mut cache.lock_all().await).await?;
+        println!("took over {} keys from {}", received, path);
+    }
+    let mut listeners = Vec::with_capacity(args.bind.len());
+    for addr in bind_addrs(&args.bind, args.port).await? {
+        let listener = match args.handoff_from {
+            Some(_) => bind_with_retry(addr).await,
+            None => TcpListener::bind(addr).await,
+        };
+        let listener =
+            listener.map_err(|e| anyhow::anyhow!("failed to listen on {}: {}", addr, e))?;
+        println!("listening on {}", addr);
+        listeners.push(listener);
+    }
+    let mut persistence = Persistence::new(args.stop_writes_on_bgsave_error);
+    persistence.dir = args.dir;
+    persistence.dbfilename = args.dbfilename;
+    persistence.rdbcompression = args.rdbcompression;
+    persistence.rdbchecksum = args.rdbchecksum;
+    persistence.save_points = save_points;
+    persistence.appendfsync = args.appendfsync;
+    persistence.aof_load_truncated = args.aof_load_truncated;
+    let mut functions = Functions::default();
+    if args.handoff_from.is_none() && !args.appendonly {
+        // The AOF is the more complete record when enabled, so the RDB file
+        // is only read without it.
+        let path = persistence.rdb_path();
+        let checksum = persistence.rdbchecksum;
+        let mut loaded = rdb::load_file(&path, &mut cache.lock_all().await, checksum)
+            .map_err(|e| anyhow::anyhow!("failed to load {}: {}", path.display(), e))?;
+        println!("loaded {} from {}", loaded, path.display());
+        functions
+            .restore(std::mem::take(&mut loaded.functions))
+            .map_err(|e| anyhow::anyhow!("failed to load {}: {}", path.display(), e))?;
+    }
+    let mut clients = Clients::new(args.maxclients, args.admin_reserved_clients);
+    for value in &args.client_output_buffer_limit {
+        let limits = obuf::parse_limits(value)
+            .map_err(|e| anyhow::anyhow!("invalid client-output-buffer-limit: {}", e))?;
+        for (class, limit) in limits {
+            clients.set_output_limit(class, limit);
+        }
+    }
+    let mut info = Info::new(Role::Master, persistence, eviction, clients);
+    info.functions = functions;
+    info.replica_read_only = args.replica_read_only;
+    info.lua_replicate_commands = args.lua_replicate_commands;
+    info.diskless_sync = args.repl_diskless_sync;
+    info.diskless_sync_delay = std::time::Duration::from_secs(args.repl_diskless_sync_delay);
+    info.min_replicas_to_write = args.min_replicas_to_write;
+    info.min_replicas_max_lag = args.min_replicas_max_lag;
+    info.bind = args.bind;
+    info.port = args.port;
+    info.announce_ip = args.replica_announce_ip;
+    info.announce_port = args.replica_announce_port;
+    info.config_file.path = args.config_file;
+    let info = Arc::new(Mutex::new(info));
+    if args.store_actor {
+        let actor = actor::StoreActor::spawn(cache.clone(), info.clone());
+        info.lock().await.store_actor = Some(actor);
+    }
+    if args.appendonly {
+        // A handed-over dataset is already current, so only replay the log on
+        // a cold start.
+        let path = Path::new(&args.appendfilename);
+        let aof = match args.handoff_from {
+            Some(_) => aof::Aof::open(path)?,
+            None => aof::load(path, cache.clone(), info.clone()).await?,
+        };
+        info.lock().await.persistence.aof = Some(aof);
+    }
+    if let Some(master) = master {
+        replica::start(&mut *info.lock().await, master, cache.clone(), info.clone());
+    }
+    {
+        // Keeps each replica's acknowledged offset, and so its lag, current,
+        // and pings them every REPL_PING_PERIOD ticks.
+        let info = info.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            for tick in 1u64.. {
+                interval.tick().await;
+                // A replica relays its master's GETACKs and PINGs instead, so
+                // that its own replicas see the same stream and offsets it does.
+                let mut info = info.lock().await;
+                if matches!(info.role, Role::Master) {
+                    if tick % REPL_PING_PERIOD == 0 {
+                        info.replicas.ping();
+                    }
+                    info.replicas.request_ack();
+                }
+            }
+        });
+    }
+    {
+        // Deletes expired keys nobody reads, so they don't linger in memory.
+        // Shards are gone through one at a time, and their deadlines show
+        // whether any key has expired before the server info is locked.
+        let cache = cache.clone();
+        let info = info.clone();
+        let gate = info.lock().await.gate.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                for i in 0..cache.shard_count() {
+                    let _shared = gate.shared().await;
+                    let mut shard = cache.lock_shard(i).await;
+                    if shard.expired(std::time::SystemTime::now(), 1).is_empty() {
+                        continue;
+                    }
+                    expire::active_expire_cycle(&mut shard, &mut *info.lock().await);
+                }
+            }
+        });
+    }
+    tokio::spawn(aof::fsync_every_second(info.clone()));
+    {
+        // Starts a BGSAVE whenever a save point is reached, or else a
+        // BGREWRITEAOF whenever the AOF is due one.
+        let cache = cache.clone();
+        let info = info.clone();
+        let gate = info.lock().await.gate.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let now = std::time::SystemTime::now();
+                // Most ticks have nothing to do, which the server info alone
+                // tells; the keyspace is only locked for a save or rewrite.
+                {
+                    let persistence = &info.lock().await.persistence;
+                    if persistence.save_point_reached(now).is_none()
+                        && persistence.aof_rewrite_due().is_none()
+                    {
+                        continue;
+                    }
+                }
+                let _shared = gate.shared().await;
+                let mut locked = cache.lock_all().await;
+                let guard = &mut *info.lock().await;
+                if let Some((seconds, changes)) = guard.persistence.save_point_reached(now) {
+                    println!("{} changes in {} seconds. Saving...", changes, seconds);
+                    let _ = persistence::bgsave(
+                        cache.clone(),
+                        &mut locked,
+                        &mut guard.persistence,
+                        &guard.functions,
+                        info.clone(),
+                    );
+                } else if let Some(reason) = guard.persistence.aof_rewrite_due() {
+                    println!("Starting automatic rewriting of AOF on {}", reason);
+                    let _ = aof::bgrewrite(
+                        &locked,
+                        &mut guard.persistence,
+                        &guard.functions,
+                        info.clone(),
+                    );
+                }
+            }
+        });
+    }
+    if let Some(path) = args.handoff_socket {
+        let (cache, info) = (cache.clone(), info.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handoff::serve(Path::new(&path), cache, info).await {
+                println!("handoff listener failed: {}", e);
+            }
+        });
+    }
+    let serving = Serving {
+        limits: Limits {
+            max_bulk_len: args.proto_max_bulk_len,
+            max_multibulk_len: args.proto_max_multibulk_len,
+        },
+        buffer_size: args.client_buffer_size,
+        workers: None,
+    };
+    let accepting = listeners.into_iter().map(|listener| {
+        let (cache, info, serving) = (cache.clone(), info.clone(), serving.clone());
+        async move {
+            match args.pin_accept {
+                true => accept_pinned(listener, cache, info, serving).await,
+                false => accept(listener, cache, info, serving).await,
+            }
+        }
+    });
+    let accepting = futures::future::try_join_all(accepting);
+    tokio::pin!(accepting);
+    let shutdown = info.lock().await.shutdown.clone();
     loop {
-        let (stream, _) = listener.accept().await?;
+        tokio::select! {
+            result = &mut accepting => {
+                result?;
+            }
+            _ = shutdown.notified() => break,
+            _ = config::hangup() => {
+                match config::reload(&mut *info.lock().await) {
+                    Ok(n) => println!("Received SIGHUP, reloaded {} parameters", n),
+                    Err(e) => println!("Received SIGHUP, config reload failed: {:#}", e),
+                }
+            }
+            signal = shutdown::signal_received() => {
+                println!("Received {}, scheduling shutdown...", signal);
+                match shutdown::prepare(&cache, &info, None).await {
+                    Ok(()) => break,
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+    }
+    println!("ready to exit, bye bye...");
+    Ok(())
+}
+
+// Runs `listener`'s accept loop on a thread of its own, handing accepted
+// connections to the current runtime.
+async fn accept_pinned(
+    listener: TcpListener,
+    cache: Arc<Store>,
+    info: Arc<Mutex<Info>>,
+    serving: Serving,
+) -> std::io::Result<()> {
+    let listener = listener.into_std()?;
+    let serving = Serving {
+        workers: Some(Handle::current()),
+        ..serving
+    };
+    let (done, result) = oneshot::channel();
+    std::thread::Builder::new()
+        .name("accept".to_string())
+        .spawn(move || {
+            let accepting = async {
+                let listener = TcpListener::from_std(listener)?;
+                accept(listener, cache, info, serving).await
+            };
+            let result = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .and_then(|runtime| runtime.block_on(accepting));
+            let _ = done.send(result);
+        })?;
+    result
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::other("accept thread panicked")))
+}
+
+async fn accept(
+    listener: TcpListener,
+    cache: Arc<Store>,
+    info: Arc<Mutex<Info>>,
+    serving: Serving,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
         let cache = cache.clone();
         let server = info.clone();
         println!("accepted new connection");
 
-        tokio::spawn(async move {
-            let mut handler = Handler::new(stream, server);
-            handler.handle_stream(cache).await;
+        let laddr = stream.local_addr()?;
+        let Some((id, control)) = info.lock().await.clients.admit(addr, laddr) else {
+            tokio::spawn(async move {
+                let mut framed = Framed::new(stream, RespCodec::default());
+                let err = Resp::error("ERR max number of clients reached");
+                let _ = framed.send(err).await;
+            });
+            continue;
+        };
+        let (limits, buffer_size) = (serving.limits, serving.buffer_size);
+        let Some(workers) = &serving.workers else {
+            tokio::spawn(async move {
+                let handler = Handler::new(
+                    stream,
+                    addr,
+                    server.clone(),
+                    id,
+                    control,
+                    limits,
+                    buffer_size,
+                );
+                serve(handler, cache, server).await;
+            });
+            continue;
+        };
+        // The stream is registered with this thread's runtime, so move it
+        // over to the workers'.
+        let stream = stream.into_std()?;
+        workers.spawn(async move {
+            let stream = match TcpStream::from_std(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("failed to hand over connection: {}", e);
+                    server.lock().await.clients.remove(id);
+                    return;
+                }
+            };
+            let handler = Handler::new(
+                stream,
+                addr,
+                server.clone(),
+                id,
+                control,
+                limits,
+                buffer_size,
+            );
+            serve(handler, cache, server).await;
         });
     }
 }
+
+async fn serve(mut handler: Handler, cache: Arc<Store>, info: Arc<Mutex<Info>>) {
+    let id = handler.id();
+    if let Err(e) = handler.handle_stream(cache.clone()).await {
+        println!("connection closed: {}", e);
+    }
+    handler.unwatch(&cache).await;
+    let mut info = info.lock().await;
+    info.clients.remove(id);
+    info.pubsub.remove(id);
+}
@@ -0,0 +1,381 @@
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use crate::protocol::Resp;
+
+// Command families allocations are attributed to. Anything allocated outside
+// of command execution (parsing, socket buffers, startup) lands in `Other`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    Other,
+    Connection,
+    String,
+    Server,
+    Replication,
+}
+
+const FAMILIES: [Family; 5] = [
+    Family::Other,
+    Family::Connection,
+    Family::String,
+    Family::Server,
+    Family::Replication,
+];
+
+impl Family {
+    fn name(&self) -> &'static str {
+        match self {
+            Family::Other => "other",
+            Family::Connection => "connection",
+            Family::String => "string",
+            Family::Server => "server",
+            Family::Replication => "replication",
+        }
+    }
+}
+
+struct Counters {
+    allocated: AtomicU64,
+    allocations: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+    allocated: AtomicU64::new(0),
+    allocations: AtomicU64::new(0),
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static COUNTERS: [Counters; FAMILIES.len()] = [ZERO; FAMILIES.len()];
+// Frees aren't attributed: whoever drops a block is rarely who allocated it.
+static FREED: AtomicU64 = AtomicU64::new(0);
+
+// The allocator underneath: jemalloc or mimalloc when built with its
+// feature, the system's otherwise.
+#[cfg(feature = "jemalloc")]
+const INNER: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+const INNER: mimalloc::MiMalloc = mimalloc::MiMalloc;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+const INNER: std::alloc::System = std::alloc::System;
+
+thread_local! {
+    static CURRENT: Cell<Family> = const { Cell::new(Family::Other) };
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn current() -> Family {
+    CURRENT.try_with(|c| c.get()).unwrap_or(Family::Other)
+}
+
+// Wraps the allocator and, when --memory-profile is on, counts every
+// allocation against whichever family the current thread is executing.
+// Frees only go towards the total, since a value allocated by SET may well
+// be dropped by a later DEL. With profiling off it costs a flag check. Every method the allocator has its own version of goes
+// straight to it, so growing a Vec or String still reallocates in place.
+pub struct ProfilingAllocator;
+
+unsafe impl GlobalAlloc for ProfilingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        allocated(layout.size());
+        INNER.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        allocated(layout.size());
+        INNER.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        freed(layout.size());
+        INNER.dealloc(ptr, layout)
+    }
+
+    // Counted as freeing the old block and allocating the new one.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = INNER.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            freed(layout.size());
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+fn allocated(size: usize) {
+    if enabled() {
+        let counters = &COUNTERS[current() as usize];
+        counters.allocated.fetch_add(size as u64, Ordering::Relaxed);
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn freed(size: usize) {
+    if enabled() {
+        FREED.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+// Future adapter that tags allocations made while polling `inner`. The tag is
+// restored after every poll so other tasks sharing the worker thread are not
+// misattributed across await points.
+pub struct Tagged<F> {
+    family: Family,
+    inner: Pin<Box<F>>,
+}
+
+pub fn tagged<F: Future>(family: Family, inner: F) -> Tagged<F> {
+    Tagged {
+        family,
+        inner: Box::pin(inner),
+    }
+}
+
+impl<F: Future> Future for Tagged<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = CURRENT.with(|c| c.replace(self.family));
+        let result = self.inner.as_mut().poll(cx);
+        CURRENT.with(|c| c.set(previous));
+        result
+    }
+}
+
+// What the allocator reports: bytes handed out, bytes in the pages it
+// keeps for them, and bytes of those pages resident in RAM. Whichever it
+// can't tell are left out.
+#[derive(Default)]
+struct Figures {
+    allocated: Option<u64>,
+    active: Option<u64>,
+    resident: Option<u64>,
+}
+
+#[cfg(feature = "jemalloc")]
+fn figures() -> (&'static str, Figures) {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // The stats are a snapshot taken as the epoch advances.
+    let _ = epoch::advance();
+    let read = |stat: tikv_jemalloc_ctl::Result<usize>| stat.ok().map(|n| n as u64);
+    let figures = Figures {
+        allocated: read(stats::allocated::read()),
+        active: read(stats::active::read()),
+        resident: read(stats::resident::read()),
+    };
+    ("jemalloc", figures)
+}
+
+// mimalloc only counts what it has committed, so what's allocated is known
+// when profiling counts it.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn figures() -> (&'static str, Figures) {
+    let (mut rss, mut commit, mut unused) = (0, 0, 0);
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut unused,
+            &mut unused,
+            &mut unused,
+            &mut rss,
+            &mut unused,
+            &mut commit,
+            &mut unused,
+            &mut unused,
+        );
+    }
+    let figures = Figures {
+        allocated: profiled(),
+        active: Some(commit as u64),
+        resident: Some(rss as u64),
+    };
+    ("mimalloc", figures)
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn figures() -> (&'static str, Figures) {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    let figures = {
+        // Large blocks are mapped on their own, outside the arenas.
+        let info = unsafe { libc::mallinfo2() };
+        Figures {
+            allocated: Some((info.uordblks + info.hblkhd) as u64),
+            active: Some((info.arena + info.hblkhd) as u64),
+            resident: resident(),
+        }
+    };
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    let figures = Figures {
+        allocated: profiled(),
+        resident: resident(),
+        ..Figures::default()
+    };
+    ("libc", figures)
+}
+
+// Bytes allocated and not yet freed, while profiling counts them.
+fn profiled() -> Option<u64> {
+    enabled().then(|| {
+        let allocated: u64 = COUNTERS
+            .iter()
+            .map(|c| c.allocated.load(Ordering::Relaxed))
+            .sum();
+        allocated.saturating_sub(FREED.load(Ordering::Relaxed))
+    })
+}
+
+// The process's resident set, from /proc where there is one.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn resident() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Snapshot of the allocator's figures and the per-family counters in
+// MEMORY STATS form. As in Redis, fragmentation is the active pages over
+// what is allocated in them, and the RSS ratio the resident set over those
+// pages; well over 1 means memory freed by the dataset hasn't gone back to
+// the allocator or to the OS.
+pub fn stats() -> Resp {
+    let (allocator, figures) = figures();
+    let mut stats = Resp::map().entry("allocator", allocator);
+    for (name, figure) in [
+        ("allocator.allocated", figures.allocated),
+        ("allocator.active", figures.active),
+        ("allocator.resident", figures.resident),
+    ] {
+        if let Some(bytes) = figure {
+            stats = stats.entry(name, bytes as i64);
+        }
+    }
+    for (name, (of, over)) in [
+        (
+            "allocator-fragmentation",
+            (figures.active, figures.allocated),
+        ),
+        ("allocator-rss", (figures.resident, figures.active)),
+    ] {
+        if let (Some(of), Some(over)) = (of, over) {
+            stats = stats
+                .entry(format!("{}.ratio", name), of as f64 / over.max(1) as f64)
+                .entry(format!("{}.bytes", name), of as i64 - over as i64);
+        }
+    }
+    stats = stats.entry("memory-profile", if enabled() { "yes" } else { "no" });
+    for family in FAMILIES {
+        let counters = &COUNTERS[family as usize];
+        let allocated = counters.allocated.load(Ordering::Relaxed) as i64;
+        let allocations = counters.allocations.load(Ordering::Relaxed) as i64;
+        stats = stats
+            .entry(format!("{}.allocated", family.name()), allocated)
+            .entry(format!("{}.allocations", family.name()), allocations);
+    }
+    if let Some(live) = profiled() {
+        stats = stats.entry("profiled.live", live as i64);
+    }
+    stats.build()
+}
+
+// Plain-language summary for MEMORY DOCTOR.
+pub fn doctor() -> String {
+    if !enabled() {
+        return "Memory profiling is off, so there is nothing to diagnose. Restart with --memory-profile to attribute allocations to command families.".to_string();
+    }
+    let mut report = String::from("Memory allocated since startup by command family:\n");
+    let mut top = (Family::Other, 0);
+    for family in FAMILIES {
+        let counters = &COUNTERS[family as usize];
+        let allocated = counters.allocated.load(Ordering::Relaxed);
+        let allocations = counters.allocations.load(Ordering::Relaxed);
+        report.push_str(&format!(
+            "  {:<12} {:>12} bytes  ({} allocations)\n",
+            family.name(),
+            allocated,
+            allocations
+        ));
+        if allocated > top.1 {
+            top = (family, allocated);
+        }
+    }
+    report.push_str(&format!(
+        "{} bytes of it are still live. Most was allocated by the {} family.",
+        profiled().unwrap_or(0),
+        top.0.name()
+    ));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tagged_allocations_are_attributed() {
+        enable();
+        let counters = &COUNTERS[Family::Replication as usize];
+        let before = counters.allocated.load(Ordering::Relaxed);
+        let buf = tagged(Family::Replication, async { vec![0u8; 4096] }).await;
+        let after = counters.allocated.load(Ordering::Relaxed);
+        assert_eq!(buf.len(), 4096);
+        assert!(after - before >= 4096);
+
+        // Growing counts the new block and frees the old one.
+        let freed = FREED.load(Ordering::Relaxed);
+        let grown = tagged(Family::Replication, async move {
+            let mut buf = buf;
+            buf.reserve_exact(8192);
+            buf
+        })
+        .await;
+        assert!(grown.capacity() >= 4096 + 8192);
+        assert!(counters.allocated.load(Ordering::Relaxed) - after >= 4096 + 8192);
+        assert!(FREED.load(Ordering::Relaxed) - freed >= 4096);
+    }
+
+    #[test]
+    fn test_allocator_figures() {
+        let buf = std::hint::black_box(vec![1u8; 1 << 20]);
+        let Resp::Map(stats) = stats() else {
+            panic!("MEMORY STATS is not a map");
+        };
+        let get = |name: &str| {
+            stats
+                .iter()
+                .find(|(key, _)| *key == Resp::bulk(name))
+                .map(|(_, value)| value.clone())
+        };
+        let counted = cfg!(any(
+            feature = "jemalloc",
+            all(target_env = "gnu", not(feature = "mimalloc"))
+        ));
+        if counted || enabled() {
+            assert!(matches!(get("allocator.allocated"), Some(Resp::Integer(n)) if n >= 1 << 20));
+        }
+        if cfg!(target_os = "linux") {
+            assert!(matches!(get("allocator.active"), Some(Resp::Integer(n)) if n >= 1 << 20));
+            assert!(matches!(get("allocator.resident"), Some(Resp::Integer(n)) if n > 0));
+            assert!(matches!(get("allocator-rss.ratio"), Some(Resp::Double(_))));
+        }
+        if counted {
+            assert!(matches!(
+                get("allocator-fragmentation.ratio"),
+                Some(Resp::Double(_))
+            ));
+        }
+        drop(buf);
+    }
+}
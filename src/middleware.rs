@@ -0,0 +1,242 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{command::CommandError, command_table::COMMANDS, protocol::Resp, server::Info};
+
+// Steps that apply to every client command rather than to any one of them.
+// Each sees a command before it runs, and may refuse it, and again after
+// with how it went, in MIDDLEWARE order. Commands replayed from the AOF or
+// a master don't go through them.
+pub trait Middleware: Sync {
+    fn before(&self, _call: &Call, _server: &mut Server) -> Result<(), CommandError> {
+        Ok(())
+    }
+    fn after(&self, _call: &Call, _outcome: &Outcome, _server: &mut Server) {}
+}
+
+// What middleware sees of the server. Info is only locked for writes, so
+// that reads, which no middleware needs it for, never queue on its lock.
+pub struct Server<'a> {
+    pub stats: &'a CommandStats,
+    pub info: Option<&'a mut Info>,
+}
+
+static MIDDLEWARE: &[&dyn Middleware] = &[&StopWritesOnError, &CountCalls, &Propagate];
+
+// A client command on its way through.
+pub struct Call<'a> {
+    // Lowercased, as Redis names commands in stats.
+    pub name: &'a str,
+    pub is_write: bool,
+    // Those of a write. Reads leave them out, as nothing looks at theirs.
+    pub keys: &'a [String],
+    // What replicas and the AOF get if the command succeeds.
+    pub propagated: &'a Resp,
+}
+
+pub enum Outcome {
+    // Refused by a middleware before it ran.
+    Rejected,
+    Failed(Duration),
+    Done(Duration),
+}
+
+pub fn before(call: &Call, server: &mut Server) -> Result<(), CommandError> {
+    MIDDLEWARE
+        .iter()
+        .try_for_each(|middleware| middleware.before(call, server))
+}
+
+pub fn after(call: &Call, outcome: &Outcome, server: &mut Server) {
+    for middleware in MIDDLEWARE {
+        middleware.after(call, outcome, server);
+    }
+}
+
+// Refuses writes with MISCONF while the last save or AOF write failed.
+struct StopWritesOnError;
+
+impl Middleware for StopWritesOnError {
+    fn before(&self, call: &Call, server: &mut Server) -> Result<(), CommandError> {
+        match &server.info {
+            Some(info) if call.is_write => info.persistence.write_error().map_or(Ok(()), Err),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Hands successful writes to replicas and the AOF.
+struct Propagate;
+
+impl Middleware for Propagate {
+    fn after(&self, call: &Call, outcome: &Outcome, server: &mut Server) {
+        match &mut server.info {
+            Some(info) if call.is_write && matches!(outcome, Outcome::Done(_)) => {
+                info.propagate(call.propagated, call.keys)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Per command counts and run time for INFO commandstats.
+struct CountCalls;
+
+impl Middleware for CountCalls {
+    fn after(&self, call: &Call, outcome: &Outcome, server: &mut Server) {
+        let Some(stat) = server.stats.get(call.name) else {
+            return;
+        };
+        let add = |counter: &AtomicU64, n| counter.fetch_add(n, Ordering::Relaxed);
+        match outcome {
+            Outcome::Rejected => add(&stat.rejected_calls, 1),
+            Outcome::Failed(elapsed) | Outcome::Done(elapsed) => {
+                add(&stat.usec, elapsed.as_micros() as u64);
+                if matches!(outcome, Outcome::Failed(_)) {
+                    add(&stat.failed_calls, 1);
+                }
+                add(&stat.calls, 1)
+            }
+        };
+    }
+}
+
+// Counters for each command in COMMANDS, at the same index, so counting a
+// call is a few atomic adds rather than taking a lock.
+pub struct CommandStats(Box<[CommandStat]>);
+
+#[derive(Default)]
+struct CommandStat {
+    calls: AtomicU64,
+    usec: AtomicU64,
+    rejected_calls: AtomicU64,
+    failed_calls: AtomicU64,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self(COMMANDS.iter().map(|_| CommandStat::default()).collect())
+    }
+}
+
+impl CommandStats {
+    fn get(&self, name: &str) -> Option<&CommandStat> {
+        let i = COMMANDS.binary_search_by(|spec| spec.name.cmp(name)).ok()?;
+        Some(&self.0[i])
+    }
+
+    // The Commandstats section of INFO, listing commands that have been
+    // called at least once.
+    pub fn info(&self) -> String {
+        let mut section = "# Commandstats".to_string();
+        for (spec, stat) in COMMANDS.iter().zip(&self.0) {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            let (calls, usec) = (load(&stat.calls), load(&stat.usec));
+            let (rejected, failed) = (load(&stat.rejected_calls), load(&stat.failed_calls));
+            if calls == 0 && rejected == 0 {
+                continue;
+            }
+            let per_call = usec as f64 / calls.max(1) as f64;
+            section.push_str(&format!(
+                "\ncmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                spec.name, calls, usec, per_call, rejected, failed
+            ));
+        }
+        section
+    }
+
+    pub fn reset(&self) {
+        for stat in self.0.iter() {
+            for counter in [
+                &stat.calls,
+                &stat.usec,
+                &stat.rejected_calls,
+                &stat.failed_calls,
+            ] {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::Clients, eviction::Eviction, format_resp, persistence::Persistence, server::Role,
+    };
+
+    #[test]
+    fn test_pipeline_refuses_propagates_and_counts() {
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        let set = format_resp!["SET", "k", "v"];
+        let keys = ["k".to_string()];
+        let call = Call {
+            name: "set",
+            is_write: true,
+            keys: &keys,
+            propagated: &set,
+        };
+
+        let stats = info.command_stats.clone();
+        let mut server = Server {
+            stats: &stats,
+            info: Some(&mut info),
+        };
+        before(&call, &mut server).unwrap();
+        after(
+            &call,
+            &Outcome::Done(Duration::from_micros(30)),
+            &mut server,
+        );
+        assert!(info.replicas.offset() > 0);
+
+        info.persistence.rdb_last_bgsave_ok = false;
+        let offset = info.replicas.offset();
+        let mut server = Server {
+            stats: &stats,
+            info: Some(&mut info),
+        };
+        assert!(matches!(
+            before(&call, &mut server),
+            Err(CommandError::Misconf(_))
+        ));
+        after(&call, &Outcome::Rejected, &mut server);
+        assert_eq!(info.replicas.offset(), offset);
+
+        // Reads run without Info, and are only counted.
+        let get = format_resp!["GET", "k"];
+        let read = Call {
+            name: "get",
+            is_write: false,
+            keys: &keys,
+            propagated: &get,
+        };
+        let mut server = Server {
+            stats: &stats,
+            info: None,
+        };
+        before(&read, &mut server).unwrap();
+        after(
+            &read,
+            &Outcome::Failed(Duration::from_micros(4)),
+            &mut server,
+        );
+
+        assert_eq!(
+            info.command_stats.info(),
+            "# Commandstats\n\
+             cmdstat_get:calls=1,usec=4,usec_per_call=4.00,rejected_calls=0,failed_calls=1\n\
+             cmdstat_set:calls=1,usec=30,usec_per_call=30.00,rejected_calls=1,failed_calls=0"
+        );
+        info.reset_stats();
+        assert_eq!(info.command_stats.info(), "# Commandstats");
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use crate::{clients::ClientType, command::CommandError};
+
+// client-output-buffer-limit: how much output a connection may have waiting
+// to be written before it is disconnected, by class of client as in Redis.
+// Past the hard limit it goes at once; past the soft limit it goes once it
+// has stayed there for the soft limit's seconds. Zero disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputLimits {
+    pub normal: Limit,
+    pub replica: Limit,
+    pub pubsub: Limit,
+}
+
+// Redis's defaults: "normal 0 0 0 replica 256mb 64mb 60 pubsub 32mb 8mb 60".
+impl Default for OutputLimits {
+    fn default() -> Self {
+        const MB: usize = 1024 * 1024;
+        Self {
+            normal: Limit::default(),
+            replica: Limit {
+                hard: 256 * MB,
+                soft: 64 * MB,
+                soft_seconds: 60,
+            },
+            pubsub: Limit {
+                hard: 32 * MB,
+                soft: 8 * MB,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+impl OutputLimits {
+    // Our link to a master never writes to it, so it has no class of its own.
+    fn of(&self, class: ClientType) -> Limit {
+        match class {
+            ClientType::Normal | ClientType::Master => self.normal,
+            ClientType::Replica => self.replica,
+            ClientType::Pubsub => self.pubsub,
+        }
+    }
+
+    pub fn set(&mut self, class: ClientType, limit: Limit) {
+        match class {
+            ClientType::Normal | ClientType::Master => self.normal = limit,
+            ClientType::Replica => self.replica = limit,
+            ClientType::Pubsub => self.pubsub = limit,
+        }
+    }
+}
+
+// `<class> <hard> <soft> <soft seconds>` groups, as in the config file and
+// CONFIG SET. Classes not mentioned keep their limits. Sizes may have a k,
+// kb, m, mb, g or gb unit.
+pub fn parse_limits(value: &str) -> Result<Vec<(ClientType, Limit)>, CommandError> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if words.is_empty() || !words.len().is_multiple_of(4) {
+        return Err(CommandError::InvalidArguments(
+            "Wrong number of arguments in buffer limit configuration.",
+        ));
+    }
+    let mut limits = Vec::with_capacity(words.len() / 4);
+    for group in words.chunks(4) {
+        let class = match group[0].parse() {
+            Ok(ClientType::Master) | Err(()) => {
+                return Err(CommandError::InvalidArguments(
+                    "Invalid client class specified in buffer limit configuration.",
+                ))
+            }
+            Ok(class) => class,
+        };
+        let (Some(hard), Some(soft), Ok(soft_seconds)) =
+            (parse_size(group[1]), parse_size(group[2]), group[3].parse())
+        else {
+            return Err(CommandError::InvalidArguments(
+                "Error in hard, soft or soft_seconds setting in buffer limit configuration.",
+            ));
+        };
+        let limit = Limit {
+            hard,
+            soft,
+            soft_seconds,
+        };
+        limits.push((class, limit));
+    }
+    Ok(limits)
+}
+
+// As CONFIG GET shows it, in bytes.
+impl fmt::Display for OutputLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let classes = [
+            ("normal", self.normal),
+            ("replica", self.replica),
+            ("pubsub", self.pubsub),
+        ];
+        for (i, (name, limit)) in classes.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(
+                f,
+                "{} {} {} {}",
+                name, limit.hard, limit.soft, limit.soft_seconds
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+// A connection's output waiting to be written: replies it has queued for
+// itself, and whatever pub/sub or replication has queued for it from
+// elsewhere, until its socket takes them. Whoever queues output checks it
+// against the connection's limit, and a connection over it is told to close
+// even while it is stuck writing.
+pub struct Output {
+    limits: Arc<Mutex<OutputLimits>>,
+    state: Mutex<State>,
+    overrun: Notify,
+}
+
+struct State {
+    class: ClientType,
+    pending: usize,
+    // Since when the soft limit has been exceeded.
+    soft_since: Option<Instant>,
+    overrun: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl Output {
+    // Limits are shared by every connection, so CONFIG SET applies to all.
+    pub fn new(limits: Arc<Mutex<OutputLimits>>) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(State {
+                class: ClientType::Normal,
+                pending: 0,
+                soft_since: None,
+                overrun: false,
+            }),
+            overrun: Notify::new(),
+        }
+    }
+
+    pub fn class(&self) -> ClientType {
+        self.state.lock().unwrap().class
+    }
+
+    pub fn set_class(&self, class: ClientType) {
+        self.state.lock().unwrap().class = class;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending
+    }
+
+    // Counts `n` more bytes waiting. False once the connection has gone
+    // over its limit, and must close.
+    pub fn queue(&self, n: usize) -> bool {
+        let limits = *self.limits.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        state.pending += n;
+        let limit = limits.of(state.class);
+        if limit.soft > 0 && state.pending >= limit.soft {
+            state.soft_since.get_or_insert_with(Instant::now);
+        } else {
+            state.soft_since = None;
+        }
+        let soft_for = Duration::from_secs(limit.soft_seconds);
+        let over = (limit.hard > 0 && state.pending >= limit.hard)
+            || state
+                .soft_since
+                .is_some_and(|since| since.elapsed() >= soft_for);
+        if over && !state.overrun {
+            state.overrun = true;
+            println!(
+                "closing client over its output buffer limit: {} bytes pending",
+                state.pending
+            );
+            // Stored if nobody is waiting yet, so the next wait ends at once.
+            self.overrun.notify_one();
+        }
+        !state.overrun
+    }
+
+    // Counts `n` bytes as written to the socket.
+    pub fn written(&self, n: usize) {
+        let limit = self.limits.lock().unwrap().of(self.class());
+        let mut state = self.state.lock().unwrap();
+        state.pending = state.pending.saturating_sub(n);
+        if state.pending < limit.soft {
+            state.soft_since = None;
+        }
+    }
+
+    // Resolves once the connection has gone over its limit.
+    pub async fn overrun(&self) {
+        self.overrun.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn limits(value: &str) -> OutputLimits {
+        let mut limits = OutputLimits::default();
+        for (class, limit) in parse_limits(value).unwrap() {
+            limits.set(class, limit);
+        }
+        limits
+    }
+
+    #[test]
+    fn test_limits_parse_by_class() {
+        assert_eq!(
+            limits("pubsub 1kb 10 5 normal 2mb 1m 0").to_string(),
+            "normal 2097152 1000000 0 replica 268435456 67108864 60 pubsub 1024 10 5"
+        );
+        for bad in [
+            "",
+            "pubsub 1 2",
+            "master 1 2 3",
+            "pubsub 1xb 2 3",
+            "pubsub 1 2 -3",
+        ] {
+            assert!(parse_limits(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_output_over_the_limit_is_overrun() {
+        let limits = Arc::new(Mutex::new(self::limits("pubsub 100 10 0")));
+        let output = Output::new(limits.clone());
+        assert!(output.queue(1000), "normal clients have no limit");
+        output.written(1000);
+
+        output.set_class(ClientType::Pubsub);
+        assert!(output.queue(9));
+        output.written(9);
+        // Over the soft limit for its 0 seconds.
+        assert!(!output.queue(10));
+        assert!(!output.queue(0), "stays overrun");
+        assert!(output.overrun().now_or_never().is_some());
+
+        *limits.lock().unwrap() = self::limits("pubsub 100 0 0");
+        let output = Output::new(limits);
+        output.set_class(ClientType::Pubsub);
+        assert!(output.queue(99));
+        assert!(!output.queue(1));
+        assert_eq!(output.pending(), 100);
+    }
+}
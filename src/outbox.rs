@@ -0,0 +1,169 @@
+use std::{
+    collections::VecDeque,
+    io::{self, IoSlice},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::{put_header, Kind, Protocol, Resp, RespEncoding};
+
+// Replies on their way to a client. Frames are encoded into one buffer,
+// except that large payloads, such as a snapshot after FULLRESYNC or big
+// values in a pipelined batch, are queued between its pieces as they are
+// rather than copied in. Everything goes out with vectored writes, so the
+// pieces still leave together.
+#[derive(Default)]
+pub struct Outbox {
+    pieces: VecDeque<Bytes>,
+    buf: BytesMut,
+}
+
+// Payloads at least this big are sent from where they are.
+const SHARE_FROM: usize = 16 * 1024;
+
+// Most pieces one write hands the kernel.
+const MAX_IOVECS: usize = 64;
+
+impl Outbox {
+    pub fn push(&mut self, resp: &Resp, protocol: Protocol) {
+        match resp {
+            Resp::Bulk(Some(value)) if value.len() >= SHARE_FROM => {
+                put_header(&mut self.buf, Kind::Bulk, value.len());
+                self.push_bytes(value.to_bytes());
+                self.buf.put_slice(b"\r\n");
+            }
+            Resp::Raw(bytes) => self.push_bytes(bytes.clone()),
+            Resp::Array(items) => {
+                put_header(&mut self.buf, Kind::Array, items.len());
+                for item in items {
+                    self.push(item, protocol);
+                }
+            }
+            Resp::Attribute(attributes, reply) => {
+                if protocol == Protocol::Resp3 {
+                    put_header(&mut self.buf, Kind::Attribute, attributes.len());
+                    for (key, value) in attributes {
+                        key.encode_into_as(protocol, &mut self.buf);
+                        value.encode_into_as(protocol, &mut self.buf);
+                    }
+                }
+                self.push(reply, protocol);
+            }
+            resp => resp.encode_into_as(protocol, &mut self.buf),
+        }
+    }
+
+    // Pre-encoded bytes, such as propagated writes, sent as they are.
+    pub fn push_bytes(&mut self, bytes: Bytes) {
+        if bytes.len() < SHARE_FROM {
+            self.buf.put_slice(&bytes);
+            return;
+        }
+        self.cut();
+        self.pieces.push_back(bytes);
+    }
+
+    // Bytes pushed and not yet written.
+    pub fn size(&self) -> usize {
+        self.buf.len() + self.pieces.iter().map(Bytes::len).sum::<usize>()
+    }
+
+    fn cut(&mut self) {
+        if !self.buf.is_empty() {
+            self.pieces.push_back(self.buf.split().freeze());
+        }
+    }
+
+    // Writes out everything pushed so far.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, dst: &mut W) -> io::Result<()> {
+        self.cut();
+        while !self.pieces.is_empty() {
+            let slices: Vec<IoSlice> = self
+                .pieces
+                .iter()
+                .take(MAX_IOVECS)
+                .map(|piece| IoSlice::new(piece))
+                .collect();
+            let mut written = dst.write_vectored(&slices).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            while written > 0 {
+                let piece = &mut self.pieces[0];
+                let n = written.min(piece.len());
+                piece.advance(n);
+                written -= n;
+                if piece.is_empty() {
+                    self.pieces.pop_front();
+                }
+            }
+        }
+        dst.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    // Takes at most 5000 bytes per write, like a socket that keeps filling up.
+    #[derive(Default)]
+    struct Trickle {
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(5000);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_payloads_are_shared_and_written_in_order() {
+        let big = Resp::bulk("x".repeat(SHARE_FROM));
+        let replies = [
+            Resp::ok(),
+            big.clone(),
+            Resp::Array(vec![Resp::Integer(1), big.clone()]),
+            Resp::RDBLen(SHARE_FROM),
+            Resp::Raw(Bytes::from(vec![b'r'; SHARE_FROM])),
+        ];
+        let mut outbox = Outbox::default();
+        let mut want = Vec::new();
+        for reply in &replies {
+            outbox.push(reply, Protocol::Resp2);
+            want.extend(reply.encode());
+        }
+        let Resp::Bulk(Some(value)) = &big else {
+            unreachable!()
+        };
+        assert!(outbox
+            .pieces
+            .iter()
+            .any(|piece| piece.as_ptr() == value.as_ptr()));
+
+        let mut dst = Trickle::default();
+        outbox.write_to(&mut dst).await.unwrap();
+        assert_eq!(dst.written, want);
+        assert!(outbox.pieces.is_empty() && outbox.buf.is_empty());
+    }
+}
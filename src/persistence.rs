@@ -0,0 +1,466 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    aof::{Aof, Fsync},
+    command::CommandError,
+    functions::Functions,
+    protocol::Resp,
+    rdb,
+    server::Info,
+    store::{Keyspace, Locked, Store},
+};
+
+// How long after a failed BGSAVE before a save point may trigger another,
+// so a broken disk isn't retried every tick.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Health of the persistence layer as seen by the write path. The RDB and AOF
+// writers record their last outcome here; while either is failing, writes are
+// refused so clients notice before the data that can't be saved piles up.
+pub struct Persistence {
+    pub stop_writes_on_bgsave_error: bool,
+    pub rdb_last_bgsave_ok: bool,
+    pub rdb_bgsave_in_progress: bool,
+    pub rdb_last_save_time: SystemTime,
+    rdb_last_bgsave_try: SystemTime,
+    rdb_last_bgsave_time: Option<Duration>,
+    // Successful saves since startup.
+    rdb_saves: u64,
+    // Keys changed since the last successful save.
+    pub dirty: u64,
+    // (seconds, changes): save once `changes` keys have changed and
+    // `seconds` have passed since the last save.
+    pub save_points: Vec<(u64, u64)>,
+    pub aof_last_write_ok: bool,
+    pub aof_last_fsync_ok: bool,
+    pub aof: Option<Aof>,
+    pub appendfsync: Fsync,
+    // When the AOF is rewritten without being asked: once it is at least
+    // min_size and has grown by percentage since the last rewrite, or holds
+    // amplification records per live key. Zero turns either trigger off.
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    pub auto_aof_rewrite_amplification: u64,
+    // Whether a log cut short by a crash is loaded up to the damage.
+    pub aof_load_truncated: bool,
+    // Where the RDB file lives: `dir` joined with `dbfilename`.
+    pub dir: String,
+    pub dbfilename: String,
+    pub rdbcompression: bool,
+    // Whether RDB files are written with a CRC64 and checked against it on
+    // load.
+    pub rdbchecksum: bool,
+}
+
+impl Persistence {
+    pub fn new(stop_writes_on_bgsave_error: bool) -> Self {
+        Self {
+            stop_writes_on_bgsave_error,
+            rdb_last_bgsave_ok: true,
+            rdb_bgsave_in_progress: false,
+            rdb_last_save_time: SystemTime::now(),
+            rdb_last_bgsave_try: UNIX_EPOCH,
+            rdb_last_bgsave_time: None,
+            rdb_saves: 0,
+            dirty: 0,
+            save_points: vec![],
+            aof_last_write_ok: true,
+            aof_last_fsync_ok: true,
+            aof: None,
+            appendfsync: Fsync::Everysec,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            auto_aof_rewrite_amplification: 10,
+            aof_load_truncated: true,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            rdbcompression: true,
+            rdbchecksum: true,
+        }
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+
+    pub fn snapshot(&self, keyspace: &dyn Keyspace, functions: &Functions) -> rdb::Snapshot {
+        rdb::Snapshot::new(keyspace)
+            .functions(functions.codes())
+            .compression(self.rdbcompression)
+            .checksum(self.rdbchecksum)
+    }
+
+    // Counts a write towards the save points and logs it to the AOF, if
+    // enabled. AOF failures are recorded rather than returned: the command
+    // already ran, and the MISCONF gate stops further writes until the log is
+    // healthy again.
+    pub fn record_write(&mut self, cmd: &Resp, keys: &[String]) {
+        self.dirty += keys.len() as u64;
+        if let Some(aof) = &mut self.aof {
+            match aof.append(cmd, keys) {
+                Ok(()) => self.aof_last_write_ok = true,
+                Err(e) => {
+                    println!("failed to append to AOF: {}", e);
+                    self.aof_last_write_ok = false;
+                    return;
+                }
+            }
+            if self.appendfsync == Fsync::Always {
+                match aof.fsync() {
+                    Ok(()) => self.aof_last_fsync_ok = true,
+                    Err(e) => {
+                        println!("failed to fsync AOF: {}", e);
+                        self.aof_last_fsync_ok = false;
+                    }
+                }
+            }
+        }
+    }
+
+    // Retries what failed to reach the AOF, as Redis does from its cron: the
+    // appends still pending, then the fsync that failed, unless everysec's
+    // background fsync is retrying it already. Writes are accepted again
+    // once both have gone through.
+    pub fn retry_aof(&mut self) {
+        let Some(aof) = &mut self.aof else {
+            return;
+        };
+        if !self.aof_last_write_ok {
+            if let Err(e) = aof.flush() {
+                println!("failed to append to AOF: {}", e);
+                return;
+            }
+            println!("AOF write error looks solved, Redis can write again.");
+            self.aof_last_write_ok = true;
+        }
+        if !self.aof_last_fsync_ok && self.appendfsync != Fsync::Everysec {
+            match aof.fsync() {
+                Ok(()) => self.aof_last_fsync_ok = true,
+                Err(e) => println!("failed to fsync AOF: {}", e),
+            }
+        }
+    }
+
+    // Returns the MISCONF error a write command should fail with, if any.
+    pub fn write_error(&self) -> Option<CommandError> {
+        if self.stop_writes_on_bgsave_error && !self.rdb_last_bgsave_ok {
+            return Some(CommandError::Misconf(
+                "Redis is configured to save RDB snapshots, but it's currently unable to persist to disk. Commands that may modify the data set are disabled, because this instance is configured to report errors during writes if RDB snapshotting fails (stop-writes-on-bgsave-error option). Please check the Redis logs for details about the RDB error.",
+            ));
+        }
+        if !self.aof_last_write_ok || !self.aof_last_fsync_ok {
+            return Some(CommandError::Misconf(
+                "Errors writing to the AOF file. Please check the Redis logs for details.",
+            ));
+        }
+        None
+    }
+
+    // Records a successful save of the dataset as it was with `dirty`
+    // changes; anything written since still counts towards the next one.
+    fn saved(&mut self, dirty: u64) {
+        self.rdb_last_bgsave_ok = true;
+        self.rdb_last_save_time = SystemTime::now();
+        self.rdb_saves += 1;
+        self.dirty = self.dirty.saturating_sub(dirty);
+    }
+
+    // The first save point that has been reached, if a BGSAVE may start now.
+    pub fn save_point_reached(&self, now: SystemTime) -> Option<(u64, u64)> {
+        let since = |t: SystemTime| now.duration_since(t).unwrap_or_default();
+        if self.rdb_bgsave_in_progress
+            || !self.rdb_last_bgsave_ok && since(self.rdb_last_bgsave_try) < BGSAVE_RETRY_DELAY
+        {
+            return None;
+        }
+        let elapsed = since(self.rdb_last_save_time).as_secs();
+        self.save_points
+            .iter()
+            .copied()
+            .find(|&(seconds, changes)| self.dirty >= changes && elapsed >= seconds)
+    }
+
+    // Why the AOF should be rewritten now, if it should. Not while a save is
+    // running, so only one snapshot is written at a time.
+    pub fn aof_rewrite_due(&self) -> Option<String> {
+        if self.rdb_bgsave_in_progress {
+            return None;
+        }
+        self.aof.as_ref()?.rewrite_due(
+            self.auto_aof_rewrite_percentage,
+            self.auto_aof_rewrite_min_size,
+            self.auto_aof_rewrite_amplification,
+        )
+    }
+
+    // Zeroes the save and rewrite counts, for CONFIG RESETSTAT.
+    pub fn reset_stats(&mut self) {
+        self.rdb_saves = 0;
+        if let Some(aof) = &mut self.aof {
+            aof.reset_stats();
+        }
+    }
+
+    // Unix time of the last successful save, for LASTSAVE.
+    pub fn last_save(&self) -> u64 {
+        let since_epoch = self.rdb_last_save_time.duration_since(UNIX_EPOCH);
+        since_epoch.unwrap_or_default().as_secs()
+    }
+
+    // The `# Persistence` section of INFO. Nothing is served until loading
+    // has finished, so `loading` is always 0.
+    pub fn info(&self, keyspace: &dyn Keyspace) -> String {
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+        let current_bgsave = self.rdb_bgsave_in_progress.then(|| {
+            let elapsed = SystemTime::now().duration_since(self.rdb_last_bgsave_try);
+            elapsed.unwrap_or_default()
+        });
+        let aof = match &self.aof {
+            Some(aof) => aof.info(keyspace),
+            None => "aof_enabled:0".to_string(),
+        };
+        [
+            "# Persistence".to_string(),
+            "loading:0".to_string(),
+            format!("rdb_changes_since_last_save:{}", self.dirty),
+            format!(
+                "rdb_bgsave_in_progress:{}",
+                self.rdb_bgsave_in_progress as u8
+            ),
+            format!("rdb_last_save_time:{}", self.last_save()),
+            format!("rdb_last_bgsave_status:{}", status(self.rdb_last_bgsave_ok)),
+            format!(
+                "rdb_last_bgsave_time_sec:{}",
+                secs(self.rdb_last_bgsave_time)
+            ),
+            format!("rdb_current_bgsave_time_sec:{}", secs(current_bgsave)),
+            format!("rdb_saves:{}", self.rdb_saves),
+            format!(
+                "aof_last_write_status:{}",
+                status(self.aof_last_write_ok && self.aof_last_fsync_ok)
+            ),
+            format!(
+                "stop_writes_on_bgsave_error:{}",
+                if self.stop_writes_on_bgsave_error {
+                    "yes"
+                } else {
+                    "no"
+                }
+            ),
+            aof,
+        ]
+        .join("\n")
+    }
+}
+
+// SAVE: writes the RDB file before replying. The caller holds the cache
+// lock throughout, so like Redis every other client waits for it.
+pub fn save(
+    keyspace: &dyn Keyspace,
+    persistence: &mut Persistence,
+    functions: &Functions,
+) -> Result<(), CommandError> {
+    if persistence.rdb_bgsave_in_progress {
+        return Err(CommandError::Persistence(
+            "Background save already in progress",
+        ));
+    }
+    let path = persistence.rdb_path();
+    match rdb::save(persistence.snapshot(keyspace, functions), &path) {
+        Ok(()) => {
+            println!("DB saved on disk");
+            let dirty = persistence.dirty;
+            persistence.saved(dirty);
+            Ok(())
+        }
+        Err(e) => {
+            println!("failed to save {}: {}", path.display(), e);
+            Err(CommandError::Persistence("Failed to save the RDB file"))
+        }
+    }
+}
+
+// BGSAVE: freezes the store, which `cache` holds all of, then copies it a
+// shard at a time and writes it out on a blocking task, recording the
+// outcome once it finishes. Writes carry on meanwhile.
+pub fn bgsave(
+    store: Arc<Store>,
+    cache: &mut Locked,
+    persistence: &mut Persistence,
+    functions: &Functions,
+    shared: Arc<Mutex<Info>>,
+) -> Result<(), CommandError> {
+    if persistence.rdb_bgsave_in_progress {
+        return Err(CommandError::Persistence(
+            "Background save already in progress",
+        ));
+    }
+    persistence.rdb_bgsave_in_progress = true;
+    persistence.rdb_last_bgsave_try = SystemTime::now();
+    let dirty = persistence.dirty;
+    let frozen = cache.freeze();
+    let functions = functions.codes();
+    let (compression, checksum) = (persistence.rdbcompression, persistence.rdbchecksum);
+    let path = persistence.rdb_path();
+    tokio::spawn(async move {
+        let snapshot = rdb::Snapshot::from_entries(store.copy_frozen(frozen).await)
+            .functions(functions)
+            .compression(compression)
+            .checksum(checksum);
+        let saving = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || rdb::save(snapshot, &path)
+        });
+        let result = saving.await.unwrap_or_else(|e| Err(io::Error::other(e)));
+        let persistence = &mut shared.lock().await.persistence;
+        persistence.rdb_bgsave_in_progress = false;
+        let started = persistence.rdb_last_bgsave_try;
+        persistence.rdb_last_bgsave_time = started.elapsed().ok();
+        match result {
+            Ok(()) => {
+                println!("Background saving terminated with success");
+                persistence.saved(dirty);
+            }
+            Err(e) => {
+                println!("background save to {} failed: {}", path.display(), e);
+                persistence.rdb_last_bgsave_ok = false;
+            }
+        }
+    });
+    Ok(())
+}
+
+// Durations in INFO are whole seconds, or -1 when there is none.
+pub fn secs(duration: Option<Duration>) -> i64 {
+    duration.map_or(-1, |d| d.as_secs() as i64)
+}
+
+// Parses save points as written for `save`: pairs of seconds and changes,
+// e.g. "3600 1 300 100". An empty string disables them.
+pub fn parse_save_points(s: &str) -> Result<Vec<(u64, u64)>, CommandError> {
+    let invalid = CommandError::InvalidArguments("Invalid save parameters");
+    let numbers = s
+        .split_whitespace()
+        .map(|n| n.parse::<u64>().map_err(|_| invalid.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(invalid);
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+pub fn format_save_points(points: &[(u64, u64)]) -> String {
+    points
+        .iter()
+        .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Query;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_failed_bgsave_blocks_writes_only_when_configured() {
+        let mut persistence = Persistence::new(true);
+        assert!(persistence.write_error().is_none());
+
+        persistence.rdb_last_bgsave_ok = false;
+        assert!(matches!(
+            persistence.write_error(),
+            Some(CommandError::Misconf(_))
+        ));
+
+        persistence.stop_writes_on_bgsave_error = false;
+        assert!(persistence.write_error().is_none());
+    }
+
+    #[test]
+    fn test_save_points_trigger_on_changes_and_time() {
+        let mut persistence = Persistence::new(true);
+        persistence.save_points = parse_save_points("900 1 300 10").unwrap();
+        assert_eq!(format_save_points(&persistence.save_points), "900 1 300 10");
+        assert!(parse_save_points("900").is_err());
+        assert_eq!(parse_save_points("").unwrap(), vec![]);
+
+        let start = persistence.rdb_last_save_time;
+        let after = |secs| start + Duration::from_secs(secs);
+        persistence.record_write(&Resp::ok(), &["k".to_string()]);
+        assert_eq!(persistence.save_point_reached(after(400)), None);
+        assert_eq!(persistence.save_point_reached(after(900)), Some((900, 1)));
+
+        persistence.dirty = 10;
+        assert_eq!(persistence.save_point_reached(after(300)), Some((300, 10)));
+        persistence.rdb_last_bgsave_ok = false;
+        persistence.rdb_last_bgsave_try = after(298);
+        assert_eq!(persistence.save_point_reached(after(300)), None);
+        persistence.saved(4);
+        assert_eq!(persistence.dirty, 6);
+    }
+
+    #[tokio::test]
+    async fn test_bgsave_refuses_concurrent_saves() {
+        let dir = std::env::temp_dir().join(format!("credis-bgsave-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut persistence = Persistence::new(true);
+        persistence.dir = dir.to_str().unwrap().to_string();
+        let path = persistence.rdb_path();
+        let info = Arc::new(Mutex::new(Info::new(
+            crate::server::Role::Master,
+            persistence,
+            crate::eviction::Eviction::new(0),
+            crate::clients::Clients::new(10, 0),
+        )));
+        let store = Arc::new(Store::default());
+        let mut keyspace = store.lock_all().await;
+        let query = Query::new("v".to_string(), None, SystemTime::now());
+        keyspace.insert("k".to_string(), query);
+
+        {
+            let guard = &mut *info.lock().await;
+            let (persistence, functions) = (&mut guard.persistence, &guard.functions);
+            bgsave(
+                store.clone(),
+                &mut keyspace,
+                persistence,
+                functions,
+                info.clone(),
+            )
+            .unwrap();
+            assert!(bgsave(
+                store.clone(),
+                &mut keyspace,
+                persistence,
+                functions,
+                info.clone()
+            )
+            .is_err());
+            assert!(save(&keyspace, persistence, functions).is_err());
+        }
+        drop(keyspace);
+        while info.lock().await.persistence.rdb_bgsave_in_progress {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let keyspace = store.read_all().await;
+        let status = info.lock().await.persistence.info(&keyspace);
+        assert!(status.contains("rdb_last_bgsave_status:ok"));
+        assert!(status.contains("rdb_saves:1"));
+        assert!(status.contains("rdb_last_bgsave_time_sec:0"));
+        assert!(status.contains("rdb_current_bgsave_time_sec:-1"));
+
+        let mut loaded = HashMap::new();
+        assert_eq!(rdb::load_file(&path, &mut loaded, true).unwrap().keys, 1);
+        assert_eq!(loaded["k"].value, "v");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
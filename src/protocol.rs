@@ -1,7 +1,18 @@
+use std::{
+    fmt::{self, Write},
+    ops::{Deref, Range},
+    str::{FromStr, Utf8Error},
+    sync::LazyLock,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Builds a command as a RESP array of bulk strings.
 #[macro_export]
 macro_rules! format_resp {
     ($($str:expr),+) => {
-        &Resp::Array(vec![$(Resp::Bulk(Some($str.to_string()))),+]).encode()
+        Resp::array([$($str.to_string()),+])
     };
 }
 
@@ -21,6 +32,7 @@ pub enum Kind {
     Map,
     Set,
     Push,
+    Attribute,
 }
 
 impl Kind {
@@ -40,6 +52,7 @@ impl Kind {
             b'%' => Some(Kind::Map),
             b'~' => Some(Kind::Set),
             b'>' => Some(Kind::Push),
+            b'|' => Some(Kind::Attribute),
             _ => None,
         }
     }
@@ -60,190 +73,811 @@ impl Kind {
             Kind::Map => '%',
             Kind::Set => '~',
             Kind::Push => '>',
+            Kind::Attribute => '|',
         }
     }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum RespError {
+    #[error("RESP Error: Incomplete - more data needed to decode a frame")]
+    Incomplete,
+    #[error("RESP Error: I/O - {}", .0)]
+    Io(#[from] std::io::Error),
     #[error("RESP Error: Invalid Data - {}", .0)]
     InvalidData(&'static str),
     #[error("RESP Error: Invalid Type - {}", .0)]
     InvalidType(&'static str),
+    #[error("RESP Error: Limit Exceeded - {}", .0)]
+    LimitExceeded(&'static str),
 }
 
-#[derive(Debug, PartialEq)]
+// UTF-8 string payload of a bulk reply. Decoded bulks share the connection's
+// read buffer instead of being copied out into a String, so commands can hold
+// on to their arguments without allocating.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct BulkString(Bytes);
+
+impl BulkString {
+    pub fn from_bytes(bytes: Bytes) -> Result<BulkString, Utf8Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(BulkString(bytes))
+    }
+
+    // The value's bytes, sharing its memory.
+    pub fn to_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: every constructor checks or guarantees valid UTF-8.
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    // The one copy of `s` every stored value equal to it shares, if it is
+    // one of SHARED_WORDS or a canonical integer under SHARED_INTEGERS.
+    // These are static, so handing them out touches no refcount.
+    pub fn shared(s: &str) -> Option<BulkString> {
+        if let Some(word) = SHARED_WORDS.iter().find(|word| **word == s) {
+            return Some(BulkString(Bytes::from_static(word.as_bytes())));
+        }
+        let canonical = (1..=4).contains(&s.len())
+            && s.bytes().all(|b| b.is_ascii_digit())
+            && (s == "0" || !s.starts_with('0'));
+        if !canonical {
+            return None;
+        }
+        let n: usize = s.parse().ok()?;
+        Some(BulkString(Bytes::from_static(
+            SHARED_INTEGER_DIGITS[n].as_bytes(),
+        )))
+    }
+
+    // Whether this is the shared copy of its contents.
+    pub fn is_shared(&self) -> bool {
+        BulkString::shared(self).is_some_and(|shared| shared.0.as_ptr() == self.0.as_ptr())
+    }
+}
+
+// As in Redis, the integers below this are stored once however many keys
+// hold them, which keeps counters and flags cheap.
+const SHARED_INTEGERS: usize = 10_000;
+
+const SHARED_WORDS: &[&str] = &["", "true", "false", "yes", "no", "on", "off", "null"];
+
+static SHARED_INTEGER_DIGITS: LazyLock<Vec<&'static str>> = LazyLock::new(|| {
+    (0..SHARED_INTEGERS)
+        .map(|n| &*Box::leak(n.to_string().into_boxed_str()))
+        .collect()
+});
+
+impl Deref for BulkString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<String> for BulkString {
+    fn from(s: String) -> BulkString {
+        BulkString(Bytes::from(s))
+    }
+}
+
+impl From<&str> for BulkString {
+    fn from(s: &str) -> BulkString {
+        BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl PartialEq<str> for BulkString {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for BulkString {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for BulkString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for BulkString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Integer of any size, kept as its validated decimal digits: nothing in the
+// server does arithmetic on these, it only has to carry them intact.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigNumber(String);
+
+impl FromStr for BigNumber {
+    type Err = RespError;
+
+    fn from_str(s: &str) -> Result<BigNumber, RespError> {
+        let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RespError::InvalidData("Invalid big number"));
+        }
+        Ok(BigNumber(s.to_string()))
+    }
+}
+
+impl From<i64> for BigNumber {
+    fn from(i: i64) -> BigNumber {
+        BigNumber(i.to_string())
+    }
+}
+
+impl BigNumber {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BigNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Wire protocol negotiated by a connection via HELLO. Every connection starts
+// out speaking RESP2.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn from_version(version: u8) -> Option<Protocol> {
+        match version {
+            2 => Some(Protocol::Resp2),
+            3 => Some(Protocol::Resp3),
+            _ => None,
+        }
+    }
+
+    pub fn version(&self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Resp {
     SimpleString(String),
+    SimpleError(String),
     Integer(i64),
-    Bulk(Option<String>),
+    Bulk(Option<BulkString>),
     Array(Vec<Resp>),
-    Verbatim(String),
+    Map(Vec<(Resp, Resp)>),
+    Set(Vec<Resp>),
+    Push(Vec<Resp>),
+    Boolean(bool),
+    Double(f64),
+    BigNumber(BigNumber),
+    // Out-of-band metadata followed by the reply it annotates. RESP2 clients
+    // only see the reply.
+    Attribute(Vec<(Resp, Resp)>, Box<Resp>),
+    // Text with a three letter format tag (`txt`, `mkd`) that RESP3 clients
+    // display as-is; RESP2 clients get a bulk string.
+    Verbatim(String, String),
+    // Pre-encoded bytes written as-is, e.g. an RDB payload after RDBLen.
+    Raw(Bytes),
     RDBLen(usize),
+    // RESP3 has a single null type. RESP2 spells null as a missing bulk
+    // string or a missing array, which some clients tell apart, so Null and
+    // Bulk(None) go out as `$-1` and NullArray as `*-1`.
     Null,
+    NullArray,
+}
+
+// Shorthands for building replies. Plain strings convert to bulk strings,
+// since that's what almost every reply wants; simple strings and errors have
+// to be asked for by name.
+impl Resp {
+    pub fn bulk(s: impl Into<BulkString>) -> Resp {
+        Resp::Bulk(Some(s.into()))
+    }
+
+    pub fn simple(s: impl Into<String>) -> Resp {
+        Resp::SimpleString(s.into())
+    }
+
+    pub fn ok() -> Resp {
+        Resp::simple("OK")
+    }
+
+    pub fn error(msg: impl Into<String>) -> Resp {
+        Resp::SimpleError(msg.into())
+    }
+
+    pub fn verbatim(text: impl Into<String>) -> Resp {
+        Resp::Verbatim("txt".to_string(), text.into())
+    }
+
+    pub fn array<T: Into<Resp>>(items: impl IntoIterator<Item = T>) -> Resp {
+        Resp::Array(items.into_iter().map(Into::into).collect())
+    }
+
+    pub fn map() -> MapBuilder {
+        MapBuilder::default()
+    }
+
+    pub fn with_attributes(self, attributes: Vec<(Resp, Resp)>) -> Resp {
+        Resp::Attribute(attributes, Box::new(self))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MapBuilder {
+    pairs: Vec<(Resp, Resp)>,
+}
+
+impl MapBuilder {
+    pub fn entry(mut self, key: impl Into<Resp>, value: impl Into<Resp>) -> Self {
+        self.pairs.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn build(self) -> Resp {
+        Resp::Map(self.pairs)
+    }
+}
+
+impl From<&str> for Resp {
+    fn from(s: &str) -> Resp {
+        Resp::bulk(s)
+    }
+}
+
+impl From<String> for Resp {
+    fn from(s: String) -> Resp {
+        Resp::bulk(s)
+    }
+}
+
+impl From<BulkString> for Resp {
+    fn from(s: BulkString) -> Resp {
+        Resp::Bulk(Some(s))
+    }
+}
+
+impl From<i64> for Resp {
+    fn from(i: i64) -> Resp {
+        Resp::Integer(i)
+    }
+}
+
+impl From<bool> for Resp {
+    fn from(b: bool) -> Resp {
+        Resp::Boolean(b)
+    }
+}
+
+impl From<f64> for Resp {
+    fn from(d: f64) -> Resp {
+        Resp::Double(d)
+    }
+}
+
+impl<T: Into<Resp>> From<Vec<T>> for Resp {
+    fn from(items: Vec<T>) -> Resp {
+        Resp::array(items)
+    }
+}
+
+impl<T: Into<Resp>> From<Option<T>> for Resp {
+    fn from(value: Option<T>) -> Resp {
+        value.map_or(Resp::Null, Into::into)
+    }
 }
 
 pub trait RespEncoding {
-    fn encoded_string(&self) -> String;
     fn encode(&self) -> Vec<u8>;
+    fn encode_as(&self, protocol: Protocol) -> Vec<u8>;
+    fn encode_into(&self, dst: &mut BytesMut);
+    fn encode_into_as(&self, protocol: Protocol, dst: &mut BytesMut);
+}
+
+// Writes an aggregate or length header such as `*3\r\n`.
+pub fn put_header(dst: &mut BytesMut, kind: Kind, len: usize) {
+    let _ = write!(dst, "{}{}\r\n", Kind::byte_char(kind), len);
+}
+
+fn put_line(dst: &mut BytesMut, kind: Kind, line: &str) {
+    dst.put_u8(Kind::byte_char(kind) as u8);
+    dst.put_slice(line.as_bytes());
+    dst.put_slice(b"\r\n");
 }
 
 impl RespEncoding for Resp {
-    fn encoded_string(&self) -> String {
+    fn encode(&self) -> Vec<u8> {
+        self.encode_as(Protocol::Resp2)
+    }
+    fn encode_as(&self, protocol: Protocol) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        self.encode_into_as(protocol, &mut dst);
+        dst.to_vec()
+    }
+    fn encode_into(&self, dst: &mut BytesMut) {
+        self.encode_into_as(Protocol::Resp2, dst)
+    }
+    fn encode_into_as(&self, protocol: Protocol, dst: &mut BytesMut) {
         match self {
-            Resp::SimpleString(s) => {
-                let mut result = Kind::byte_char(Kind::SimpleString).to_string();
-                result.push_str(s);
-                result.push_str("\r\n");
-                result
-            }
+            Resp::SimpleString(s) => put_line(dst, Kind::SimpleString, s),
+            Resp::SimpleError(e) => put_line(dst, Kind::SimpleError, e),
             Resp::Integer(i) => {
-                let mut result = Kind::byte_char(Kind::Integer).to_string();
-                result.push_str(&i.to_string());
-                result.push_str("\r\n");
-                result
+                let _ = write!(dst, ":{}\r\n", i);
             }
-            Resp::Bulk(data) => {
-                let mut result = Kind::byte_char(Kind::Bulk).to_string();
-                if let Some(value) = data {
-                    result.push_str(&value.len().to_string());
-                    result.push_str("\r\n");
-                    result.push_str(value);
-                    result.push_str("\r\n");
-                } else {
-                    result.push_str("-1\r\n");
-                }
-                result
+            Resp::Bulk(Some(value)) => {
+                put_header(dst, Kind::Bulk, value.len());
+                dst.put_slice(value.as_bytes());
+                dst.put_slice(b"\r\n");
             }
+            Resp::Bulk(None) | Resp::Null | Resp::NullArray if protocol == Protocol::Resp3 => {
+                dst.put_slice(b"_\r\n")
+            }
+            Resp::Bulk(None) | Resp::Null => dst.put_slice(b"$-1\r\n"),
+            Resp::NullArray => dst.put_slice(b"*-1\r\n"),
             Resp::Array(list) => {
-                let mut result = Kind::byte_char(Kind::Array).to_string();
-                result.push_str(&list.len().to_string());
-                result.push_str("\r\n");
+                put_header(dst, Kind::Array, list.len());
                 for item in list {
-                    result.push_str(&item.encoded_string());
+                    item.encode_into_as(protocol, dst);
+                }
+            }
+            Resp::Set(items) | Resp::Push(items) => {
+                // Sets and pushes are plain arrays to a RESP2 client.
+                let kind = match (protocol, self) {
+                    (Protocol::Resp2, _) => Kind::Array,
+                    (Protocol::Resp3, Resp::Set(_)) => Kind::Set,
+                    (Protocol::Resp3, _) => Kind::Push,
+                };
+                put_header(dst, kind, items.len());
+                for item in items {
+                    item.encode_into_as(protocol, dst);
                 }
-                result
             }
-            Resp::RDBLen(file_len) => format!("${}\r\n", file_len).to_string(),
-            Resp::Verbatim(content) => content.to_string(),
-            Resp::Null => "$-1\r\n".to_string(),
+            Resp::Boolean(b) => match protocol {
+                Protocol::Resp2 => Resp::Integer(*b as i64).encode_into_as(protocol, dst),
+                Protocol::Resp3 => put_line(dst, Kind::Boolean, if *b { "t" } else { "f" }),
+            },
+            Resp::BigNumber(n) => match protocol {
+                // RESP2 has no big number type; Redis sends them as bulk strings.
+                Protocol::Resp2 => Resp::bulk(n.as_str()).encode_into_as(protocol, dst),
+                Protocol::Resp3 => put_line(dst, Kind::Big, n.as_str()),
+            },
+            Resp::Double(d) => {
+                let repr = if d.is_nan() {
+                    "nan".to_string()
+                } else {
+                    d.to_string()
+                };
+                match protocol {
+                    Protocol::Resp2 => Resp::bulk(repr).encode_into_as(protocol, dst),
+                    Protocol::Resp3 => put_line(dst, Kind::Double, &repr),
+                }
+            }
+            Resp::Map(pairs) => {
+                // RESP2 has no map type, so maps are flattened into an array
+                // of alternating keys and values.
+                match protocol {
+                    Protocol::Resp2 => put_header(dst, Kind::Array, pairs.len() * 2),
+                    Protocol::Resp3 => put_header(dst, Kind::Map, pairs.len()),
+                }
+                for (key, value) in pairs {
+                    key.encode_into_as(protocol, dst);
+                    value.encode_into_as(protocol, dst);
+                }
+            }
+            Resp::Attribute(attributes, reply) => {
+                if protocol == Protocol::Resp3 {
+                    put_header(dst, Kind::Attribute, attributes.len());
+                    for (key, value) in attributes {
+                        key.encode_into_as(protocol, dst);
+                        value.encode_into_as(protocol, dst);
+                    }
+                }
+                reply.encode_into_as(protocol, dst);
+            }
+            Resp::RDBLen(file_len) => put_header(dst, Kind::Bulk, *file_len),
+            Resp::Verbatim(format, text) => match protocol {
+                Protocol::Resp2 => Resp::bulk(text.as_str()).encode_into_as(protocol, dst),
+                Protocol::Resp3 => {
+                    put_header(dst, Kind::VerbatimString, format.len() + 1 + text.len());
+                    dst.put_slice(format.as_bytes());
+                    dst.put_u8(b':');
+                    dst.put_slice(text.as_bytes());
+                    dst.put_slice(b"\r\n");
+                }
+            },
+            Resp::Raw(content) => dst.put_slice(content),
         }
     }
-    fn encode(&self) -> Vec<u8> {
-        self.encoded_string().into_bytes()
+}
+
+// Deepest aggregate nesting accepted from a peer. Real commands are flat
+// arrays; this only has to stop `*1\r\n*1\r\n...` from exhausting the stack.
+const MAX_DEPTH: usize = 64;
+
+// Longest header or simple value line accepted from a peer, as Redis caps
+// inline requests. Past it, a line with no CRLF in sight is refused rather
+// than buffered.
+const MAX_LINE: usize = 64 * 1024;
+
+// Reads a frame front to back. Every read advances `pos` by exactly the
+// bytes it consumed, terminators included, so nested values need no offset
+// arithmetic and whatever follows the frame is left untouched.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8, RespError> {
+        let byte = *self.buf.get(self.pos).ok_or(RespError::Incomplete)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    // The rest of the current line, without its CRLF.
+    fn line(&mut self) -> Result<&'a [u8], RespError> {
+        let rest = &self.buf[self.pos..];
+        let searched = &rest[..rest.len().min(MAX_LINE + 2)];
+        let Some(len) = searched.windows(2).position(|window| window == b"\r\n") else {
+            return match searched.len() > MAX_LINE {
+                true => Err(RespError::LimitExceeded("too big line")),
+                false => Err(RespError::Incomplete),
+            };
+        };
+        self.pos += len + 2;
+        Ok(&rest[..len])
+    }
+
+    fn text(&mut self, err: &'static str) -> Result<&'a str, RespError> {
+        std::str::from_utf8(self.line()?).map_err(|_| RespError::InvalidData(err))
+    }
+
+    fn number<T: FromStr>(&mut self, err: &'static str) -> Result<T, RespError> {
+        self.text(err)?
+            .parse()
+            .map_err(|_| RespError::InvalidData(err))
+    }
+
+    // A `len` byte payload and its CRLF. Returns where the payload sits in
+    // the buffer so callers holding `Bytes` can slice it without copying.
+    fn payload(&mut self, len: usize) -> Result<Range<usize>, RespError> {
+        let start = self.pos;
+        let end = start
+            .checked_add(len)
+            .ok_or(RespError::InvalidData("Invalid bulk string length"))?;
+        if end + 2 > self.buf.len() {
+            return Err(RespError::Incomplete);
+        }
+        if &self.buf[end..end + 2] != b"\r\n" {
+            return Err(RespError::InvalidData(
+                "Improperly terminated data payload for bulk string",
+            ));
+        }
+        self.pos = end + 2;
+        Ok(start..end)
     }
 }
 
 // Parses data based on Resp kind as indicated by the first byte.
-// Creates and returns corresponding Resp variant.
-pub fn readnext_resp(b: &[u8]) -> Result<(Resp, usize), RespError> {
-    if b.is_empty() {
-        return Err(RespError::InvalidData("incoming bytestream empty"));
+// Returns the value and the number of bytes it occupied in `b`.
+pub fn readnext_resp(b: &Bytes) -> Result<(Resp, usize), RespError> {
+    let mut cur = Cursor::new(b);
+    let value = read_value(b, &mut cur, 0)?;
+    Ok((value, cur.pos))
+}
+
+fn read_value(b: &Bytes, cur: &mut Cursor, depth: usize) -> Result<Resp, RespError> {
+    if depth > MAX_DEPTH {
+        return Err(RespError::LimitExceeded("too many nested aggregates"));
     }
 
-    let resp_kind =
-        Kind::from_byte(b[0]).ok_or(RespError::InvalidType("unrecognized datatype prefix byte"))?;
+    let resp_kind = Kind::from_byte(cur.byte()?)
+        .ok_or(RespError::InvalidType("unrecognized datatype prefix byte"))?;
 
     match resp_kind {
-        Kind::SimpleString => parse_string(&b[1..]),
-        Kind::Integer => parse_integer(&b[1..]),
-        Kind::Bulk => parse_bulk(&b[1..]),
-        Kind::Array => parse_array(&b[1..]),
+        Kind::SimpleString => {
+            let string = cur.text("Invalid UTF-8 in Simple String")?;
+            Ok(Resp::SimpleString(string.to_string()))
+        }
+        Kind::SimpleError => {
+            let string = cur.text("Invalid UTF-8 in Simple Error")?;
+            Ok(Resp::SimpleError(string.to_string()))
+        }
+        Kind::Integer => Ok(Resp::Integer(cur.number("Invalid integer value")?)),
+        Kind::Bulk => Ok(Resp::Bulk(parse_bulk(b, cur)?)),
+        Kind::VerbatimString => parse_verbatim(b, cur),
+        Kind::Array => Ok(parse_aggregate(b, cur, 1, depth)?.map_or(Resp::NullArray, Resp::Array)),
+        Kind::Null => parse_null(cur),
+        Kind::Boolean => parse_boolean(cur),
+        Kind::Double => Ok(Resp::Double(cur.number("Invalid double value")?)),
+        Kind::Big => {
+            let number = cur.text("Invalid UTF-8 in big number")?.parse()?;
+            Ok(Resp::BigNumber(number))
+        }
+        Kind::Map => parse_map(b, cur, depth),
+        Kind::Set => parse_aggregate(b, cur, 1, depth)?
+            .map(Resp::Set)
+            .ok_or(RespError::InvalidData("set length cannot be negative")),
+        Kind::Push => parse_aggregate(b, cur, 1, depth)?
+            .map(Resp::Push)
+            .ok_or(RespError::InvalidData("push length cannot be negative")),
+        Kind::Attribute => parse_attribute(b, cur, depth),
         _ => Err(RespError::InvalidType("unsupported RESP type")),
     }
 }
 
-fn parse_string(b: &[u8]) -> Result<(Resp, usize), RespError> {
-    let end =
-        find_clrf_index(b).ok_or(RespError::InvalidData("improperly terminated data payload"))?;
-    let string = String::from_utf8(b[..end - 2].to_vec())
-        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in Simple String"))?;
-    Ok((Resp::SimpleString(string), end))
+fn parse_bulk(b: &Bytes, cur: &mut Cursor) -> Result<Option<BulkString>, RespError> {
+    let len: isize = cur.number("Invalid bulk string length")?;
+    if len == -1 {
+        return Ok(None);
+    }
+    if len < -1 {
+        return Err(RespError::InvalidData("bulk string length cannot be < -1"));
+    }
+
+    let data = b.slice(cur.payload(len as usize)?);
+    BulkString::from_bytes(data)
+        .map(Some)
+        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in bulk string"))
 }
 
-fn parse_integer(b: &[u8]) -> Result<(Resp, usize), RespError> {
-    let end = find_clrf_index(b).ok_or(RespError::InvalidData(
-        "improperly terminated data payload for integer",
-    ))?;
-    let integer = std::str::from_utf8(&b[..end - 2])
-        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in Integer"))?
-        .parse::<i64>()
-        .map_err(|_| RespError::InvalidData("Invalid integer value"))?;
-    Ok((Resp::Integer(integer), end))
+// Verbatim strings are bulk strings whose payload starts with `fmt:`.
+fn parse_verbatim(b: &Bytes, cur: &mut Cursor) -> Result<Resp, RespError> {
+    let Some(data) = parse_bulk(b, cur)? else {
+        return Err(RespError::InvalidData("verbatim string cannot be null"));
+    };
+    match data.split_once(':') {
+        Some((format, text)) if format.len() == 3 => {
+            Ok(Resp::Verbatim(format.to_string(), text.to_string()))
+        }
+        _ => Err(RespError::InvalidData(
+            "verbatim string is missing its format",
+        )),
+    }
 }
 
-fn parse_bulk(b: &[u8]) -> Result<(Resp, usize), RespError> {
-    let len_end = find_clrf_index(b).ok_or(RespError::InvalidData(
-        "CLRF not found in bulk string length specification",
-    ))?;
-    let len = std::str::from_utf8(&b[..len_end - 2])
-        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in bulk string length specification"))?
-        .parse::<isize>()
-        .map_err(|_| RespError::InvalidData("Invalid bulk string length"))?;
+fn parse_map(b: &Bytes, cur: &mut Cursor, depth: usize) -> Result<Resp, RespError> {
+    let items = parse_aggregate(b, cur, 2, depth)?
+        .ok_or(RespError::InvalidData("map length cannot be negative"))?;
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key, value));
+    }
+    Ok(Resp::Map(pairs))
+}
+
+// Attributes are a map followed by the value they annotate.
+fn parse_attribute(b: &Bytes, cur: &mut Cursor, depth: usize) -> Result<Resp, RespError> {
+    let Resp::Map(attributes) = parse_map(b, cur, depth)? else {
+        unreachable!("parse_map only returns maps");
+    };
+    let reply = read_value(b, cur, depth + 1)?;
+    Ok(Resp::Attribute(attributes, Box::new(reply)))
+}
 
+// Reads a length header followed by `len * per_entry` nested values. A
+// length of -1 (RESP2 null array) yields None.
+fn parse_aggregate(
+    b: &Bytes,
+    cur: &mut Cursor,
+    per_entry: usize,
+    depth: usize,
+) -> Result<Option<Vec<Resp>>, RespError> {
+    let len: isize = cur.number("Invalid aggregate length")?;
+    if len < -1 {
+        return Err(RespError::InvalidData("aggregate length cannot be < -1"));
+    }
     if len == -1 {
-        return Ok((Resp::Null, 0));
+        return Ok(None);
     }
 
-    let data_start = len_end;
-    let data_end = data_start + len as usize;
+    let count = (len as usize)
+        .checked_mul(per_entry)
+        .ok_or(RespError::InvalidData("Invalid aggregate length"))?;
+    // Every value takes at least three bytes, so don't trust the declared
+    // count further than the buffer could possibly hold.
+    let mut items = Vec::with_capacity(count.min((b.len() - cur.pos) / 3));
+    for _ in 0..count {
+        items.push(read_value(b, cur, depth + 1)?);
+    }
+    Ok(Some(items))
+}
 
-    if data_end + 2 > b.len() || &b[data_end..data_end + 2] != b"\r\n" {
-        return Err(RespError::InvalidData(
-            "Improperly terminated data payload for bulk string",
-        ));
+fn parse_null(cur: &mut Cursor) -> Result<Resp, RespError> {
+    if !cur.line()?.is_empty() {
+        return Err(RespError::InvalidData("null must not carry data"));
     }
+    Ok(Resp::Null)
+}
 
-    let data = std::str::from_utf8(&b[data_start..data_end])
-        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in bulk string"))?;
-    // HACK: Add 2 to the buffer size as we remove two datatype specification
-    // bytes with calls to readnext_resp (one for the $[length]), and one for the
-    // actual string... or something like that...
-    // This is terrible and a magic number?
-    // I don't know...
-    Ok((Resp::Bulk(Some(data.to_string())), data_end + 2))
+fn parse_boolean(cur: &mut Cursor) -> Result<Resp, RespError> {
+    match cur.line()? {
+        b"t" => Ok(Resp::Boolean(true)),
+        b"f" => Ok(Resp::Boolean(false)),
+        _ => Err(RespError::InvalidData("boolean must be 't' or 'f'")),
+    }
 }
 
-fn parse_array(b: &[u8]) -> Result<(Resp, usize), RespError> {
-    let len_end = find_clrf_index(b).ok_or(RespError::InvalidData(
-        "CLRF not found in array length specification",
-    ))?;
-    let len = std::str::from_utf8(&b[..len_end - 2])
-        .map_err(|_| RespError::InvalidData("Invalid UTF-8 in array length specification"))?
-        .parse::<isize>()
-        .map_err(|_| RespError::InvalidData("Invalid array length"))?;
+// How far the codec has walked a partial frame: where its next value
+// starts, and how many values each aggregate still open there needs.
+// Kept between reads so a frame arriving in pieces is only walked once.
+#[derive(Debug, Default)]
+struct Scan {
+    pos: usize,
+    open: Vec<usize>,
+}
 
-    if len < -1 {
-        return Err(RespError::InvalidData("array length cannot be < -1"));
+// Length of the complete frame at the start of `b`, found by walking the
+// headers without decoding any payloads. Lets the codec split exactly one
+// frame off the read buffer before parsing it. On Incomplete, `scan` holds
+// where to pick up once more has been read.
+fn frame_len(b: &[u8], limits: &Limits, scan: &mut Scan) -> Result<usize, RespError> {
+    let mut cur = Cursor {
+        buf: b,
+        pos: scan.pos,
+    };
+    loop {
+        if scan.open.len() > MAX_DEPTH {
+            return Err(RespError::LimitExceeded("too many nested aggregates"));
+        }
+        let start = cur.pos;
+        match skip_header(&mut cur, limits) {
+            Ok(0) => {}
+            Ok(entries) => {
+                scan.open.push(entries);
+                continue;
+            }
+            Err(RespError::Incomplete) => {
+                scan.pos = start;
+                return Err(RespError::Incomplete);
+            }
+            Err(e) => return Err(e),
+        }
+        // A value is complete, and with it any aggregate it was the last of.
+        loop {
+            let Some(remaining) = scan.open.last_mut() else {
+                *scan = Scan::default();
+                return Ok(cur.pos);
+            };
+            *remaining -= 1;
+            if *remaining > 0 {
+                break;
+            }
+            scan.open.pop();
+        }
     }
+}
 
-    if len == -1 {
-        return Ok((Resp::Null, 0));
+// Skips one value's header, and its payload if it has one. Returns how many
+// values follow as its entries.
+fn skip_header(cur: &mut Cursor, limits: &Limits) -> Result<usize, RespError> {
+    let kind = Kind::from_byte(cur.byte()?)
+        .ok_or(RespError::InvalidType("unrecognized datatype prefix byte"))?;
+    let entries: isize = match kind {
+        Kind::Bulk | Kind::VerbatimString => {
+            let len: isize = cur.number("Invalid bulk string length")?;
+            if len < 0 {
+                return Ok(0);
+            }
+            if len as usize > limits.max_bulk_len {
+                return Err(RespError::LimitExceeded("invalid bulk length"));
+            }
+            cur.payload(len as usize)?;
+            return Ok(0);
+        }
+        Kind::Array | Kind::Set | Kind::Push | Kind::Map | Kind::Attribute => {
+            cur.number("Invalid aggregate length")?
+        }
+        _ => {
+            cur.line()?;
+            return Ok(0);
+        }
+    };
+    if entries > 0 && entries as usize > limits.max_multibulk_len {
+        return Err(RespError::LimitExceeded("invalid multibulk length"));
+    }
+    // Attributes are a map followed by the value they annotate.
+    let entries = match kind {
+        Kind::Map => entries * 2,
+        Kind::Attribute => entries.max(0) * 2 + 1,
+        _ => entries,
+    };
+    Ok(entries.max(0) as usize)
+}
+
+// Frames RESP values over a byte stream. Decoding yields one complete value
+// at a time and leaves partial frames buffered until the rest arrives;
+// encoding uses whichever protocol the connection has negotiated.
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    pub protocol: Protocol,
+    pub limits: Limits,
+    scan: Scan,
+}
+
+impl RespCodec {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
     }
+}
+
+// Caps on what a peer may declare in a frame header. Checked before waiting
+// for the payload, so a hostile length can't make us buffer without bound.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+}
 
-    let mut items = Vec::with_capacity(len as usize);
-    let mut rest = &b[len_end..];
-    for _ in 0..len {
-        let (item, remaining) = parse_next_arr_value(rest)?;
-        items.push(item);
-        rest = remaining;
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+        }
     }
+}
 
-    Ok((Resp::Array(items), b.len()))
+impl Decoder for RespCodec {
+    type Item = Resp;
+    type Error = RespError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Resp>, RespError> {
+        let len = match frame_len(src, &self.limits, &mut self.scan) {
+            Ok(len) => len,
+            Err(RespError::Incomplete) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let frame = src.split_to(len).freeze();
+        let (resp, _) = readnext_resp(&frame)?;
+        Ok(Some(resp))
+    }
 }
 
-fn parse_next_arr_value(b: &[u8]) -> Result<(Resp, &[u8]), RespError> {
-    let (val, size) = readnext_resp(b)?;
-    // HACK: Add 1 to the buffer size to account for the one taken off during
-    // the call to the readnext_resp function. This is terrible and a magic number?
-    // I don't know...
-    Ok((val, &b[size + 1..]))
+impl Encoder<Resp> for RespCodec {
+    type Error = RespError;
+
+    fn encode(&mut self, item: Resp, dst: &mut BytesMut) -> Result<(), RespError> {
+        item.encode_into_as(self.protocol, dst);
+        Ok(())
+    }
 }
 
-fn find_clrf_index(b: &[u8]) -> Option<usize> {
-    b.windows(2)
-        .position(|window| window == b"\r\n")
-        .map(|pos| pos + 2)
+// Pre-encoded payloads (e.g. propagated replication frames) pass through as-is.
+impl Encoder<Bytes> for RespCodec {
+    type Error = RespError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), RespError> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -252,25 +886,382 @@ mod tests {
 
     #[test]
     fn test_parse_array() {
-        let input = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
-        let (parsed, _) = readnext_resp(input).unwrap();
+        let input = Bytes::from_static(b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n");
+        let (parsed, _) = readnext_resp(&input).unwrap();
         assert_eq!(
             parsed,
             Resp::Array(vec![
-                Resp::Bulk(Some("ECHO".to_string())),
-                Resp::Bulk(Some("hey".to_string()))
+                Resp::Bulk(Some("ECHO".into())),
+                Resp::Bulk(Some("hey".into()))
             ])
         );
     }
 
+    #[test]
+    fn test_bulk_strings_share_the_frame_buffer() {
+        let input = Bytes::from_static(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+        let (Resp::Array(items), _) = readnext_resp(&input).unwrap() else {
+            panic!("expected an array");
+        };
+        let Resp::Bulk(Some(key)) = &items[1] else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(key, "key");
+        assert!(input.as_ptr_range().contains(&key.0.as_ptr()));
+        assert!(BulkString::from_bytes(Bytes::from_static(b"\xff")).is_err());
+    }
+
     #[test]
     fn test_parse_int() {
-        let input = b"*2\r\n:51\r\n:33\r\n";
-        let (parsed, _) = readnext_resp(input).unwrap();
+        let input = Bytes::from_static(b"*2\r\n:51\r\n:33\r\n");
+        let (parsed, _) = readnext_resp(&input).unwrap();
 
         assert_eq!(
             parsed,
             Resp::Array(vec![Resp::Integer(51), Resp::Integer(33),])
         );
     }
+
+    #[test]
+    fn test_parse_resp3_scalars() {
+        assert_eq!(
+            readnext_resp(&Bytes::from_static(b"_\r\n")).unwrap(),
+            (Resp::Null, 3)
+        );
+        assert_eq!(
+            readnext_resp(&Bytes::from_static(b"#t\r\n")).unwrap().0,
+            Resp::Boolean(true)
+        );
+        assert_eq!(
+            readnext_resp(&Bytes::from_static(b"#f\r\n")).unwrap().0,
+            Resp::Boolean(false)
+        );
+        assert_eq!(
+            readnext_resp(&Bytes::from_static(b",3.25\r\n")).unwrap().0,
+            Resp::Double(3.25)
+        );
+        assert_eq!(
+            readnext_resp(&Bytes::from_static(b",-inf\r\n")).unwrap().0,
+            Resp::Double(f64::NEG_INFINITY)
+        );
+        assert!(readnext_resp(&Bytes::from_static(b"#x\r\n")).is_err());
+    }
+
+    #[test]
+    fn test_parse_resp3_nested_aggregates() {
+        let input =
+            b"%2\r\n+first\r\n~2\r\n:1\r\n#t\r\n$6\r\nsecond\r\n_\r\n>2\r\n+message\r\n,1.5\r\n";
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&input[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Resp::Map(vec![
+                (
+                    Resp::SimpleString("first".to_string()),
+                    Resp::Set(vec![Resp::Integer(1), Resp::Boolean(true)])
+                ),
+                (Resp::Bulk(Some("second".into())), Resp::Null),
+            ]))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Resp::Push(vec![
+                Resp::SimpleString("message".to_string()),
+                Resp::Double(1.5)
+            ]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_encode_into_appends_to_buffer() {
+        let mut dst = BytesMut::from(&b"+OK\r\n"[..]);
+        let reply = Resp::Map(vec![(
+            Resp::Bulk(Some("proto".into())),
+            Resp::Array(vec![
+                Resp::Integer(-3),
+                Resp::Bulk(None),
+                Resp::Boolean(true),
+            ]),
+        )]);
+        reply.encode_into_as(Protocol::Resp3, &mut dst);
+        assert_eq!(
+            &dst[..],
+            b"+OK\r\n%1\r\n$5\r\nproto\r\n*3\r\n:-3\r\n_\r\n#t\r\n"
+        );
+        assert_eq!(
+            reply.encode(),
+            b"*2\r\n$5\r\nproto\r\n*3\r\n:-3\r\n$-1\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_codec_rejects_oversized_declarations() {
+        let mut codec = RespCodec::new(Limits {
+            max_bulk_len: 8,
+            max_multibulk_len: 2,
+        });
+        // Rejected from the header alone, without waiting for the payload.
+        let mut buf = BytesMut::from(&b"*1\r\n$1000000000\r\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(RespError::LimitExceeded("invalid bulk length"))
+        ));
+        let mut buf = BytesMut::from(&b"*3\r\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(RespError::LimitExceeded("invalid multibulk length"))
+        ));
+        let mut buf = BytesMut::from(&b"*2\r\n$8\r\n12345678\r\n$-1\r\n"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_truncated_frames_are_incomplete() {
+        let frame = b"*3\r\n$3\r\nSET\r\n%1\r\n+k\r\n~1\r\n:1\r\n$-1\r\n";
+        for cut in 0..frame.len() {
+            let mut buf = BytesMut::from(&frame[..cut]);
+            assert!(matches!(RespCodec::default().decode(&mut buf), Ok(None)));
+            assert!(matches!(
+                readnext_resp(&Bytes::copy_from_slice(&frame[..cut])),
+                Err(RespError::Incomplete)
+            ));
+        }
+        let mut buf = BytesMut::from(&frame[..]);
+        assert!(RespCodec::default().decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        for input in [
+            &b"$abc\r\n"[..],
+            b"$-5\r\n",
+            b"*-7\r\n",
+            b"*99999999999999999999\r\n",
+            b"$3\r\nabcde\r\n",
+            b"?\r\n",
+        ] {
+            let mut buf = BytesMut::from(input);
+            assert!(
+                RespCodec::default().decode(&mut buf).is_err(),
+                "{:?}",
+                input
+            );
+        }
+        // A huge declared count must not be trusted for preallocation.
+        assert!(readnext_resp(&Bytes::from_static(b"*4611686018427387903\r\n:1\r\n")).is_err());
+    }
+
+    #[test]
+    fn test_long_lines_are_refused_and_partial_frames_resume() {
+        for start in [&b"+"[..], b"$", b"*1\r\n:"] {
+            let mut buf = BytesMut::from(start);
+            buf.extend_from_slice(&b"1".repeat(MAX_LINE + 1));
+            assert!(matches!(
+                RespCodec::default().decode(&mut buf),
+                Err(RespError::LimitExceeded("too big line"))
+            ));
+        }
+        let mut buf = BytesMut::from(&b"+"[..]);
+        buf.extend_from_slice(&b"a".repeat(MAX_LINE));
+        buf.extend_from_slice(b"\r\n");
+        assert!(RespCodec::default().decode(&mut buf).unwrap().is_some());
+
+        // Fed a byte at a time, the walk picks up where it stopped.
+        let frame = b"*2\r\n$3\r\nGET\r\n*1\r\n%1\r\n+k\r\n:1\r\n";
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::new();
+        for &byte in &frame[..frame.len() - 1] {
+            buf.extend_from_slice(&[byte]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        assert!(codec.scan.pos > frame.len() - 5);
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!((codec.scan.pos, buf.len()), (0, 0));
+    }
+
+    #[test]
+    fn test_nesting_bomb_is_rejected() {
+        let bomb = Bytes::from(b"*1\r\n".repeat(100_000));
+        let mut buf = BytesMut::from(&bomb[..]);
+        assert!(matches!(
+            RespCodec::default().decode(&mut buf),
+            Err(RespError::LimitExceeded(_))
+        ));
+        assert!(matches!(
+            readnext_resp(&bomb),
+            Err(RespError::LimitExceeded(_))
+        ));
+
+        let mut nested = b"*1\r\n".repeat(MAX_DEPTH);
+        nested.extend_from_slice(b":1\r\n");
+        let mut buf = BytesMut::from(&nested[..]);
+        assert!(RespCodec::default().decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_attribute_roundtrip() {
+        let reply = Resp::Bulk(Some("value".into())).with_attributes(vec![(
+            Resp::SimpleString("popularity".to_string()),
+            Resp::Double(0.5),
+        )]);
+        let encoded = reply.encode_as(Protocol::Resp3);
+        assert_eq!(encoded, b"|1\r\n+popularity\r\n,0.5\r\n$5\r\nvalue\r\n");
+        assert_eq!(reply.encode(), b"$5\r\nvalue\r\n");
+
+        let mut buf = BytesMut::from(&encoded[..]);
+        buf.extend_from_slice(b":1\r\n");
+        let mut codec = RespCodec::default();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(reply));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Resp::Integer(1)));
+    }
+
+    #[test]
+    fn test_null_encoding_follows_protocol() {
+        assert_eq!(Resp::Null.encode(), b"$-1\r\n");
+        assert_eq!(Resp::NullArray.encode(), b"*-1\r\n");
+        for null in [Resp::Null, Resp::NullArray, Resp::Bulk(None)] {
+            assert_eq!(null.encode_as(Protocol::Resp3), b"_\r\n");
+        }
+
+        let parse = |b: &'static [u8]| readnext_resp(&Bytes::from_static(b)).unwrap().0;
+        assert_eq!(parse(b"$-1\r\n"), Resp::Bulk(None));
+        assert_eq!(parse(b"*-1\r\n"), Resp::NullArray);
+        assert_eq!(parse(b"_\r\n"), Resp::Null);
+    }
+
+    #[test]
+    fn test_construction_helpers() {
+        assert_eq!(Resp::bulk("x"), Resp::Bulk(Some("x".into())));
+        assert_eq!(Resp::ok(), Resp::SimpleString("OK".to_string()));
+        assert_eq!(Resp::from(None::<i64>), Resp::Null);
+        assert_eq!(
+            Resp::from(vec![Some("a"), None]),
+            Resp::Array(vec![Resp::bulk("a"), Resp::Null])
+        );
+        assert_eq!(
+            Resp::map().entry("n", 1).entry("on", true).build(),
+            Resp::Map(vec![
+                (Resp::bulk("n"), Resp::Integer(1)),
+                (Resp::bulk("on"), Resp::Boolean(true)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verbatim_roundtrip_and_downgrade() {
+        let reply = Resp::verbatim("# Server\nrole:master");
+        let encoded = reply.encode_as(Protocol::Resp3);
+        assert_eq!(encoded, b"=24\r\ntxt:# Server\nrole:master\r\n");
+        assert_eq!(readnext_resp(&Bytes::from(encoded)).unwrap().0, reply);
+        assert_eq!(reply.encode(), b"$20\r\n# Server\nrole:master\r\n");
+        assert!(readnext_resp(&Bytes::from_static(b"=3\r\nabc\r\n")).is_err());
+    }
+
+    #[test]
+    fn test_big_number_roundtrip() {
+        let digits = "3492890328409238509324850943850943825024385";
+        let input = Bytes::from(format!("({}\r\n", digits));
+        let (parsed, _) = readnext_resp(&input).unwrap();
+        assert_eq!(parsed, Resp::BigNumber(digits.parse().unwrap()));
+        assert_eq!(parsed.encode_as(Protocol::Resp3), &input[..]);
+        assert_eq!(parsed.encode(), format!("$43\r\n{}\r\n", digits).as_bytes());
+
+        assert!(readnext_resp(&Bytes::from_static(b"(-12\r\n")).is_ok());
+        for bad in [&b"(\r\n"[..], b"(12a\r\n", b"(-\r\n", b"(1.5\r\n"] {
+            assert!(readnext_resp(&Bytes::copy_from_slice(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_simple_error_roundtrip() {
+        let err = Resp::SimpleError("ERR unknown command".to_string());
+        assert_eq!(err.encode(), b"-ERR unknown command\r\n");
+        assert_eq!(readnext_resp(&Bytes::from(err.encode())).unwrap().0, err);
+    }
+
+    #[test]
+    fn test_codec_waits_for_complete_frames() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Resp::Array(vec![Resp::Bulk(Some("PING".into()))]))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Resp::Array(vec![Resp::Bulk(Some("PING".into()))]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_encode_map_per_protocol() {
+        let map = Resp::Map(vec![(Resp::Bulk(Some("proto".into())), Resp::Integer(3))]);
+        assert_eq!(
+            map.encode_as(Protocol::Resp2),
+            b"*2\r\n$5\r\nproto\r\n:3\r\n"
+        );
+        assert_eq!(
+            map.encode_as(Protocol::Resp3),
+            b"%1\r\n$5\r\nproto\r\n:3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_consumes_exactly_one_value() {
+        let trailer = b"+next\r\n";
+        for frame in [
+            &b"+OK\r\n"[..],
+            b"-ERR bad\r\n",
+            b":-42\r\n",
+            b"$5\r\nhello\r\n",
+            b"$0\r\n\r\n",
+            b"$-1\r\n",
+            b"=7\r\ntxt:abc\r\n",
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+            b"*0\r\n",
+            b"*-1\r\n",
+            b"~2\r\n:1\r\n#t\r\n",
+            b">1\r\n+msg\r\n",
+            b"%1\r\n+k\r\n*1\r\n$1\r\nv\r\n",
+            b"|1\r\n+ttl\r\n:5\r\n$1\r\nv\r\n",
+            b"_\r\n",
+            b"#f\r\n",
+            b",1.5\r\n",
+            b"(12345678901234567890\r\n",
+        ] {
+            let mut input = frame.to_vec();
+            input.extend_from_slice(trailer);
+            let input = Bytes::from(input);
+
+            let (_, size) = readnext_resp(&input).unwrap();
+            assert_eq!(size, frame.len(), "{:?}", frame);
+            let (rest, _) = readnext_resp(&input.slice(size..)).unwrap();
+            assert_eq!(rest, Resp::simple("next"));
+
+            let mut buf = BytesMut::from(&input[..]);
+            RespCodec::default().decode(&mut buf).unwrap().unwrap();
+            assert_eq!(&buf[..], trailer, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn test_small_integers_and_common_words_are_shared() {
+        for s in ["0", "1", "9999", "true", ""] {
+            let shared = BulkString::shared(s).unwrap();
+            assert_eq!(shared, s);
+            assert!(shared.is_shared());
+            assert_eq!(BulkString::shared(s).unwrap().0.as_ptr(), shared.0.as_ptr());
+        }
+        for s in ["10000", "01", "+1", "-1", "1.0", "TRUE"] {
+            assert!(BulkString::shared(s).is_none());
+        }
+        assert!(!BulkString::from("1").is_shared());
+    }
 }
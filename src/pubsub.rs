@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::AbortHandle,
+};
+
+use crate::{
+    clients::{Control, Mailbox},
+    glob::glob_match,
+    protocol::{BulkString, Resp, RespEncoding},
+};
+
+// Who is subscribed to what, for PUBLISH to deliver to and PUBSUB to report
+// on. Every channel and pattern is a broadcast channel, so PUBLISH sends a
+// message once however many subscribers there are, only holding the
+// registry long enough to find the senders. Each subscription is a task
+// that forwards what it receives to its connection's control channel,
+// which writes it out between requests, and counts it against the
+// connection's output buffer limit. Messages and (un)subscribe
+// confirmations are pushes: out-of-band `>` frames on RESP3, so replies to
+// other commands can come in between, and plain arrays on RESP2.
+#[derive(Default)]
+pub struct PubSub(Mutex<Registry>);
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<String, Topic>,
+    patterns: HashMap<String, Topic>,
+    subscribers: HashMap<u64, Subscriptions>,
+}
+
+struct Topic {
+    // Messages with their encoded size, worked out once for everyone.
+    sender: broadcast::Sender<(Resp, usize)>,
+    subscribers: usize,
+}
+
+// A connection's forwarding tasks, by what they forward.
+#[derive(Default)]
+struct Subscriptions {
+    channels: BTreeMap<String, AbortHandle>,
+    patterns: BTreeMap<String, AbortHandle>,
+}
+
+impl Subscriptions {
+    fn of(&mut self, kind: Kind) -> &mut BTreeMap<String, AbortHandle> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+// Messages a subscriber may fall behind by before it is disconnected, even
+// if they are small enough to stay within its output buffer limit.
+const BACKLOG: usize = 1024;
+
+// What a connection in subscriber mode may still run, as in Redis.
+pub const SUBSCRIBER_COMMANDS: &[&str] = &[
+    "ping",
+    "psubscribe",
+    "punsubscribe",
+    "quit",
+    "reset",
+    "subscribe",
+    "unsubscribe",
+];
+
+// Channels by name, as with SUBSCRIBE, or by glob pattern, as with
+// PSUBSCRIBE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+}
+
+impl Kind {
+    pub fn subscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+        }
+    }
+
+    pub fn unsubscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+        }
+    }
+}
+
+// A (un)subscribe confirmation: what was done, to which channel or
+// pattern, and how many subscriptions the connection has now.
+pub fn confirmation(action: &'static str, name: Option<String>, count: usize) -> Resp {
+    Resp::Push(vec![
+        action.into(),
+        name.map_or(Resp::Null, Resp::from),
+        Resp::Integer(count as i64),
+    ])
+}
+
+impl Registry {
+    fn topics(&mut self, kind: Kind) -> &mut HashMap<String, Topic> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+}
+
+impl PubSub {
+    // Subscribes connection `id`, delivered to through `mailbox`, to
+    // `name`, returning how many channels and patterns it is subscribed to
+    // now. Without a mailbox nothing is delivered.
+    pub fn subscribe(&self, id: u64, kind: Kind, name: &str, mailbox: Option<Mailbox>) -> usize {
+        let registry = &mut *self.0.lock().unwrap();
+        let subscriptions = registry.subscribers.entry(id).or_default();
+        if subscriptions.of(kind).contains_key(name) {
+            return subscriptions.len();
+        }
+        let topic = registry
+            .topics(kind)
+            .entry(name.to_string())
+            .or_insert_with(|| Topic {
+                sender: broadcast::channel(BACKLOG).0,
+                subscribers: 0,
+            });
+        topic.subscribers += 1;
+        let forward = tokio::spawn(forward(topic.sender.subscribe(), mailbox));
+        let subscriptions = registry.subscribers.entry(id).or_default();
+        subscriptions
+            .of(kind)
+            .insert(name.to_string(), forward.abort_handle());
+        subscriptions.len()
+    }
+
+    // As subscribe, the other way around. Unsubscribing from something the
+    // connection isn't subscribed to changes nothing.
+    pub fn unsubscribe(&self, id: u64, kind: Kind, name: &str) -> usize {
+        let registry = &mut *self.0.lock().unwrap();
+        let Some(subscriptions) = registry.subscribers.get_mut(&id) else {
+            return 0;
+        };
+        if let Some(forward) = subscriptions.of(kind).remove(name) {
+            forward.abort();
+            let topics = registry.topics(kind);
+            if let Some(topic) = topics.get_mut(name) {
+                topic.subscribers -= 1;
+                if topic.subscribers == 0 {
+                    topics.remove(name);
+                }
+            }
+        }
+        let left = registry.subscribers[&id].len();
+        if left == 0 {
+            registry.subscribers.remove(&id);
+        }
+        left
+    }
+
+    // What UNSUBSCRIBE or PUNSUBSCRIBE with no arguments leaves.
+    pub fn subscriptions(&self, id: u64, kind: Kind) -> Vec<String> {
+        let registry = &mut *self.0.lock().unwrap();
+        match registry.subscribers.get_mut(&id) {
+            Some(subscriptions) => subscriptions.of(kind).keys().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    // Drops everything a closed connection was subscribed to.
+    pub fn remove(&self, id: u64) {
+        for kind in [Kind::Channel, Kind::Pattern] {
+            for name in self.subscriptions(id, kind) {
+                self.unsubscribe(id, kind, &name);
+            }
+        }
+    }
+
+    // Sends `message` to the subscribers of `channel` and of patterns
+    // matching it, returning how many subscriptions it went to.
+    pub fn publish(&self, channel: &str, message: &BulkString) -> usize {
+        let mut sends = Vec::new();
+        let mut receivers = 0;
+        {
+            let registry = self.0.lock().unwrap();
+            if let Some(topic) = registry.channels.get(channel) {
+                let frame = Resp::Push(vec![
+                    "message".into(),
+                    channel.into(),
+                    message.clone().into(),
+                ]);
+                let size = frame.encode().len();
+                sends.push((topic.sender.clone(), (frame, size)));
+                receivers += topic.subscribers;
+            }
+            for (pattern, topic) in &registry.patterns {
+                if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                    let frame = Resp::Push(vec![
+                        "pmessage".into(),
+                        pattern.as_str().into(),
+                        channel.into(),
+                        message.clone().into(),
+                    ]);
+                    let size = frame.encode().len();
+                    sends.push((topic.sender.clone(), (frame, size)));
+                    receivers += topic.subscribers;
+                }
+            }
+        }
+        for (sender, frame) in sends {
+            // Fails only if every subscriber left meanwhile.
+            let _ = sender.send(frame);
+        }
+        receivers
+    }
+
+    // PUBSUB CHANNELS: channels with at least one subscriber, optionally
+    // only those matching `pattern`.
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let mut channels: Vec<String> = self
+            .0
+            .lock()
+            .unwrap()
+            .channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|p| glob_match(p.as_bytes(), channel.as_bytes())))
+            .cloned()
+            .collect();
+        channels.sort_unstable();
+        channels
+    }
+
+    // PUBSUB NUMSUB: subscribers to `channel`, not counting patterns.
+    pub fn numsub(&self, channel: &str) -> usize {
+        let registry = self.0.lock().unwrap();
+        registry
+            .channels
+            .get(channel)
+            .map_or(0, |topic| topic.subscribers)
+    }
+
+    // PUBSUB NUMPAT: distinct patterns subscribed to, by anyone.
+    pub fn numpat(&self) -> usize {
+        self.0.lock().unwrap().patterns.len()
+    }
+}
+
+// One subscription's task: hands every message to the connection until it
+// unsubscribes, which aborts the task, or goes away.
+async fn forward(mut rx: broadcast::Receiver<(Resp, usize)>, mailbox: Option<Mailbox>) {
+    let Some(mailbox) = mailbox else {
+        return;
+    };
+    loop {
+        match rx.recv().await {
+            Ok((message, size)) => {
+                if !mailbox.deliver(message, size) {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(_)) => {
+                let _ = mailbox.control.send(Control::Kill);
+                return;
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::ClientType,
+        obuf::{Limit, Output, OutputLimits},
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        sync::mpsc::{self, UnboundedReceiver},
+        time::timeout,
+    };
+
+    fn mailbox(output: Output) -> (Mailbox, UnboundedReceiver<Control>) {
+        let (control, rx) = mpsc::unbounded_channel();
+        let output = Arc::new(output);
+        (Mailbox { control, output }, rx)
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_are_counted_and_delivered() {
+        let (a_mailbox, mut a_rx) = mailbox(Output::default());
+        let (b_mailbox, mut b_rx) = mailbox(Output::default());
+        let (a, b) = (1, 2);
+        let pubsub = PubSub::default();
+        let subscribe = |id, kind, name, mailbox: &Mailbox| {
+            pubsub.subscribe(id, kind, name, Some(mailbox.clone()))
+        };
+        assert_eq!(subscribe(a, Kind::Channel, "news", &a_mailbox), 1);
+        assert_eq!(subscribe(a, Kind::Channel, "news", &a_mailbox), 1);
+        assert_eq!(subscribe(a, Kind::Pattern, "n*", &a_mailbox), 2);
+        assert_eq!(subscribe(b, Kind::Channel, "news", &b_mailbox), 1);
+        assert_eq!(subscribe(b, Kind::Channel, "sport", &b_mailbox), 2);
+
+        assert_eq!(pubsub.active_channels(None), ["news", "sport"]);
+        assert_eq!(pubsub.active_channels(Some("s*")), ["sport"]);
+        assert_eq!((pubsub.numsub("news"), pubsub.numsub("n*")), (2, 0));
+        assert_eq!(pubsub.numpat(), 1);
+
+        assert_eq!(pubsub.publish("news", &"hi".into()), 3);
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let next = timeout(Duration::from_secs(5), a_rx.recv()).await.unwrap();
+            let Some(Control::Message(Resp::Push(message))) = next else {
+                panic!("expected a message");
+            };
+            received.push(message[0].clone());
+        }
+        received.sort_by_key(|kind| format!("{:?}", kind));
+        assert_eq!(received, [Resp::from("message"), Resp::from("pmessage")]);
+        assert!(timeout(Duration::from_secs(5), b_rx.recv()).await.is_ok());
+        let message_size = Resp::array(["message", "news", "hi"]).encode().len();
+        assert_eq!(b_mailbox.output.pending(), message_size);
+
+        pubsub.remove(a);
+        assert_eq!(pubsub.unsubscribe(b, Kind::Channel, "news"), 1);
+        assert_eq!(pubsub.subscriptions(b, Kind::Channel), ["sport"]);
+        assert_eq!(pubsub.active_channels(None), ["sport"]);
+        assert_eq!(pubsub.numpat(), 0);
+        assert_eq!(pubsub.publish("news", &"hi".into()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_that_fall_behind_are_disconnected() {
+        let (mailbox, mut rx) = mailbox(Output::default());
+        let pubsub = PubSub::default();
+        pubsub.subscribe(1, Kind::Channel, "news", Some(mailbox));
+        // The forwarding task doesn't get to run until this yields.
+        for _ in 0..=BACKLOG {
+            pubsub.publish("news", &"hi".into());
+        }
+        let mut last = None;
+        while let Ok(Some(control)) = timeout(Duration::from_secs(5), rx.recv()).await {
+            last = Some(control);
+        }
+        assert_eq!(last, Some(Control::Kill));
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_over_their_output_limit_are_disconnected() {
+        let limits = OutputLimits {
+            pubsub: Limit {
+                hard: 100,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            ..OutputLimits::default()
+        };
+        let output = Output::new(Arc::new(std::sync::Mutex::new(limits)));
+        output.set_class(ClientType::Pubsub);
+        let (mailbox, mut rx) = mailbox(output);
+        let pubsub = PubSub::default();
+        pubsub.subscribe(1, Kind::Channel, "news", Some(mailbox));
+        // Unwritten, so each message adds to what the connection has pending.
+        pubsub.publish("news", &"x".repeat(60).into());
+        pubsub.publish("news", &"x".repeat(60).into());
+        let first = timeout(Duration::from_secs(5), rx.recv()).await;
+        assert!(matches!(first, Ok(Some(Control::Message(_)))));
+        let second = timeout(Duration::from_secs(5), rx.recv()).await;
+        assert_eq!(second, Ok(Some(Control::Kill)));
+    }
+}
@@ -0,0 +1,1455 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+
+use crate::{
+    lzf,
+    server::Query,
+    store::Keyspace,
+    value::{Consumer, ConsumerGroup, Fields, Pending, Stream, StreamId, Value},
+};
+
+// Snapshot of the keyspace in the RDB format, as written by SAVE and sent
+// to replicas after FULLRESYNC, in a form real Redis can load too. Both
+// directions cover the header, aux fields, function libraries, database
+// selector, expiries, every value type and the CRC64 trailer. Containers are
+// written in their plain encodings; loading also reads the ziplist, intset
+// and listpack ones older and current Redis versions write for small values.
+
+const VERSION: &[u8] = b"REDIS0011";
+
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Quicklist 2 nodes hold either one large element or a listpack of them.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// Flags on stream entries within a listpack.
+const STREAM_ITEM_DELETED: i64 = 1;
+const STREAM_ITEM_SAMEFIELDS: i64 = 2;
+
+// How many entries go in each listpack of a saved stream, as with Redis's
+// default stream-node-max-entries.
+const STREAM_NODE_ENTRIES: usize = 100;
+
+// Types that exist but can't be read: module values, zipmap hashes from
+// before Redis 2.6 and hashes with field expiries from Redis 7.4. Naming
+// them makes the refusal clear.
+fn type_name(value_type: u8) -> Option<&'static str> {
+    let name = match value_type {
+        6 | 7 => "module",
+        9 | 22..=25 => "hash",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+pub fn dump(keyspace: &dyn Keyspace) -> Vec<u8> {
+    Snapshot::new(keyspace).encode()
+}
+
+// Roughly how much encoded data each chunk of a streamed snapshot holds.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// The live entries of a keyspace, copied out so they can be encoded and
+// streamed piece by piece after the cache lock is released, without ever
+// holding the whole RDB in memory.
+pub struct Snapshot {
+    entries: Vec<(String, Query)>,
+    // The code of each function library, saved ahead of the keys.
+    functions: Vec<Arc<str>>,
+    compression: bool,
+    checksum: bool,
+}
+
+impl Snapshot {
+    pub fn new(keyspace: &dyn Keyspace) -> Self {
+        let now = SystemTime::now();
+        let entries = keyspace
+            .iter()
+            .filter(|(_, query)| !query.is_expired(now))
+            .map(|(key, query)| (key.clone(), query.clone()))
+            .collect();
+        Self::from_entries(entries)
+    }
+
+    // Entries copied already, such as by Store::copy_frozen.
+    pub fn from_entries(entries: Vec<(String, Query)>) -> Self {
+        Self {
+            entries,
+            functions: Vec::new(),
+            compression: true,
+            checksum: true,
+        }
+    }
+
+    pub fn functions(mut self, functions: Vec<Arc<str>>) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    // Whether long strings are LZF compressed, as with rdbcompression.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Whether the trailer carries a CRC64, as with rdbchecksum. Without one
+    // it is zero, which loaders take to mean "not checked".
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(key, _)| key.as_str())
+    }
+
+    // The whole RDB at once.
+    pub fn encode(self) -> Vec<u8> {
+        self.chunks().flatten().collect()
+    }
+
+    // The encoded RDB: the header, then the entries about CHUNK_SIZE bytes at
+    // a time, then EOF and the checksum of everything before it.
+    pub fn chunks(self) -> impl Iterator<Item = Vec<u8>> {
+        let mut header = Some(self.header());
+        let compression = self.compression;
+        let checksum = self.checksum;
+        let mut entries = self.entries.into_iter();
+        let mut crc = Some(0);
+        std::iter::from_fn(move || {
+            let mut out = header.take().unwrap_or_default();
+            while out.len() < CHUNK_SIZE {
+                let Some((key, query)) = entries.next() else {
+                    break;
+                };
+                put_entry(&mut out, &key, &query, compression);
+            }
+            if out.is_empty() {
+                // Out of entries: finish with the trailer, once.
+                out.push(OPCODE_EOF);
+                let crc = crc64_update(crc.take()?, &out);
+                let crc = if checksum { crc } else { 0 };
+                out.extend_from_slice(&crc.to_le_bytes());
+                return Some(out);
+            }
+            crc = crc.map(|crc| crc64_update(crc, &out));
+            Some(out)
+        })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let mut out = VERSION.to_vec();
+        for (key, value) in [
+            ("redis-ver", "7.2.0"),
+            ("redis-bits", "64"),
+            ("ctime", &ctime),
+            ("aof-base", "0"),
+        ] {
+            out.push(OPCODE_AUX);
+            put_string(&mut out, key.as_bytes(), false);
+            put_string(&mut out, value.as_bytes(), false);
+        }
+        for code in &self.functions {
+            out.push(OPCODE_FUNCTION2);
+            put_string(&mut out, code.as_bytes(), self.compression);
+        }
+        if !self.entries.is_empty() {
+            let expiring = self.entries.iter().filter(|(_, q)| q.expiry.is_some());
+            out.push(OPCODE_SELECTDB);
+            put_length(&mut out, 0);
+            out.push(OPCODE_RESIZEDB);
+            put_length(&mut out, self.entries.len() as u64);
+            put_length(&mut out, expiring.count() as u64);
+        }
+        out
+    }
+}
+
+fn put_entry(out: &mut Vec<u8>, key: &str, query: &Query, compression: bool) {
+    if let Some(expiry) = query.expiry {
+        let millis = expiry.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.push(OPCODE_EXPIRETIME_MS);
+        out.extend_from_slice(&(millis.as_millis() as u64).to_le_bytes());
+    }
+    out.push(value_type(&query.value));
+    put_string(out, key.as_bytes(), compression);
+    put_value(out, &query.value, compression);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn put_value(out: &mut Vec<u8>, value: &Value, compression: bool) {
+    match value {
+        Value::String(s) => put_string(out, s.as_bytes(), compression),
+        Value::List(items) | Value::Set(items) => {
+            put_length(out, items.len() as u64);
+            for item in items.iter() {
+                put_string(out, item, compression);
+            }
+        }
+        Value::SortedSet(members) => {
+            put_length(out, members.len() as u64);
+            for (member, score) in members.iter() {
+                put_string(out, member, compression);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Hash(fields) => {
+            put_length(out, fields.len() as u64);
+            for (field, value) in fields.iter() {
+                put_string(out, field, compression);
+                put_string(out, value, compression);
+            }
+        }
+        Value::Stream(stream) => put_stream(out, stream, compression),
+    }
+}
+
+// Streams are saved as Redis 7.2 does: the entries in listpacks keyed by the
+// ID of their first entry, then the counters, then each consumer group with
+// its pending entries and consumers.
+fn put_stream(out: &mut Vec<u8>, stream: &Stream, compression: bool) {
+    let nodes = stream.entries.chunks(STREAM_NODE_ENTRIES);
+    put_length(out, nodes.len() as u64);
+    for node in nodes {
+        let (master, master_fields) = &node[0];
+        put_string(out, &raw_stream_id(*master), false);
+        put_string(out, &stream_node(*master, master_fields, node), compression);
+    }
+    put_length(out, stream.entries.len() as u64);
+    put_stream_id(out, stream.last_id);
+    put_stream_id(out, stream.first_id);
+    put_stream_id(out, stream.max_deleted_id);
+    put_length(out, stream.entries_added);
+    put_length(out, stream.groups.len() as u64);
+    for group in &stream.groups {
+        put_string(out, &group.name, false);
+        put_stream_id(out, group.last_id);
+        put_length(out, group.entries_read);
+        put_length(out, group.pending.len() as u64);
+        for pending in &group.pending {
+            out.extend_from_slice(&raw_stream_id(pending.id));
+            out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            put_length(out, pending.delivery_count);
+        }
+        put_length(out, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            put_string(out, &consumer.name, false);
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.active_time.to_le_bytes());
+            put_length(out, consumer.pending.len() as u64);
+            for id in &consumer.pending {
+                out.extend_from_slice(&raw_stream_id(*id));
+            }
+        }
+    }
+}
+
+// One listpack of a stream. It opens with a master entry: the count of live
+// and deleted entries and the field names of the first entry, which later
+// entries with the same names leave out. Each entry stores its ID relative
+// to the master's and ends with how many items it took up.
+fn stream_node(
+    master: StreamId,
+    master_fields: &Fields,
+    entries: &[(StreamId, Fields)],
+) -> Vec<u8> {
+    let mut lp = Listpack::default();
+    lp.int(entries.len() as i64);
+    lp.int(0);
+    lp.int(master_fields.len() as i64);
+    for (field, _) in master_fields {
+        lp.string(field);
+    }
+    lp.int(0);
+    for (id, fields) in entries {
+        let same = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields)
+                .all(|((a, _), (b, _))| a == b);
+        lp.int(if same { STREAM_ITEM_SAMEFIELDS } else { 0 });
+        lp.int(id.ms.wrapping_sub(master.ms) as i64);
+        lp.int(id.seq.wrapping_sub(master.seq) as i64);
+        if same {
+            for (_, value) in fields {
+                lp.string(value);
+            }
+            lp.int(fields.len() as i64 + 3);
+        } else {
+            lp.int(fields.len() as i64);
+            for (field, value) in fields {
+                lp.string(field);
+                lp.string(value);
+            }
+            lp.int(fields.len() as i64 * 2 + 4);
+        }
+    }
+    lp.finish()
+}
+
+fn put_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    put_length(out, id.ms);
+    put_length(out, id.seq);
+}
+
+// IDs in listpack keys and pending lists are 16 big-endian bytes.
+fn raw_stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+// Builds a listpack: a header with its total size and item count, the items
+// each followed by its own length so it can be walked backwards, and an end
+// marker. Items that are canonical integers are stored as integers.
+#[derive(Default)]
+struct Listpack {
+    items: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    fn int(&mut self, n: i64) {
+        let mut item = Vec::with_capacity(9);
+        match n {
+            0..=127 => item.push(n as u8),
+            -4096..=4095 => item.extend_from_slice(&(0xc000 | (n as u16 & 0x1fff)).to_be_bytes()),
+            _ if i16::try_from(n).is_ok() => {
+                item.push(0xf1);
+                item.extend_from_slice(&(n as i16).to_le_bytes());
+            }
+            -0x80_0000..=0x7f_ffff => {
+                item.push(0xf2);
+                item.extend_from_slice(&(n as i32).to_le_bytes()[..3]);
+            }
+            _ if i32::try_from(n).is_ok() => {
+                item.push(0xf3);
+                item.extend_from_slice(&(n as i32).to_le_bytes());
+            }
+            _ => {
+                item.push(0xf4);
+                item.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        self.push(item);
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        let int = std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok().filter(|n| n.to_string() == s));
+        if let Some(n) = int {
+            return self.int(n);
+        }
+        let len = s.len();
+        let mut item = Vec::with_capacity(len + 5);
+        match len {
+            0..64 => item.push(0x80 | len as u8),
+            64..4096 => item.extend_from_slice(&(0xe000 | len as u16).to_be_bytes()),
+            _ => {
+                item.push(0xf0);
+                item.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+        }
+        item.extend_from_slice(s);
+        self.push(item);
+    }
+
+    // The length after each item is stored in 7 bit groups, most significant
+    // first, with the top bit set on all but the first.
+    fn push(&mut self, item: Vec<u8>) {
+        let len = item.len();
+        self.items.extend_from_slice(&item);
+        let size = backlen_size(len);
+        for i in 0..size {
+            let group = (len >> (7 * (size - 1 - i))) as u8 & 0x7f;
+            self.items.push(if i == 0 { group } else { group | 0x80 });
+        }
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.items.len() + 1;
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        // Counts past u16::MAX are stored as that, meaning "unknown".
+        out.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        out.extend_from_slice(&self.items);
+        out.push(0xff);
+        out
+    }
+}
+
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    }
+}
+
+// Lengths use the top two bits of the first byte to pick 6, 14, 32 or 64
+// bits of big-endian payload.
+fn put_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&(0x4000 | len as u16).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+// How many bytes `value` takes up in an RDB file, as DEBUG OBJECT reports.
+pub fn serialized_len(value: &Value, compression: bool) -> usize {
+    let mut out = Vec::new();
+    put_value(&mut out, value, compression);
+    out.len()
+}
+
+// A value on its own, with its type, as the handoff sends containers.
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    put_value(&mut out, value, false);
+    out
+}
+
+pub fn restore_value(data: &[u8]) -> anyhow::Result<Value> {
+    let mut reader = Reader { data, pos: 0 };
+    let value_type = reader.byte()?;
+    let value = reader.value(value_type)?;
+    if reader.pos != data.len() {
+        bail!("trailing data after value");
+    }
+    Ok(value)
+}
+
+// Strings that are the canonical form of a 32 bit integer are stored as
+// the integer, like Redis does. Others are LZF compressed if allowed and
+// that saves at least 4 bytes, or else stored raw.
+fn put_string(out: &mut Vec<u8>, s: &[u8], compression: bool) {
+    let int = std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i32>().ok().filter(|n| n.to_string() == s));
+    match int {
+        Some(n) if i8::try_from(n).is_ok() => out.extend_from_slice(&[0xc0, n as u8]),
+        Some(n) if i16::try_from(n).is_ok() => {
+            out.push(0xc1);
+            out.extend_from_slice(&(n as i16).to_le_bytes());
+        }
+        Some(n) => {
+            out.push(0xc2);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        None => {
+            let compressed = (compression && s.len() > 20)
+                .then(|| lzf::compress(s))
+                .filter(|compressed| compressed.len() + 4 <= s.len());
+            if let Some(compressed) = compressed {
+                out.push(0xc3);
+                put_length(out, compressed.len() as u64);
+                put_length(out, s.len() as u64);
+                out.extend_from_slice(&compressed);
+            } else {
+                put_length(out, s.len() as u64);
+                out.extend_from_slice(s);
+            }
+        }
+    }
+}
+
+// CRC-64/Jones as used by Redis for the RDB checksum: reflected, initial
+// value and final xor of zero.
+const CRC64_TABLE: [u64; 256] = {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc64(data: &[u8]) -> u64 {
+    crc64_update(0, data)
+}
+
+// Extends `crc` over `data`, so a checksum can be built up chunk by chunk.
+fn crc64_update(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// What a load read in: keys stored, keys skipped because they had already
+// expired, and the code of the function libraries, for the caller to load.
+#[derive(Debug, Default, PartialEq)]
+pub struct Loaded {
+    pub keys: usize,
+    pub expired: usize,
+    pub functions: Vec<String>,
+}
+
+impl std::fmt::Display for Loaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} keys ({} expired)", self.keys, self.expired)
+    }
+}
+
+// Writes a snapshot to `path` through a temporary file in the same
+// directory, so a failed save never leaves a truncated RDB behind.
+pub fn save(snapshot: Snapshot, path: &Path) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let written = (|| {
+        let mut file = File::create(&temp)?;
+        for chunk in snapshot.chunks() {
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+// Loads the RDB file at `path` at startup. A missing file just means there
+// is nothing to load yet.
+pub fn load_file(
+    path: &Path,
+    keyspace: &mut dyn Keyspace,
+    checksum: bool,
+) -> anyhow::Result<Loaded> {
+    match std::fs::read(path) {
+        Ok(data) => load(&data, keyspace, checksum),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Loaded::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads a snapshot into `keyspace`, skipping keys that have already expired.
+// With `checksum` off, as with
+// rdbchecksum no, the trailing CRC64 is not checked.
+pub fn load(data: &[u8], keyspace: &mut dyn Keyspace, checksum: bool) -> anyhow::Result<Loaded> {
+    Ok(load_prefix(data, keyspace, checksum)?.0)
+}
+
+// Like `load`, for a snapshot followed by other data, as in an AOF with an
+// RDB preamble. Also returns how many bytes the snapshot took up.
+pub fn load_prefix(
+    data: &[u8],
+    keyspace: &mut dyn Keyspace,
+    checksum: bool,
+) -> anyhow::Result<(Loaded, usize)> {
+    let mut reader = Reader { data, pos: 0 };
+    if !reader.take(VERSION.len())?.starts_with(b"REDIS") {
+        bail!("not an RDB file");
+    }
+    let now = SystemTime::now();
+    let mut expiry = None;
+    let mut loaded = Loaded::default();
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => {
+                verify_checksum(&data[..reader.pos], &mut reader, checksum)?;
+                return Ok((loaded, reader.pos));
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_FUNCTION2 => loaded.functions.push(reader.string()?),
+            OPCODE_SELECTDB => {
+                reader.length()?;
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                let millis = u64::from_le_bytes(reader.take(8)?.try_into()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_millis(millis));
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.take(4)?.try_into()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+            }
+            value_type => {
+                let key = reader.string()?;
+                let query = Query::new(reader.value(value_type)?, expiry.take(), now);
+                if query.is_expired(now) {
+                    loaded.expired += 1;
+                } else {
+                    keyspace.insert(key, query);
+                    loaded.keys += 1;
+                }
+            }
+        }
+    }
+}
+
+// The checksum covers everything up to and including the EOF opcode. Older
+// files have none, and a zero checksum means the writer skipped it.
+fn verify_checksum(covered: &[u8], reader: &mut Reader, verify: bool) -> anyhow::Result<()> {
+    if reader.pos == reader.data.len() {
+        return Ok(());
+    }
+    // Read the trailer either way, so an AOF preamble still ends after it.
+    let expected = u64::from_le_bytes(reader.take(8)?.try_into()?);
+    if !verify || expected == 0 {
+        return Ok(());
+    }
+    let computed = crc64(covered);
+    if expected != computed {
+        bail!(
+            "RDB checksum mismatch (expected {:#018x}, computed {:#018x}); set rdbchecksum no to load it anyway",
+            expected,
+            computed
+        );
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .ok_or_else(|| anyhow!("RDB ends unexpectedly"))?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| anyhow!("RDB ends unexpectedly"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length(&mut self) -> anyhow::Result<u64> {
+        match self.length_or_encoding()? {
+            Ok(len) => Ok(len),
+            Err(_) => bail!("expected a length, found an encoded value"),
+        }
+    }
+
+    // Either a plain length, or the format tag of a specially encoded string
+    // when the top two bits are set.
+    fn length_or_encoding(&mut self) -> anyhow::Result<Result<u64, u8>> {
+        let first = self.byte()?;
+        let len = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => u16::from_be_bytes([first & 0x3f, self.byte()?]) as u64,
+            2 if first == 0x80 => u32::from_be_bytes(self.take(4)?.try_into()?) as u64,
+            2 if first == 0x81 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            2 => bail!("invalid RDB length prefix {:#04x}", first),
+            _ => return Ok(Err(first & 0x3f)),
+        };
+        Ok(Ok(len))
+    }
+
+    // Strings are stored raw, LZF compressed or, when they look like
+    // integers, as 8, 16 or 32 bit little-endian values. Ziplists, listpacks
+    // and stream IDs are stored the same way but aren't text.
+    fn blob(&mut self) -> anyhow::Result<Vec<u8>> {
+        let blob = match self.length_or_encoding()? {
+            Ok(len) => self.take(len as usize)?.to_vec(),
+            Err(0) => (self.byte()? as i8).to_string().into_bytes(),
+            Err(1) => i16::from_le_bytes(self.take(2)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Err(2) => i32::from_le_bytes(self.take(4)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Err(3) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                let compressed = self.take(compressed_len)?;
+                lzf::decompress(compressed, len)
+                    .ok_or_else(|| anyhow!("invalid LZF compressed string"))?
+            }
+            Err(other) => bail!("invalid RDB string encoding {}", other),
+        };
+        Ok(blob)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.blob()?)?)
+    }
+
+    fn element(&mut self) -> anyhow::Result<Bytes> {
+        Ok(self.blob()?.into())
+    }
+
+    fn value(&mut self, value_type: u8) -> anyhow::Result<Value> {
+        let value = match value_type {
+            TYPE_STRING => Value::String(self.string()?.into()),
+            TYPE_LIST => Value::List(Arc::new(self.elements()?)),
+            TYPE_SET => Value::Set(Arc::new(self.elements()?)),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let members = (0..self.length()?)
+                    .map(|_| {
+                        let member = self.element()?;
+                        let score = match value_type {
+                            TYPE_ZSET_2 => f64::from_le_bytes(self.take(8)?.try_into()?),
+                            _ => self.text_score()?,
+                        };
+                        Ok((member, score))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Value::SortedSet(Arc::new(members))
+            }
+            TYPE_HASH => {
+                let fields = (0..self.length()?)
+                    .map(|_| Ok((self.element()?, self.element()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                Value::Hash(Arc::new(fields))
+            }
+            TYPE_LIST_ZIPLIST => Value::List(Arc::new(strings(ziplist(&self.blob()?)?))),
+            TYPE_SET_INTSET => Value::Set(Arc::new(intset(&self.blob()?)?)),
+            TYPE_ZSET_ZIPLIST => Value::SortedSet(Arc::new(scored(ziplist(&self.blob()?)?)?)),
+            TYPE_HASH_ZIPLIST => Value::Hash(Arc::new(pairs(ziplist(&self.blob()?)?)?)),
+            TYPE_LIST_QUICKLIST => {
+                let mut items = Vec::new();
+                for _ in 0..self.length()? {
+                    items.extend(strings(ziplist(&self.blob()?)?));
+                }
+                Value::List(Arc::new(items))
+            }
+            TYPE_HASH_LISTPACK => Value::Hash(Arc::new(pairs(listpack(&self.blob()?)?)?)),
+            TYPE_ZSET_LISTPACK => Value::SortedSet(Arc::new(scored(listpack(&self.blob()?)?)?)),
+            TYPE_SET_LISTPACK => Value::Set(Arc::new(strings(listpack(&self.blob()?)?))),
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut items = Vec::new();
+                for _ in 0..self.length()? {
+                    match self.length()? {
+                        QUICKLIST_NODE_PLAIN => items.push(self.element()?),
+                        QUICKLIST_NODE_PACKED => items.extend(strings(listpack(&self.blob()?)?)),
+                        other => bail!("invalid quicklist node container {}", other),
+                    }
+                }
+                Value::List(Arc::new(items))
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Value::Stream(Arc::new(self.stream(value_type)?))
+            }
+            other => match type_name(other) {
+                Some(name) => bail!(
+                    "RDB holds a {} (type {}), which can't be loaded",
+                    name,
+                    other
+                ),
+                None => bail!("unsupported RDB value type {:#04x}", other),
+            },
+        };
+        Ok(value)
+    }
+
+    fn elements(&mut self) -> anyhow::Result<Vec<Bytes>> {
+        (0..self.length()?).map(|_| self.element()).collect()
+    }
+
+    // Scores of the oldest sorted set type are text, with a length byte
+    // whose top values stand for NaN and the infinities.
+    fn text_score(&mut self) -> anyhow::Result<f64> {
+        let score = match self.byte()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => std::str::from_utf8(self.take(len as usize)?)?.parse()?,
+        };
+        Ok(score)
+    }
+
+    // The first stream type lacks the first and max deleted IDs, the added
+    // count and group read counters; the second lacks consumer active times.
+    fn stream(&mut self, value_type: u8) -> anyhow::Result<Stream> {
+        let mut stream = Stream::default();
+        for _ in 0..self.length()? {
+            let master = self.blob()?;
+            let master = parse_raw_stream_id(&master)?;
+            read_stream_node(&mut stream.entries, master, &listpack(&self.blob()?)?)?;
+        }
+        let length = self.length()?;
+        stream.last_id = self.stream_id()?;
+        if value_type == TYPE_STREAM_LISTPACKS {
+            stream.first_id = stream
+                .entries
+                .first()
+                .map(|(id, _)| *id)
+                .unwrap_or_default();
+            stream.entries_added = length;
+        } else {
+            stream.first_id = self.stream_id()?;
+            stream.max_deleted_id = self.stream_id()?;
+            stream.entries_added = self.length()?;
+        }
+        for _ in 0..self.length()? {
+            let name = self.element()?;
+            let last_id = self.stream_id()?;
+            let entries_read = match value_type {
+                TYPE_STREAM_LISTPACKS => u64::MAX,
+                _ => self.length()?,
+            };
+            let pending = (0..self.length()?)
+                .map(|_| {
+                    Ok(Pending {
+                        id: parse_raw_stream_id(self.take(16)?)?,
+                        delivery_time: self.millis()?,
+                        delivery_count: self.length()?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            let consumers = (0..self.length()?)
+                .map(|_| {
+                    let name = self.element()?;
+                    let seen_time = self.millis()?;
+                    let active_time = match value_type {
+                        TYPE_STREAM_LISTPACKS_3 => self.millis()?,
+                        _ => seen_time,
+                    };
+                    let pending = (0..self.length()?)
+                        .map(|_| parse_raw_stream_id(self.take(16)?))
+                        .collect::<anyhow::Result<_>>()?;
+                    Ok(Consumer {
+                        name,
+                        seen_time,
+                        active_time,
+                        pending,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            stream.groups.push(ConsumerGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+        Ok(stream)
+    }
+
+    fn stream_id(&mut self) -> anyhow::Result<StreamId> {
+        Ok(StreamId {
+            ms: self.length()?,
+            seq: self.length()?,
+        })
+    }
+
+    fn millis(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+fn parse_raw_stream_id(raw: &[u8]) -> anyhow::Result<StreamId> {
+    if raw.len() != 16 {
+        bail!("invalid stream ID of {} bytes", raw.len());
+    }
+    Ok(StreamId {
+        ms: u64::from_be_bytes(raw[..8].try_into()?),
+        seq: u64::from_be_bytes(raw[8..].try_into()?),
+    })
+}
+
+// Appends the live entries of one stream listpack, laid out as
+// `stream_node` writes them.
+fn read_stream_node(
+    entries: &mut Vec<(StreamId, Fields)>,
+    master: StreamId,
+    items: &[Packed],
+) -> anyhow::Result<()> {
+    let mut items = items.iter();
+    let mut next = || {
+        items
+            .next()
+            .ok_or_else(|| anyhow!("truncated stream listpack"))
+    };
+    let count = next()?.int()?;
+    let deleted = next()?.int()?;
+    let master_fields = (0..next()?.int()?)
+        .map(|_| Ok(next()?.string()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    next()?;
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = next()?.int()?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(next()?.int()? as u64),
+            seq: master.seq.wrapping_add(next()?.int()? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?.string())))
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            (0..next()?.int()?)
+                .map(|_| Ok((next()?.string(), next()?.string())))
+                .collect::<anyhow::Result<_>>()?
+        };
+        next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            entries.push((id, fields));
+        }
+    }
+    Ok(())
+}
+
+// An item of a ziplist or listpack, which holds small integers as such.
+enum Packed<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl Packed<'_> {
+    fn string(&self) -> Bytes {
+        match self {
+            Packed::Int(n) => n.to_string().into(),
+            Packed::Str(s) => Bytes::copy_from_slice(s),
+        }
+    }
+
+    fn int(&self) -> anyhow::Result<i64> {
+        match self {
+            Packed::Int(n) => Ok(*n),
+            Packed::Str(s) => Ok(std::str::from_utf8(s)?.parse()?),
+        }
+    }
+}
+
+fn strings(items: Vec<Packed>) -> Vec<Bytes> {
+    items.iter().map(Packed::string).collect()
+}
+
+fn pairs(items: Vec<Packed>) -> anyhow::Result<Vec<(Bytes, Bytes)>> {
+    if !items.len().is_multiple_of(2) {
+        bail!("odd number of items in a packed hash");
+    }
+    Ok(items
+        .chunks_exact(2)
+        .map(|pair| (pair[0].string(), pair[1].string()))
+        .collect())
+}
+
+fn scored(items: Vec<Packed>) -> anyhow::Result<Vec<(Bytes, f64)>> {
+    pairs(items)?
+        .into_iter()
+        .map(|(member, score)| Ok((member, std::str::from_utf8(&score)?.parse()?)))
+        .collect()
+}
+
+// A ziplist: a 10 byte header, then items each preceded by the previous
+// item's length and an encoding byte, then 0xFF.
+fn ziplist(blob: &[u8]) -> anyhow::Result<Vec<Packed<'_>>> {
+    let mut reader = Reader {
+        data: blob,
+        pos: 10,
+    };
+    let mut items = Vec::new();
+    loop {
+        match reader.byte()? {
+            0xff => return Ok(items),
+            0xfe => {
+                reader.take(4)?;
+            }
+            _ => {}
+        }
+        let encoding = reader.byte()?;
+        let item = match encoding >> 6 {
+            0 => Packed::Str(reader.take((encoding & 0x3f) as usize)?),
+            1 => {
+                let len = u16::from_be_bytes([encoding & 0x3f, reader.byte()?]);
+                Packed::Str(reader.take(len as usize)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.take(4)?.try_into()?);
+                Packed::Str(reader.take(len as usize)?)
+            }
+            _ => Packed::Int(match encoding {
+                0xc0 => i16::from_le_bytes(reader.take(2)?.try_into()?) as i64,
+                0xd0 => i32::from_le_bytes(reader.take(4)?.try_into()?) as i64,
+                0xe0 => i64::from_le_bytes(reader.take(8)?.try_into()?),
+                0xf0 => {
+                    let b = reader.take(3)?;
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                }
+                0xfe => reader.byte()? as i8 as i64,
+                0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                other => bail!("invalid ziplist encoding {:#04x}", other),
+            }),
+        };
+        items.push(item);
+    }
+}
+
+// A listpack, as `Listpack` builds them.
+fn listpack(blob: &[u8]) -> anyhow::Result<Vec<Packed<'_>>> {
+    let mut reader = Reader { data: blob, pos: 6 };
+    let mut items = Vec::new();
+    loop {
+        let start = reader.pos;
+        let encoding = reader.byte()?;
+        let item = match encoding {
+            0xff => return Ok(items),
+            _ if encoding & 0x80 == 0 => Packed::Int(encoding as i64),
+            _ if encoding & 0xc0 == 0x80 => Packed::Str(reader.take((encoding & 0x3f) as usize)?),
+            _ if encoding & 0xe0 == 0xc0 => {
+                let n = u16::from_be_bytes([encoding & 0x1f, reader.byte()?]) as i64;
+                Packed::Int(if n >= 1 << 12 { n - (1 << 13) } else { n })
+            }
+            _ if encoding & 0xf0 == 0xe0 => {
+                let len = u16::from_be_bytes([encoding & 0x0f, reader.byte()?]);
+                Packed::Str(reader.take(len as usize)?)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(reader.take(4)?.try_into()?);
+                Packed::Str(reader.take(len as usize)?)
+            }
+            0xf1 => Packed::Int(i16::from_le_bytes(reader.take(2)?.try_into()?) as i64),
+            0xf2 => {
+                let b = reader.take(3)?;
+                Packed::Int((i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64)
+            }
+            0xf3 => Packed::Int(i32::from_le_bytes(reader.take(4)?.try_into()?) as i64),
+            0xf4 => Packed::Int(i64::from_le_bytes(reader.take(8)?.try_into()?)),
+            other => bail!("invalid listpack encoding {:#04x}", other),
+        };
+        reader.take(backlen_size(reader.pos - start))?;
+        items.push(item);
+    }
+}
+
+// An intset: the width of its integers, their count, then the integers.
+fn intset(blob: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+    let mut reader = Reader { data: blob, pos: 0 };
+    let width = u32::from_le_bytes(reader.take(4)?.try_into()?) as usize;
+    let len = u32::from_le_bytes(reader.take(4)?.try_into()?) as usize;
+    if ![2, 4, 8].contains(&width) || blob.len() != 8 + width * len {
+        bail!("invalid intset");
+    }
+    let ints = blob[8..].chunks_exact(width).map(|n| match width {
+        2 => i16::from_le_bytes([n[0], n[1]]) as i64,
+        4 => i32::from_le_bytes([n[0], n[1], n[2], n[3]]) as i64,
+        _ => i64::from_le_bytes(n.try_into().unwrap_or_default()),
+    });
+    Ok(ints.map(|n| n.to_string().into()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, time::Duration};
+
+    #[test]
+    fn test_length_encoding() {
+        for (len, want) in [
+            (10, &[0x0a][..]),
+            (700, &[0x42, 0xbc]),
+            (17000, &[0x80, 0x00, 0x00, 0x42, 0x68]),
+        ] {
+            let mut out = Vec::new();
+            put_length(&mut out, len);
+            assert_eq!(out, want);
+        }
+    }
+
+    #[test]
+    fn test_integer_strings_are_encoded() {
+        for (s, want) in [
+            ("-1", &[0xc0, 0xff][..]),
+            ("12345", &[0xc1, 0x39, 0x30]),
+            ("100000", &[0xc2, 0xa0, 0x86, 0x01, 0x00]),
+            ("007", &[0x03, b'0', b'0', b'7']),
+            ("3000000000", b"\x0a3000000000"),
+        ] {
+            let mut out = Vec::new();
+            put_string(&mut out, s.as_bytes(), true);
+            assert_eq!(out, want, "{}", s);
+            let mut reader = Reader { data: &out, pos: 0 };
+            assert_eq!(reader.string().unwrap(), s);
+        }
+    }
+
+    #[test]
+    fn test_long_strings_are_compressed() {
+        let long = "session-token:".repeat(50);
+        let mut compressed = Vec::new();
+        put_string(&mut compressed, long.as_bytes(), true);
+        assert_eq!(compressed[0], 0xc3);
+        assert!(compressed.len() < long.len() / 4);
+        let mut reader = Reader {
+            data: &compressed,
+            pos: 0,
+        };
+        assert_eq!(reader.string().unwrap(), long);
+
+        // Short or incompressible strings, and compression turned off, stay raw.
+        for (s, compression) in [("short but repeated", true), (&long[..], false)] {
+            let mut out = Vec::new();
+            put_string(&mut out, s.as_bytes(), compression);
+            assert_eq!(&out[out.len() - s.len()..], s.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_oversized_lengths_are_rejected() {
+        // A raw string claiming u64::MAX bytes, and a compressed one claiming
+        // to expand four bytes into 4GiB.
+        let raw = [&[0x81][..], &[0xff; 8]].concat();
+        let compressed = [&[0xc3, 0x04, 0x80][..], &[0xff; 4], b"\x02abc"].concat();
+        for data in [raw, compressed] {
+            let mut reader = Reader {
+                data: &data,
+                pos: 0,
+            };
+            assert!(reader.string().is_err());
+        }
+    }
+
+    #[test]
+    fn test_dump_writes_live_entries() {
+        let now = SystemTime::now();
+        let query =
+            |value: &str, expiry: Option<SystemTime>| Query::new(value.to_string(), expiry, now);
+        let empty = dump(&HashMap::new());
+        assert!(empty.starts_with(b"REDIS0011"));
+        assert_eq!(empty[empty.len() - 9], OPCODE_EOF);
+
+        let mut keyspace = HashMap::new();
+        let expiry = UNIX_EPOCH + Duration::from_millis(4_102_444_800_000);
+        keyspace.insert("k".to_string(), query("v", Some(expiry)));
+        keyspace.insert(
+            "gone".to_string(),
+            query("x", Some(now - Duration::from_secs(1))),
+        );
+        let rdb = dump(&keyspace);
+
+        let body = &rdb[empty.len() - 9..rdb.len() - 9];
+        let mut want = vec![
+            OPCODE_SELECTDB,
+            0,
+            OPCODE_RESIZEDB,
+            1,
+            1,
+            OPCODE_EXPIRETIME_MS,
+        ];
+        want.extend_from_slice(&4_102_444_800_000u64.to_le_bytes());
+        want.extend_from_slice(&[TYPE_STRING, 1, b'k', 1, b'v']);
+        assert_eq!(body, &want[..]);
+    }
+
+    #[test]
+    fn test_load_round_trips_dump() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        for (key, value, expiry) in [
+            ("plain", "a".repeat(100), None),
+            ("ttl", "b".to_string(), Some(now + Duration::from_secs(60))),
+        ] {
+            let query = Query::new(value, expiry, now);
+            keyspace.insert(key.to_string(), query);
+        }
+
+        let library = "#!lua name=lib\nredis.register_function('f', function() end)";
+        let mut rdb = Snapshot::new(&keyspace)
+            .functions(vec![library.into()])
+            .encode();
+        // Snapshots leave out expired keys, so write one in by hand.
+        let expired = Query::new("c".to_string(), Some(now - Duration::from_millis(1)), now);
+        let mut gone = vec![];
+        put_entry(&mut gone, "gone", &expired, false);
+        rdb.splice(VERSION.len()..VERSION.len(), gone);
+
+        let mut loaded = HashMap::new();
+        let counts = load(&rdb, &mut loaded, false).unwrap();
+        assert_eq!(
+            counts,
+            Loaded {
+                keys: 2,
+                expired: 1,
+                functions: vec![library.to_string()],
+            }
+        );
+        assert!(!loaded.contains_key("gone"));
+        assert_eq!(loaded["plain"].value, "a".repeat(100).as_str());
+        assert_eq!(loaded["plain"].expiry, None);
+        let millis = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(
+            millis(loaded["ttl"].expiry.unwrap()),
+            millis(keyspace["ttl"].expiry.unwrap())
+        );
+    }
+
+    #[test]
+    fn test_snapshot_streams_in_chunks() {
+        let now = SystemTime::now();
+        let mut keyspace = HashMap::new();
+        for i in 0..1000 {
+            let query = Query::new("x".repeat(100), None, now);
+            keyspace.insert(format!("key:{}", i), query);
+        }
+        let snapshot = Snapshot::new(&keyspace).compression(false);
+        let chunks: Vec<_> = snapshot.chunks().collect();
+        assert!(chunks.len() > 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() < 2 * CHUNK_SIZE));
+
+        let mut loaded = HashMap::new();
+        assert_eq!(
+            load(&chunks.concat(), &mut loaded, true).unwrap().keys,
+            1000
+        );
+    }
+
+    #[test]
+    fn test_load_verifies_checksum() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+
+        let mut rdb = dump(&HashMap::new());
+        let covered = rdb.len() - 8;
+        assert_eq!(rdb[covered..], crc64(&rdb[..covered]).to_le_bytes());
+        let mut keyspace = HashMap::new();
+        assert!(load(&rdb, &mut keyspace, true).is_ok());
+
+        rdb[covered] ^= 1;
+        let err = load(&rdb, &mut keyspace, true).unwrap_err();
+        assert!(err.to_string().starts_with("RDB checksum mismatch"));
+        assert!(load(&rdb, &mut keyspace, false).is_ok());
+        rdb[covered..].fill(0);
+        assert!(load(&rdb, &mut keyspace, true).is_ok());
+
+        let unchecked = Snapshot::new(&HashMap::new()).checksum(false).encode();
+        assert_eq!(unchecked[unchecked.len() - 8..], [0; 8]);
+        let missing = std::env::temp_dir().join("credis-no-such-dump.rdb");
+        assert_eq!(load_file(&missing, &mut keyspace, true).unwrap().keys, 0);
+    }
+
+    #[test]
+    fn test_load_round_trips_containers() {
+        let now = SystemTime::now();
+        let items = |items: &[&str]| {
+            Arc::new(
+                items
+                    .iter()
+                    .map(|s| Bytes::copy_from_slice(s.as_bytes()))
+                    .collect(),
+            )
+        };
+        let id = |ms, seq| StreamId { ms, seq };
+        let long = "x".repeat(5000);
+        // Enough entries for several listpacks, with IDs whose sequence goes
+        // down from the master's and fields that differ from it.
+        let mut entries: Vec<(StreamId, Fields)> = (0..250)
+            .map(|i| {
+                (
+                    id(1000 + i / 3, 5 - i % 3),
+                    vec![("n".into(), i.to_string().into())],
+                )
+            })
+            .collect();
+        entries[1].1 = vec![
+            ("big".into(), long.clone().into()),
+            ("neg".into(), "-70000".into()),
+        ];
+        entries[2].1 = vec![
+            ("mid".into(), "y".repeat(100).into()),
+            ("i64".into(), i64::MIN.to_string().into()),
+        ];
+        let stream = Stream {
+            last_id: id(2000, 0),
+            first_id: entries[0].0,
+            max_deleted_id: id(900, 1),
+            entries_added: 260,
+            groups: vec![ConsumerGroup {
+                name: "g".into(),
+                last_id: id(1001, 4),
+                entries_read: u64::MAX,
+                pending: vec![Pending {
+                    id: id(1001, 4),
+                    delivery_time: 1_700_000_000_000,
+                    delivery_count: 2,
+                }],
+                consumers: vec![Consumer {
+                    name: "c".into(),
+                    seen_time: 1_700_000_000_001,
+                    active_time: 1_700_000_000_002,
+                    pending: vec![id(1001, 4)],
+                }],
+            }],
+            entries,
+        };
+        let values = [
+            Value::List(items(&["a", "7", "a", &long])),
+            Value::Set(items(&["x", "-3"])),
+            // Elements needn't be text.
+            Value::Set(Arc::new(vec![Bytes::from_static(b"\xff\x00bin")])),
+            Value::SortedSet(Arc::new(vec![
+                ("m".into(), 1.5),
+                ("n".into(), f64::INFINITY),
+            ])),
+            Value::Hash(Arc::new(vec![("f".into(), "v".into())])),
+            Value::Stream(Arc::new(stream)),
+            Value::Stream(Arc::default()),
+        ];
+        let mut keyspace = HashMap::new();
+        for (i, value) in values.iter().enumerate() {
+            keyspace.insert(i.to_string(), Query::new(value.clone(), None, now));
+        }
+
+        let mut loaded = HashMap::new();
+        assert_eq!(load(&dump(&keyspace), &mut loaded, true).unwrap().keys, 7);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(&loaded[&i.to_string()].value, value);
+            assert_eq!(restore_value(&dump_value(value)).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn test_load_reads_packed_encodings() {
+        let load_value = |value_type: u8, blob: &[u8]| {
+            let mut rdb = vec![value_type];
+            put_string(&mut rdb, blob, false);
+            restore_value(&rdb).unwrap()
+        };
+        let strings = |items: &[&str]| {
+            items
+                .iter()
+                .map(|s| Bytes::copy_from_slice(s.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+
+        // "a", 5 as an immediate, 300 as an int16 and -2 as an int24.
+        let mut ziplist = vec![0; 10];
+        ziplist.extend_from_slice(&[0, 0x01, b'a', 3, 0xf6, 2, 0xc0, 0x2c, 0x01, 4, 0xf0]);
+        ziplist.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            load_value(TYPE_LIST_ZIPLIST, &ziplist),
+            Value::List(Arc::new(strings(&["a", "5", "300", "-2"])))
+        );
+
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0xfe, 0xff];
+        assert_eq!(
+            load_value(TYPE_SET_INTSET, &intset),
+            Value::Set(Arc::new(strings(&["1", "-2"])))
+        );
+
+        // "f" → 1000 as a 13 bit int and "g" → -1, each item followed by
+        // its length.
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, b'f', 2, 0xc3, 0xe8, 2, 0x81, b'g', 2]);
+        listpack.extend_from_slice(&[0xdf, 0xff, 2, 0xff]);
+        assert_eq!(
+            load_value(TYPE_HASH_LISTPACK, &listpack),
+            Value::Hash(Arc::new(vec![
+                ("f".into(), "1000".into()),
+                ("g".into(), "-1".into())
+            ]))
+        );
+        assert_eq!(
+            load_value(TYPE_ZSET_LISTPACK, &listpack),
+            Value::SortedSet(Arc::new(vec![("f".into(), 1000.0), ("g".into(), -1.0)]))
+        );
+    }
+
+    #[test]
+    fn test_load_names_unsupported_types() {
+        // A hash with field expiries, as Redis 7.4 writes them.
+        let mut rdb = b"REDIS0011\xfe\x00\x18\x01h".to_vec();
+        rdb.extend_from_slice(&[0; 16]);
+        let err = load(&rdb, &mut HashMap::new(), true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "RDB holds a hash (type 24), which can't be loaded"
+        );
+        rdb[11] = 0x7f;
+        let err = load(&rdb, &mut HashMap::new(), true).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported RDB value type 0x7f");
+    }
+
+    #[test]
+    fn test_load_reads_integer_encoded_strings() {
+        // The empty snapshot real servers send, with redis-bits as an int8.
+        let mut rdb = b"REDIS0011\xfa\x0aredis-bits\xc0\x40".to_vec();
+        rdb.extend_from_slice(b"\xfe\x00\xfb\x01\x00\x00\x01n\xc1\x39\x30\xff");
+        rdb.extend_from_slice(&[0; 8]);
+        let mut keyspace = HashMap::new();
+        assert_eq!(load(&rdb, &mut keyspace, true).unwrap().keys, 1);
+        assert_eq!(keyspace["n"].value, "12345");
+        assert!(load(&rdb[..20], &mut keyspace, true).is_err());
+    }
+}
@@ -0,0 +1,671 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use bytes::{Buf, Bytes};
+use futures::{SinkExt, StreamExt};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+    sync::Mutex,
+    task::AbortHandle,
+};
+use tokio_util::codec::{Framed, FramedParts};
+
+use crate::{
+    command::{self, Command, ReplconfArgs},
+    context::ConnCtx,
+    format_resp,
+    protocol::{Resp, RespCodec, RespError},
+    rdb,
+    replication::{random_id, Unframer},
+    server::{HostSpec, Info, Role},
+    store::{Keyspace, Store},
+};
+
+// Replica side of replication: performs the handshake, loads the master's
+// snapshot and then applies every propagated write to the local dataset.
+// Replies are discarded except for GETACK, the one command the master
+// expects an answer to. Commands the master wrapped in checked frames are
+// unwrapped and checked first. The stream is relayed as it was before any
+// framing to replicas of our own, so a chain of them all agree on offsets.
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+const EOF_MARK_LEN: usize = 40;
+
+// This replica's view of its connection to the master, for INFO replication.
+// Owns the task that follows the master and stops it when dropped.
+pub struct MasterLink {
+    pub host: String,
+    pub port: u16,
+    pub up: bool,
+    // When anything last arrived from the master.
+    pub last_io: Option<Instant>,
+    task: Option<AbortHandle>,
+}
+
+impl MasterLink {
+    pub fn new(master: &HostSpec) -> Self {
+        Self {
+            host: master.host.clone(),
+            port: master.port,
+            up: false,
+            last_io: None,
+            task: None,
+        }
+    }
+
+    pub fn is(&self, master: &HostSpec) -> bool {
+        self.host == master.host && self.port == master.port
+    }
+
+    pub fn info(&self) -> String {
+        format!(
+            "master_host:{}\nmaster_port:{}\nmaster_link_status:{}\nmaster_last_io_seconds_ago:{}",
+            self.host,
+            self.port,
+            if self.up { "up" } else { "down" },
+            self.last_io.map_or(-1, |t| t.elapsed().as_secs() as i64)
+        )
+    }
+}
+
+impl Drop for MasterLink {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+// Address a replica gives its master to reach it by, for INFO and ROLE.
+// Without an announced ip the master uses the connection's peer address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub ip: Option<String>,
+    pub port: u16,
+}
+
+// Turns this node into a replica of `master`, dropping any link to a previous
+// master. The dataset is replaced once the new master's snapshot arrives,
+// unless the master shares our history and can continue from where it is.
+pub fn start(info: &mut Info, master: HostSpec, cache: Arc<Store>, shared: Arc<Mutex<Info>>) {
+    let mut link = MasterLink::new(&master);
+    let announce = Announce {
+        ip: info.announce_ip.clone(),
+        port: info.announce_port.unwrap_or(info.port),
+    };
+    let position = Position {
+        replid: info.master_replid.clone(),
+        offset: info.replicas.offset(),
+    };
+    let task = tokio::spawn(follow(announce, master, position, cache, shared));
+    link.task = Some(task.abort_handle());
+    info.master_link = Some(link);
+    info.role = Role::Slave;
+    info.replicas.disconnect_all();
+}
+
+// Promotes this node to master, keeping its dataset. Writes from here on
+// start a new history, so replicas of the old master that follow us can
+// continue only up to this point.
+pub fn stop(info: &mut Info) {
+    if info.master_link.take().is_some() {
+        info.shift_replid(random_id());
+    }
+    info.role = Role::Master;
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("master closed the connection while waiting for the {} reply", .0)]
+    Closed(&'static str),
+    #[error("master rejected {}: {}", .0, .1)]
+    Rejected(&'static str, String),
+    #[error("unexpected reply to {}: {:?}", .0, .1)]
+    Unexpected(&'static str, Resp),
+    #[error("invalid snapshot from master: {}", .0)]
+    Snapshot(&'static str),
+    #[error(transparent)]
+    Protocol(#[from] RespError),
+}
+
+// Where a replica left off in its master's stream: the master's replication
+// id and the offset of the last byte applied.
+#[derive(Debug, Clone, PartialEq)]
+struct Position {
+    replid: String,
+    offset: u64,
+}
+
+// Handshake steps. Each sends one command on entry and checks the master's
+// reply before moving to the next. The ip-address step only runs when there
+// is an ip to announce. PSYNC asks to resume from `Position` when there is
+// one; the master either agrees or starts a full resync.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Ping,
+    ListeningPort,
+    IpAddress,
+    Capa,
+    Psync(Option<Position>),
+    FullResync(Position),
+    Continue(Option<String>),
+}
+
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Ping => "PING",
+            Step::ListeningPort => "REPLCONF listening-port",
+            Step::IpAddress => "REPLCONF ip-address",
+            Step::Capa => "REPLCONF capa",
+            Step::Psync(_) => "PSYNC",
+            Step::FullResync(_) => "FULLRESYNC",
+            Step::Continue(_) => "CONTINUE",
+        }
+    }
+
+    fn request(&self, announce: &Announce) -> Option<Resp> {
+        match self {
+            Step::Ping => Some(format_resp!["PING"]),
+            Step::ListeningPort => Some(format_resp!["REPLCONF", "listening-port", announce.port]),
+            Step::IpAddress => Some(format_resp![
+                "REPLCONF",
+                "ip-address",
+                announce.ip.as_deref().unwrap_or_default()
+            ]),
+            Step::Capa => Some(format_resp![
+                "REPLCONF", "capa", "eof", "capa", "psync2", "capa", "crc32", "capa", "seq"
+            ]),
+            Step::Psync(None) => Some(format_resp!["PSYNC", "?", "-1"]),
+            Step::Psync(Some(at)) => Some(format_resp!["PSYNC", at.replid, at.offset + 1]),
+            Step::FullResync(_) | Step::Continue(_) => None,
+        }
+    }
+
+    fn advance(self, reply: Resp) -> Result<Step, HandshakeError> {
+        let name = self.name();
+        match (self, reply) {
+            (_, Resp::SimpleError(e)) => Err(HandshakeError::Rejected(name, e)),
+            (Step::Ping, Resp::SimpleString(s)) if s == "PONG" => Ok(Step::ListeningPort),
+            (Step::ListeningPort, Resp::SimpleString(s)) if s == "OK" => Ok(Step::IpAddress),
+            (Step::IpAddress, Resp::SimpleString(s)) if s == "OK" => Ok(Step::Capa),
+            (Step::Capa, Resp::SimpleString(s)) if s == "OK" => Ok(Step::Psync(None)),
+            (Step::Psync(_), Resp::SimpleString(s)) => {
+                match s.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["FULLRESYNC", replid, offset] => match offset.parse() {
+                        Ok(offset) => Ok(Step::FullResync(Position {
+                            replid: replid.to_string(),
+                            offset,
+                        })),
+                        Err(_) => Err(HandshakeError::Unexpected(name, Resp::SimpleString(s))),
+                    },
+                    ["CONTINUE"] => Ok(Step::Continue(None)),
+                    ["CONTINUE", replid] => Ok(Step::Continue(Some(replid.to_string()))),
+                    _ => Err(HandshakeError::Unexpected(name, Resp::SimpleString(s))),
+                }
+            }
+            (_, reply) => Err(HandshakeError::Unexpected(name, reply)),
+        }
+    }
+}
+
+// How the master agreed to bring us up to date.
+enum Sync {
+    // Its replication position and a snapshot of the dataset at that point.
+    Full(Position, Bytes),
+    // The rest of the stream from where we left off, possibly under a new
+    // replication id.
+    Partial(Option<String>),
+}
+
+async fn handshake<T>(
+    stream: T,
+    announce: &Announce,
+    resume: Option<Position>,
+) -> Result<(Sync, Framed<T, RespCodec>), HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, RespCodec::default());
+    let mut step = Step::Ping;
+    while let Some(request) = step.request(announce) {
+        framed.send(request).await?;
+        let reply = framed
+            .next()
+            .await
+            .ok_or(HandshakeError::Closed(step.name()))??;
+        step = match step.advance(reply)? {
+            Step::IpAddress if announce.ip.is_none() => Step::Capa,
+            Step::Psync(_) => Step::Psync(resume.clone()),
+            step => step,
+        };
+    }
+    match step {
+        Step::FullResync(position) => {
+            let (snapshot, framed) = receive_snapshot(framed).await?;
+            Ok((Sync::Full(position, snapshot), framed))
+        }
+        Step::Continue(replid) => Ok((Sync::Partial(replid), framed)),
+        _ => unreachable!("only a finished handshake has no request"),
+    }
+}
+
+// The snapshot is framed like a bulk string but has no trailing CRLF, so it
+// can't go through the codec. Reads it off the raw stream, starting with
+// whatever the codec had already buffered, and hands the rest back framed.
+// A streamed snapshot has `EOF:<mark>` in place of its length and ends at
+// the next occurrence of the mark.
+async fn receive_snapshot<T>(
+    framed: Framed<T, RespCodec>,
+) -> Result<(Bytes, Framed<T, RespCodec>), HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let FramedParts {
+        mut io,
+        codec,
+        mut read_buf,
+        ..
+    } = framed.into_parts();
+    // How much of a streamed snapshot has been searched for the mark.
+    let mut searched: usize = 0;
+    let snapshot = loop {
+        if let Some(end) = read_buf.windows(2).position(|w| w == b"\r\n") {
+            if read_buf[0] != b'$' {
+                return Err(HandshakeError::Snapshot("expected a bulk payload"));
+            }
+            if let Some(mark) = read_buf[1..end].strip_prefix(b"EOF:") {
+                if mark.len() != EOF_MARK_LEN {
+                    return Err(HandshakeError::Snapshot("invalid EOF mark"));
+                }
+                let mark = mark.to_vec();
+                let body = &read_buf[end + 2..];
+                let from = searched.saturating_sub(EOF_MARK_LEN);
+                if let Some(pos) = body[from..].windows(EOF_MARK_LEN).position(|w| w == mark) {
+                    read_buf.advance(end + 2);
+                    let snapshot = read_buf.split_to(from + pos).freeze();
+                    read_buf.advance(EOF_MARK_LEN);
+                    break snapshot;
+                }
+                searched = body.len();
+            } else {
+                let len: usize = std::str::from_utf8(&read_buf[1..end])
+                    .ok()
+                    .and_then(|len| len.parse().ok())
+                    .ok_or(HandshakeError::Snapshot("invalid payload length"))?;
+                if read_buf.len() >= end + 2 + len {
+                    read_buf.advance(end + 2);
+                    break read_buf.split_to(len).freeze();
+                }
+            }
+        }
+        if io.read_buf(&mut read_buf).await.map_err(RespError::Io)? == 0 {
+            return Err(HandshakeError::Closed("snapshot"));
+        }
+    };
+    let mut parts = FramedParts::new::<Resp>(io, codec);
+    parts.read_buf = read_buf;
+    Ok((snapshot, Framed::from_parts(parts)))
+}
+
+// Connects and catches up with the master: from the backlog when resuming
+// is possible, otherwise by replacing the local dataset with its snapshot.
+async fn sync(
+    announce: &Announce,
+    master: &HostSpec,
+    resume: Option<Position>,
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<Info>>,
+) -> anyhow::Result<Framed<TcpStream, RespCodec>> {
+    let stream = TcpStream::connect(master.to_string()).await?;
+    let (sync, framed) = handshake(stream, announce, resume).await?;
+
+    let gate = info.lock().await.gate.clone();
+    let _shared = gate.shared().await;
+    let mut cache = cache.lock_all().await;
+    let mut info = info.lock().await;
+    match sync {
+        Sync::Full(position, snapshot) => {
+            let mut loaded = HashMap::new();
+            let mut count = rdb::load(&snapshot, &mut loaded, info.persistence.rdbchecksum)?;
+            cache.clear();
+            for (key, query) in loaded {
+                cache.insert(key, query);
+            }
+            info.functions.flush();
+            info.functions
+                .restore(std::mem::take(&mut count.functions))?;
+            println!("loaded {} from master {}", count, master);
+            info.set_replid(position.replid);
+            info.replicas.reset(position.offset);
+        }
+        Sync::Partial(replid) => {
+            println!(
+                "resumed replication from master {} at offset {}",
+                master,
+                info.replicas.offset()
+            );
+            if let Some(replid) = replid.filter(|replid| *replid != info.master_replid) {
+                info.shift_replid(replid);
+            }
+        }
+    }
+    if let Some(link) = &mut info.master_link {
+        link.up = true;
+        link.last_io = Some(Instant::now());
+    }
+    Ok(framed)
+}
+
+// Keeps the link to the master alive for as long as this node is its
+// replica: every time the connection drops, reconnects with backoff and
+// resumes from where the stream stopped.
+async fn follow(
+    announce: Announce,
+    master: HostSpec,
+    position: Position,
+    cache: Arc<Store>,
+    info: Arc<Mutex<Info>>,
+) {
+    let mut resume = Some(position);
+    loop {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut framed = loop {
+            match sync(&announce, &master, resume.clone(), &cache, &info).await {
+                Ok(framed) => break framed,
+                Err(e) => {
+                    println!(
+                        "sync with master {} failed: {}; retrying in {:?}",
+                        master, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        };
+
+        let e = stream(&mut framed, &cache, &info).await;
+        println!("lost connection to master {}: {}", master, e);
+        let mut info = info.lock().await;
+        if let Some(link) = &mut info.master_link {
+            link.up = false;
+        }
+        resume = Some(Position {
+            replid: info.master_replid.clone(),
+            offset: info.replicas.offset(),
+        });
+    }
+}
+
+// Applies the master's write stream until the link drops, and says why.
+async fn stream(
+    framed: &mut Framed<TcpStream, RespCodec>,
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<Info>>,
+) -> anyhow::Error {
+    let mut ctx = ConnCtx::default();
+    let mut frames = Unframer::default();
+    let gate = info.lock().await.gate.clone();
+    // Held from a MULTI the master sent to its EXEC, so our own clients see
+    // all of a transaction or script's writes or none of them.
+    let mut batch = None;
+    while let Some(req) = framed.next().await {
+        // A frame that fails its checks drops the link, and with it anything
+        // after it, so we resync from the last command known to be good.
+        let req = match req {
+            Ok(req) => frames.unframe(req),
+            Err(e) => return e.into(),
+        };
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => return e.into(),
+        };
+        let replies = match Command::parse(&req) {
+            Ok(cmd) => {
+                if matches!(cmd, Command::Multi) && batch.is_none() {
+                    batch = Some(gate.exclusive().await);
+                }
+                let ends = matches!(cmd, Command::Exec);
+                let _shared = match batch {
+                    Some(_) => None,
+                    None => Some(gate.shared().await),
+                };
+                let replies = apply(cmd, &req, &mut ctx, cache, info).await;
+                if ends {
+                    batch = None;
+                }
+                replies
+            }
+            Err(e) => {
+                println!("ignoring unparseable command from master: {}", e);
+                vec![]
+            }
+        };
+        for reply in replies {
+            if let Err(e) = framed.send(reply).await {
+                return e.into();
+            }
+        }
+        // Relaying advances our offset past every byte of the stream, GETACK
+        // included, but a GETACK reports the offset from just before it.
+        let mut info = info.lock().await;
+        info.replicas.propagate(&req);
+        if let Some(link) = &mut info.master_link {
+            link.last_io = Some(Instant::now());
+        }
+    }
+    anyhow!("master closed the connection")
+}
+
+// Executes one command from the master and returns what to send back.
+async fn apply(
+    cmd: Command,
+    req: &Resp,
+    ctx: &mut ConnCtx,
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<Info>>,
+) -> Vec<Resp> {
+    let is_write = cmd.is_write();
+    let keys = cmd.keys();
+    let is_getack = matches!(cmd, Command::Replconf(ReplconfArgs::GetAck));
+    match command::execute_command(cmd, ctx, cache.clone(), info.clone()).await {
+        Ok(replies) => {
+            if is_write {
+                info.lock().await.persistence.record_write(req, &keys);
+            }
+            if is_getack {
+                replies
+            } else {
+                vec![]
+            }
+        }
+        Err(e) => {
+            println!("command from master failed: {}", e);
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANNOUNCE: Announce = Announce {
+        ip: None,
+        port: 6380,
+    };
+    use crate::protocol::RespEncoding;
+    use crate::server::Query;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    // Answers each request the replica sends with the next canned reply.
+    async fn master(stream: DuplexStream, replies: Vec<Vec<u8>>) {
+        let mut framed = Framed::new(stream, RespCodec::default());
+        for reply in replies {
+            if framed.next().await.is_none() {
+                return;
+            }
+            framed.get_mut().write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_completes_full_resync() {
+        let snapshot = rdb::dump(&HashMap::new());
+        let mut fullresync = format!("+FULLRESYNC abc 7\r\n${}\r\n", snapshot.len()).into_bytes();
+        fullresync.extend_from_slice(&snapshot);
+        fullresync.extend_from_slice(&format_resp!["SET", "k", "v"].encode());
+        let replies = vec![
+            b"+PONG\r\n".to_vec(),
+            b"+OK\r\n".to_vec(),
+            b"+OK\r\n".to_vec(),
+            fullresync,
+        ];
+
+        let (a, b) = tokio::io::duplex(64);
+        let (synced, _) = tokio::join!(handshake(a, &ANNOUNCE, None), master(b, replies));
+        let (sync, mut framed) = synced.unwrap();
+        let Sync::Full(position, received) = sync else {
+            panic!("expected a full resync");
+        };
+        assert_eq!(position.replid, "abc");
+        assert_eq!(position.offset, 7);
+        assert_eq!(received, snapshot);
+        // Anything sent after the snapshot is left for the command stream.
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            format_resp!["SET", "k", "v"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_reads_eof_marked_snapshot() {
+        let mut keyspace = HashMap::new();
+        keyspace.insert(
+            "k".to_string(),
+            Query::new("v".repeat(300), None, std::time::SystemTime::now()),
+        );
+        // Dumped once: the header's ctime could tick over between two dumps.
+        let dump = rdb::dump(&keyspace);
+        let mark = "m".repeat(EOF_MARK_LEN);
+        let mut fullresync = format!("+FULLRESYNC abc 0\r\n$EOF:{}\r\n", mark).into_bytes();
+        fullresync.extend_from_slice(&dump);
+        fullresync.extend_from_slice(mark.as_bytes());
+        fullresync.extend_from_slice(&format_resp!["PING"].encode());
+        let replies = vec![
+            b"+PONG\r\n".to_vec(),
+            b"+OK\r\n".to_vec(),
+            b"+OK\r\n".to_vec(),
+            fullresync,
+        ];
+
+        let (a, b) = tokio::io::duplex(64);
+        let (synced, _) = tokio::join!(handshake(a, &ANNOUNCE, None), master(b, replies));
+        let (Sync::Full(_, snapshot), mut framed) = synced.unwrap() else {
+            panic!("expected a full resync");
+        };
+        assert_eq!(snapshot, dump);
+        assert_eq!(framed.next().await.unwrap().unwrap(), format_resp!["PING"]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_resumes_from_position() {
+        let (a, b) = tokio::io::duplex(64);
+        let master = async move {
+            let mut framed = Framed::new(b, RespCodec::default());
+            for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+                framed.next().await.unwrap().unwrap();
+                framed.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            let psync = framed.next().await.unwrap().unwrap();
+            framed
+                .get_mut()
+                .write_all(b"+CONTINUE def\r\n")
+                .await
+                .unwrap();
+            psync
+        };
+        let resume = Position {
+            replid: "abc".to_string(),
+            offset: 41,
+        };
+        let (synced, psync) = tokio::join!(handshake(a, &ANNOUNCE, Some(resume)), master);
+        assert_eq!(psync, format_resp!["PSYNC", "abc", "42"]);
+        let (sync, _) = synced.unwrap();
+        assert!(matches!(sync, Sync::Partial(Some(replid)) if replid == "def"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_checks_each_reply() {
+        let (a, b) = tokio::io::duplex(64);
+        let replies = vec![b"+PONG\r\n".to_vec(), b"-ERR not now\r\n".to_vec()];
+        let (result, _) = tokio::join!(handshake(a, &ANNOUNCE, None), master(b, replies));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "master rejected REPLCONF listening-port: ERR not now"
+        );
+
+        let (a, b) = tokio::io::duplex(64);
+        let replies = vec![b"+HELLO\r\n".to_vec()];
+        let (result, _) = tokio::join!(handshake(a, &ANNOUNCE, None), master(b, replies));
+        assert!(matches!(result, Err(HandshakeError::Unexpected("PING", _))));
+
+        // The master hangs up after reading the request.
+        let (a, b) = tokio::io::duplex(64);
+        let hang_up = async move {
+            Framed::new(b, RespCodec::default()).next().await;
+        };
+        let (result, _) = tokio::join!(handshake(a, &ANNOUNCE, None), hang_up);
+        assert!(matches!(result, Err(HandshakeError::Closed("PING"))));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_announces_ip_and_port() {
+        let (a, b) = tokio::io::duplex(64);
+        let master = async move {
+            let mut framed = Framed::new(b, RespCodec::default());
+            let mut requests = vec![];
+            for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n", "+OK\r\n"] {
+                requests.push(framed.next().await.unwrap().unwrap());
+                framed.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            requests
+        };
+        let announce = Announce {
+            ip: Some("10.1.2.3".to_string()),
+            port: 7000,
+        };
+        let (_, requests) = tokio::join!(handshake(a, &announce, None), master);
+        assert_eq!(
+            requests[1..3],
+            [
+                format_resp!["REPLCONF", "listening-port", "7000"],
+                format_resp!["REPLCONF", "ip-address", "10.1.2.3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_master_link_info() {
+        let master: HostSpec = "127.0.0.1 6379".parse().unwrap();
+        let mut link = MasterLink::new(&master);
+        assert!(link.info().contains("master_link_status:down"));
+        assert!(link.info().contains("master_last_io_seconds_ago:-1"));
+
+        link.up = true;
+        link.last_io = Some(Instant::now());
+        assert_eq!(
+            link.info(),
+            "master_host:127.0.0.1\nmaster_port:6379\nmaster_link_status:up\nmaster_last_io_seconds_ago:0"
+        );
+    }
+}
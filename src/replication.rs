@@ -0,0 +1,698 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fmt::Write,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    format_resp,
+    obuf::Output,
+    protocol::{readnext_resp, Resp, RespEncoding},
+    server::HostSpec,
+};
+
+// Optional replication stream features a replica can ask for with
+// `REPLCONF capa <name>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    pub crc32: bool,
+    pub seq: bool,
+    // Accepts a snapshot of unknown length, ended by a marker.
+    pub eof: bool,
+}
+
+impl Capabilities {
+    pub fn merge(&mut self, capa: &[String]) {
+        for c in capa {
+            match c.to_lowercase().as_str() {
+                "crc32" => self.crc32 = true,
+                "seq" => self.seq = true,
+                "eof" => self.eof = true,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn framed(&self) -> bool {
+        self.crc32 || self.seq
+    }
+}
+
+struct Replica {
+    id: u64,
+    // Where the replica accepts connections, as announced with REPLCONF
+    // ip-address and listening-port or else as seen by us.
+    addr: HostSpec,
+    capabilities: Capabilities,
+    next_seq: u64,
+    tx: UnboundedSender<Bytes>,
+    // The connection's, which what is queued on `tx` counts towards.
+    output: Arc<Output>,
+    // Replication offset the replica last reported via REPLCONF ACK. Until
+    // the first ack, lag counts from registration.
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+impl Replica {
+    fn listens_at(&self, addr: &HostSpec) -> bool {
+        self.addr == *addr
+    }
+
+    // Queues a payload for the replica, wrapped in a checked frame if it
+    // negotiated one. False once the connection has gone away, or has so
+    // much queued already that it's over its output buffer limit.
+    fn send(&mut self, payload: &Bytes) -> bool {
+        let data = if self.capabilities.framed() {
+            let seq = self.capabilities.seq.then_some(self.next_seq);
+            self.next_seq += 1;
+            Bytes::from(frame(payload, seq, self.capabilities.crc32))
+        } else {
+            payload.clone()
+        };
+        self.output.queue(data.len()) && self.tx.send(data).is_ok()
+    }
+}
+
+const BACKLOG_SIZE: usize = 1024 * 1024;
+
+// Snapshot chunks queued per replica before the encoder waits for it, so a
+// diskless snapshot is produced only as fast as replicas consume it.
+const SNAPSHOT_CHUNKS_IN_FLIGHT: usize = 4;
+
+// Tail of the replication stream, kept so a replica whose link dropped can
+// resume with `PSYNC <replid> <offset>` instead of a full resync. Held as
+// whole commands so each can be framed for whichever replica asks.
+#[derive(Default)]
+struct Backlog {
+    commands: VecDeque<Bytes>,
+    size: usize,
+    // Stream offset just past the newest command.
+    offset: u64,
+}
+
+impl Backlog {
+    fn push(&mut self, payload: Bytes) {
+        self.offset += payload.len() as u64;
+        self.size += payload.len();
+        self.commands.push_back(payload);
+        while self.size > BACKLOG_SIZE {
+            let oldest = self.commands.pop_front().expect("backlog size is nonzero");
+            self.size -= oldest.len();
+        }
+    }
+
+    // The commands after `offset`, if the backlog still reaches back that
+    // far and `offset` falls on a command boundary.
+    fn since(&self, offset: u64) -> Option<Vec<Bytes>> {
+        let mut start = self.offset - self.size as u64;
+        let mut commands = self.commands.iter();
+        while start < offset {
+            start += commands.next()?.len() as u64;
+        }
+        (start == offset).then(|| commands.cloned().collect())
+    }
+}
+
+// Registry of replicas attached to this master. Each replica connection owns
+// the receiving end of a channel and writes whatever arrives to its socket.
+#[derive(Default)]
+pub struct Replicas {
+    replicas: Vec<Replica>,
+    backlog: Backlog,
+    // Offset just past the last write, ignoring the GETACKs and PINGs that
+    // may follow it. A replica that has acked this far has every write.
+    write_offset: u64,
+    // Replicas waiting for the next diskless snapshot, with where to send it.
+    waiting: Vec<(Replica, mpsc::Sender<Bytes>)>,
+}
+
+// Receiving ends for a replica waiting on a diskless snapshot: the snapshot
+// itself, then the writes that follow it.
+pub struct Waiting {
+    pub snapshot: mpsc::Receiver<Bytes>,
+    pub stream: UnboundedReceiver<Bytes>,
+}
+
+fn new_replica(
+    id: u64,
+    addr: HostSpec,
+    capabilities: Capabilities,
+    output: Arc<Output>,
+) -> (Replica, UnboundedReceiver<Bytes>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let replica = Replica {
+        id,
+        addr,
+        capabilities,
+        next_seq: 0,
+        tx,
+        output,
+        ack_offset: 0,
+        last_ack: Instant::now(),
+    };
+    (replica, rx)
+}
+
+impl Replicas {
+    // Registers the replica on connection `id`, listening on `addr`, whose
+    // output is `output`.
+    pub fn register(
+        &mut self,
+        id: u64,
+        addr: HostSpec,
+        capabilities: Capabilities,
+        output: Arc<Output>,
+    ) -> UnboundedReceiver<Bytes> {
+        let (replica, rx) = new_replica(id, addr, capabilities, output);
+        self.replicas.push(replica);
+        rx
+    }
+
+    // Queues the replica on connection `id` for the next diskless snapshot.
+    // True if it is the first to wait, and so should schedule the snapshot.
+    pub fn wait_for_snapshot(
+        &mut self,
+        id: u64,
+        addr: HostSpec,
+        capabilities: Capabilities,
+        output: Arc<Output>,
+    ) -> (Waiting, bool) {
+        let (replica, stream) = new_replica(id, addr, capabilities, output);
+        let (tx, snapshot) = mpsc::channel(SNAPSHOT_CHUNKS_IN_FLIGHT);
+        let first = self.waiting.is_empty();
+        self.waiting.push((replica, tx));
+        (Waiting { snapshot, stream }, first)
+    }
+
+    // Registers every waiting replica as of the current offset, which the
+    // snapshot about to be taken must match, and returns where to send it.
+    pub fn start_snapshot(&mut self) -> Vec<mpsc::Sender<Bytes>> {
+        let (replicas, senders): (Vec<_>, Vec<_>) = self.waiting.drain(..).unzip();
+        self.replicas.extend(replicas);
+        senders
+    }
+
+    // Closes every replica's stream so they reconnect and resync, as after
+    // this node starts following a different master.
+    pub fn disconnect_all(&mut self) {
+        self.replicas.clear();
+        self.waiting.clear();
+    }
+
+    // Offset of the replication stream: how many bytes have been sent down it.
+    pub fn offset(&self) -> u64 {
+        self.backlog.offset
+    }
+
+    // Starts the stream afresh at `offset`, as after a full resync with our
+    // own master. Replicas of ours hold the old dataset, so they must resync.
+    pub fn reset(&mut self, offset: u64) {
+        self.backlog = Backlog {
+            offset,
+            ..Backlog::default()
+        };
+        self.write_offset = offset;
+        self.disconnect_all();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replicas.is_empty()
+    }
+
+    // How many replicas have acked within `max_lag`.
+    pub fn acked_within(&self, max_lag: Duration) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.last_ack.elapsed() <= max_lag)
+            .count()
+    }
+
+    // Whether a replica listens at `addr`.
+    pub fn contains(&self, addr: &HostSpec) -> bool {
+        self.replicas.iter().any(|replica| replica.listens_at(addr))
+    }
+
+    // Address of a replica that has acknowledged every write, restricted to
+    // the one at `target` if given.
+    pub fn caught_up(&self, target: Option<&HostSpec>) -> Option<HostSpec> {
+        self.replicas
+            .iter()
+            .filter(|replica| target.is_none_or(|target| replica.listens_at(target)))
+            .find(|replica| replica.ack_offset >= self.write_offset)
+            .map(|replica| replica.addr.clone())
+    }
+
+    // Whether every replica has acknowledged every write.
+    pub fn all_caught_up(&self) -> bool {
+        self.replicas
+            .iter()
+            .all(|replica| replica.ack_offset >= self.write_offset)
+    }
+
+    // Sends the replica on connection `id` everything after `offset`, or
+    // returns false if the backlog no longer covers it.
+    pub fn resume(&mut self, id: u64, offset: u64) -> bool {
+        let Some(commands) = self.backlog.since(offset) else {
+            return false;
+        };
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == id) {
+            for payload in &commands {
+                replica.send(payload);
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.replicas.retain(|replica| replica.id != id);
+        self.waiting.retain(|(replica, _)| replica.id != id);
+    }
+
+    pub fn ack(&mut self, id: u64, offset: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    // Asks every replica to report how far it has got. The request travels
+    // in the replication stream, so it is answered only once everything
+    // before it has been applied.
+    pub fn request_ack(&mut self) {
+        if !self.replicas.is_empty() {
+            self.send(&format_resp!["REPLCONF", "GETACK", "*"]);
+        }
+    }
+
+    // Keeps idle links visibly alive, so a replica can tell a quiet master
+    // from a dead one. Like any other command, it advances the offset.
+    pub fn ping(&mut self) {
+        if !self.replicas.is_empty() {
+            self.send(&format_resp!["PING"]);
+        }
+    }
+
+    // One `slaveN` line per replica for INFO replication. Replicas are only
+    // registered once their snapshot has gone out, so all of them are online.
+    // Lag is the number of seconds since the replica last acknowledged.
+    pub fn info(&self) -> String {
+        let mut out = format!("connected_slaves:{}", self.replicas.len());
+        for (i, replica) in self.replicas.iter().enumerate() {
+            let _ = write!(
+                out,
+                "\nslave{}:ip={},port={},state=online,offset={},lag={}",
+                i,
+                replica.addr.host,
+                replica.addr.port,
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            );
+        }
+        out
+    }
+
+    // `[host, port, offset]` per replica, as listed by ROLE.
+    pub fn role(&self) -> Resp {
+        Resp::Array(
+            self.replicas
+                .iter()
+                .map(|replica| {
+                    Resp::Array(vec![
+                        Resp::bulk(replica.addr.host.as_str()),
+                        Resp::bulk(replica.addr.port.to_string()),
+                        Resp::bulk(replica.ack_offset.to_string()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    // Forwards a write command to every replica, wrapping it in a checked
+    // frame for the ones that negotiated it, and keeps it in the backlog.
+    // Replicas whose connection has gone away are dropped from the registry.
+    // On a replica this relays everything from the master, so every byte
+    // counts as a write.
+    pub fn propagate(&mut self, cmd: &Resp) {
+        self.send(cmd);
+        self.write_offset = self.offset();
+    }
+
+    fn send(&mut self, cmd: &Resp) {
+        let mut payload = BytesMut::new();
+        cmd.encode_into(&mut payload);
+        let payload = payload.freeze();
+        self.replicas.retain_mut(|replica| replica.send(&payload));
+        self.backlog.push(payload);
+    }
+}
+
+// 40 random hex characters, as used for replication ids and EOF marks.
+// Read from the OS's secure source, so ids from distinct servers never
+// collide, with hashing seeds as a fallback where there is none.
+pub fn random_id() -> String {
+    let mut bytes = [0u8; 20];
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .is_err()
+    {
+        for chunk in bytes.chunks_mut(8) {
+            let seed = RandomState::new().build_hasher().finish().to_le_bytes();
+            chunk.copy_from_slice(&seed[..chunk.len()]);
+        }
+    }
+    let mut id = String::with_capacity(40);
+    for b in bytes {
+        let _ = write!(id, "{:02x}", b);
+    }
+    id
+}
+
+// Wraps an encoded command as `REPLFRAME [SEQ <n>] [CRC <crc32>] <payload>` so
+// the replica can detect dropped, reordered or corrupted frames. The payload
+// goes in byte for byte, as the checksum covers it.
+pub fn frame(payload: &[u8], seq: Option<u64>, crc: bool) -> Vec<u8> {
+    let mut parts = vec![Resp::bulk("REPLFRAME")];
+    if let Some(seq) = seq {
+        parts.push(Resp::bulk("SEQ"));
+        parts.push(Resp::bulk(seq.to_string()));
+    }
+    if crc {
+        parts.push(Resp::bulk("CRC"));
+        parts.push(Resp::bulk(format!("{:08x}", crc32(payload))));
+    }
+    let mut out = format!("*{}\r\n", parts.len() + 1).into_bytes();
+    for part in parts {
+        out.extend_from_slice(&part.encode());
+    }
+    out.extend_from_slice(format!("${}\r\n", payload.len()).as_bytes());
+    out.extend_from_slice(payload);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FrameError {
+    #[error("malformed REPLFRAME from master")]
+    Malformed,
+    #[error("frame checksum mismatch (expected {:08x}, computed {:08x})", .0, .1)]
+    Checksum(u32, u32),
+    #[error("frame {} arrived when {} was due", .1, .0)]
+    Sequence(u64, u64),
+    #[error("unframed command from master after framed ones")]
+    Unframed,
+}
+
+// The replica's end of checked framing, one per connection to the master,
+// whose numbering starts over with each. Masters that don't know the
+// capabilities we ask for send commands as they are, but once one arrives
+// framed every later one must be.
+#[derive(Default)]
+pub struct Unframer {
+    framed: bool,
+    next_seq: u64,
+}
+
+impl Unframer {
+    // The command a frame carries, once its sequence number and checksum
+    // are found to be right.
+    pub fn unframe(&mut self, req: Resp) -> Result<Resp, FrameError> {
+        let Resp::Array(mut parts) = req else {
+            return self.plain(req);
+        };
+        match parts.first() {
+            Some(Resp::Bulk(Some(name))) if name.eq_ignore_ascii_case("REPLFRAME") => {}
+            _ => return self.plain(Resp::Array(parts)),
+        }
+        self.framed = true;
+        let Some(Resp::Bulk(Some(payload))) = parts.pop() else {
+            return Err(FrameError::Malformed);
+        };
+        let payload = Bytes::copy_from_slice(payload.as_bytes());
+        let mut fields = parts[1..].iter().map(|part| match part {
+            Resp::Bulk(Some(field)) => Ok(field.as_str()),
+            _ => Err(FrameError::Malformed),
+        });
+        while let Some(field) = fields.next() {
+            let value = fields.next().ok_or(FrameError::Malformed)??;
+            match field?.to_ascii_uppercase().as_str() {
+                "SEQ" => {
+                    let seq = value.parse().map_err(|_| FrameError::Malformed)?;
+                    if seq != self.next_seq {
+                        return Err(FrameError::Sequence(self.next_seq, seq));
+                    }
+                    self.next_seq += 1;
+                }
+                "CRC" => {
+                    let expected =
+                        u32::from_str_radix(value, 16).map_err(|_| FrameError::Malformed)?;
+                    let computed = crc32(&payload);
+                    if expected != computed {
+                        return Err(FrameError::Checksum(expected, computed));
+                    }
+                }
+                _ => return Err(FrameError::Malformed),
+            }
+        }
+        match readnext_resp(&payload) {
+            Ok((cmd, len)) if len == payload.len() => Ok(cmd),
+            _ => Err(FrameError::Malformed),
+        }
+    }
+
+    fn plain(&self, req: Resp) -> Result<Resp, FrameError> {
+        match self.framed {
+            true => Err(FrameError::Unframed),
+            false => Ok(req),
+        }
+    }
+}
+
+// CRC-32 (IEEE 802.3), bitwise. Propagated frames are small enough that a
+// lookup table isn't worth it.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::ClientType,
+        command::{Command, ReplconfArgs},
+        obuf::{Limit, OutputLimits},
+    };
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_frame_carries_seq_and_crc() {
+        let payload = b"*1\r\n$4\r\nPING\r\n";
+        let (framed, _) = readnext_resp(&Bytes::from(frame(payload, Some(7), true))).unwrap();
+        assert_eq!(
+            framed,
+            Resp::Array(vec![
+                Resp::Bulk(Some("REPLFRAME".into())),
+                Resp::Bulk(Some("SEQ".into())),
+                Resp::Bulk(Some("7".into())),
+                Resp::Bulk(Some("CRC".into())),
+                Resp::Bulk(Some(format!("{:08x}", crc32(payload)).into())),
+                Resp::Bulk(Some(String::from_utf8(payload.to_vec()).unwrap().into())),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unframe_checks_seq_and_crc() {
+        let set = Resp::Array(
+            ["SET", "k", "v"]
+                .iter()
+                .map(|&s| Resp::Bulk(Some(s.into())))
+                .collect(),
+        );
+        let payload = set.encode();
+        let parse = |data: Vec<u8>| readnext_resp(&Bytes::from(data)).unwrap().0;
+
+        let mut unframer = Unframer::default();
+        assert_eq!(unframer.unframe(set.clone()), Ok(set.clone()));
+        for seq in 0..2 {
+            let framed = parse(frame(&payload, Some(seq), true));
+            assert_eq!(unframer.unframe(framed), Ok(set.clone()));
+        }
+        assert_eq!(
+            unframer.unframe(parse(frame(&payload, Some(3), true))),
+            Err(FrameError::Sequence(2, 3))
+        );
+        assert_eq!(unframer.unframe(set.clone()), Err(FrameError::Unframed));
+
+        // One flipped bit in the value, which still parses.
+        let mut corrupt = frame(&payload, None, true);
+        let at = corrupt.len() - 3;
+        corrupt[at] ^= 0x01;
+        let err = Unframer::default().unframe(parse(corrupt)).unwrap_err();
+        assert!(matches!(err, FrameError::Checksum(..)));
+    }
+
+    #[test]
+    fn test_propagate_only_frames_negotiated_replicas() {
+        let mut replicas = Replicas::default();
+        let addr: HostSpec = "127.0.0.1 6380".parse().unwrap();
+        let mut plain = replicas.register(1, addr.clone(), Capabilities::default(), Arc::default());
+        let mut caps = Capabilities::default();
+        caps.merge(&["seq".to_string()]);
+        let mut framed = replicas.register(2, addr, caps, Arc::default());
+
+        let cmd = Resp::Array(vec![Resp::Bulk(Some("PING".into()))]);
+        replicas.propagate(&cmd);
+        replicas.propagate(&cmd);
+
+        assert_eq!(plain.try_recv().unwrap(), cmd.encode());
+        assert_eq!(
+            framed.try_recv().unwrap(),
+            frame(&cmd.encode(), Some(0), false)
+        );
+        assert_eq!(
+            framed.try_recv().unwrap(),
+            frame(&cmd.encode(), Some(1), false)
+        );
+    }
+
+    #[test]
+    fn test_acks_are_recorded_per_replica() {
+        let mut replicas = Replicas::default();
+        let addr = |s: &str| s.parse::<HostSpec>().unwrap();
+        let mut first = replicas.register(
+            1,
+            addr("10.0.0.1 6380"),
+            Capabilities::default(),
+            Arc::default(),
+        );
+        let _second = replicas.register(
+            2,
+            addr("10.0.0.2 6381"),
+            Capabilities::default(),
+            Arc::default(),
+        );
+
+        replicas.request_ack();
+        assert_eq!(
+            first.try_recv().unwrap(),
+            format_resp!["REPLCONF", "GETACK", "*"].encode()
+        );
+
+        replicas.ack(2, 120);
+        let info = replicas.info();
+        assert!(info.contains("connected_slaves:2"));
+        assert!(info.contains("slave0:ip=10.0.0.1,port=6380,state=online,offset=0,lag=0"));
+        assert!(info.contains("slave1:ip=10.0.0.2,port=6381,state=online,offset=120,lag=0"));
+
+        let ack = Command::from_resp(format_resp!["REPLCONF", "ACK", "120"]).unwrap();
+        assert!(matches!(ack, Command::Replconf(ReplconfArgs::Ack(120))));
+    }
+
+    #[test]
+    fn test_caught_up_ignores_trailing_acks_and_pings() {
+        let mut replicas = Replicas::default();
+        let addr = |s: &str| s.parse::<HostSpec>().unwrap();
+        let _first = replicas.register(
+            1,
+            addr("10.0.0.1 6380"),
+            Capabilities::default(),
+            Arc::default(),
+        );
+        let _second = replicas.register(
+            2,
+            addr("10.0.0.2 6381"),
+            Capabilities::default(),
+            Arc::default(),
+        );
+        let target: HostSpec = "10.0.0.2 6381".parse().unwrap();
+        assert!(replicas.contains(&target));
+
+        let set = format_resp!["SET", "k", "v"];
+        replicas.propagate(&set);
+        replicas.request_ack();
+        replicas.ping();
+        assert_eq!(replicas.caught_up(None), None);
+
+        // Each replica acks the offset from just before the GETACK.
+        let written = set.encode().len() as u64;
+        replicas.ack(2, written);
+        assert_eq!(replicas.caught_up(None), Some(target.clone()));
+        assert_eq!(replicas.caught_up(Some(&target)), Some(target));
+        let other: HostSpec = "10.0.0.1 6380".parse().unwrap();
+        assert_eq!(replicas.caught_up(Some(&other)), None);
+    }
+
+    #[test]
+    fn test_resume_replays_backlog_after_offset() {
+        let mut replicas = Replicas::default();
+        let set = format_resp!["SET", "k", "v"];
+        let del = format_resp!["DEL", "k"];
+        replicas.propagate(&set);
+        replicas.propagate(&del);
+        let after_set = set.encode().len() as u64;
+        assert_eq!(replicas.offset(), after_set + del.encode().len() as u64);
+
+        let addr: HostSpec = "127.0.0.1 6380".parse().unwrap();
+        let mut rx = replicas.register(1, addr, Capabilities::default(), Arc::default());
+        assert!(replicas.resume(1, after_set));
+        assert_eq!(rx.try_recv().unwrap(), del.encode());
+        assert!(rx.try_recv().is_err());
+
+        // Mid-command, ahead of the stream, or from before a reset.
+        assert!(!replicas.resume(1, after_set + 1));
+        assert!(!replicas.resume(1, replicas.offset() + 1));
+        replicas.reset(100);
+        assert!(replicas.resume(1, 100));
+        assert!(!replicas.resume(1, 0));
+    }
+
+    #[test]
+    fn test_replicas_over_their_output_limit_are_dropped() {
+        let limits = OutputLimits {
+            replica: Limit {
+                hard: 50,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            ..OutputLimits::default()
+        };
+        let output = Arc::new(Output::new(Arc::new(std::sync::Mutex::new(limits))));
+        output.set_class(ClientType::Replica);
+        let mut replicas = Replicas::default();
+        let addr: HostSpec = "127.0.0.1 6380".parse().unwrap();
+        let mut rx = replicas.register(1, addr, Capabilities::default(), output.clone());
+
+        let set = format_resp!["SET", "k", "v"];
+        replicas.propagate(&set);
+        assert_eq!(output.pending(), set.encode().len());
+        // Written out, so it no longer counts.
+        output.written(rx.try_recv().unwrap().len());
+        replicas.propagate(&set);
+        assert!(!replicas.is_empty());
+        replicas.propagate(&set);
+        assert!(replicas.is_empty());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,525 @@
+// Serde support for Resp, behind the `serde` feature. `to_resp` turns any
+// Serialize type into a reply tree (structs and maps become RESP maps,
+// sequences and tuples become arrays) and `from_resp` reads one back, so
+// embedders and test harnesses can work with typed values instead of
+// hand-built trees. The server itself doesn't use it; it's part of the
+// library.
+
+use std::fmt::Display;
+
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+
+use crate::protocol::Resp;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SerdeError {
+    #[error("{0}")]
+    Message(String),
+    #[error("unsupported value: {0}")]
+    Unsupported(&'static str),
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+pub fn to_resp<T: Serialize + ?Sized>(value: &T) -> Result<Resp, SerdeError> {
+    value.serialize(Serializer)
+}
+
+pub fn from_resp<T: DeserializeOwned>(resp: Resp) -> Result<T, SerdeError> {
+    T::deserialize(Deserializer(resp))
+}
+
+pub struct Serializer;
+
+// Collects the elements of a sequence, map or struct. Variants carry their
+// name so the finished value can be wrapped as `{name: value}`.
+pub struct Compound {
+    variant: Option<&'static str>,
+    items: Vec<Resp>,
+    pairs: Vec<(Resp, Resp)>,
+    next_key: Option<Resp>,
+}
+
+impl Compound {
+    fn new(variant: Option<&'static str>) -> Self {
+        Compound {
+            variant,
+            items: Vec::new(),
+            pairs: Vec::new(),
+            next_key: None,
+        }
+    }
+
+    fn wrap(variant: Option<&'static str>, value: Resp) -> Resp {
+        match variant {
+            Some(name) => Resp::Map(vec![(Resp::bulk(name), value)]),
+            None => value,
+        }
+    }
+
+    fn finish_seq(self) -> Resp {
+        Compound::wrap(self.variant, Resp::Array(self.items))
+    }
+
+    fn finish_map(self) -> Resp {
+        Compound::wrap(self.variant, Resp::Map(self.pairs))
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Resp;
+    type Error = SerdeError;
+    type SerializeSeq = Compound;
+    type SerializeTuple = Compound;
+    type SerializeTupleStruct = Compound;
+    type SerializeTupleVariant = Compound;
+    type SerializeMap = Compound;
+    type SerializeStruct = Compound;
+    type SerializeStructVariant = Compound;
+
+    fn serialize_bool(self, v: bool) -> Result<Resp, SerdeError> {
+        Ok(Resp::Boolean(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Resp, SerdeError> {
+        Ok(Resp::Integer(v))
+    }
+    fn serialize_u8(self, v: u8) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_u16(self, v: u16) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_u32(self, v: u32) -> Result<Resp, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_u64(self, v: u64) -> Result<Resp, SerdeError> {
+        i64::try_from(v)
+            .map(Resp::Integer)
+            .map_err(|_| SerdeError::Unsupported("integer does not fit in a RESP integer"))
+    }
+    fn serialize_f32(self, v: f32) -> Result<Resp, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f64(self, v: f64) -> Result<Resp, SerdeError> {
+        Ok(Resp::Double(v))
+    }
+    fn serialize_char(self, v: char) -> Result<Resp, SerdeError> {
+        Ok(Resp::bulk(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> Result<Resp, SerdeError> {
+        Ok(Resp::bulk(v))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Resp, SerdeError> {
+        let s = std::str::from_utf8(v)
+            .map_err(|_| SerdeError::Unsupported("bulk strings must be UTF-8"))?;
+        Ok(Resp::bulk(s))
+    }
+    fn serialize_none(self) -> Result<Resp, SerdeError> {
+        Ok(Resp::Null)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Resp, SerdeError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Resp, SerdeError> {
+        Ok(Resp::Null)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Resp, SerdeError> {
+        Ok(Resp::Null)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Resp, SerdeError> {
+        Ok(Resp::bulk(variant))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Resp, SerdeError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Resp, SerdeError> {
+        Ok(Resp::Map(vec![(
+            Resp::bulk(variant),
+            value.serialize(self)?,
+        )]))
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(None))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(None))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(None))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(Some(variant)))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(None))
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(None))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound, SerdeError> {
+        Ok(Compound::new(Some(variant)))
+    }
+}
+
+impl ser::SerializeSeq for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(to_resp(value)?);
+        Ok(())
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_seq())
+    }
+}
+
+impl ser::SerializeTuple for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_seq())
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_seq())
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_seq())
+    }
+}
+
+impl ser::SerializeMap for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        self.next_key = Some(to_resp(key)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or(SerdeError::Unsupported("map value without a key"))?;
+        self.pairs.push((key, to_resp(value)?));
+        Ok(())
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_map())
+    }
+}
+
+impl ser::SerializeStruct for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.pairs.push((Resp::bulk(key), to_resp(value)?));
+        Ok(())
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_map())
+    }
+}
+
+impl ser::SerializeStructVariant for Compound {
+    type Ok = Resp;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+    fn end(self) -> Result<Resp, SerdeError> {
+        Ok(self.finish_map())
+    }
+}
+
+pub struct Deserializer(Resp);
+
+impl<'de> IntoDeserializer<'de, SerdeError> for Resp {
+    type Deserializer = Deserializer;
+
+    fn into_deserializer(self) -> Deserializer {
+        Deserializer(self)
+    }
+}
+
+// Replies often carry numbers as bulk strings (cursors, INFO fields), so
+// numeric targets accept those too, along with big numbers that fit.
+macro_rules! deserialize_number {
+    ($method:ident, $visit:ident, $ty:ty) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            let text = match &self.0 {
+                Resp::Bulk(Some(s)) => s.to_string(),
+                Resp::BigNumber(n) => n.to_string(),
+                _ => return Deserializer(self.0).deserialize_any(visitor),
+            };
+            let n = text
+                .parse::<$ty>()
+                .map_err(|_| de::Error::custom(format!("expected a number, got {:?}", text)))?;
+            visitor.$visit(n)
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Resp::SimpleString(s) => visitor.visit_string(s),
+            Resp::Bulk(Some(s)) => visitor.visit_string(s.to_string()),
+            Resp::Verbatim(_, text) => visitor.visit_string(text),
+            Resp::Integer(i) => visitor.visit_i64(i),
+            Resp::BigNumber(n) => visitor.visit_string(n.to_string()),
+            Resp::Double(d) => visitor.visit_f64(d),
+            Resp::Boolean(b) => visitor.visit_bool(b),
+            Resp::Bulk(None) | Resp::Null | Resp::NullArray => visitor.visit_unit(),
+            Resp::Array(items) | Resp::Set(items) | Resp::Push(items) => {
+                visitor.visit_seq(de::value::SeqDeserializer::new(items.into_iter()))
+            }
+            Resp::Map(pairs) => {
+                visitor.visit_map(de::value::MapDeserializer::new(pairs.into_iter()))
+            }
+            Resp::Attribute(_, reply) => Deserializer(*reply).deserialize_any(visitor),
+            Resp::SimpleError(e) => Err(SerdeError::Message(e)),
+            Resp::Raw(_) | Resp::RDBLen(_) => Err(SerdeError::Unsupported("raw payload")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Resp::Bulk(None) | Resp::Null | Resp::NullArray => visitor.visit_none(),
+            other => visitor.visit_some(Deserializer(other)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Resp::Map(mut pairs) if pairs.len() == 1 => {
+                let (variant, value) = pairs.remove(0);
+                visitor.visit_enum(Enum { variant, value })
+            }
+            Resp::Bulk(Some(s)) => visitor.visit_enum(s.to_string().into_deserializer()),
+            Resp::SimpleString(s) => visitor.visit_enum(s.into_deserializer()),
+            _ => Err(SerdeError::Unsupported(
+                "enums must be a variant name or a single-entry map",
+            )),
+        }
+    }
+
+    deserialize_number!(deserialize_i8, visit_i8, i8);
+    deserialize_number!(deserialize_i16, visit_i16, i16);
+    deserialize_number!(deserialize_i32, visit_i32, i32);
+    deserialize_number!(deserialize_i64, visit_i64, i64);
+    deserialize_number!(deserialize_u8, visit_u8, u8);
+    deserialize_number!(deserialize_u16, visit_u16, u16);
+    deserialize_number!(deserialize_u32, visit_u32, u32);
+    deserialize_number!(deserialize_u64, visit_u64, u64);
+    deserialize_number!(deserialize_f32, visit_f32, f32);
+    deserialize_number!(deserialize_f64, visit_f64, f64);
+
+    serde::forward_to_deserialize_any! {
+        bool char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Enum {
+    variant: Resp,
+    value: Resp,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = SerdeError;
+    type Variant = Deserializer;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Deserializer), SerdeError> {
+        let variant = seed.deserialize(Deserializer(self.variant))?;
+        Ok((variant, Deserializer(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        de::Deserialize::deserialize(self)
+    }
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Role {
+        Master,
+        Replica { offset: u64 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Node {
+        id: u64,
+        name: Option<String>,
+        role: Role,
+        flags: Vec<String>,
+    }
+
+    #[test]
+    fn test_struct_roundtrip() {
+        let node = Node {
+            id: 7,
+            name: None,
+            role: Role::Replica { offset: 42 },
+            flags: vec!["noeviction".to_string()],
+        };
+        let resp = to_resp(&node).unwrap();
+        assert_eq!(
+            resp,
+            Resp::map()
+                .entry("id", 7)
+                .entry("name", Resp::Null)
+                .entry(
+                    "role",
+                    Resp::map()
+                        .entry("Replica", Resp::map().entry("offset", 42).build())
+                        .build()
+                )
+                .entry("flags", vec!["noeviction"])
+                .build()
+        );
+        assert_eq!(from_resp::<Node>(resp).unwrap(), node);
+        assert_eq!(to_resp(&Role::Master).unwrap(), Resp::bulk("Master"));
+    }
+
+    #[test]
+    fn test_numbers_read_from_bulk_strings() {
+        // VSCAN-style reply: a cursor as a bulk string next to a key list.
+        let reply = Resp::array([Resp::bulk("1234"), Resp::from(vec!["a", "b"])]);
+        let (cursor, keys): (u64, Vec<String>) = from_resp(reply).unwrap();
+        assert_eq!(cursor, 1234);
+        assert_eq!(keys, ["a", "b"]);
+        assert!(from_resp::<u64>(Resp::bulk("nope")).is_err());
+    }
+}
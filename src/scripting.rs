@@ -0,0 +1,450 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mlua::{Function, Lua, LuaOptions, LuaSerdeExt, StdLib, Table, Value, Variadic};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    command::CommandError,
+    protocol::{BulkString, Resp},
+};
+
+// EVAL and EVALSHA: a Lua 5.1 script, as in Redis, run with the key names
+// it was given in KEYS and the rest of its arguments in ARGV.
+#[derive(Debug, Clone)]
+pub struct Eval {
+    pub script: Source,
+    pub keys: Vec<BulkString>,
+    pub args: Vec<BulkString>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Body(BulkString),
+    Sha(String),
+}
+
+// Every script EVAL has been sent or SCRIPT LOAD has loaded, by the SHA1
+// of its body, for EVALSHA to run again without sending it. Only SCRIPT
+// FLUSH empties it.
+#[derive(Default)]
+pub struct Scripts(HashMap<String, Arc<str>>);
+
+// A command a script called with redis.call or redis.pcall, for the task
+// running the script to execute, and where the reply goes.
+pub struct Called {
+    pub args: Vec<BulkString>,
+    pub reply: oneshot::Sender<Result<Resp, CommandError>>,
+}
+
+// The script's end of the channel its calls go through.
+pub type Bridge = mpsc::UnboundedSender<Called>;
+
+impl Scripts {
+    // The digest and body of the script to run, cached if it's new.
+    pub fn resolve(&mut self, script: &Source) -> Result<(String, Arc<str>), CommandError> {
+        match script {
+            Source::Body(body) => {
+                let sha = self.load(body);
+                Ok((sha.clone(), self.0[&sha].clone()))
+            }
+            Source::Sha(sha) => {
+                let sha = sha.to_lowercase();
+                match self.0.get(&sha) {
+                    Some(body) => Ok((sha, body.clone())),
+                    None => Err(CommandError::NoScript),
+                }
+            }
+        }
+    }
+
+    pub fn load(&mut self, body: &str) -> String {
+        let sha = sha1(body);
+        self.0.entry(sha.clone()).or_insert_with(|| body.into());
+        sha
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.0.contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&mut self) {
+        self.0.clear();
+    }
+}
+
+pub fn sha1(body: &str) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+// Runs a script to completion in a Lua state of its own and converts what
+// it returns to a reply. It blocks for as long as the script runs, and for
+// each command it calls until the reply comes back over the bridge.
+pub fn run(
+    sha: &str,
+    body: &str,
+    keys: &[BulkString],
+    args: &[BulkString],
+    bridge: Bridge,
+) -> Result<Resp, CommandError> {
+    let failed = |e: mlua::Error| failed(&e, sha);
+    let lua = sandbox().map_err(failed)?;
+    connect(&lua, bridge).map_err(failed)?;
+    let globals = lua.globals();
+    globals
+        .set("KEYS", sequence(&lua, keys).map_err(failed)?)
+        .map_err(failed)?;
+    globals
+        .set("ARGV", sequence(&lua, args).map_err(failed)?)
+        .map_err(failed)?;
+
+    let function = load(&lua, body)?;
+    let value = function.call::<_, Value>(()).map_err(failed)?;
+    reply(value)
+}
+
+// A Lua state with only the libraries Redis gives scripts, cjson among
+// them, and a `redis` table with the helpers that don't call commands.
+pub fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    {
+        let globals = lua.globals();
+        for unsafe_global in ["dofile", "loadfile"] {
+            globals.set(unsafe_global, Value::Nil)?;
+        }
+        let redis = lua.create_table()?;
+        let status_reply = lua.create_function(|lua, status: String| field(lua, "ok", status))?;
+        let error_reply = lua.create_function(|lua, e: String| field(lua, "err", e))?;
+        redis.set("status_reply", status_reply)?;
+        redis.set("error_reply", error_reply)?;
+        globals.set("redis", redis)?;
+
+        let cjson = lua.create_table()?;
+        let encode = lua.create_function(|_, value: Value| {
+            serde_json::to_string(&value).map_err(mlua::Error::external)
+        })?;
+        let decode = lua.create_function(|lua, json: String| {
+            let value: serde_json::Value =
+                serde_json::from_str(&json).map_err(mlua::Error::external)?;
+            lua.to_value(&value)
+        })?;
+        cjson.set("encode", encode)?;
+        cjson.set("decode", decode)?;
+        globals.set("cjson", cjson)?;
+    }
+    Ok(lua)
+}
+
+// redis.call and redis.pcall, which send a command over the bridge and wait
+// for its reply. If the command fails, call raises its error and pcall
+// returns it as an error table, as in Redis.
+pub fn connect(lua: &Lua, bridge: Bridge) -> mlua::Result<()> {
+    let redis: Table = lua.globals().get("redis")?;
+    let protected = bridge.clone();
+    let call = lua.create_function(move |lua, args: Variadic<Value>| {
+        let reply = dispatch(&bridge, args).map_err(mlua::Error::external)?;
+        to_lua(lua, reply)
+    })?;
+    let pcall =
+        lua.create_function(
+            move |lua, args: Variadic<Value>| match dispatch(&protected, args) {
+                Ok(reply) => to_lua(lua, reply),
+                Err(e) => to_lua(lua, e.to_resp()),
+            },
+        )?;
+    redis.set("call", call)?;
+    redis.set("pcall", pcall)
+}
+
+fn dispatch(bridge: &Bridge, args: Variadic<Value>) -> Result<Resp, CommandError> {
+    let args = args
+        .into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(BulkString::from(s.to_string_lossy().into_owned())),
+            Value::Integer(n) => Ok(BulkString::from(n.to_string())),
+            Value::Number(n) => Ok(BulkString::from(n.to_string())),
+            _ => Err(CommandError::Script(
+                "ERR Lua redis lib command arguments must be strings or integers".into(),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if args.is_empty() {
+        return Err(CommandError::Script(
+            "ERR Please specify at least one argument for this redis lib call".into(),
+        ));
+    }
+    send(bridge, args)
+}
+
+// Runs a command over the bridge, waiting for its reply.
+pub fn send(bridge: &Bridge, args: Vec<BulkString>) -> Result<Resp, CommandError> {
+    let (reply, replied) = oneshot::channel();
+    let gone = || CommandError::Script("ERR the script's connection went away".into());
+    bridge.send(Called { args, reply }).map_err(|_| gone())?;
+    replied.blocking_recv().unwrap_or_else(|_| Err(gone()))
+}
+
+fn field<'lua>(lua: &'lua Lua, name: &str, value: String) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(name, value)?;
+    Ok(table)
+}
+
+// Arguments as a Lua array, as in KEYS and ARGV.
+pub fn sequence<'lua>(lua: &'lua Lua, strings: &[BulkString]) -> mlua::Result<Table<'lua>> {
+    lua.create_sequence_from(strings.iter().map(|s| s.as_str()))
+}
+
+// The reply to what a script returned, or the error it returned.
+pub fn reply(value: Value) -> Result<Resp, CommandError> {
+    match to_resp(value) {
+        Resp::SimpleError(e) => Err(CommandError::Script(e)),
+        reply => Ok(reply),
+    }
+}
+
+// A script that raised an error, named as Redis names it. The error a
+// command replied to redis.call with is kept as it was.
+pub fn failed(e: &mlua::Error, script: &str) -> CommandError {
+    match raised(e) {
+        Some(e) => CommandError::Script(format!("{} script: {}", e, script)),
+        None => CommandError::Script(format!("ERR {} script: {}", message(e), script)),
+    }
+}
+
+fn raised(e: &mlua::Error) -> Option<&CommandError> {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => raised(cause),
+        e => e.downcast_ref(),
+    }
+}
+
+// Whether a script compiles, for SCRIPT LOAD to refuse one that doesn't.
+pub fn compile(body: &str) -> Result<(), CommandError> {
+    let lua = Lua::new_with(StdLib::NONE, LuaOptions::default())
+        .map_err(|e| CommandError::Script(format!("ERR {}", message(&e))))?;
+    load(&lua, body).map(drop)
+}
+
+fn load<'lua>(lua: &'lua Lua, body: &str) -> Result<Function<'lua>, CommandError> {
+    lua.load(body)
+        .set_name("@user_script")
+        .into_function()
+        .map_err(|e| {
+            CommandError::Script(format!(
+                "ERR Error compiling script (new function): {}",
+                message(&e)
+            ))
+        })
+}
+
+// Lua values as Redis replies them: numbers are truncated to integers,
+// false and nil are null, and a table is an array up to its first nil,
+// unless it has an `err` or `ok` field for an error or status reply.
+fn to_resp(value: Value) -> Resp {
+    match value {
+        Value::Boolean(true) => Resp::Integer(1),
+        Value::Integer(n) => Resp::Integer(n),
+        Value::Number(n) => Resp::Integer(n as i64),
+        Value::String(s) => Resp::bulk(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Table(table) => table_to_resp(table),
+        _ => Resp::Bulk(None),
+    }
+}
+
+// A reply as RESP2 would carry it, as Redis gives scripts replies: nulls
+// are false, and a status or an error is a table with an `ok` or `err`
+// field.
+fn to_lua<'lua>(lua: &'lua Lua, reply: Resp) -> mlua::Result<Value<'lua>> {
+    let string = |s: &str| lua.create_string(s).map(Value::String);
+    let sequence = |items: Vec<Resp>| {
+        let items = items.into_iter().map(|item| to_lua(lua, item));
+        lua.create_sequence_from(items.collect::<mlua::Result<Vec<_>>>()?)
+            .map(Value::Table)
+    };
+    match reply {
+        Resp::SimpleString(status) => field(lua, "ok", status).map(Value::Table),
+        Resp::SimpleError(e) => field(lua, "err", e).map(Value::Table),
+        Resp::Integer(n) => Ok(Value::Integer(n)),
+        Resp::Boolean(b) => Ok(Value::Integer(b as i64)),
+        Resp::Bulk(Some(s)) => string(s.as_str()),
+        Resp::Verbatim(_, text) => string(&text),
+        Resp::BigNumber(n) => string(n.as_str()),
+        Resp::Double(d) if d.is_nan() => string("nan"),
+        Resp::Double(d) => string(&d.to_string()),
+        Resp::Array(items) | Resp::Set(items) | Resp::Push(items) => sequence(items),
+        Resp::Map(pairs) => sequence(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect()),
+        Resp::Attribute(_, reply) => to_lua(lua, *reply),
+        Resp::Bulk(None) | Resp::Null | Resp::NullArray | Resp::Raw(_) | Resp::RDBLen(_) => {
+            Ok(Value::Boolean(false))
+        }
+    }
+}
+
+fn table_to_resp(table: Table) -> Resp {
+    let field = |name| match table.raw_get::<_, Value>(name) {
+        Ok(Value::String(s)) => Some(s.to_string_lossy().into_owned()),
+        _ => None,
+    };
+    if let Some(e) = field("err") {
+        return Resp::error(e);
+    }
+    if let Some(status) = field("ok") {
+        return Resp::simple(status);
+    }
+    let items = table
+        .clone()
+        .sequence_values::<Value>()
+        .map_while(Result::ok);
+    Resp::Array(items.map(to_resp).collect())
+}
+
+// What Lua said went wrong, without mlua's wrapping or the traceback.
+pub fn message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        mlua::Error::RuntimeError(message) => message
+            .split("\nstack traceback:")
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        mlua::Error::CallbackError { cause, .. } => message(cause),
+        e => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a script with the commands it calls echoed back as their replies,
+    // except FAIL, which fails.
+    fn eval(body: &str, keys: &[&str], args: &[&str]) -> Result<Resp, CommandError> {
+        let bulks = |strings: &[&str]| {
+            strings
+                .iter()
+                .map(|&s| BulkString::from(s))
+                .collect::<Vec<_>>()
+        };
+        let (bridge, mut calls) = mpsc::unbounded_channel::<Called>();
+        std::thread::spawn(move || {
+            while let Some(Called { args, reply }) = calls.blocking_recv() {
+                let _ = reply.send(match args[0].as_str() {
+                    "FAIL" => Err(CommandError::Script("WRONGTYPE failed".into())),
+                    _ => Ok(Resp::array(args)),
+                });
+            }
+        });
+        run(&sha1(body), body, &bulks(keys), &bulks(args), bridge)
+    }
+
+    #[test]
+    fn test_script_replies_convert_as_in_redis() {
+        assert_eq!(
+            eval(
+                "return {KEYS[1], ARGV[2], #ARGV, 3.9, true, {false}, nil, 'dropped'}",
+                &["k"],
+                &["a", "b"]
+            )
+            .unwrap(),
+            Resp::Array(vec![
+                Resp::bulk("k"),
+                Resp::bulk("b"),
+                Resp::Integer(2),
+                Resp::Integer(3),
+                Resp::Integer(1),
+                Resp::Array(vec![Resp::Bulk(None)]),
+            ])
+        );
+        assert_eq!(
+            eval("return {ok='FINE'}", &[], &[]).unwrap(),
+            Resp::simple("FINE")
+        );
+        assert_eq!(
+            eval("return {{err='ERR nested'}}", &[], &[]).unwrap(),
+            Resp::Array(vec![Resp::error("ERR nested")])
+        );
+        assert_eq!(
+            eval("return {err='MY failure'}", &[], &[])
+                .unwrap_err()
+                .to_string(),
+            "MY failure"
+        );
+        assert!(eval("return", &[], &[]).unwrap() == Resp::Bulk(None));
+    }
+
+    #[test]
+    fn test_script_errors_and_sandbox() {
+        let error = eval("return (", &[], &[]).unwrap_err().to_string();
+        assert!(
+            error.starts_with("ERR Error compiling script (new function): user_script:1:"),
+            "{}",
+            error
+        );
+        let body = "error('boom')";
+        assert_eq!(
+            eval(body, &[], &[]).unwrap_err().to_string(),
+            format!("ERR user_script:1: boom script: {}", sha1(body))
+        );
+        assert!(eval("return io.open('x')", &[], &[]).is_err());
+        assert!(eval("return dofile('x')", &[], &[]).is_err());
+
+        let mut scripts = Scripts::default();
+        let sha = sha1("return 1");
+        assert!(matches!(
+            scripts.resolve(&Source::Sha(sha.clone())),
+            Err(CommandError::NoScript)
+        ));
+        scripts.resolve(&Source::Body("return 1".into())).unwrap();
+        let (found, body) = scripts.resolve(&Source::Sha(sha.to_uppercase())).unwrap();
+        assert_eq!((found, &*body), (sha.clone(), "return 1"));
+        scripts.flush();
+        assert!(!scripts.exists(&sha));
+        assert_eq!(scripts.load("return 1"), sha);
+        assert!(scripts.exists(&sha));
+        assert!(compile("return (").is_err() && compile("return 1").is_ok());
+    }
+
+    #[test]
+    fn test_scripts_call_commands_and_helpers() {
+        assert_eq!(
+            eval("return redis.call('ECHO', KEYS[1], 2, 2.5)", &["k"], &[]).unwrap(),
+            Resp::array(["ECHO", "k", "2", "2.5"])
+        );
+        let body = "redis.call('FAIL'); return 1";
+        assert_eq!(
+            eval(body, &[], &[]).unwrap_err().to_string(),
+            format!("WRONGTYPE failed script: {}", sha1(body))
+        );
+        assert_eq!(
+            eval("local e = redis.pcall('FAIL'); return {e.err}", &[], &[]).unwrap(),
+            Resp::array(["WRONGTYPE failed"])
+        );
+        assert!(eval("return redis.call({})", &[], &[])
+            .unwrap_err()
+            .to_string()
+            .starts_with("ERR Lua redis lib command arguments must be strings or integers"));
+
+        assert_eq!(
+            eval("return redis.status_reply('DONE')", &[], &[]).unwrap(),
+            Resp::simple("DONE")
+        );
+        assert_eq!(
+            eval("return redis.error_reply('MINE bad')", &[], &[])
+                .unwrap_err()
+                .to_string(),
+            "MINE bad"
+        );
+        assert_eq!(
+            eval(
+                "local t = cjson.decode(ARGV[1]); return {t.a[2], cjson.encode({n = t.n})}",
+                &[],
+                &[r#"{"a":["x","y"],"n":3}"#]
+            )
+            .unwrap(),
+            Resp::array(["y", r#"{"n":3}"#])
+        );
+    }
+}
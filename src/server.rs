@@ -1,21 +1,50 @@
 use std::{
-    collections::HashMap,
-    net::{IpAddr, Ipv4Addr},
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
-    sync::Arc,
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use bytes::BytesMut;
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::Mutex,
+    sync::{
+        mpsc::{Receiver, UnboundedReceiver},
+        Mutex, MutexGuard, Notify,
+    },
 };
+use tokio_util::codec::Framed;
 
 use crate::{
-    command,
-    protocol::{readnext_resp, Resp, RespEncoding},
+    actor::StoreActor,
+    atomic::Gate,
+    clients::{Activity, ClientType, Clients, Control},
+    command::{self, ClientArgs, Command, CommandError, PsyncArgs, ReplconfArgs},
+    command_table,
+    config::ConfigFile,
+    context::ConnCtx,
+    diskless,
+    eviction::{self, Eviction},
+    failover::{self, Failover},
+    functions::Functions,
+    memprof,
+    middleware::{self, Call, CommandStats, Outcome, Server},
+    obuf::Output,
+    outbox::Outbox,
+    persistence::Persistence,
+    protocol::{BulkString, Limits, Resp, RespCodec, RespError},
+    pubsub::{self, PubSub},
+    replica::MasterLink,
+    replication::{random_id, Capabilities, Replicas},
+    scripting::Scripts,
+    store::Store,
+    transaction::{Transaction, Watches},
+    value::Value,
 };
 
 pub enum Role {
@@ -25,155 +54,1373 @@ pub enum Role {
 
 pub struct Info {
     pub role: Role,
+    // The history our dataset follows. After a promotion, replid2 is the id
+    // of the history we left, which we still share up to second_repl_offset
+    // and can continue replicas of.
     pub master_replid: String,
-    pub master_repl_offset: u64,
+    master_replid2: Option<(String, u64)>,
+    pub replicas: Replicas,
+    pub persistence: Persistence,
+    pub eviction: Eviction,
+    pub clients: Clients,
+    // Whether a replica refuses writes from its own clients.
+    pub replica_read_only: bool,
+    // Whether full syncs stream a snapshot to replicas that accept one, and
+    // how long to wait for other replicas to share it.
+    pub diskless_sync: bool,
+    pub diskless_sync_delay: Duration,
+    // A master refuses writes unless this many replicas have acked within
+    // the last `min_replicas_max_lag` seconds. Off if either is zero.
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+    // Set on replicas only.
+    pub master_link: Option<MasterLink>,
+    // Addresses and port we accept connections on. The port is announced to
+    // masters unless `announce_port` overrides it, and `announce_ip` is
+    // announced if set.
+    pub bind: Vec<String>,
+    pub port: u16,
+    pub announce_ip: Option<String>,
+    pub announce_port: Option<u16>,
+    // Set while a FAILOVER is pausing writes.
+    pub failover: Option<Failover>,
+    // Client writes that have been let through but not yet propagated.
+    writes_in_flight: usize,
+    // Tells main to stop serving and exit, once SHUTDOWN has prepared for it.
+    pub shutdown: Arc<Notify>,
+    // Whether expired keys are deleted in the background, as well as when
+    // they are read. Only DEBUG SET-ACTIVE-EXPIRE turns it off.
+    pub active_expire: bool,
+    // Shared with connections, which count calls without the Info lock.
+    pub command_stats: Arc<CommandStats>,
+    pub config_file: ConfigFile,
+    // Set with --store-actor, to run data commands on.
+    pub store_actor: Option<StoreActor>,
+    // Shared with pub/sub commands, which take it from here and then work
+    // without the Info lock.
+    pub pubsub: Arc<PubSub>,
+    // Held exclusively while EXEC or a script runs, so nothing runs between
+    // their commands.
+    pub gate: Gate,
+    // Writes held back while EXEC or a script runs, to go out together once
+    // it's done.
+    batched: Option<Vec<(Resp, Vec<String>)>>,
+    // Whether a script reaches replicas and the AOF as the writes it made,
+    // or as the EVAL or FCALL that ran it, for lua-replicate-commands.
+    pub lua_replicate_commands: bool,
+    // Scripts EVAL has run, for EVALSHA.
+    pub scripts: Scripts,
+    // Libraries FUNCTION LOAD has loaded, for FCALL.
+    pub functions: Functions,
 }
 
 impl Info {
-    pub fn new(role: Role) -> Self {
+    pub fn new(role: Role, persistence: Persistence, eviction: Eviction, clients: Clients) -> Self {
         Self {
             role,
-            master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-            master_repl_offset: 0,
+            master_replid: random_id(),
+            master_replid2: None,
+            replicas: Replicas::default(),
+            persistence,
+            eviction,
+            clients,
+            replica_read_only: true,
+            diskless_sync: false,
+            diskless_sync_delay: Duration::from_secs(5),
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            master_link: None,
+            bind: vec![Ipv4Addr::LOCALHOST.to_string()],
+            port: 6379,
+            announce_ip: None,
+            announce_port: None,
+            failover: None,
+            writes_in_flight: 0,
+            shutdown: Arc::new(Notify::new()),
+            active_expire: true,
+            command_stats: Arc::default(),
+            config_file: ConfigFile::default(),
+            store_actor: None,
+            pubsub: Arc::default(),
+            gate: Gate::default(),
+            batched: None,
+            lua_replicate_commands: true,
+            scripts: Scripts::default(),
+            functions: Functions::default(),
         }
     }
     pub fn role(&self) -> String {
         match self.role {
-            Role::Master => "role:master".to_string(),
-            Role::Slave => "role:slave".to_string(),
+            Role::Master => "master".to_string(),
+            Role::Slave => "slave".to_string(),
         }
     }
+    // Hands a write command that just executed to everything downstream of
+    // the dataset: connected replicas and the append-only file. During a
+    // batch it waits for the batch to end.
+    pub fn propagate(&mut self, cmd: &Resp, keys: &[String]) {
+        if let Some(batched) = &mut self.batched {
+            batched.push((cmd.clone(), keys.to_vec()));
+            return;
+        }
+        self.replicas.propagate(cmd);
+        self.persistence.record_write(cmd, keys);
+    }
+    // Starts holding back writes for a batch, unless one already is, as for
+    // a script EXEC runs. Returns whether it started, and so should end it.
+    pub fn begin_batch(&mut self) -> bool {
+        let begun = self.batched.is_none();
+        if begun {
+            self.batched = Some(Vec::new());
+        }
+        begun
+    }
+    // Propagates a batch's writes, between MULTI and EXEC if there's more
+    // than one, so replicas and the AOF apply all of them or none.
+    pub fn end_batch(&mut self) {
+        let Some(batched) = self.batched.take() else {
+            return;
+        };
+        let wrapped = batched.len() > 1;
+        if wrapped {
+            self.propagate(&Resp::array(["MULTI"]), &[]);
+        }
+        for (cmd, keys) in batched {
+            self.propagate(&cmd, &keys);
+        }
+        if wrapped {
+            self.propagate(&Resp::array(["EXEC"]), &[]);
+        }
+    }
+    // Writes from clients would diverge a replica from its master, so they
+    // are only taken when the operator has asked for it.
+    pub fn rejects_writes(&self) -> bool {
+        matches!(self.role, Role::Slave) && self.replica_read_only
+    }
+    // Why a write would be refused right now, if it would. MULTI asks as
+    // writes are queued, so EXEC isn't left to fail them one by one.
+    pub fn refuses_write(&self) -> Result<(), CommandError> {
+        if self.rejects_writes() {
+            return Err(CommandError::ReadOnly);
+        }
+        if self.lacks_good_replicas() {
+            return Err(CommandError::NoReplicas);
+        }
+        self.persistence.write_error().map_or(Ok(()), Err)
+    }
+    // Whether too few replicas are keeping up for a write to be safe from a
+    // failover. Only checked on masters; replicas relay what they are sent.
+    pub fn lacks_good_replicas(&self) -> bool {
+        self.min_replicas_to_write > 0
+            && self.min_replicas_max_lag > 0
+            && matches!(self.role, Role::Master)
+            && self.good_replicas() < self.min_replicas_to_write
+    }
+    fn good_replicas(&self) -> usize {
+        self.replicas
+            .acked_within(Duration::from_secs(self.min_replicas_max_lag))
+    }
+    // A replica that has every write, once no more are on their way.
+    pub fn caught_up_replica(&self, target: Option<&HostSpec>) -> Option<HostSpec> {
+        if self.writes_in_flight > 0 {
+            return None;
+        }
+        self.replicas.caught_up(target)
+    }
+    // Reply to ROLE: a master lists its replicas at their announced
+    // addresses, a replica describes its link to the master.
+    pub fn role_reply(&self) -> Resp {
+        let offset = Resp::Integer(self.replicas.offset() as i64);
+        match &self.master_link {
+            Some(link) => Resp::Array(vec![
+                Resp::bulk("slave"),
+                Resp::bulk(link.host.as_str()),
+                Resp::Integer(link.port as i64),
+                Resp::bulk(if link.up { "connected" } else { "connect" }),
+                offset,
+            ]),
+            None => Resp::Array(vec![Resp::bulk("master"), offset, self.replicas.role()]),
+        }
+    }
+    // Starts a new history, remembering the old one, as when a replica is
+    // promoted or its master continues it under a new id.
+    pub fn shift_replid(&mut self, replid: String) {
+        let old = std::mem::replace(&mut self.master_replid, replid);
+        self.master_replid2 = Some((old, self.replicas.offset() + 1));
+    }
+    // Adopts a master's history outright, as after a full resync.
+    pub fn set_replid(&mut self, replid: String) {
+        self.master_replid = replid;
+        self.master_replid2 = None;
+    }
+    // Whether a replica that is missing `offset` onwards of history `replid`
+    // shares our history up to there.
+    pub fn shares_history(&self, replid: &str, offset: u64) -> bool {
+        replid == self.master_replid
+            || matches!(&self.master_replid2, Some((id, end)) if id == replid && offset <= *end)
+    }
     pub fn id(&self) -> String {
         self.master_replid.to_string()
     }
+    // The Stats section of INFO.
+    pub fn stats(&self) -> String {
+        format!("# Stats\n{}", self.clients.stats())
+    }
+    // CONFIG RESETSTAT: zeroes the counters INFO reports since startup.
+    pub fn reset_stats(&mut self) {
+        self.persistence.reset_stats();
+        self.clients.reset_stats();
+        self.command_stats.reset();
+    }
     pub fn replication(&self) -> String {
-        format!(
-            "# Replication\nrole:{}\nmaster_replid:{}\nmaster_repl_offset:{}",
-            self.role(),
+        let mut sections = vec![format!("# Replication\nrole:{}", self.role())];
+        if let Some(link) = &self.master_link {
+            sections.push(link.info());
+        }
+        sections.push(self.replicas.info());
+        if self.min_replicas_to_write > 0 && self.min_replicas_max_lag > 0 {
+            sections.push(format!("min_slaves_good_slaves:{}", self.good_replicas()));
+        }
+        sections.push(format!(
+            "master_failover_state:{}",
+            failover::state(self.failover.as_ref())
+        ));
+        let (replid2, second_offset) = match &self.master_replid2 {
+            Some((id, offset)) => (id.as_str(), *offset as i64),
+            None => ("0000000000000000000000000000000000000000", -1),
+        };
+        sections.push(format!(
+            "master_replid:{}\nmaster_replid2:{}\nmaster_repl_offset:{}\nsecond_repl_offset:{}",
             self.master_replid,
-            self.master_repl_offset
-        )
+            replid2,
+            self.replicas.offset(),
+            second_offset
+        ));
+        sections.join("\n")
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct HostSpec {
-    host: String,
-    port: u16,
+    pub host: String,
+    pub port: u16,
 }
 
+// Accepts "host port" and "host:port", where an IPv6 host must be
+// bracketed in the second form. Hostnames are kept as given and resolved
+// when connecting.
 impl FromStr for HostSpec {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let components: Vec<&str> = s.split_whitespace().collect();
+        let (host, port) = match components[..] {
+            [host, port] => (host, port),
+            [addr] => match addr.strip_prefix('[') {
+                Some(rest) => rest.split_once("]:").ok_or("Invalid IPv6 address")?,
+                None => match addr.rsplit_once(':') {
+                    Some((host, _)) if host.contains(':') => {
+                        return Err("IPv6 addresses must be written as [addr]:port".to_string())
+                    }
+                    Some(split) => split,
+                    None => return Err("Invalid Master Host and Port specification.".to_string()),
+                },
+            },
+            _ => return Err("Invalid Master Host and Port specification.".to_string()),
+        };
 
-        if components.len() != 2 {
-            return Err("Invalid Master Host and Port specification.".to_string());
-        }
-
-        let host = components[0];
-        let port = components[1].parse::<u16>().map_err(|_| "Invalid Port")?;
+        let port = port.parse::<u16>().map_err(|_| "Invalid Port")?;
 
         if !(1024..=65535).contains(&port) {
             return Err("Port must be between 1024 and 65535".to_string());
         }
 
-        let ip_addr: IpAddr = match host.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                if host != "localhost" {
-                    return Err("Invalid host".to_string());
-                }
-                IpAddr::V4(Ipv4Addr::LOCALHOST)
-            }
-        };
-
         Ok(HostSpec {
-            host: ip_addr.to_string(),
+            host: parse_host(host)?,
             port,
         })
     }
 }
 
-impl ToString for HostSpec {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.host, self.port)
+impl fmt::Display for HostSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
     }
 }
 
-pub async fn _repl_handshake(address: HostSpec) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(address.to_string()).await?;
-    stream.write_all(b"*1\r\n$4\r\nping\r\n").await?;
-    Ok(())
+// An IPv4 or IPv6 address, optionally bracketed, or a hostname. Addresses
+// are normalized and localhost becomes 127.0.0.1, so that the same host
+// always compares equal.
+pub fn parse_host(host: &str) -> Result<String, String> {
+    let literal = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+    if host.eq_ignore_ascii_case("localhost") {
+        return Ok(Ipv4Addr::LOCALHOST.to_string());
+    }
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.len() > 253 || !name.split('.').all(valid_label) {
+        return Err("Invalid host".to_string());
+    }
+    Ok(name.to_ascii_lowercase())
 }
 
 #[derive(Clone)]
 pub struct Query {
-    pub value: String,
-    pub created_at: SystemTime,
+    // Shared rather than copied by every read that returns it.
+    pub value: Value,
     pub expiry: Option<SystemTime>,
+    pub access: Access,
+}
+
+// How a key has been read, for the eviction policies and DEBUG OBJECT.
+// Reads record themselves here while holding only a shared lock on the
+// key's shard, so concurrent touches may occasionally lose an update, as
+// they can in Redis too.
+pub struct Access {
+    // Milliseconds since the epoch.
+    last: AtomicU64,
+    hits: AtomicU64,
+    // The LFU counter, which eviction::lfu_touch keeps.
+    counter: AtomicU8,
+}
+
+impl Access {
+    pub fn new(last: SystemTime, hits: u64, counter: u8) -> Self {
+        let last = last.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            last: AtomicU64::new(last.as_millis() as u64),
+            hits: AtomicU64::new(hits),
+            counter: AtomicU8::new(counter),
+        }
+    }
+
+    pub fn last(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn counter(&self) -> u8 {
+        self.counter.load(Ordering::Relaxed)
+    }
+
+    pub fn set_counter(&self, counter: u8) {
+        self.counter.store(counter, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Self::new(self.last(), self.hits(), self.counter())
+    }
+}
+
+impl Query {
+    // Strings with a shared copy keep that instead of their own.
+    pub fn new(value: impl Into<Value>, expiry: Option<SystemTime>, now: SystemTime) -> Self {
+        let value = match value.into() {
+            Value::String(s) => Value::String(BulkString::shared(&s).unwrap_or(s)),
+            value => value,
+        };
+        Self {
+            value,
+            expiry,
+            access: Access::new(now, 0, eviction::LFU_INIT_VAL),
+        }
+    }
+
+    // Records a read at `now`, for the eviction policies.
+    pub fn touch(&self, now: SystemTime) {
+        eviction::lfu_touch(&self.access, now);
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.access
+            .last
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+        self.access.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expiry, Some(expiry) if expiry < now)
+    }
+}
+
+// What every command touches that can be reached without the Info lock,
+// taken from Info once per connection.
+struct Lockless {
+    activity: Arc<Activity>,
+    pausing: Arc<AtomicBool>,
+    command_stats: Arc<CommandStats>,
+    store_actor: Option<StoreActor>,
+    gate: Gate,
 }
 
 pub struct Handler {
-    stream: TcpStream,
+    ctx: ConnCtx,
+    addr: SocketAddr,
+    framed: Framed<TcpStream, RespCodec>,
     info: Arc<Mutex<Info>>,
-    buf: BytesMut,
+    capabilities: Capabilities,
+    // Address announced with REPLCONF listening-port and ip-address, if
+    // this is a replica.
+    listening_port: Option<u16>,
+    announced_ip: Option<String>,
+    // Propagated writes for this connection once it has resynced, preceded
+    // by a streamed snapshot for a diskless sync.
+    replica_stream: Option<UnboundedReceiver<Bytes>>,
+    replica_snapshot: Option<Receiver<Bytes>>,
+    control: UnboundedReceiver<Control>,
+    // Replies not yet written. Framed is only used to read requests.
+    outbox: Outbox,
+    // What the outbox and the connection's channels hold, against its
+    // output buffer limit.
+    output: Arc<Output>,
+    // Set from MULTI until EXEC or DISCARD.
+    transaction: Option<Transaction>,
+    // Kept from WATCH until EXEC, DISCARD or UNWATCH.
+    watches: Watches,
+    // Scratch space for a write's keys, reused from one request to the next.
+    keys: Vec<String>,
+    // Set by QUIT: the connection closes once its reply is written.
+    quitting: bool,
 }
 
 impl Handler {
-    pub fn new(stream: TcpStream, server: Arc<Mutex<Info>>) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        addr: SocketAddr,
+        server: Arc<Mutex<Info>>,
+        id: u64,
+        control: UnboundedReceiver<Control>,
+        limits: Limits,
+        buffer_size: usize,
+    ) -> Self {
         Self {
-            stream,
+            ctx: ConnCtx::new(id),
+            addr,
+            framed: Framed::with_capacity(stream, RespCodec::new(limits), buffer_size),
             info: server,
-            buf: BytesMut::with_capacity(1024),
+            capabilities: Capabilities::default(),
+            listening_port: None,
+            announced_ip: None,
+            replica_stream: None,
+            replica_snapshot: None,
+            control,
+            outbox: Outbox::default(),
+            output: Arc::default(),
+            transaction: None,
+            watches: Watches::default(),
+            keys: Vec::new(),
+            quitting: false,
         }
     }
-    pub async fn handle_stream(&mut self, cache: Arc<Mutex<HashMap<String, Query>>>) {
+    pub fn id(&self) -> u64 {
+        self.ctx.id
+    }
+    // Stops watching keys for a connection that has closed.
+    pub async fn unwatch(&mut self, cache: &Store) {
+        self.watches.clear(cache).await;
+    }
+    pub async fn handle_stream(&mut self, cache: Arc<Store>) -> anyhow::Result<()> {
+        let lockless = {
+            let info = self.info.lock().await;
+            self.output = info.clients.output(self.ctx.id).unwrap_or_default();
+            Lockless {
+                activity: info.clients.activity(self.ctx.id).unwrap_or_default(),
+                pausing: info.clients.pausing(),
+                command_stats: info.command_stats.clone(),
+                store_actor: info.store_actor.clone(),
+                gate: info.gate.clone(),
+            }
+        };
         loop {
-            let req = self.read_resp().await.unwrap();
-
-            let resp_queue = if let Some(req) = req {
-                let cmd = command::Command::from_resp(req).unwrap();
-                command::execute_command(cmd, cache.clone(), self.info.clone())
-                    .await
-                    .unwrap()
-            } else {
+            // Replies are only buffered until every request already read has
+            // been answered, so a pipelined batch goes out in one write.
+            let req = match self.framed.next().now_or_never() {
+                Some(req) => req,
+                None => {
+                    self.flush().await?;
+                    tokio::select! {
+                        req = self.framed.next() => req,
+                        Some(control) = self.control.recv() => match control {
+                            Control::Kill => return Ok(()),
+                            Control::Message(message) => {
+                                // Already counted by whoever sent it.
+                                self.outbox.push(&message, self.ctx.protocol);
+                                continue;
+                            }
+                        },
+                    }
+                }
+            };
+            let Some(req) = req else {
                 break;
             };
-            println!("sending response: {:?}", resp_queue);
+            let req = match req {
+                Ok(req) => req,
+                Err(RespError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    // There's no way to find the next frame boundary after a
+                    // framing error, so report it and hang up like Redis does.
+                    self.write_resp(Resp::error(format!("ERR Protocol error: {}", e)))
+                        .await?;
+                    self.flush().await?;
+                    return Ok(());
+                }
+            };
+            let (resp_queue, is_sync) = match self.run_command(req, &cache, &lockless).await {
+                Ok(result) => result,
+                Err(e) => (vec![e.to_resp()], false),
+            };
             for r in resp_queue {
-                match r {
-                    Resp::SimpleString(x) => {
-                        if x.starts_with("FULLRESYNC") {
-                            self.write_resp(Resp::RDBLen(crate::RDB_64.len()))
-                                .await
-                                .unwrap();
-                            self.write_resp(Resp::Verbatim(crate::RDB_64.to_string()))
-                                .await
-                                .unwrap();
-                        } else {
-                            self.write_resp(Resp::SimpleString(x)).await.unwrap();
-                        }
+                self.write_resp(r).await?;
+            }
+            if self.quitting {
+                return self.flush().await;
+            }
+            if is_sync {
+                self.flush().await?;
+                self.info.lock().await.clients.set_replica(self.ctx.id);
+                let result = self.serve_replica().await;
+                self.info.lock().await.replicas.remove(self.ctx.id);
+                return result;
+            }
+        }
+        // A client that stops sending still gets its last replies.
+        self.flush().await
+    }
+    // Parses and executes one request, returning the replies and whether the
+    // connection has just become a replica.
+    async fn run_command(
+        &mut self,
+        req: Resp,
+        cache: &Arc<Store>,
+        lockless: &Lockless,
+    ) -> Result<(Vec<Resp>, bool), CommandError> {
+        // The table's entry for the command, so its name needn't be allocated
+        // per request. Unknown commands fail to parse below, and aren't
+        // recorded.
+        let spec = match &req {
+            Resp::Array(args) => match args.first() {
+                Some(Resp::Bulk(Some(name))) => command_table::lookup(name),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(spec) = spec {
+            lockless.activity.record(spec.name);
+        }
+        let name = spec.map_or("", |spec| spec.name);
+        let parsed = Command::parse(&req).and_then(|cmd| {
+            match self.ctx.in_subscriber_mode() && !pubsub::SUBSCRIBER_COMMANDS.contains(&name) {
+                true => Err(CommandError::SubscriberMode(name)),
+                false => Ok(cmd),
+            }
+        });
+        // Refused before it could be queued: EXEC will run none of them.
+        let cmd = match parsed {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                if let Some(transaction) = &mut self.transaction {
+                    transaction.abort();
+                }
+                return Err(e);
+            }
+        };
+        match (cmd, &mut self.transaction) {
+            // Neither is queued by MULTI.
+            (Command::Quit, _) => {
+                self.quitting = true;
+                Ok((vec![Resp::ok()], false))
+            }
+            (Command::Reset, _) => {
+                self.reset(cache).await;
+                Ok((vec![Resp::simple("RESET")], false))
+            }
+            (Command::Multi, Some(_)) => {
+                Err(CommandError::Transaction("MULTI calls can not be nested"))
+            }
+            (Command::Multi, None) => {
+                self.transaction = Some(Transaction::default());
+                Ok((vec![Resp::ok()], false))
+            }
+            (Command::Discard, None) => Err(CommandError::Transaction("DISCARD without MULTI")),
+            (Command::Discard, Some(_)) => {
+                self.transaction = None;
+                self.watches.clear(cache).await;
+                Ok((vec![Resp::ok()], false))
+            }
+            (Command::Watch(_), Some(_)) => Err(CommandError::Transaction(
+                "WATCH inside MULTI is not allowed",
+            )),
+            // Like any other command, these wait for a running EXEC.
+            (Command::Watch(keys), None) => {
+                let _shared = lockless.gate.shared().await;
+                self.watches.watch(cache, keys).await;
+                Ok((vec![Resp::ok()], false))
+            }
+            (Command::Unwatch, None) => {
+                let _shared = lockless.gate.shared().await;
+                self.watches.clear(cache).await;
+                Ok((vec![Resp::ok()], false))
+            }
+            (Command::Exec, None) => Err(CommandError::Transaction("EXEC without MULTI")),
+            (Command::Exec, Some(_)) => self.exec(cache, lockless).await,
+            (_, Some(transaction)) if spec.is_some_and(|spec| spec.flags.contains(&"no_multi")) => {
+                transaction.abort();
+                Err(CommandError::Transaction(
+                    "Command not allowed inside a transaction",
+                ))
+            }
+            // A write that would be refused now fails the transaction. Errors
+            // only found as a command runs, such as a key of the wrong type,
+            // are left for EXEC to reply with.
+            (cmd, Some(transaction)) => {
+                if cmd.is_write() {
+                    if let Err(e) = self.info.lock().await.refuses_write() {
+                        transaction.abort();
+                        return Err(e);
                     }
-                    _ => {
-                        self.write_resp(r).await.unwrap();
+                }
+                transaction.queue(req, cmd, name);
+                Ok((vec![Resp::simple("QUEUED")], false))
+            }
+            // A script runs with nothing else running, like EXEC.
+            (cmd @ (Command::Eval(_) | Command::Fcall(_)), None) => {
+                let _batch = lockless.gate.exclusive().await;
+                self.execute(req, cmd, name, cache, lockless).await
+            }
+            (cmd, None) => {
+                let _shared = lockless.gate.shared().await;
+                self.execute(req, cmd, name, cache, lockless).await
+            }
+        }
+    }
+    // RESET: puts the connection back as it was when it connected, out of
+    // any transaction or subscriptions, unnamed and speaking RESP2.
+    async fn reset(&mut self, cache: &Store) {
+        self.transaction = None;
+        self.watches.clear(cache).await;
+        let mut info = self.info.lock().await;
+        info.pubsub.remove(self.ctx.id);
+        if let Some(output) = info.clients.output(self.ctx.id) {
+            output.set_class(ClientType::Normal);
+        }
+        info.clients.set_name(self.ctx.id, None);
+        self.ctx = ConnCtx::new(self.ctx.id);
+    }
+    // Runs the commands MULTI queued, with nothing else running in between,
+    // and replies with all of their replies. A command that fails doesn't
+    // stop the rest, and its error is among the replies. If a watched key
+    // has changed, nothing runs and the reply is a null array.
+    async fn exec(
+        &mut self,
+        cache: &Arc<Store>,
+        lockless: &Lockless,
+    ) -> Result<(Vec<Resp>, bool), CommandError> {
+        let _batch = lockless.gate.exclusive().await;
+        let changed = self.watches.changed(cache).await;
+        self.watches.clear(cache).await;
+        let queued = match self.transaction.take() {
+            Some(transaction) => transaction.commands()?,
+            None => return Err(CommandError::Transaction("EXEC without MULTI")),
+        };
+        if changed {
+            return Ok((vec![Resp::NullArray], false));
+        }
+        self.info.lock().await.begin_batch();
+        let mut replies = Vec::with_capacity(queued.len());
+        for queued in queued {
+            match self
+                .execute(queued.req, queued.cmd, queued.name, cache, lockless)
+                .await
+            {
+                Ok((resps, _)) => replies.extend(resps),
+                Err(e) => replies.push(e.to_resp()),
+            }
+        }
+        self.info.lock().await.end_batch();
+        Ok((vec![Resp::Array(replies)], false))
+    }
+    // Executes a parsed command, returning the replies and whether the
+    // connection has just become a replica.
+    async fn execute(
+        &mut self,
+        req: Resp,
+        cmd: Command,
+        name: &'static str,
+        cache: &Arc<Store>,
+        lockless: &Lockless,
+    ) -> Result<(Vec<Resp>, bool), CommandError> {
+        match &cmd {
+            Command::Replconf(ReplconfArgs::Capa(capa)) => self.capabilities.merge(capa),
+            Command::Replconf(ReplconfArgs::Port(port)) => self.listening_port = Some(*port),
+            Command::Replconf(ReplconfArgs::IpAddress(ip)) => self.announced_ip = Some(ip.clone()),
+            _ => {}
+        }
+        let is_write = cmd.is_write();
+        // UNPAUSE has to get through, or a paused server could only be
+        // waited out.
+        if !matches!(cmd, Command::Client(ClientArgs::Unpause)) {
+            self.wait_while_paused(is_write, &lockless.pausing).await;
+        }
+        if is_write {
+            self.begin_write().await?;
+        }
+        let mut keys = std::mem::take(&mut self.keys);
+        keys.clear();
+        if is_write {
+            cmd.keys_into(&mut keys);
+        }
+        let rewritten = cmd.propagated();
+        let is_sync = matches!(cmd, Command::Psync(_));
+        let call = Call {
+            name,
+            is_write,
+            keys: &keys,
+            propagated: rewritten.as_ref().unwrap_or(&req),
+        };
+        let stats = &lockless.command_stats;
+        let started = Instant::now();
+        let mut info = self.info_for(is_write).await;
+        let admitted = middleware::before(
+            &call,
+            &mut Server {
+                stats,
+                info: info.as_deref_mut(),
+            },
+        );
+        drop(info);
+        let rejected = admitted.is_err();
+        let result = match cmd {
+            _ if rejected => admitted.map(|()| vec![]),
+            Command::Psync(PsyncArgs::Question) => Ok(self.full_resync(cache).await),
+            Command::Psync(PsyncArgs::Id(replid, offset)) => {
+                self.partial_resync(cache, replid, offset).await
+            }
+            cmd => match &lockless.store_actor {
+                Some(actor) if cmd.family() == memprof::Family::String => {
+                    actor.submit(cmd, &self.ctx).await
+                }
+                _ => {
+                    let family = cmd.family();
+                    let execute = command::execute_command(
+                        cmd,
+                        &mut self.ctx,
+                        cache.clone(),
+                        self.info.clone(),
+                    );
+                    // Tagging boxes the command's future, so only profiling
+                    // pays for it.
+                    match memprof::enabled() {
+                        true => memprof::tagged(family, execute).await,
+                        false => execute.await,
                     }
                 }
-                // self.write_resp(r).await.unwrap();
+            },
+        };
+        let outcome = match &result {
+            _ if rejected => Outcome::Rejected,
+            Ok(_) => Outcome::Done(started.elapsed()),
+            Err(_) => Outcome::Failed(started.elapsed()),
+        };
+        let mut info = self.info_for(is_write).await;
+        if let Some(info) = &mut info {
+            info.writes_in_flight -= 1;
+        }
+        middleware::after(
+            &call,
+            &outcome,
+            &mut Server {
+                stats,
+                info: info.as_deref_mut(),
+            },
+        );
+        drop(info);
+        self.keys = keys;
+        Ok((result?, is_sync))
+    }
+    // Info, for the middleware of writes. Reads run theirs without it.
+    async fn info_for(&self, is_write: bool) -> Option<MutexGuard<'_, Info>> {
+        match is_write {
+            true => Some(self.info.lock().await),
+            false => None,
+        }
+    }
+    // Holds a command for as long as CLIENT PAUSE covers it. The lock is
+    // only taken to check while a pause may be in effect.
+    async fn wait_while_paused(&self, is_write: bool, pausing: &AtomicBool) {
+        while pausing.load(Ordering::Acquire) {
+            let info = self.info.lock().await;
+            let Some((until, unpaused)) = info.clients.paused(is_write) else {
+                return;
+            };
+            let resumed = unpaused.notified();
+            drop(info);
+            let _ = tokio::time::timeout_at(until.into(), resumed).await;
+        }
+    }
+    // Lets a write through once no failover is pausing writes, or refuses it
+    // if this node has become a read-only replica in the meantime. Writes let
+    // through are counted until propagated, so a failover can wait for them.
+    async fn begin_write(&self) -> Result<(), CommandError> {
+        loop {
+            let mut info = self.info.lock().await;
+            if info.rejects_writes() {
+                return Err(CommandError::ReadOnly);
+            }
+            let Some(failover) = &info.failover else {
+                if info.lacks_good_replicas() {
+                    return Err(CommandError::NoReplicas);
+                }
+                info.writes_in_flight += 1;
+                return Ok(());
+            };
+            let notify = failover.resumed();
+            let resumed = notify.notified();
+            drop(info);
+            resumed.await;
+        }
+    }
+    // Snapshots the dataset and registers this connection as a replica
+    // under the same locks, so every write lands either in the snapshot or
+    // in the replica's stream.
+    async fn full_resync(&mut self, cache: &Arc<Store>) -> Vec<Resp> {
+        if self.capabilities.eof {
+            let mut info = self.info.lock().await;
+            if info.diskless_sync {
+                let addr = self.replica_addr();
+                let (waiting, first) = info.replicas.wait_for_snapshot(
+                    self.ctx.id,
+                    addr,
+                    self.capabilities,
+                    self.output.clone(),
+                );
+                if first {
+                    let delay = info.diskless_sync_delay;
+                    tokio::spawn(diskless::transfer(delay, cache.clone(), self.info.clone()));
+                }
+                self.replica_snapshot = Some(waiting.snapshot);
+                self.replica_stream = Some(waiting.stream);
+                // FULLRESYNC goes out with the snapshot, once its offset is known.
+                return vec![];
             }
-            self.stream.flush().await.unwrap();
+        }
+        let cache = cache.lock_all().await;
+        let mut info = self.info.lock().await;
+        let reply = command::full_resync(&cache, &info);
+        self.replica_stream = Some(self.register_replica(&mut info));
+        reply
+    }
+    // Answers `PSYNC <replid> <offset>`, where offset is the first byte the
+    // replica is missing. Continues from the backlog when it still covers
+    // that point in our stream, and falls back to a full resync otherwise.
+    async fn partial_resync(
+        &mut self,
+        cache: &Arc<Store>,
+        replid: String,
+        offset: String,
+    ) -> Result<Vec<Resp>, CommandError> {
+        let offset = offset
+            .parse::<u64>()
+            .map_err(|_| CommandError::InvalidArguments("byte offset must be a valid number"))?;
+        {
+            let mut info = self.info.lock().await;
+            if offset > 0 && info.shares_history(&replid, offset) {
+                let rx = self.register_replica(&mut info);
+                if info.replicas.resume(self.ctx.id, offset - 1) {
+                    self.replica_stream = Some(rx);
+                    return Ok(vec![Resp::simple(format!("CONTINUE {}", info.id()))]);
+                }
+                info.replicas.remove(self.ctx.id);
+            }
+        }
+        Ok(self.full_resync(cache).await)
+    }
+    fn register_replica(&self, info: &mut Info) -> UnboundedReceiver<Bytes> {
+        info.replicas.register(
+            self.ctx.id,
+            self.replica_addr(),
+            self.capabilities,
+            self.output.clone(),
+        )
+    }
+    fn replica_addr(&self) -> HostSpec {
+        HostSpec {
+            host: self
+                .announced_ip
+                .clone()
+                .unwrap_or_else(|| self.addr.ip().to_string()),
+            port: self.listening_port.unwrap_or(self.addr.port()),
         }
     }
-    pub async fn read_resp(&mut self) -> Result<Option<Resp>, anyhow::Error> {
-        let bytes_read = self.stream.read_buf(&mut self.buf).await?;
-        if bytes_read == 0 {
-            return Ok(None);
+    // Once a connection has completed PSYNC it stops being a normal client and
+    // only streams propagated writes until the replica disconnects.
+    async fn serve_replica(&mut self) -> anyhow::Result<()> {
+        if let Some(mut snapshot) = self.replica_snapshot.take() {
+            while let Some(chunk) = snapshot.recv().await {
+                // Counted like a reply, for as long as it takes to write.
+                self.output.queue(chunk.len());
+                self.outbox.push_bytes(chunk);
+                self.flush().await?;
+            }
+        }
+        let mut rx = match self.replica_stream.take() {
+            Some(rx) => rx,
+            None => self.register_replica(&mut *self.info.lock().await),
+        };
+        loop {
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Some(frame) => {
+                        // Counted when it was queued for us.
+                        self.outbox.push_bytes(frame);
+                        self.flush().await?;
+                    }
+                    None => return Ok(()),
+                },
+                req = self.framed.next() => match req {
+                    Some(req) => {
+                        if let Ok(Command::Replconf(ReplconfArgs::Ack(offset))) =
+                            Command::from_resp(req?)
+                        {
+                            self.info.lock().await.replicas.ack(self.ctx.id, offset);
+                        }
+                    }
+                    None => return Ok(()),
+                },
+            }
         }
-        let (resp, _) = readnext_resp(&self.buf.split())?;
-        Ok(Some(resp))
     }
+    // HELLO replies in the protocol it switches to, since the context has
+    // switched by the time its reply is written.
     pub async fn write_resp(&mut self, resp: Resp) -> anyhow::Result<()> {
-        self.stream.write_all(&resp.encode()).await?;
+        let before = self.outbox.size();
+        self.outbox.push(&resp, self.ctx.protocol);
+        if !self.output.queue(self.outbox.size() - before) {
+            anyhow::bail!("client output buffer limit reached");
+        }
+        Ok(())
+    }
+    // Gives up on a connection that goes over its output buffer limit while
+    // this waits for it to take what's pending.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let size = self.outbox.size();
+        tokio::select! {
+            written = self.outbox.write_to(self.framed.get_mut()) => written?,
+            _ = self.output.overrun() => anyhow::bail!("client output buffer limit reached"),
+        }
+        self.output.written(size);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format_resp, protocol::RespEncoding, store::Keyspace};
+
+    #[tokio::test]
+    async fn test_pipelined_requests_are_answered_in_order() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let (_control, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = Handler::new(stream, addr, info, 1, rx, Limits::default(), 64);
+        let serving = tokio::spawn(async move { handler.handle_stream(Arc::default()).await });
+
+        let mut requests = Vec::new();
+        for i in 0..100 {
+            requests.extend(format_resp!["ECHO", i.to_string()].encode());
+        }
+        client.write_all(&requests).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        let want: String = (0..100)
+            .map(|i: usize| format!("${}\r\n{}\r\n", i.to_string().len(), i))
+            .collect();
+        assert_eq!(replies, want);
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reads_do_not_wait_for_info() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let (_control, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = Handler::new(stream, addr, info.clone(), 1, rx, Limits::default(), 64);
+        tokio::spawn(async move { handler.handle_stream(Arc::default()).await });
+
+        let mut reply = [0; 5];
+        client
+            .write_all(&format_resp!["SET", "k", "v"].encode())
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        let held = info.lock().await;
+        client
+            .write_all(&format_resp!["GET", "k"].encode())
+            .await
+            .unwrap();
+        let mut reply = [0; 7];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("GET waited for the Info lock")
+            .unwrap();
+        assert_eq!(&reply, b"$1\r\nv\r\n");
+        assert!(held.command_stats.info().contains("cmdstat_get:calls=1"));
+    }
+
+    #[tokio::test]
+    async fn test_resp2_subscribers_only_manage_subscriptions() {
+        use futures::SinkExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let (_control, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = Handler::new(stream, addr, info, 1, rx, Limits::default(), 64);
+        tokio::spawn(async move { handler.handle_stream(Arc::default()).await });
+        let mut client = Framed::new(client, RespCodec::default());
+        let mut call = async |args: &[&str]| {
+            client
+                .send(Resp::array(args.iter().copied()))
+                .await
+                .unwrap();
+            client.next().await.unwrap().unwrap()
+        };
+
+        call(&["SUBSCRIBE", "news"]).await;
+        let refused = call(&["GET", "k"]).await;
+        assert_eq!(refused, CommandError::SubscriberMode("get").to_resp());
+        assert_eq!(call(&["PING"]).await, Resp::array(["pong", ""]));
+        call(&["UNSUBSCRIBE"]).await;
+        assert_eq!(call(&["GET", "k"]).await, Resp::Bulk(None));
+
+        // RESP3 subscribers can run anything.
+        call(&["HELLO", "3"]).await;
+        call(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(call(&["PING"]).await, Resp::simple("PONG"));
+
+        // RESET leaves no subscriptions behind, and is back on RESP2.
+        call(&["SUBSCRIBE", "more"]).await;
+        call(&["MULTI"]).await;
+        assert_eq!(call(&["RESET"]).await, Resp::simple("RESET"));
+        assert_eq!(call(&["GET", "k"]).await, Resp::Bulk(None));
+        assert_eq!(call(&["PING"]).await, Resp::simple("PONG"));
+        call(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(call(&["QUIT"]).await, Resp::ok());
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_multi_queues_commands_until_exec() {
+        use futures::SinkExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let (_control, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = Handler::new(stream, addr, info.clone(), 1, rx, Limits::default(), 64);
+        let cache = Arc::new(Store::default());
+        tokio::spawn({
+            let cache = cache.clone();
+            async move { handler.handle_stream(cache).await }
+        });
+        let mut client = Framed::new(client, RespCodec::default());
+        let mut call = async |args: &[&str]| {
+            client
+                .send(Resp::array(args.iter().copied()))
+                .await
+                .unwrap();
+            client.next().await.unwrap().unwrap()
+        };
+        let queued = Resp::simple("QUEUED");
+        let error = |e: CommandError| e.to_resp();
+
+        assert_eq!(
+            call(&["EXEC"]).await,
+            error(CommandError::Transaction("EXEC without MULTI"))
+        );
+        assert_eq!(call(&["MULTI"]).await, Resp::ok());
+        assert_eq!(
+            call(&["MULTI"]).await,
+            error(CommandError::Transaction("MULTI calls can not be nested"))
+        );
+        assert_eq!(call(&["SET", "k", "v"]).await, queued);
+        assert_eq!(call(&["GET", "k"]).await, queued);
+        assert!(cache.lock_all().await.is_empty());
+        assert_eq!(
+            call(&["EXEC"]).await,
+            Resp::Array(vec![Resp::ok(), Resp::bulk("v")])
+        );
+
+        call(&["MULTI"]).await;
+        assert_eq!(call(&["DEL", "k"]).await, queued);
+        assert_eq!(call(&["DISCARD"]).await, Resp::ok());
+        assert_eq!(call(&["GET", "k"]).await, Resp::bulk("v"));
+
+        // A command that can't be queued dooms the whole transaction.
+        call(&["MULTI"]).await;
+        assert_eq!(call(&["DEL", "k"]).await, queued);
+        assert!(matches!(call(&["GET"]).await, Resp::SimpleError(_)));
+        assert_eq!(call(&["EXEC"]).await, error(CommandError::ExecAbort));
+        assert_eq!(call(&["GET", "k"]).await, Resp::bulk("v"));
+        // So does a write a read-only replica would refuse.
+        info.lock().await.role = Role::Slave;
+        call(&["MULTI"]).await;
+        assert_eq!(call(&["GET", "k"]).await, queued);
+        assert_eq!(
+            call(&["SET", "k", "x"]).await,
+            error(CommandError::ReadOnly)
+        );
+        assert_eq!(call(&["EXEC"]).await, error(CommandError::ExecAbort));
+        info.lock().await.role = Role::Master;
+        // One that fails as it runs doesn't stop the rest.
+        call(&["MULTI"]).await;
+        assert_eq!(call(&["CONFIG", "SET", "maxmemory", "lots"]).await, queued);
+        assert_eq!(call(&["SET", "k", "v1"]).await, queued);
+        let Resp::Array(replies) = call(&["EXEC"]).await else {
+            panic!("EXEC should reply with an array");
+        };
+        assert!(matches!(&replies[..], [Resp::SimpleError(_), reply] if *reply == Resp::ok()));
+        assert_eq!(call(&["GET", "k"]).await, Resp::bulk("v1"));
+
+        // As if another connection wrote a watched key.
+        assert_eq!(call(&["WATCH", "k"]).await, Resp::ok());
+        let query = Query::new("w", None, SystemTime::now());
+        cache.lock(&["k"]).await.insert("k".to_string(), query);
+        call(&["MULTI"]).await;
+        assert_eq!(
+            call(&["WATCH", "k"]).await,
+            error(CommandError::Transaction(
+                "WATCH inside MULTI is not allowed"
+            ))
+        );
+        call(&["SET", "k", "mine"]).await;
+        assert_eq!(call(&["EXEC"]).await, Resp::NullArray);
+        assert_eq!(call(&["GET", "k"]).await, Resp::bulk("w"));
+        // EXEC stopped watching it.
+        call(&["MULTI"]).await;
+        call(&["SET", "k", "mine"]).await;
+        assert_eq!(call(&["EXEC"]).await, Resp::Array(vec![Resp::ok()]));
+    }
+
+    #[test]
+    fn test_host_specs() {
+        let parse = |s: &str| s.parse::<HostSpec>().map(|spec| spec.to_string());
+        assert_eq!(parse("localhost 6380").unwrap(), "127.0.0.1:6380");
+        assert_eq!(parse("10.0.0.1:6380").unwrap(), "10.0.0.1:6380");
+        assert_eq!(parse("::1 6380").unwrap(), "[::1]:6380");
+        assert_eq!(parse("[0:0::1]:6380").unwrap(), "[::1]:6380");
+        assert_eq!(
+            parse("Redis-1.example.com:6380").unwrap(),
+            "redis-1.example.com:6380"
+        );
+        for bad in [
+            "::1:6380",
+            "[::1]6380",
+            "host",
+            "-host:6380",
+            "a..b 6380",
+            "host 80",
+            "a b c",
+        ] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_only_read_only_replicas_reject_writes() {
+        let info = |role| {
+            Info::new(
+                role,
+                Persistence::new(true),
+                Eviction::new(0),
+                Clients::new(10, 0),
+            )
+        };
+        assert!(!info(Role::Master).rejects_writes());
+        assert!(info(Role::Slave).rejects_writes());
+
+        let mut replica = info(Role::Slave);
+        replica.replica_read_only = false;
+        assert!(!replica.rejects_writes());
+    }
+
+    #[test]
+    fn test_min_replicas_gates_writes_on_masters() {
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        assert!(!info.lacks_good_replicas());
+        info.min_replicas_to_write = 1;
+        assert!(info.lacks_good_replicas());
+        assert!(info.replication().contains("min_slaves_good_slaves:0"));
+
+        let addr = "127.0.0.1 6380".parse().unwrap();
+        let _rx = info
+            .replicas
+            .register(1, addr, Capabilities::default(), Arc::default());
+        assert!(!info.lacks_good_replicas());
+        info.min_replicas_to_write = 2;
+        assert!(info.lacks_good_replicas());
+        info.min_replicas_max_lag = 0;
+        assert!(!info.lacks_good_replicas());
+    }
+
+    #[test]
+    fn test_promotion_keeps_old_history_for_continuing() {
+        let new_info = || {
+            Info::new(
+                Role::Master,
+                Persistence::new(true),
+                Eviction::new(0),
+                Clients::new(10, 0),
+            )
+        };
+        let mut info = new_info();
+        let old = info.id();
+        assert_ne!(old, new_info().id());
+        assert_eq!(old.len(), 40);
+        assert!(info.replication().contains("second_repl_offset:-1"));
+
+        info.replicas.propagate(&format_resp!["SET", "k", "v"]);
+        let offset = info.replicas.offset();
+        info.shift_replid(random_id());
+        info.replicas.propagate(&format_resp!["SET", "k", "w"]);
+        assert!(info.shares_history(&info.id(), info.replicas.offset() + 1));
+        assert!(info.shares_history(&old, offset + 1));
+        assert!(!info.shares_history(&old, offset + 2));
+        assert!(info
+            .replication()
+            .contains(&format!("master_replid2:{}\n", old)));
+
+        info.set_replid(random_id());
+        assert!(!info.shares_history(&old, offset + 1));
+    }
+
+    #[test]
+    fn test_repl_offset_counts_every_streamed_byte() {
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        let addr = "127.0.0.1 6380".parse().unwrap();
+        let _rx = info
+            .replicas
+            .register(1, addr, Capabilities::default(), Arc::default());
+        let set = format_resp!["SET", "k", "v"];
+        info.propagate(&set, &["k".to_string()]);
+        info.replicas.ping();
+        info.replicas.request_ack();
+
+        let want = set.encode().len()
+            + format_resp!["PING"].encode().len()
+            + format_resp!["REPLCONF", "GETACK", "*"].encode().len();
+        assert!(info
+            .replication()
+            .contains(&format!("master_repl_offset:{}", want)));
+    }
+
+    #[test]
+    fn test_batches_propagate_their_writes_together() {
+        let mut info = Info::new(
+            Role::Master,
+            Persistence::new(true),
+            Eviction::new(0),
+            Clients::new(10, 0),
+        );
+        let addr = "127.0.0.1 6380".parse().unwrap();
+        let mut rx = info
+            .replicas
+            .register(1, addr, Capabilities::default(), Arc::default());
+        let mut sent = || {
+            let mut sent = Vec::new();
+            while let Ok(bytes) = rx.try_recv() {
+                sent.extend_from_slice(&bytes);
+            }
+            sent
+        };
+        let (a, b) = (format_resp!["SET", "a", "1"], format_resp!["DEL", "b"]);
+
+        assert!(info.begin_batch());
+        info.propagate(&a, &["a".to_string()]);
+        // A script EXEC runs joins its batch.
+        assert!(!info.begin_batch());
+        info.propagate(&b, &["b".to_string()]);
+        assert!(sent().is_empty());
+        info.end_batch();
+        let wrapped = [format_resp!["MULTI"], a.clone(), b, format_resp!["EXEC"]];
+        assert_eq!(
+            sent(),
+            wrapped.iter().flat_map(Resp::encode).collect::<Vec<_>>()
+        );
+
+        info.begin_batch();
+        info.propagate(&a, &["a".to_string()]);
+        info.end_batch();
+        assert_eq!(sent(), a.encode());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{command::CommandError, persistence, server::Info, store::Store};
+
+// SHUTDOWN, SIGTERM and SIGINT. Client writes are paused so the dataset
+// holds still while replicas catch up and it is saved, then the AOF is
+// flushed and every connection told to close. If saving fails the pause is
+// lifted and the server keeps running, as Redis does.
+
+// Longest we wait for replicas to acknowledge the last writes.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Gets the server ready to exit. `save` is SHUTDOWN SAVE or NOSAVE; without
+// either the dataset is saved if any save points are configured.
+pub async fn prepare(
+    cache: &Arc<Store>,
+    info: &Arc<Mutex<Info>>,
+    save: Option<bool>,
+) -> Result<(), CommandError> {
+    // Long enough to outlast everything below; lifted if we don't exit.
+    info.lock().await.clients.pause(SHUTDOWN_TIMEOUT * 6, true);
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    loop {
+        {
+            let mut info = info.lock().await;
+            let caught_up = info.replicas.all_caught_up();
+            if (caught_up && !info.persistence.rdb_bgsave_in_progress) || Instant::now() >= deadline
+            {
+                if !caught_up {
+                    println!("Replicas didn't catch up before shutdown; shutting down anyway");
+                }
+                break;
+            }
+            info.replicas.request_ack();
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let cache = cache.lock_all().await;
+    let info = &mut *info.lock().await;
+    let save = save.unwrap_or(!info.persistence.save_points.is_empty());
+    if save {
+        println!("Saving the final RDB snapshot before exiting.");
+        if let Err(e) = persistence::save(&cache, &mut info.persistence, &info.functions) {
+            println!("Error trying to save the DB, can't exit: {}", e);
+            info.clients.unpause();
+            return Err(CommandError::Persistence(
+                "Errors trying to SHUTDOWN. Check logs.",
+            ));
+        }
+    }
+    if let Some(aof) = &mut info.persistence.aof {
+        println!("Calling fsync() on the AOF file.");
+        if let Err(e) = aof.fsync() {
+            println!("failed to fsync the AOF on shutdown: {}", e);
+        }
+    }
+    // Dropping their streams ends the replicas' connections.
+    info.replicas.disconnect_all();
+    info.clients.kill(&[]);
+    Ok(())
+}
+
+// Resolves on SIGTERM or SIGINT, with the signal's name.
+pub async fn signal_received() -> &'static str {
+    let mut term = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clients::Clients, eviction::Eviction, persistence::Persistence, server::Role};
+
+    #[tokio::test]
+    async fn test_failed_save_keeps_server_running() {
+        let mut persistence = Persistence::new(true);
+        persistence.dir = "/nonexistent/credis".to_string();
+        persistence.save_points = vec![(60, 1)];
+        let info = Arc::new(Mutex::new(Info::new(
+            Role::Master,
+            persistence,
+            Eviction::new(0),
+            Clients::new(10, 0),
+        )));
+        let cache = Arc::new(Store::default());
+
+        assert!(prepare(&cache, &info, None).await.is_err());
+        assert!(!info.lock().await.clients.writes_paused());
+        assert!(prepare(&cache, &info, Some(false)).await.is_ok());
+        assert!(info.lock().await.clients.writes_paused());
+    }
+}
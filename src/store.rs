@@ -0,0 +1,607 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    ops::{Deref, Index},
+    time::SystemTime,
+};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::server::Query;
+
+// The keyspace, split into shards that each have their own lock so that
+// commands on unrelated keys don't wait for each other. A key lives in the
+// shard its hash picks. Shards are always locked in index order, whether
+// one, a command's keys' or all of them, so two lockers can't deadlock.
+pub struct Store {
+    shards: Box<[RwLock<Shard>]>,
+    // Whether reads share a shard with each other, only writes taking it
+    // to themselves, or take it to themselves too.
+    shared_reads: bool,
+}
+
+// A shard's keys, and those with a TTL again in order of when they expire,
+// so expired keys can be found without looking at any others.
+#[derive(Default)]
+struct Shard {
+    keys: HashMap<String, Query>,
+    deadlines: BTreeSet<(SystemTime, String)>,
+    // While a snapshot is yet to copy the shard, what each key written
+    // since it was frozen held before, or None if it didn't exist.
+    frozen: Option<HashMap<String, Option<Query>>>,
+    // Keys some connection WATCHes, with how many do and how many times the
+    // key has changed since the first started to. Nothing else is counted.
+    watched: HashMap<String, Watchers>,
+}
+
+struct Watchers {
+    count: usize,
+    version: u64,
+}
+
+impl Shard {
+    fn insert(&mut self, key: String, query: Query) -> Option<Query> {
+        let old = self.remove(&key);
+        self.preserve(&key, None);
+        self.touch(&key);
+        if let Some(expiry) = query.expiry {
+            self.deadlines.insert((expiry, key.clone()));
+        }
+        self.keys.insert(key, query);
+        old
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Query> {
+        let query = self.keys.remove(key)?;
+        self.preserve(key, Some(&query));
+        self.touch(key);
+        if let Some(expiry) = query.expiry {
+            self.deadlines.remove(&(expiry, key.to_string()));
+        }
+        Some(query)
+    }
+
+    fn clear(&mut self) {
+        for (key, watchers) in &mut self.watched {
+            if self.keys.contains_key(key) {
+                watchers.version += 1;
+            }
+        }
+        if let Some(frozen) = &mut self.frozen {
+            for (key, query) in self.keys.drain() {
+                frozen.entry(key).or_insert(Some(query));
+            }
+        }
+        self.keys.clear();
+        self.deadlines.clear();
+    }
+
+    // Keeps what `key` held when the shard was frozen, the first time it
+    // changes since.
+    fn preserve(&mut self, key: &str, old: Option<&Query>) {
+        if let Some(frozen) = &mut self.frozen {
+            if !frozen.contains_key(key) {
+                frozen.insert(key.to_string(), old.cloned());
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some(watchers) = self.watched.get_mut(key) {
+            watchers.version += 1;
+        }
+    }
+
+    // Keys that expired before `now`, soonest first.
+    fn expired(&self, now: SystemTime) -> impl Iterator<Item = &(SystemTime, String)> {
+        self.deadlines.range(..(now, String::new()))
+    }
+}
+
+pub const SHARDS: usize = 16;
+
+// What picks a key's shard, and orders the keys within it for scans.
+fn hash_of(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(SHARDS, true)
+    }
+}
+
+impl Store {
+    pub fn new(shards: usize, shared_reads: bool) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            shared_reads,
+        }
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        (hash_of(key) % self.shards.len() as u64) as usize
+    }
+
+    // Locks the shards `keys` live in, for commands that only touch those.
+    pub async fn lock(&self, keys: &[&str]) -> Locked<'_> {
+        self.lock_shards(self.indices(keys), false).await
+    }
+
+    // Locks every shard, for whatever needs the keyspace as a whole or to
+    // stay unchanged meanwhile: snapshots, eviction, scans and the like.
+    pub async fn lock_all(&self) -> Locked<'_> {
+        self.lock_shards((0..self.shards.len()).collect(), false)
+            .await
+    }
+
+    // As lock and lock_all, for commands that only read. Other readers of
+    // the same shards can go ahead meanwhile, unless shared reads are off.
+    pub async fn read(&self, keys: &[&str]) -> Locked<'_> {
+        self.lock_shards(self.indices(keys), self.shared_reads)
+            .await
+    }
+
+    pub async fn read_all(&self) -> Locked<'_> {
+        self.lock_shards((0..self.shards.len()).collect(), self.shared_reads)
+            .await
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Locks the shard numbered `i` alone, for background work that goes
+    // through the keyspace a shard at a time rather than stopping it all.
+    pub async fn lock_shard(&self, i: usize) -> Locked<'_> {
+        self.lock_shards(vec![i], false).await
+    }
+
+    // The unexpired entries as they were when `frozen` was taken. Shards
+    // are copied one at a time, each only held for reading while it is, so
+    // writers wait for at most one shard's copy rather than the whole
+    // keyspace's.
+    pub async fn copy_frozen(&self, frozen: Frozen) -> Vec<(String, Query)> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let copied: Vec<(String, Query)> = shard
+                .read()
+                .await
+                .keys
+                .iter()
+                .map(|(key, query)| (key.clone(), query.clone()))
+                .collect();
+            // Taken after the copy, so it covers whatever changed during it.
+            let old = shard.write().await.frozen.take().unwrap_or_default();
+            entries.extend(copied.into_iter().filter(|(key, _)| !old.contains_key(key)));
+            entries.extend(
+                old.into_iter()
+                    .filter_map(|(key, query)| Some((key, query?))),
+            );
+        }
+        entries.retain(|(_, query)| !query.is_expired(frozen.at));
+        entries
+    }
+
+    // Visits up to `count` keys from where `cursor` left off, collecting
+    // what `visit` makes of them, and returns the cursor to carry on from,
+    // 0 once every key has been visited. Shards are gone through in order,
+    // each in order of its keys' hashes, so a cursor is the hash of the next
+    // key and says which shard that is in. Keys there for the whole scan are
+    // visited at least once, whatever else changes between calls. Only one
+    // shard is locked at a time.
+    pub async fn scan<T>(
+        &self,
+        cursor: u64,
+        count: usize,
+        mut visit: impl FnMut(&str, &Query) -> Option<T>,
+    ) -> (u64, Vec<T>) {
+        let shards = self.shards.len() as u64;
+        let mut found = Vec::new();
+        let mut visited = 0;
+        let mut from = cursor;
+        for i in (cursor % shards) as usize..self.shards.len() {
+            let locked = self.lock_shards(vec![i], self.shared_reads).await;
+            let shard = &locked.shards[0].1;
+            let mut keys: Vec<_> = shard
+                .keys
+                .iter()
+                .map(|(key, query)| (hash_of(key), key, query))
+                .filter(|(hash, ..)| *hash >= from)
+                .collect();
+            // Only the lowest hashes are visited this time, so there's no
+            // need to sort the rest.
+            let wanted = count - visited;
+            let next = (keys.len() > wanted).then(|| {
+                keys.select_nth_unstable_by_key(wanted, |(hash, key, _)| (*hash, *key));
+                keys[wanted].0
+            });
+            keys.truncate(wanted);
+            keys.sort_unstable_by_key(|(hash, key, _)| (*hash, *key));
+            for (_, key, query) in keys {
+                visited += 1;
+                found.extend(visit(key, query));
+            }
+            if let Some(next) = next {
+                return (next, found);
+            }
+            // The lowest hash the next shard's keys can have.
+            from = i as u64 + 1;
+        }
+        (0, found)
+    }
+
+    fn indices(&self, keys: &[&str]) -> Vec<usize> {
+        let mut indices: Vec<usize> = keys.iter().map(|key| self.shard_of(key)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    async fn lock_shards(&self, indices: Vec<usize>, shared: bool) -> Locked<'_> {
+        let mut shards = Vec::with_capacity(indices.len());
+        for i in indices {
+            let guard = match shared {
+                true => Guard::Shared(self.shards[i].read().await),
+                false => Guard::Exclusive(self.shards[i].write().await),
+            };
+            shards.push((i, guard));
+        }
+        Locked {
+            store: self,
+            shards,
+        }
+    }
+}
+
+// Some or all of a store's shards, locked. Touching a key whose shard isn't
+// held, or changing one only held for reading, is a bug, and panics.
+pub struct Locked<'a> {
+    store: &'a Store,
+    shards: Vec<(usize, Guard<'a>)>,
+}
+
+enum Guard<'a> {
+    Shared(RwLockReadGuard<'a, Shard>),
+    Exclusive(RwLockWriteGuard<'a, Shard>),
+}
+
+impl Deref for Guard<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match self {
+            Guard::Shared(shard) => shard,
+            Guard::Exclusive(shard) => shard,
+        }
+    }
+}
+
+impl Guard<'_> {
+    fn get_mut(&mut self) -> &mut Shard {
+        match self {
+            Guard::Exclusive(shard) => shard,
+            Guard::Shared(_) => panic!("shard is only locked for reading"),
+        }
+    }
+}
+
+// A point in time a snapshot is copied as of, with Store::copy_frozen.
+#[must_use]
+pub struct Frozen {
+    at: SystemTime,
+}
+
+impl Locked<'_> {
+    // Freezes the whole store, which must be locked, as it is now: until
+    // copy_frozen has copied a shard, changes to it keep what they replace.
+    pub fn freeze(&mut self) -> Frozen {
+        assert_eq!(
+            self.shards.len(),
+            self.store.shards.len(),
+            "not all shards are locked"
+        );
+        for (_, shard) in &mut self.shards {
+            shard.get_mut().frozen = Some(HashMap::new());
+        }
+        Frozen {
+            at: SystemTime::now(),
+        }
+    }
+
+    // Starts counting changes to `key` for another watcher, and returns the
+    // count so far, to compare with version's later.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        let watchers = self
+            .shard_mut(key)
+            .watched
+            .entry(key.to_string())
+            .or_insert(Watchers {
+                count: 0,
+                version: 0,
+            });
+        watchers.count += 1;
+        watchers.version
+    }
+
+    // Undoes a watch. The count is forgotten once nobody watches the key.
+    pub fn unwatch(&mut self, key: &str) {
+        let watched = &mut self.shard_mut(key).watched;
+        if let Some(watchers) = watched.get_mut(key) {
+            watchers.count -= 1;
+            if watchers.count == 0 {
+                watched.remove(key);
+            }
+        }
+    }
+
+    // How many times a watched `key` has changed.
+    pub fn version(&self, key: &str) -> u64 {
+        self.shard(key)
+            .watched
+            .get(key)
+            .map_or(0, |watchers| watchers.version)
+    }
+
+    fn position(&self, key: &str) -> usize {
+        let shard = self.store.shard_of(key);
+        self.shards
+            .binary_search_by_key(&shard, |(i, _)| *i)
+            .unwrap_or_else(|_| panic!("shard of key '{}' is not locked", key))
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.position(key)].1
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let position = self.position(key);
+        self.shards[position].1.get_mut()
+    }
+}
+
+impl Index<&str> for Locked<'_> {
+    type Output = Query;
+
+    fn index(&self, key: &str) -> &Query {
+        self.get(key).expect("no such key")
+    }
+}
+
+// The map operations that code working on a keyspace needs, so that it can
+// be handed locked shards of the store or, in tests, a plain map.
+pub trait Keyspace: Send + Sync {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn get(&self, key: &str) -> Option<&Query>;
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+    fn insert(&mut self, key: String, query: Query) -> Option<Query>;
+    fn remove(&mut self, key: &str) -> Option<Query>;
+    fn clear(&mut self);
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Query)> + Send + '_>;
+    // Up to `limit` keys that expired before `now`.
+    fn expired(&self, now: SystemTime, limit: usize) -> Vec<String> {
+        self.iter()
+            .filter(|(_, query)| query.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect()
+    }
+}
+
+impl Keyspace for Locked<'_> {
+    fn len(&self) -> usize {
+        self.shards.iter().map(|(_, shard)| shard.keys.len()).sum()
+    }
+    fn get(&self, key: &str) -> Option<&Query> {
+        self.shard(key).keys.get(key)
+    }
+    fn insert(&mut self, key: String, query: Query) -> Option<Query> {
+        self.shard_mut(&key).insert(key, query)
+    }
+    fn remove(&mut self, key: &str) -> Option<Query> {
+        self.shard_mut(key).remove(key)
+    }
+    fn clear(&mut self) {
+        for (_, shard) in &mut self.shards {
+            shard.get_mut().clear();
+        }
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Query)> + Send + '_> {
+        Box::new(self.shards.iter().flat_map(|(_, shard)| shard.keys.iter()))
+    }
+    // The soonest expired across the shards, from each shard's deadlines.
+    fn expired(&self, now: SystemTime, limit: usize) -> Vec<String> {
+        let mut expired: Vec<&(SystemTime, String)> = self
+            .shards
+            .iter()
+            .flat_map(|(_, shard)| shard.expired(now).take(limit))
+            .collect();
+        expired.sort_unstable();
+        expired
+            .into_iter()
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+impl Keyspace for HashMap<String, Query> {
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+    fn get(&self, key: &str) -> Option<&Query> {
+        HashMap::get(self, key)
+    }
+    fn insert(&mut self, key: String, query: Query) -> Option<Query> {
+        HashMap::insert(self, key, query)
+    }
+    fn remove(&mut self, key: &str) -> Option<Query> {
+        HashMap::remove(self, key)
+    }
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Query)> + Send + '_> {
+        Box::new(HashMap::iter(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_commands_on_other_shards_do_not_wait() {
+        let store = Store::new(4, true);
+        let a = "a".to_string();
+        let b = (0..)
+            .map(|i| format!("b{}", i))
+            .find(|b| store.shard_of(b) != store.shard_of(&a))
+            .unwrap();
+        let wait = Duration::from_millis(50);
+
+        let held = store.lock(&[&a]).await;
+        assert!(timeout(wait, store.lock(&[&b])).await.is_ok());
+        assert!(timeout(wait, store.lock(&[&b, &a])).await.is_err());
+        assert!(timeout(wait, store.lock_all()).await.is_err());
+        assert!(timeout(wait, store.lock_shard(store.shard_of(&b)))
+            .await
+            .is_ok());
+        assert!(timeout(wait, store.lock_shard(store.shard_of(&a)))
+            .await
+            .is_err());
+        drop(held);
+
+        let query = Query::new("v".to_string(), None, SystemTime::now());
+        let mut all = store.lock_all().await;
+        all.insert(a.clone(), query.clone());
+        all.insert(b.clone(), query);
+        drop(all);
+        let mut both = store.lock(&[&b, &a]).await;
+        assert_eq!(both.len(), 2);
+        assert!(both.remove(&a).is_some());
+        assert_eq!(both.iter().map(|(key, _)| key).collect::<Vec<_>>(), [&b]);
+    }
+
+    #[tokio::test]
+    async fn test_reads_share_a_shard_unless_turned_off() {
+        let wait = Duration::from_millis(50);
+        let store = Store::new(1, true);
+        let reading = store.read(&["a"]).await;
+        assert!(timeout(wait, store.read_all()).await.is_ok());
+        assert!(timeout(wait, store.lock(&["b"])).await.is_err());
+        drop(reading);
+        let writing = store.lock(&["a"]).await;
+        assert!(timeout(wait, store.read(&["b"])).await.is_err());
+        drop(writing);
+
+        let store = Store::new(1, false);
+        let _reading = store.read(&["a"]).await;
+        assert!(timeout(wait, store.read(&["b"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_keys_come_soonest_first() {
+        let store = Store::new(4, true);
+        let mut all = store.lock_all().await;
+        let now = SystemTime::now();
+        for i in 0..10 {
+            let expiry = now - Duration::from_secs(10 - i) + Duration::from_secs(5);
+            let query = Query::new("v".to_string(), Some(expiry), now);
+            all.insert(format!("k{}", i), query);
+        }
+        all.insert("plain".to_string(), Query::new("v".to_string(), None, now));
+        // Overwritten without a TTL, and deleted.
+        all.insert("k0".to_string(), Query::new("v".to_string(), None, now));
+        all.remove("k2");
+
+        assert_eq!(all.expired(now, 3), ["k1", "k3", "k4"]);
+        assert_eq!(all.expired(now, 10).len(), 3);
+        all.clear();
+        assert!(all.expired(now + Duration::from_secs(60), 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scans_resume_shard_by_shard() {
+        let store = Store::new(4, true);
+        let now = SystemTime::now();
+        let mut all = store.lock_all().await;
+        for i in 0..100 {
+            let query = Query::new("v".to_string(), None, now);
+            all.insert(format!("k{}", i), query);
+        }
+        drop(all);
+
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 7, |key, _| Some(key.to_string())).await;
+            assert!(keys.len() <= 7);
+            seen.extend(keys);
+            pages += 1;
+            // Changes between pages don't make keys there throughout go missing.
+            let mut locked = store.lock_all().await;
+            locked.insert(
+                format!("new{}", pages),
+                Query::new("v".to_string(), None, now),
+            );
+            locked.remove(&format!("new{}", pages - 1));
+            drop(locked);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in 0..100 {
+            assert!(seen.contains(&format!("k{}", i)));
+        }
+        assert!(pages <= 100 / 7 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_frozen_copies_see_the_store_as_it_was() {
+        let store = Store::new(4, true);
+        let now = SystemTime::now();
+        let value = |v: &str| Query::new(v.to_string(), None, now);
+        let mut all = store.lock_all().await;
+        for key in ["a", "b", "c"] {
+            all.insert(key.to_string(), value("old"));
+        }
+        let frozen = all.freeze();
+        drop(all);
+
+        let mut a = store.lock(&["a", "b", "d"]).await;
+        a.insert("a".to_string(), value("new"));
+        a.insert("a".to_string(), value("newer"));
+        a.remove("b");
+        a.insert("d".to_string(), value("new"));
+        drop(a);
+        store.lock_all().await.remove("c");
+
+        let mut copied = store.copy_frozen(frozen).await;
+        copied.sort_by(|x, y| x.0.cmp(&y.0));
+        let copied: Vec<_> = copied
+            .iter()
+            .map(|(k, q)| (k.as_str(), q.value.as_string().unwrap().as_str()))
+            .collect();
+        assert_eq!(copied, [("a", "old"), ("b", "old"), ("c", "old")]);
+        assert!(store
+            .shards
+            .iter()
+            .all(|shard| shard.try_read().unwrap().frozen.is_none()));
+        assert_eq!(store.read(&["a"]).await["a"].value, "newer");
+    }
+}
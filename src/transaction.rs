@@ -0,0 +1,142 @@
+use std::time::SystemTime;
+
+use crate::{
+    command::{Command, CommandError},
+    protocol::Resp,
+    store::{Keyspace, Store},
+};
+
+// A MULTI in progress on a connection: the commands queued for EXEC, each
+// with the request it came as, for propagation, and its table name. A
+// command that fails to queue, such as one with the wrong number of
+// arguments, dooms the whole transaction, as in Redis.
+#[derive(Default)]
+pub struct Transaction {
+    queued: Vec<Queued>,
+    aborted: bool,
+}
+
+pub struct Queued {
+    pub req: Resp,
+    pub cmd: Command,
+    pub name: &'static str,
+}
+
+impl Transaction {
+    pub fn queue(&mut self, req: Resp, cmd: Command, name: &'static str) {
+        self.queued.push(Queued { req, cmd, name });
+    }
+
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    // What EXEC runs, unless something failed to queue.
+    pub fn commands(self) -> Result<Vec<Queued>, CommandError> {
+        match self.aborted {
+            true => Err(CommandError::ExecAbort),
+            false => Ok(self.queued),
+        }
+    }
+}
+
+// The keys a connection WATCHes, each with how many times it had changed
+// when it started to, and whether it existed. EXEC gives up if any has
+// changed since, whoever changed it, as in Redis.
+#[derive(Default)]
+pub struct Watches(Vec<Watch>);
+
+struct Watch {
+    key: String,
+    version: u64,
+    existed: bool,
+}
+
+impl Watches {
+    pub async fn watch(&mut self, cache: &Store, keys: Vec<String>) {
+        let mut cache = cache
+            .lock(&keys.iter().map(String::as_str).collect::<Vec<_>>())
+            .await;
+        let now = SystemTime::now();
+        for key in keys {
+            if self.0.iter().any(|watch| watch.key == key) {
+                continue;
+            }
+            let existed = cache.get(&key).is_some_and(|query| !query.is_expired(now));
+            let version = cache.watch(&key);
+            self.0.push(Watch {
+                key,
+                version,
+                existed,
+            });
+        }
+    }
+
+    // Whether a watched key has changed. One that has expired since counts,
+    // though nothing has deleted it yet.
+    pub async fn changed(&self, cache: &Store) -> bool {
+        let cache = cache.read(&self.keys()).await;
+        let now = SystemTime::now();
+        self.0.iter().any(|watch| {
+            let expired = cache
+                .get(&watch.key)
+                .is_none_or(|query| query.is_expired(now));
+            cache.version(&watch.key) != watch.version || (watch.existed && expired)
+        })
+    }
+
+    // UNWATCH, and what EXEC and DISCARD do once they're done, and closing
+    // the connection.
+    pub async fn clear(&mut self, cache: &Store) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut cache = cache.lock(&self.keys()).await;
+        for watch in self.0.drain(..) {
+            cache.unwatch(&watch.key);
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|watch| watch.key.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::BulkString, server::Query};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_watched_keys_count_as_changed_once_written_or_expired() {
+        let store = &Store::default();
+        let set = async |key: &str, expiry| {
+            let value = BulkString::from("v");
+            let query = Query::new(value, expiry, SystemTime::now());
+            store.lock(&[key]).await.insert(key.to_string(), query);
+        };
+        set(
+            "expiring",
+            Some(SystemTime::now() + Duration::from_millis(50)),
+        )
+        .await;
+
+        let (mut first, mut second) = (Watches::default(), Watches::default());
+        first.watch(store, vec!["k".into(), "k".into()]).await;
+        second.watch(store, vec!["k".into(), "other".into()]).await;
+        assert!(!first.changed(store).await);
+        set("k", None).await;
+        assert!(first.changed(store).await && second.changed(store).await);
+
+        // Nothing is counted once nobody watches.
+        first.clear(store).await;
+        second.clear(store).await;
+        assert_eq!(store.lock_all().await.version("k"), 0);
+
+        first.watch(store, vec!["expiring".into()]).await;
+        assert!(!first.changed(store).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(first.changed(store).await);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::protocol::BulkString;
+
+// What a key holds. Commands here only work with strings; the other types
+// come in with snapshots and are kept as they were read, so saving them
+// again loses nothing. Their elements are raw bytes, as Redis doesn't
+// require them to be text. Containers are shared, so copying a key out for
+// a snapshot doesn't copy its elements.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(BulkString),
+    List(Arc<Vec<Bytes>>),
+    Set(Arc<Vec<Bytes>>),
+    // Members and their scores.
+    SortedSet(Arc<Vec<(Bytes, f64)>>),
+    // Fields and their values.
+    Hash(Arc<Vec<(Bytes, Bytes)>>),
+    Stream(Arc<Stream>),
+}
+
+impl Value {
+    pub fn as_string(&self) -> Option<&BulkString> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // What TYPE calls it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+impl From<BulkString> for Value {
+    fn from(s: BulkString) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.into())
+    }
+}
+
+impl PartialEq<&str> for Value {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_string().is_some_and(|s| s == other)
+    }
+}
+
+// A stream entry's ID: milliseconds, then a sequence number within them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+// Fields and values of one stream entry, in the order they were added.
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+// A stream as Redis 7 keeps it: its entries in ID order, the IDs and
+// counters XINFO reports, and its consumer groups.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    pub entries: Vec<(StreamId, Fields)>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Bytes,
+    pub last_id: StreamId,
+    // u64::MAX when unknown, as Redis stores its -1.
+    pub entries_read: u64,
+    // Entries delivered and not yet acknowledged.
+    pub pending: Vec<Pending>,
+    pub consumers: Vec<Consumer>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub id: StreamId,
+    // Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: Bytes,
+    // Unix times in milliseconds.
+    pub seen_time: u64,
+    pub active_time: u64,
+    // The IDs of the group's pending entries delivered to this consumer.
+    pub pending: Vec<StreamId>,
+}
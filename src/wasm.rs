@@ -0,0 +1,267 @@
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use wasmi::{
+    core::{TrapCode, ValType},
+    Caller, Config, Engine, Extern, ExternType, Instance, Linker, Module, Store,
+};
+
+use crate::{
+    command::CommandError,
+    functions::{Fcall, Registered},
+    protocol::{readnext_resp, BulkString, Resp, RespEncoding},
+    scripting::{self, Bridge},
+};
+
+// `#!wasm` libraries, with the wasm feature: a WebAssembly module in text
+// form, since a binary one can't be sent as an argument. Its functions are
+// those it exports taking a single i32, and talk to the server in RESP,
+// through memory the module exports as `memory` and three functions it can
+// import from `redis`:
+//
+//   read(ptr)            copies the pending message to ptr
+//   call(ptr, len) -> n  runs the command at ptr, an array of bulk strings,
+//                        and makes its reply, n bytes, the pending message
+//   reply(ptr, len)      replies with what's at ptr, an error failing the call
+//
+// A function is called with the length of the first pending message: an
+// array of the call's keys and an array of its args. Commands it calls run
+// as a Lua script's do, atomically and with the same effects, and their
+// errors come back as error replies, as with redis.pcall.
+//
+// The module is compiled once, when the library is loaded, and instantiated
+// afresh for each call. Every instantiation gets FUEL to run on, so a
+// function that never returns fails instead of holding the dataset forever.
+
+// Instructions, roughly, a function or start function may run. About a
+// second's work.
+const FUEL: u64 = 1_000_000_000;
+
+// A library's module, compiled for an engine of its own that meters fuel.
+pub struct Compiled {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+}
+
+// What a call's imports work with.
+struct Host {
+    bridge: Bridge,
+    pending: Vec<u8>,
+    reply: Option<Resp>,
+}
+
+fn registering(e: wasmi::Error) -> CommandError {
+    CommandError::Function(format!("Error registering functions: {}", e))
+}
+
+pub fn compile(body: &str) -> Result<Compiled, CommandError> {
+    let wasm = wat::parse_str(body).map_err(|e| registering(wasmi::Error::new(e.to_string())))?;
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, &wasm).map_err(registering)?;
+    Ok(Compiled {
+        engine,
+        module,
+        fuel: FUEL,
+    })
+}
+
+// The functions a library's module exports, once it's shown to instantiate,
+// sorted by name as wasmi doesn't keep the module's export order.
+// Its start function, if any, runs with no commands to call.
+pub fn register(compiled: &Compiled) -> Result<Vec<Registered>, CommandError> {
+    instantiate(compiled, mpsc::unbounded_channel().0, Vec::new()).map_err(registering)?;
+    let functions = compiled
+        .module
+        .exports()
+        .filter_map(|export| match export.ty() {
+            ExternType::Func(ty) if ty.params() == [ValType::I32] && ty.results().is_empty() => {
+                Some(Registered {
+                    name: export.name().to_string(),
+                    flags: Vec::new(),
+                })
+            }
+            _ => None,
+        });
+    let mut functions: Vec<_> = functions.collect();
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(functions)
+}
+
+// Instantiates a library's module and calls one of its functions. It blocks
+// for as long as the function runs, as Lua's do, or until it runs out of
+// fuel.
+pub fn call(compiled: &Compiled, fcall: &Fcall, bridge: Bridge) -> Result<Resp, CommandError> {
+    let failed = |e: wasmi::Error| {
+        let e = match e.as_trap_code() {
+            Some(TrapCode::OutOfFuel) => "ran out of fuel".to_string(),
+            _ => e.to_string(),
+        };
+        CommandError::Script(format!("ERR {} script: {}", e, fcall.function))
+    };
+    let bulks = |strings: &[BulkString]| {
+        Resp::Array(
+            strings
+                .iter()
+                .map(|s| Resp::Bulk(Some(s.clone())))
+                .collect(),
+        )
+    };
+    let input = Resp::Array(vec![bulks(&fcall.keys), bulks(&fcall.args)]).encode();
+    let len = input.len() as i32;
+    let (mut store, instance) = instantiate(compiled, bridge, input).map_err(failed)?;
+    instance
+        .get_typed_func::<i32, ()>(&store, &fcall.function)
+        .and_then(|function| function.call(&mut store, len))
+        .map_err(failed)?;
+    match store.into_data().reply {
+        Some(Resp::SimpleError(e)) => Err(CommandError::Script(e)),
+        Some(reply) => Ok(reply),
+        None => Ok(Resp::Null),
+    }
+}
+
+fn instantiate(
+    compiled: &Compiled,
+    bridge: Bridge,
+    pending: Vec<u8>,
+) -> Result<(Store<Host>, Instance), wasmi::Error> {
+    let host = Host {
+        bridge,
+        pending,
+        reply: None,
+    };
+    let mut store = Store::new(&compiled.engine, host);
+    store.set_fuel(compiled.fuel)?;
+    let mut linker = Linker::<Host>::new(&compiled.engine);
+    linker
+        .func_wrap("redis", "read", read)?
+        .func_wrap("redis", "call", call_command)?
+        .func_wrap("redis", "reply", reply)?;
+    let instance = linker
+        .instantiate(&mut store, &compiled.module)?
+        .start(&mut store)?;
+    Ok((store, instance))
+}
+
+fn read(mut caller: Caller<'_, Host>, ptr: i32) -> Result<(), wasmi::Error> {
+    let memory = memory(&caller)?;
+    let pending = std::mem::take(&mut caller.data_mut().pending);
+    memory.write(&mut caller, ptr as u32 as usize, &pending)?;
+    caller.data_mut().pending = pending;
+    Ok(())
+}
+
+fn call_command(mut caller: Caller<'_, Host>, ptr: i32, len: i32) -> Result<i32, wasmi::Error> {
+    let args = match guest_resp(&caller, ptr, len)? {
+        Resp::Array(items) if !items.is_empty() => items
+            .into_iter()
+            .map(|item| match item {
+                Resp::Bulk(Some(arg)) => Some(arg),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>(),
+        _ => None,
+    };
+    let args = args.ok_or_else(|| wasmi::Error::new("commands are arrays of bulk strings"))?;
+    let reply = scripting::send(&caller.data().bridge, args).unwrap_or_else(|e| e.to_resp());
+    let pending = reply.encode();
+    let len = pending.len() as i32;
+    caller.data_mut().pending = pending;
+    Ok(len)
+}
+
+fn reply(mut caller: Caller<'_, Host>, ptr: i32, len: i32) -> Result<(), wasmi::Error> {
+    let reply = guest_resp(&caller, ptr, len)?;
+    caller.data_mut().reply = Some(reply);
+    Ok(())
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("the module exports no memory"))
+}
+
+// A RESP value the module wrote to its memory.
+fn guest_resp(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<Resp, wasmi::Error> {
+    let memory = memory(caller)?;
+    let start = ptr as u32 as usize;
+    let bytes = memory
+        .data(caller)
+        .get(start..start + len as u32 as usize)
+        .ok_or_else(|| wasmi::Error::new("out of bounds memory access"))?;
+    let (resp, _) = readnext_resp(&Bytes::copy_from_slice(bytes))
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripting::Called;
+
+    // `echo` replies with what ECHO hi does, and `input` with its input.
+    const LIBRARY: &str = r#"(module
+  (import "redis" "read" (func $read (param i32)))
+  (import "redis" "call" (func $call (param i32 i32) (result i32)))
+  (import "redis" "reply" (func $reply (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n")
+  (func (export "echo") (param $len i32) (local $n i32)
+    (local.set $n (call $call (i32.const 0) (i32.const 22)))
+    (call $read (i32.const 1024))
+    (call $reply (i32.const 1024) (local.get $n)))
+  (func (export "input") (param $len i32)
+    (call $read (i32.const 1024))
+    (call $reply (i32.const 1024) (local.get $len)))
+  (func (export "helper") (result i32) (i32.const 0)))"#;
+
+    fn fcall(compiled: &Compiled, function: &str) -> Result<Resp, CommandError> {
+        let (bridge, mut calls) = mpsc::unbounded_channel::<Called>();
+        std::thread::spawn(move || {
+            while let Some(Called { args, reply }) = calls.blocking_recv() {
+                let _ = reply.send(Ok(Resp::Bulk(args.get(1).cloned())));
+            }
+        });
+        let fcall = Fcall {
+            function: function.into(),
+            keys: vec!["k".into()],
+            args: vec!["a".into()],
+            read_only: false,
+        };
+        call(compiled, &fcall, bridge)
+    }
+
+    #[test]
+    fn test_modules_export_functions_that_call_commands() {
+        let compiled = compile(LIBRARY).unwrap();
+        let names: Vec<_> = register(&compiled)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, ["echo", "input"]);
+        assert_eq!(fcall(&compiled, "echo").unwrap(), Resp::bulk("hi"));
+        assert_eq!(
+            fcall(&compiled, "input").unwrap(),
+            Resp::Array(vec![Resp::array(["k"]), Resp::array(["a"])])
+        );
+        let helper = fcall(&compiled, "helper").unwrap_err();
+        assert!(helper.to_string().starts_with("ERR "));
+        assert!(compile("(module").is_err());
+        let unknown = compile(r#"(module (import "redis" "other" (func)))"#).unwrap();
+        assert!(register(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_functions_that_never_return_run_out_of_fuel() {
+        let spin = r#"(module (func (export "spin") (param i32) (loop $l (br $l))))"#;
+        let mut compiled = compile(spin).unwrap();
+        compiled.fuel = 10_000;
+        let err = fcall(&compiled, "spin").unwrap_err().to_string();
+        assert_eq!(err, "ERR ran out of fuel script: spin");
+    }
+}